@@ -0,0 +1,43 @@
+use std::net::Ipv4Addr;
+
+const ARP_TABLE: &str = "/proc/net/arp";
+// ATF_COM, the entry has a resolved hardware address
+const COMPLETE_ENTRY: u32 = 0x2;
+
+// The address the host's neighbour table has for `mac`, e.g. "10.0.0.3" once the guest talked to
+// the bridge. Incomplete and stale-mac entries are skipped.
+fn find_ip(table: &str, mac: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let columns = line.split_whitespace().collect::<Vec<_>>();
+        let [ip, _, flags, hw_address, ..] = columns.as_slice() else {
+            return None;
+        };
+        let flags = u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok()?;
+        (flags & COMPLETE_ENTRY != 0 && hw_address.eq_ignore_ascii_case(mac))
+            .then(|| ip.parse().ok())
+            .flatten()
+    })
+}
+
+// The address the guest with this mac actually uses, as far as the host has seen it
+pub(crate) fn guest_ip(mac: &str) -> Option<Ipv4Addr> {
+    find_ip(&std::fs::read_to_string(ARP_TABLE).ok()?, mac)
+}
+
+#[test]
+fn arp_table() {
+    let table = "IP address       HW type     Flags       HW address            Mask     Device\n\
+                 10.0.0.7         0x1         0x0         52:54:00:00:00:03     *        tbr0\n\
+                 10.0.0.3         0x1         0x2         52:54:00:00:00:03     *        tbr0\n\
+                 10.0.0.4         0x1         0x2         52:54:00:00:00:04     *        tbr0\n";
+    assert_eq!(
+        find_ip(table, "52:54:00:00:00:03"),
+        Some(Ipv4Addr::new(10, 0, 0, 3))
+    );
+    assert_eq!(
+        find_ip(table, "52:54:00:00:00:04".to_uppercase().as_str()),
+        Some(Ipv4Addr::new(10, 0, 0, 4))
+    );
+    assert_eq!(find_ip(table, "52:54:00:00:00:05"), None);
+    assert_eq!(find_ip("", "52:54:00:00:00:03"), None);
+}
@@ -0,0 +1,116 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use serde::Serialize;
+use thiserror::Error;
+use tracing::info;
+
+use crate::qemu::QemuFirmwareConfig;
+use crate::shell::{run_shell_command_in_dir, ShellError};
+
+const BUILD_SPEC_FILE: &str = "build-spec.json";
+const BUILT_IMAGE_FILE: &str = "image";
+
+#[derive(Error, Debug)]
+pub(crate) enum BuildError {
+    #[error("Image build command failed")]
+    Shell(#[source] ShellError),
+    #[error("IO Error when: {1}")]
+    IO(#[source] std::io::Error, &'static str),
+    #[error("Build command did not write the image to {0:?}")]
+    MissingImage(PathBuf),
+}
+
+// What a vm boots from
+#[derive(Debug)]
+pub(crate) struct BuiltImage {
+    pub(crate) image_path: PathBuf,
+    // passed with -fw_cfg, e.g. the ignition config of a flatcar worker
+    pub(crate) firmware: Vec<QemuFirmwareConfig>,
+}
+
+pub(crate) type BuildFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<BuiltImage, BuildError>> + Send + 'a>>;
+
+// Builds the image of a single vm into its vm dir. `S` describes the image, e.g. the butane config
+// of a flatcar worker. Butane and ops are the defaults, other pipelines plug in here and are
+// selected per worker.
+pub(crate) trait ImageBuilder<S>: std::fmt::Debug + Send + Sync {
+    fn build<'a>(&'a self, spec: &'a S, vm_dir: &'a Path) -> BuildFuture<'a>;
+}
+
+// A host command which builds the image, e.g. mkosi or a script fetching a prebuilt image. It
+// runs as `sh -c <command>` in the vm dir with the environment
+//   VML_BUILD_SPEC  json file with the spec of the image
+//   VML_VM_DIR      the vm dir
+//   VML_IMAGE       where the image has to be written
+// Anything the default builder passes as firmware has to be baked into the image.
+#[derive(Debug, Clone)]
+pub(crate) struct CommandBuilder {
+    pub(crate) command: String,
+}
+
+impl<S: Serialize + Sync> ImageBuilder<S> for CommandBuilder {
+    fn build<'a>(&'a self, spec: &'a S, vm_dir: &'a Path) -> BuildFuture<'a> {
+        Box::pin(async move {
+            let spec_path = vm_dir.join(BUILD_SPEC_FILE);
+            let image_path = vm_dir.join(BUILT_IMAGE_FILE);
+            std::fs::write(&spec_path, serde_json::to_string(spec).unwrap())
+                .map_err(|e| BuildError::IO(e, "writing the build spec"))?;
+            info!(command = self.command, dir = ?vm_dir, "Building image");
+            run_shell_command_in_dir(
+                "sh",
+                &vec!["-c", &self.command],
+                vec![
+                    ("VML_BUILD_SPEC", spec_path.to_str().unwrap()),
+                    ("VML_VM_DIR", vm_dir.to_str().unwrap()),
+                    ("VML_IMAGE", image_path.to_str().unwrap()),
+                ],
+                Some(vm_dir),
+            )
+            .await
+            .map_err(BuildError::Shell)?;
+            if !image_path.is_file() {
+                return Err(BuildError::MissingImage(image_path));
+            }
+            Ok(BuiltImage {
+                image_path,
+                firmware: vec![],
+            })
+        })
+    }
+}
+
+#[test]
+fn command_builder() {
+    use async_std::task::block_on;
+    let dir = tempdir::TempDir::new("builder").unwrap();
+    let spec = serde_json::json!({"image": "mkosi"});
+    let builder = |command: &str| CommandBuilder {
+        command: command.to_string(),
+    };
+
+    let built = block_on(builder("cp \"$VML_BUILD_SPEC\" image").build(&spec, dir.path())).unwrap();
+    assert_eq!(built.image_path, dir.path().join(BUILT_IMAGE_FILE));
+    assert_eq!(
+        std::fs::read_to_string(&built.image_path).unwrap(),
+        r#"{"image":"mkosi"}"#
+    );
+    std::fs::remove_file(&built.image_path).unwrap();
+
+    // the command runs in the vm directory, whatever characters its path contains
+    let quoted = dir.path().join("it's a dir");
+    std::fs::create_dir(&quoted).unwrap();
+    let built = block_on(builder("touch image").build(&spec, &quoted)).unwrap();
+    assert!(built.image_path.is_file());
+
+    assert!(matches!(
+        block_on(builder("true").build(&spec, dir.path())),
+        Err(BuildError::MissingImage(_))
+    ));
+    assert!(matches!(
+        block_on(builder("exit 1").build(&spec, dir.path())),
+        Err(BuildError::Shell(_))
+    ));
+}
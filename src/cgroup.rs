@@ -0,0 +1,137 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use thiserror::Error;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const LAUNCHER_SLICE: &str = "vmlauncher.slice";
+// cpu.max quota is given per period, 100ms is the kernel's default period
+const CPU_PERIOD_US: usize = 100_000;
+
+#[derive(Error, Debug)]
+pub(crate) enum CgroupError {
+    #[error("Could not {1} {2:?}")]
+    IO(#[source] std::io::Error, &'static str, PathBuf),
+    #[error("Invalid cpu limit: {0}")]
+    InvalidLimit(String),
+}
+
+// Caps the cpu time of a whole vm, independent of the number of vcpus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CpuLimit {
+    // percent of a single core, e.g. 150 for one and a half cores
+    pub(crate) max_percent: Option<usize>,
+    // relative share under contention, 1 to 10000 (default 100)
+    pub(crate) weight: Option<usize>,
+}
+
+impl CpuLimit {
+    fn validate(&self) -> Result<(), CgroupError> {
+        if self.max_percent == Some(0) {
+            return Err(CgroupError::InvalidLimit(
+                "cpuMaxPercent has to be positive".to_string(),
+            ));
+        }
+        if let Some(weight) = self.weight.filter(|w| !(1..=10000).contains(w)) {
+            return Err(CgroupError::InvalidLimit(format!(
+                "cpuWeight {weight} is not between 1 and 10000"
+            )));
+        }
+        Ok(())
+    }
+
+    // In the format of cpu.max, "<quota> <period>"
+    fn cpu_max(&self) -> String {
+        match self.max_percent {
+            Some(percent) => format!("{} {CPU_PERIOD_US}", percent * CPU_PERIOD_US / 100),
+            None => format!("max {CPU_PERIOD_US}"),
+        }
+    }
+}
+
+fn write(path: PathBuf, content: &str) -> Result<(), CgroupError> {
+    std::fs::write(&path, content).map_err(|e| CgroupError::IO(e, "write", path))
+}
+
+// cgroup v2 group of a single vm, `/sys/fs/cgroup/vmlauncher.slice/<name>`
+#[derive(Debug)]
+pub(crate) struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    pub(crate) fn create(name: &str, limit: &CpuLimit) -> Result<Self, CgroupError> {
+        Self::create_in(Path::new(CGROUP_ROOT), name, limit)
+    }
+
+    fn create_in(root: &Path, name: &str, limit: &CpuLimit) -> Result<Self, CgroupError> {
+        limit.validate()?;
+        let slice = root.join(LAUNCHER_SLICE);
+        std::fs::create_dir_all(&slice).map_err(|e| CgroupError::IO(e, "create", slice.clone()))?;
+        // the cpu controller has to be enabled on every level above the vm's group
+        write(root.join("cgroup.subtree_control"), "+cpu")?;
+        write(slice.join("cgroup.subtree_control"), "+cpu")?;
+
+        let path = slice.join(name);
+        match std::fs::create_dir(&path) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => {
+                return Err(CgroupError::IO(e, "create", path))
+            }
+            _ => {}
+        }
+        write(path.join("cpu.max"), &limit.cpu_max())?;
+        write(
+            path.join("cpu.weight"),
+            &limit.weight.unwrap_or(100).to_string(),
+        )?;
+        Ok(Cgroup { path })
+    }
+
+    // Moves the process with all of its threads
+    pub(crate) fn add_process(&self, pid: usize) -> Result<(), CgroupError> {
+        write(self.path.join("cgroup.procs"), &pid.to_string())
+    }
+
+    // Only succeeds once the vm has exited
+    pub(crate) fn remove(&self) -> Result<(), CgroupError> {
+        match std::fs::remove_dir(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(CgroupError::IO(e, "remove", self.path.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn cpu_limits() {
+    let root = tempdir::TempDir::new("cgroup").unwrap();
+    let limit = CpuLimit {
+        max_percent: Some(150),
+        weight: Some(200),
+    };
+    let cgroup = Cgroup::create_in(root.path(), "nes-tap1", &limit).unwrap();
+    cgroup.add_process(42).unwrap();
+
+    let read = |file: &str| std::fs::read_to_string(cgroup.path.join(file)).unwrap();
+    assert_eq!(read("cpu.max"), "150000 100000");
+    assert_eq!(read("cpu.weight"), "200");
+    assert_eq!(read("cgroup.procs"), "42");
+
+    let unlimited = CpuLimit {
+        max_percent: None,
+        weight: None,
+    };
+    assert_eq!(unlimited.cpu_max(), "max 100000");
+    assert!(Cgroup::create_in(
+        root.path(),
+        "nes-tap2",
+        &CpuLimit {
+            weight: Some(0),
+            ..unlimited
+        }
+    )
+    .is_err());
+}
@@ -0,0 +1,241 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+use tracing::warn;
+
+const ISOLATED_CPUS: &str = "/sys/devices/system/cpu/isolated";
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+#[derive(Error, Debug)]
+pub(crate) enum CpuError {
+    #[error("Could not read {1}")]
+    IO(#[source] std::io::Error, &'static str),
+    #[error("Invalid cpu list: {0}")]
+    InvalidCpuList(String),
+    #[error("The host has no isolated cpus, boot it with isolcpus=")]
+    NoIsolatedCpus,
+    #[error("Not enough isolated cpus left for {0} cores, only {1} are free")]
+    Exhausted(usize, usize),
+    #[error("Invalid vcpu overcommit ratio {0}, it has to be positive")]
+    InvalidRatio(f64),
+    #[error("{0} vcpus on {1} host cpus is an overcommit of {3:.2}, more than the allowed {2}")]
+    Overcommitted(usize, usize, f64, f64),
+}
+
+#[derive(Debug)]
+struct CpuPool {
+    cpus: Vec<usize>,
+    free: BTreeSet<usize>,
+    // index into `cpus` where the next assignment starts looking
+    next: usize,
+}
+
+// Hands out the host's isolated cpus round-robin, each cpu is used by at most one vm
+#[derive(Debug, Clone)]
+pub(crate) struct IsolatedCpus {
+    pool: Arc<Mutex<CpuPool>>,
+}
+
+// Returns its cpus to the pool when dropped
+#[derive(Debug)]
+pub(crate) struct CpuAssignment {
+    cpus: Vec<usize>,
+    // none for cpus picked by the script, e.g. the cores of one numa node
+    pool: Option<Arc<Mutex<CpuPool>>>,
+}
+
+impl CpuAssignment {
+    // Bypasses --use-isolated-cpus, the cpus may be shared with other vms
+    pub(crate) fn fixed(cpus: Vec<usize>) -> Self {
+        CpuAssignment { cpus, pool: None }
+    }
+
+    // In the format taskset expects, e.g. "2,3,7"
+    pub(crate) fn cpu_list(&self) -> String {
+        self.cpus
+            .iter()
+            .map(|cpu| cpu.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl Drop for CpuAssignment {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.as_ref() {
+            pool.lock().unwrap().free.extend(self.cpus.iter());
+        }
+    }
+}
+
+impl IsolatedCpus {
+    pub(crate) fn from_sysfs() -> Result<Self, CpuError> {
+        let list =
+            std::fs::read_to_string(ISOLATED_CPUS).map_err(|e| CpuError::IO(e, ISOLATED_CPUS))?;
+        let cpus = parse_cpu_list(&list)?;
+        if cpus.is_empty() {
+            return Err(CpuError::NoIsolatedCpus);
+        }
+        Ok(Self::new(cpus))
+    }
+
+    fn new(cpus: Vec<usize>) -> Self {
+        IsolatedCpus {
+            pool: Arc::new(Mutex::new(CpuPool {
+                free: cpus.iter().cloned().collect(),
+                cpus,
+                next: 0,
+            })),
+        }
+    }
+
+    pub(crate) fn assign(&self, cores: usize) -> Result<CpuAssignment, CpuError> {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.free.len() < cores {
+            return Err(CpuError::Exhausted(cores, pool.free.len()));
+        }
+
+        let mut cpus = Vec::with_capacity(cores);
+        let mut index = pool.next;
+        while cpus.len() < cores {
+            let cpu = pool.cpus[index % pool.cpus.len()];
+            if pool.free.remove(&cpu) {
+                cpus.push(cpu);
+            }
+            index += 1;
+        }
+        pool.next = index % pool.cpus.len();
+
+        Ok(CpuAssignment {
+            cpus,
+            pool: Some(self.pool.clone()),
+        })
+    }
+}
+
+// Keeps the vcpus of all running vms below `ratio` times the host's online cpus
+#[derive(Debug, Clone)]
+pub(crate) struct VcpuBudget {
+    host_cpus: usize,
+    ratio: f64,
+    // exceeding the ratio only warns
+    force: bool,
+    assigned: Arc<Mutex<usize>>,
+}
+
+// Gives its vcpus back to the budget when dropped
+#[derive(Debug)]
+pub(crate) struct VcpuReservation {
+    vcpus: usize,
+    assigned: Arc<Mutex<usize>>,
+}
+
+impl Drop for VcpuReservation {
+    fn drop(&mut self) {
+        *self.assigned.lock().unwrap() -= self.vcpus;
+    }
+}
+
+impl VcpuBudget {
+    pub(crate) fn from_sysfs(ratio: f64, force: bool) -> Result<Self, CpuError> {
+        let list =
+            std::fs::read_to_string(ONLINE_CPUS).map_err(|e| CpuError::IO(e, ONLINE_CPUS))?;
+        Self::new(parse_cpu_list(&list)?.len(), ratio, force)
+    }
+
+    fn new(host_cpus: usize, ratio: f64, force: bool) -> Result<Self, CpuError> {
+        if !ratio.is_finite() || ratio <= 0.0 {
+            return Err(CpuError::InvalidRatio(ratio));
+        }
+        Ok(VcpuBudget {
+            host_cpus,
+            ratio,
+            force,
+            assigned: Arc::new(Mutex::new(0)),
+        })
+    }
+
+    pub(crate) fn reserve(&self, vcpus: usize) -> Result<VcpuReservation, CpuError> {
+        let mut assigned = self.assigned.lock().unwrap();
+        let total = *assigned + vcpus;
+        let current = total as f64 / self.host_cpus.max(1) as f64;
+        if current > self.ratio {
+            let e = CpuError::Overcommitted(total, self.host_cpus, self.ratio, current);
+            if !self.force {
+                return Err(e);
+            }
+            warn!(%e, "Overcommitting vcpus");
+        }
+        *assigned = total;
+        Ok(VcpuReservation {
+            vcpus,
+            assigned: self.assigned.clone(),
+        })
+    }
+}
+
+// Parses the kernel's cpu list format, e.g. "1-3,8,10-11". Empty if no cpu is listed.
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>, CpuError> {
+    let invalid = || CpuError::InvalidCpuList(list.trim().to_string());
+    let mut cpus = vec![];
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let start = start.parse::<usize>().map_err(|_| invalid())?;
+                let end = end.parse::<usize>().map_err(|_| invalid())?;
+                if end < start {
+                    return Err(invalid());
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(range.parse().map_err(|_| invalid())?),
+        }
+    }
+    Ok(cpus)
+}
+
+#[test]
+fn isolated_cpu_assignment() {
+    assert_eq!(parse_cpu_list("\n").unwrap(), Vec::<usize>::new());
+    assert_eq!(
+        parse_cpu_list("2-4,8,10-11\n").unwrap(),
+        vec![2, 3, 4, 8, 10, 11]
+    );
+    assert!(parse_cpu_list("4-2").is_err());
+
+    let pool = IsolatedCpus::new(vec![2, 3, 4, 8]);
+    let first = pool.assign(2).unwrap();
+    let second = pool.assign(1).unwrap();
+    assert_eq!(first.cpu_list(), "2,3");
+    assert_eq!(second.cpu_list(), "4");
+    assert!(pool.assign(2).is_err());
+
+    // released cpus are reused once the round-robin wraps around
+    drop(first);
+    assert_eq!(pool.assign(2).unwrap().cpu_list(), "8,2");
+
+    // fixed cpus never end up in the pool
+    drop(CpuAssignment::fixed(vec![0, 9]));
+    assert!(matches!(pool.assign(4), Err(CpuError::Exhausted(4, 3))));
+}
+
+#[test]
+fn vcpu_overcommit() {
+    assert!(VcpuBudget::new(4, 0.0, false).is_err());
+
+    let budget = VcpuBudget::new(4, 1.5, false).unwrap();
+    let first = budget.reserve(4).unwrap();
+    let _second = budget.reserve(2).unwrap();
+    let Err(CpuError::Overcommitted(total, 4, _, current)) = budget.reserve(1) else {
+        panic!("expected the overcommit to be refused");
+    };
+    assert_eq!((total, current), (7, 1.75));
+
+    // stopped vms free their vcpus
+    drop(first);
+    let _third = budget.reserve(4).unwrap();
+
+    let forced = VcpuBudget::new(4, 1.0, true).unwrap();
+    let _all = forced.reserve(16).unwrap();
+}
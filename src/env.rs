@@ -0,0 +1,227 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+use crate::qemu::QEMU_BINARY;
+use crate::shell::run_shell_command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Version(u32, u32, u32);
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+struct Tool {
+    binary: Cow<'static, str>,
+    version_args: &'static [&'static str],
+    // oldest version which works, and what breaks before it
+    minimum: Option<(Version, &'static str)>,
+    purpose: &'static str,
+}
+
+const QEMU: Tool = Tool {
+    binary: Cow::Borrowed(QEMU_BINARY),
+    version_args: &["--version"],
+    minimum: Some((
+        Version(2, 11, 0),
+        "-sandbox elevateprivileges/resourcecontrol",
+    )),
+    purpose: "runs every vm",
+};
+const DOCKER: Tool = Tool {
+    binary: Cow::Borrowed("docker"),
+    version_args: &["--version"],
+    minimum: None,
+    purpose: "runs butane for worker ignition configs",
+};
+const OPS: Tool = Tool {
+    binary: Cow::Borrowed("ops"),
+    version_args: &["version"],
+    minimum: None,
+    purpose: "builds unikernel images",
+};
+const SWTPM: Tool = Tool {
+    binary: Cow::Borrowed("swtpm"),
+    version_args: &["--version"],
+    minimum: Some((Version(0, 2, 0), "--tpm2 socket mode")),
+    purpose: "emulated TPM (--tpm)",
+};
+const TASKSET: Tool = Tool {
+    binary: Cow::Borrowed("taskset"),
+    version_args: &["--version"],
+    minimum: None,
+    purpose: "cpu pinning (--use-isolated-cpus)",
+};
+const SHA256SUM: Tool = Tool {
+    binary: Cow::Borrowed("sha256sum"),
+    version_args: &["--version"],
+    minimum: None,
+    purpose: "verifies the flatcar image (--image-sha256)",
+};
+const CURL: Tool = Tool {
+    binary: Cow::Borrowed("curl"),
+    version_args: &["--version"],
+    minimum: None,
+    purpose: "downloads http(s) flatcar images",
+};
+const AWS: Tool = Tool {
+    binary: Cow::Borrowed("aws"),
+    version_args: &["--version"],
+    minimum: None,
+    purpose: "downloads s3 flatcar images",
+};
+
+// What the launch is going to use, decides which tools are required
+#[derive(Debug)]
+pub(crate) struct Backends {
+    pub(crate) workers: bool,
+    pub(crate) unikernels: bool,
+    pub(crate) tpm: bool,
+    pub(crate) isolated_cpus: bool,
+    pub(crate) image_checksum: bool,
+    // scheme of the flatcar image url, if it is not a local path
+    pub(crate) image_scheme: Option<String>,
+    // --qemu-binary, instead of qemu-system-x86_64 from PATH
+    pub(crate) qemu_binary: Option<PathBuf>,
+}
+
+impl Backends {
+    fn tools(&self) -> Vec<(Tool, bool)> {
+        let scheme = self.image_scheme.as_deref().filter(|_| self.workers);
+        let qemu = match self.qemu_binary.as_ref() {
+            Some(path) => Tool {
+                binary: path.to_string_lossy().into_owned().into(),
+                ..QEMU
+            },
+            None => QEMU,
+        };
+        vec![
+            (qemu, true),
+            (DOCKER, self.workers),
+            (OPS, self.unikernels),
+            (SWTPM, self.workers && self.tpm),
+            (TASKSET, self.isolated_cpus),
+            (SHA256SUM, self.workers && self.image_checksum),
+            (CURL, matches!(scheme, Some("http") | Some("https"))),
+            (AWS, scheme == Some("s3")),
+        ]
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ToolStatus {
+    Found(PathBuf, Option<Version>),
+    TooOld(Version, Version, &'static str),
+    Missing,
+}
+
+pub(crate) struct ToolReport {
+    binary: Cow<'static, str>,
+    purpose: &'static str,
+    required: bool,
+    status: ToolStatus,
+}
+
+impl ToolReport {
+    // Missing or too old tools are only a problem if they are required
+    pub(crate) fn is_ok(&self) -> bool {
+        !self.required || matches!(self.status, ToolStatus::Found(..))
+    }
+}
+
+impl Display for ToolReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let marker = match (self.is_ok(), self.required) {
+            (false, _) => "FAIL",
+            (true, true) => "ok",
+            (true, false) => "-",
+        };
+        let status = match &self.status {
+            ToolStatus::Found(path, Some(version)) => format!("{version} ({})", path.display()),
+            ToolStatus::Found(path, None) => format!("unknown version ({})", path.display()),
+            ToolStatus::TooOld(version, minimum, reason) => {
+                format!("{version} is too old, {minimum} is needed for {reason}")
+            }
+            ToolStatus::Missing => "not installed".to_string(),
+        };
+        write!(
+            f,
+            "{marker:<4} {:<20} {status:<50} {}",
+            self.binary, self.purpose
+        )
+    }
+}
+
+// First dotted number in the output, e.g. "QEMU emulator version 8.2.2 (Debian ...)"
+fn parse_version(output: &str) -> Option<Version> {
+    output
+        .split(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .map(|word| word.trim_matches('.'))
+        .find(|word| word.contains('.'))
+        .and_then(|word| {
+            let mut parts = word.split('.').map(|part| part.parse::<u32>());
+            let major = parts.next()?.ok()?;
+            let minor = parts.next()?.ok()?;
+            let patch = parts.next().and_then(|p| p.ok()).unwrap_or(0);
+            Some(Version(major, minor, patch))
+        })
+}
+
+async fn probe(tool: &Tool) -> ToolStatus {
+    let Ok(path) = which::which(tool.binary.as_ref()) else {
+        return ToolStatus::Missing;
+    };
+    let version = run_shell_command(&tool.binary, &tool.version_args.to_vec())
+        .await
+        .ok()
+        .and_then(|output| parse_version(&output));
+    match (version, tool.minimum) {
+        (Some(version), Some((minimum, reason))) if version < minimum => {
+            ToolStatus::TooOld(version, minimum, reason)
+        }
+        _ => ToolStatus::Found(path, version),
+    }
+}
+
+pub(crate) async fn validate_env(backends: &Backends) -> Vec<ToolReport> {
+    let mut reports = vec![];
+    for (tool, required) in backends.tools() {
+        let status = probe(&tool).await;
+        reports.push(ToolReport {
+            binary: tool.binary,
+            purpose: tool.purpose,
+            required,
+            status,
+        });
+    }
+    reports
+}
+
+#[test]
+fn tool_versions() {
+    assert_eq!(
+        parse_version("QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)\n"),
+        Some(Version(8, 2, 2))
+    );
+    assert_eq!(
+        parse_version("Docker version 24.0.7, build afdd53b"),
+        Some(Version(24, 0, 7))
+    );
+    assert_eq!(
+        parse_version("Ops version: 0.1.40\nNanos version: 0.1.49"),
+        Some(Version(0, 1, 40))
+    );
+    assert_eq!(
+        parse_version("aws-cli/2.15.30 Python/3.11.8"),
+        Some(Version(2, 15, 30))
+    );
+    assert_eq!(
+        parse_version("taskset from util-linux 2.39"),
+        Some(Version(2, 39, 0))
+    );
+    assert_eq!(parse_version("no version here"), None);
+    assert!(Version(2, 10, 1) < Version(2, 11, 0));
+}
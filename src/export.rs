@@ -0,0 +1,118 @@
+use std::io::Write;
+use std::path::Path;
+
+use clap::ValueEnum;
+use itertools::Itertools;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::forward::ForwardedPort;
+use crate::profile::ResourceProfile;
+
+#[derive(Error, Debug)]
+pub(crate) enum ExportError {
+    #[error("Could not write export file")]
+    IO(#[source] std::io::Error),
+    #[error("Could not serialize topology")]
+    Json(#[source] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum ExportFormat {
+    Csv,
+    Json,
+}
+
+// Everything needed to correlate results with the topology they were produced on
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct InstanceRecord {
+    pub(crate) id: usize,
+    pub(crate) kind: &'static str,
+    pub(crate) parent_id: Option<usize>,
+    pub(crate) ip: String,
+    pub(crate) mac: String,
+    pub(crate) tap: String,
+    pub(crate) ports: Vec<u16>,
+    pub(crate) resources: Option<ResourceProfile>,
+    pub(crate) host_ports: Vec<ForwardedPort>,
+}
+
+const CSV_HEADER: &str =
+    "id,kind,parentId,ip,mac,tap,ports,memoryInMegabytes,numberOfWorkerThreads,\
+bufferSize,totalNumberOfBuffers,numberOfSourceBuffers,numberOfBuffersPerThread,hostPorts";
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn to_csv(records: &[InstanceRecord]) -> String {
+    let mut csv = format!("{CSV_HEADER}\n");
+    for r in records {
+        let resources = r.resources.clone().unwrap_or_default();
+        let row = [
+            r.id.to_string(),
+            r.kind.to_string(),
+            optional(r.parent_id),
+            r.ip.clone(),
+            r.mac.clone(),
+            r.tap.clone(),
+            // ';' separated, so the column count stays fixed
+            r.ports.iter().join(";"),
+            optional(resources.memory_in_megabytes),
+            optional(resources.number_of_worker_threads),
+            optional(resources.buffer_size),
+            optional(resources.total_number_of_buffers),
+            optional(resources.number_of_source_buffers),
+            optional(resources.number_of_buffers_per_thread),
+            r.host_ports.iter().join(";"),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+pub(crate) fn export(
+    records: &[InstanceRecord],
+    format: ExportFormat,
+    out: &Path,
+) -> Result<(), ExportError> {
+    let content = match format {
+        ExportFormat::Csv => to_csv(records),
+        ExportFormat::Json => serde_json::to_string_pretty(records).map_err(ExportError::Json)?,
+    };
+    std::fs::File::create(out)
+        .and_then(|mut f| f.write_all(content.as_bytes()))
+        .map_err(ExportError::IO)
+}
+
+#[test]
+fn csv_export() {
+    let records = [InstanceRecord {
+        id: 2,
+        kind: "worker",
+        parent_id: Some(1),
+        ip: "10.0.0.2".to_string(),
+        mac: "00:60:2F:01:02:03".to_string(),
+        tap: "tap1".to_string(),
+        ports: vec![8071, 8072],
+        resources: Some(ResourceProfile {
+            number_of_worker_threads: Some(4),
+            ..Default::default()
+        }),
+        host_ports: vec![ForwardedPort {
+            host: 20000,
+            guest: 8432,
+        }],
+    }];
+
+    let csv = to_csv(&records);
+    let lines = csv.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(
+        lines[1],
+        "2,worker,1,10.0.0.2,00:60:2F:01:02:03,tap1,8071;8072,,4,,,,,20000->8432"
+    );
+    assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+}
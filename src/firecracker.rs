@@ -1,27 +1,40 @@
-use async_std::io::WriteExt;
-use async_std::os::unix::net::UnixStream;
 use std::fmt::{Display, Formatter};
 use std::io::ErrorKind;
-use std::num::ParseIntError;
-use std::path::PathBuf;
-use std::str::{from_utf8, Utf8Error};
-use std::time::Duration;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use async_std::io::{ReadExt, WriteExt};
+use async_std::os::unix::net::UnixStream;
 use async_std::task;
-use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
 use serde::Serialize;
-use tempdir::TempDir;
-use tracing::instrument;
+use thiserror::Error;
+use tracing::{error, info, instrument, warn};
 
 use crate::network::TapUser;
-use crate::shell;
-use crate::shell::{run_command_without_output, run_shell_command, ShellError};
+use crate::qemu::{self, StopTimeouts, VmStatus};
+use crate::rundir::VmDir;
+use crate::vm::{VmError, VmFuture, VmHandle};
+
+const FIRECRACKER_BINARY: &str = "firecracker";
+const API_SOCKET: &str = "firecracker.socket";
+const CONFIG_FILE: &str = "firecracker-config.json";
+const LOG_FILE: &str = "firecracker.log";
+const DEFAULT_MEMORY_IN_MEGABYTES: usize = 512;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const KILL_TIMEOUT: Duration = Duration::from_secs(2);
 
-const FIRECRACKER_BINARY: &'static str = "/home/lukas-ldap/.local/bin/firecracker";
 #[derive(Serialize, Debug)]
 struct BootSource {
-    kernel_image_path: String,
-    boot_args: String,
+    kernel_image_path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    boot_args: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -34,7 +47,7 @@ struct NetworkInterface {
 #[derive(Serialize, Debug)]
 struct Drive {
     drive_id: String,
-    path_on_host: String,
+    path_on_host: PathBuf,
     is_root_device: bool,
     is_read_only: bool,
 }
@@ -43,9 +56,10 @@ struct Drive {
 struct MachineConfig {
     vcpu_count: usize,
     mem_size_mib: usize,
-    ht_enabled: bool,
+    smt: bool,
 }
 
+// The --config-file firecracker boots from, the same as configuring it over the api
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 struct VMConfig {
@@ -59,200 +73,446 @@ impl VMConfig {
     fn new(lc: &LaunchConfiguration) -> Self {
         VMConfig {
             boot_source: BootSource {
-                kernel_image_path: lc.image_path.to_str().unwrap().to_string(),
-                boot_args: "".to_string(),
+                kernel_image_path: lc.kernel.clone(),
+                boot_args: lc.boot_args.clone(),
             },
-            drives: vec![],
+            // nanos finds its root filesystem on the first disk
+            drives: vec![Drive {
+                drive_id: "rootfs".to_string(),
+                path_on_host: lc.image_path.clone(),
+                is_root_device: false,
+                is_read_only: false,
+            }],
             network_interfaces: vec![NetworkInterface {
-                iface_id: "en1".to_string(),
+                iface_id: "eth0".to_string(),
                 guest_mac: lc.tap.mac().to_string(),
-                host_dev_name: lc.tap.device().to_string(),
+                host_dev_name: lc.tap.device(),
             }],
             machine_config: MachineConfig {
-                vcpu_count: lc.num_cores.unwrap(),
-                mem_size_mib: lc.memory_in_mega_bytes.unwrap(),
-                ht_enabled: true,
+                vcpu_count: lc.num_cores.unwrap_or(1),
+                mem_size_mib: lc
+                    .memory_in_mega_bytes
+                    .unwrap_or(DEFAULT_MEMORY_IN_MEGABYTES),
+                smt: false,
             },
         }
     }
 }
 
+// A unikernel booted by firecracker: nanos' kernel with the unikernel image as its disk.
+// Firecracker has no firmware, 9p shares or monitor, only the basics of a qemu launch carry over.
 #[derive(Debug)]
-pub struct LaunchConfiguration<'nc> {
-    pub(crate) tap: TapUser<'nc>,
+pub(crate) struct LaunchConfiguration {
+    pub(crate) tap: TapUser,
+    // uncompressed kernel, e.g. ~/.ops/<version>/kernel.img for nanos
+    pub(crate) kernel: PathBuf,
     pub(crate) image_path: PathBuf,
-    pub(crate) temp_dir: TempDir,
+    pub(crate) boot_args: Option<String>,
+    pub(crate) vm_dir: VmDir,
     pub(crate) num_cores: Option<usize>,
     pub(crate) memory_in_mega_bytes: Option<usize>,
 }
 
-#[derive(Debug)]
-pub struct FirecrackerProcessHandle<'nc> {
-    lc: Option<LaunchConfiguration<'nc>>,
+impl LaunchConfiguration {
+    // What firecracker can boot of a qemu launch, anything it can't is refused
+    pub(crate) fn from_qemu(lc: qemu::LaunchConfiguration, kernel: PathBuf) -> Result<Self> {
+        if lc.output.is_some() {
+            return Err(FirecrackerError::Unsupported("output directories"));
+        }
+        if lc.gdb.is_some() {
+            return Err(FirecrackerError::Unsupported("gdb stubs"));
+        }
+        if lc.nic_queues > 1 {
+            return Err(FirecrackerError::Unsupported(
+                "multiqueue network interfaces",
+            ));
+        }
+        let qemu::BootSource::Image(image_path) = lc.boot else {
+            return Err(FirecrackerError::Unsupported(
+                "booting a kernel without an image",
+            ));
+        };
+        Ok(LaunchConfiguration {
+            tap: lc.tap,
+            kernel,
+            image_path,
+            boot_args: None,
+            vm_dir: lc.vm_dir,
+            num_cores: lc.num_cores,
+            memory_in_mega_bytes: lc.memory_in_mega_bytes,
+        })
+    }
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum FirecrackerError {
-    #[error("While executing shell command")]
-    Shell(#[source] ShellError),
-    #[error("Could not stop VM")]
-    CouldNotKill(&'static str),
-    #[error("While performing IO: {1}")]
+#[derive(Error, Debug)]
+pub(crate) enum FirecrackerError {
+    #[error("IO Error when: {1}")]
     IO(#[source] std::io::Error, &'static str),
-    #[error("VM is not running")]
-    NotRunning(),
-    #[error("Could not locate pidfile")]
-    PidFileNonUtf(#[source] Utf8Error),
-    #[error("Pidfile contains garbage")]
-    PidFileNonNumeric(#[source] ParseIntError),
+    #[error("Firecracker can not boot vms with {0}")]
+    Unsupported(&'static str),
+    #[error("Firecracker failed to start ({0}), see {1:?} for its output")]
+    StartupFailed(ExitStatus, PathBuf),
+    #[error("Firecracker did not open its api socket within {0:?}")]
+    StartupTimeout(Duration),
+    #[error("Api request `{0}` failed: {1}")]
+    Api(String, String),
+    #[error("Could not signal firecracker")]
+    Signal(#[source] nix::Error),
+    #[error("Firecracker did not exit after it was killed")]
+    CouldNotKill,
 }
 
 type Result<T> = core::result::Result<T, FirecrackerError>;
-impl<'nc> FirecrackerProcessHandle<'nc> {
-    fn monitor_path(&self) -> PathBuf {
+
+#[derive(Debug)]
+pub(crate) struct FirecrackerProcessHandle {
+    lc: Option<LaunchConfiguration>,
+    pid: usize,
+    // set by the thread waiting for firecracker, which reaps it as soon as it exits
+    exit: Arc<Mutex<Option<ExitStatus>>>,
+    // an exit after `stop` is not a crash
+    stopping: AtomicBool,
+    // the host's end of the guest console, firecracker's stdin and stdout
+    console: Option<UnixStream>,
+    // firecracker's end of the console, a restarted firecracker gets it again so the console
+    // attached to the host's end keeps working
+    guest_console: OwnedFd,
+}
+
+impl FirecrackerProcessHandle {
+    fn api_socket_path(&self) -> PathBuf {
         self.lc
             .as_ref()
             .expect("invalid state")
-            .temp_dir
+            .vm_dir
             .path()
-            .join("monitor.socket")
+            .join(API_SOCKET)
     }
-    pub fn serial_path(&self) -> PathBuf {
-        self.lc
-            .as_ref()
-            .expect("invalid state")
-            .temp_dir
-            .path()
-            .join("serial.socket")
+
+    pub(crate) fn tap(&self) -> &TapUser {
+        &self.lc.as_ref().expect("invalid state").tap
     }
-    fn pid_file_path(&self) -> PathBuf {
-        self.lc
-            .as_ref()
-            .expect("invalid state")
-            .temp_dir
-            .path()
-            .join("pidfile")
+
+    // The console can be taken once, by the SerialConsole reading it
+    pub(crate) fn take_console(&mut self) -> Option<UnixStream> {
+        self.console.take()
+    }
+
+    fn exit_status(&self) -> Option<ExitStatus> {
+        *self.exit.lock().unwrap()
+    }
+
+    pub(crate) fn status(&self) -> VmStatus {
+        match self.exit_status() {
+            None => VmStatus::Running { pid: self.pid },
+            Some(status) if status.success() || self.stopping.load(Ordering::Relaxed) => {
+                VmStatus::Stopped
+            }
+            Some(_) => VmStatus::Crashed,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.exit_status().is_none()
+    }
+
+    async fn wait_for_exit(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.exit_status().is_none() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            task::sleep(EXIT_POLL_INTERVAL).await;
+        }
+        true
+    }
+
+    fn signal(&self, signal: Signal) -> Result<()> {
+        kill(Pid::from_raw(self.pid as i32), signal).map_err(FirecrackerError::Signal)
     }
 
     #[instrument]
     pub(crate) async fn restart(&mut self) -> Result<()> {
-        self.lc = start_qemu(self.lc.take().unwrap()).await?.lc.take();
+        let guest_console = self
+            .guest_console
+            .try_clone()
+            .map_err(|e| FirecrackerError::IO(e, "creating console"))?;
+        let mut started =
+            spawn_firecracker(self.lc.take().expect("invalid state"), guest_console, None).await?;
+        self.lc = started.lc.take();
+        self.pid = started.pid;
+        self.exit = started.exit.clone();
+        self.stopping.store(false, Ordering::Relaxed);
         Ok(())
     }
+
+    // Sends ctrl+alt+del, the closest firecracker has to a power button, and terminates and
+    // kills firecracker if the guest does not shut down within the grace period. Firecracker has
+    // nothing like qemu's quit, the quit timeout is unused.
     #[instrument]
-    pub(crate) async fn stop(&self) -> Result<()> {
-        if !self.is_running().await? {
+    pub(crate) async fn stop(&self, timeouts: StopTimeouts) -> Result<()> {
+        if !self.is_running() {
             return Ok(());
         }
-
-        let pid = self.get_pid().await?;
-        let mut monitor_socket = UnixStream::connect(self.monitor_path())
-            .await
-            .expect("Could not open monitor socket");
-        monitor_socket
-            .write_all("q\n".as_bytes())
+        self.stopping.store(true, Ordering::Relaxed);
+        if !timeouts.grace_period.is_zero() {
+            match api_request(
+                &self.api_socket_path(),
+                "PUT",
+                "/actions",
+                r#"{"action_type": "SendCtrlAltDel"}"#,
+            )
             .await
-            .expect("Could not write to socket");
-
-        let wait_until_pid_stops_existing = async {
-            while run_command_without_output("ps", vec!["-p", &pid.to_string()]).await? {}
-            Ok(())
-        };
-
-        match async_std::future::timeout(Duration::from_secs(2), wait_until_pid_stops_existing)
-            .await
-        {
-            Ok(r) => r.map_err(FirecrackerError::Shell),
-            Err(_) => {
-                let killed =
-                    shell::run_command_without_output("kill", vec!["-9", &pid.to_string()])
-                        .await
-                        .map_err(FirecrackerError::Shell)?;
-                if killed {
-                    Ok(())
-                } else {
-                    Err(FirecrackerError::CouldNotKill("kill failed"))
-                }
+            {
+                Ok(()) if self.wait_for_exit(timeouts.grace_period).await => return Ok(()),
+                Ok(()) => {}
+                Err(e) => warn!(%e, "Could not send ctrl+alt+del"),
             }
         }
-    }
-    async fn get_pid(&self) -> Result<usize> {
-        let pid_file_path = self
-            .lc
-            .as_ref()
-            .expect("qemu handle in invalid state")
-            .temp_dir
-            .path()
-            .join("pidfile");
-
-        let mut buf = vec![0; 64];
-        let read_len = match async_std::fs::File::open(pid_file_path).await {
-            Ok(mut f) => f
-                .read(&mut buf)
-                .await
-                .map_err(|e| FirecrackerError::IO(e, "reading pidfile"))?,
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                return Err(FirecrackerError::NotRunning());
-            }
-            Err(e) => {
-                return Err(FirecrackerError::IO(e, "opening pidfile"));
+        if !timeouts.terminate.is_zero() {
+            warn!(pid = self.pid, "Firecracker did not stop, terminating it");
+            self.signal(Signal::SIGTERM)?;
+            if self.wait_for_exit(timeouts.terminate).await {
+                return Ok(());
             }
-        };
-
-        assert!(read_len > 0);
-        let pid_slice =
-            from_utf8(&buf[0..read_len - 1]).map_err(FirecrackerError::PidFileNonUtf)?;
-        pid_slice
-            .parse::<usize>()
-            .map_err(FirecrackerError::PidFileNonNumeric)
-    }
-    // Test if the pid file exists
-    async fn is_running(&self) -> Result<bool> {
-        match self.get_pid().await {
-            Ok(pid) => run_command_without_output("ps", vec!["-p", &pid.to_string()])
-                .await
-                .map_err(FirecrackerError::Shell),
-            Err(FirecrackerError::NotRunning()) => Ok(false),
-            Err(e) => Err(e),
+        }
+        warn!(pid = self.pid, "Firecracker did not terminate, killing it");
+        self.signal(Signal::SIGKILL)?;
+        match self.wait_for_exit(KILL_TIMEOUT).await {
+            true => Ok(()),
+            false => Err(FirecrackerError::CouldNotKill),
         }
     }
 }
 
-impl<'nc> Display for FirecrackerProcessHandle<'nc> {
+impl Display for FirecrackerProcessHandle {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
-            "TapDevice: {}, Ip: {}",
-            self.lc.as_ref().unwrap().tap.device(),
-            self.lc.as_ref().unwrap().tap.ip()
+            "TapDevice: {}, Ip: {} (firecracker)",
+            self.tap().device(),
+            self.tap().ip()
         ))
     }
 }
 
-impl<'nc> Drop for FirecrackerProcessHandle<'nc> {
+impl VmHandle for FirecrackerProcessHandle {
+    fn tap(&self) -> &TapUser {
+        FirecrackerProcessHandle::tap(self)
+    }
+    fn pid(&self) -> Option<usize> {
+        Some(self.pid)
+    }
+    fn status(&self) -> VmFuture<'_, VmStatus> {
+        Box::pin(async move { Ok(FirecrackerProcessHandle::status(self)) })
+    }
+    fn stop(&self, timeouts: StopTimeouts) -> VmFuture<'_, ()> {
+        Box::pin(async move {
+            FirecrackerProcessHandle::stop(self, timeouts)
+                .await
+                .map_err(VmError::Firecracker)
+        })
+    }
+    fn restart(&mut self) -> VmFuture<'_, ()> {
+        Box::pin(async move {
+            FirecrackerProcessHandle::restart(self)
+                .await
+                .map_err(VmError::Firecracker)
+        })
+    }
+}
+
+impl Drop for FirecrackerProcessHandle {
     fn drop(&mut self) {
         if self.lc.is_some() {
-            task::block_on(self.stop());
+            info!("Stopping Firecracker");
+            if let Err(e) = task::block_on(self.stop(StopTimeouts::ON_DROP)) {
+                error!("Failed to stop firecracker: {e:?}");
+            }
         }
     }
 }
 
-#[instrument]
-pub async fn start_qemu<'nc>(
-    lc: LaunchConfiguration<'nc>,
-) -> Result<FirecrackerProcessHandle<'nc>> {
-    let config = VMConfig::new(&lc);
-    let fc_config_file = lc.temp_dir.path().join("firecracker-config.json");
-    let fc_config_string = serde_json::to_string(&fc_config_file).unwrap();
-    async_std::fs::File::create(fc_config_file)
+// A single request to firecracker's http api, which answers 204 when it accepted it
+async fn api_request(socket: &Path, method: &str, path: &str, body: &str) -> Result<()> {
+    let mut stream = UnixStream::connect(socket)
         .await
-        .map_err(|e| FirecrackerError::IO(e, "Creating Config file"))?
-        .write_all(fc_config_string)
+        .map_err(|e| FirecrackerError::IO(e, "connecting to the api socket"))?;
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    stream
+        .write_all(request.as_bytes())
         .await
-        .map_err(|e| FirecrackerError::IO(e, "Writing Config file"));
-
-    run_shell_command(FIRECRACKER_BINARY)
+        .map_err(|e| FirecrackerError::IO(e, "writing api request"))?;
+    let mut response = vec![0u8; 4096];
+    let read = stream
+        .read(&mut response)
         .await
-        .map_err(|e| FirecrackerError::Shell(e))?;
+        .map_err(|e| FirecrackerError::IO(e, "reading api response"))?;
+    let response = String::from_utf8_lossy(&response[..read]);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(FirecrackerError::Api(
+            format!("{method} {path}"),
+            status_line.to_string(),
+        )),
+    }
+}
+
+#[instrument]
+pub(crate) async fn start_firecracker(lc: LaunchConfiguration) -> Result<FirecrackerProcessHandle> {
+    let (host, guest) = std::os::unix::net::UnixStream::pair()
+        .map_err(|e| FirecrackerError::IO(e, "creating console"))?;
+    spawn_firecracker(lc, OwnedFd::from(guest), Some(UnixStream::from(host))).await
+}
+
+// Starts firecracker with `guest_console` as its stdin and stdout
+async fn spawn_firecracker(
+    lc: LaunchConfiguration,
+    guest_console: OwnedFd,
+    console: Option<UnixStream>,
+) -> Result<FirecrackerProcessHandle> {
+    let dir = lc.vm_dir.path();
+    let config_file = dir.join(CONFIG_FILE);
+    let api_socket = dir.join(API_SOCKET);
+    let log = dir.join(LOG_FILE);
+    std::fs::write(
+        &config_file,
+        serde_json::to_string_pretty(&VMConfig::new(&lc)).unwrap(),
+    )
+    .map_err(|e| FirecrackerError::IO(e, "writing config file"))?;
+    // firecracker refuses to start on a leftover socket, e.g. after a restart
+    match std::fs::remove_file(&api_socket) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(FirecrackerError::IO(e, "removing api socket"));
+        }
+        _ => {}
+    }
+
+    let mut child = Command::new(FIRECRACKER_BINARY)
+        .arg("--api-sock")
+        .arg(&api_socket)
+        .arg("--config-file")
+        .arg(&config_file)
+        .stdin(Stdio::from(
+            guest_console
+                .try_clone()
+                .map_err(|e| FirecrackerError::IO(e, "creating console"))?,
+        ))
+        .stdout(Stdio::from(
+            guest_console
+                .try_clone()
+                .map_err(|e| FirecrackerError::IO(e, "creating console"))?,
+        ))
+        .stderr(Stdio::from(
+            std::fs::File::create(&log).map_err(|e| FirecrackerError::IO(e, "creating log"))?,
+        ))
+        .spawn()
+        .map_err(|e| FirecrackerError::IO(e, "spawning firecracker"))?;
+
+    let pid = child.id() as usize;
+    let exit = Arc::new(Mutex::new(None));
+    let reaped = exit.clone();
+    std::thread::spawn(move || match child.wait() {
+        Ok(status) => *reaped.lock().unwrap() = Some(status),
+        Err(e) => error!(pid, %e, "Could not wait for firecracker"),
+    });
 
-    Ok(FirecrackerProcessHandle { lc: Some(lc) })
+    let handle = FirecrackerProcessHandle {
+        lc: Some(lc),
+        pid,
+        exit,
+        stopping: AtomicBool::new(false),
+        console,
+        guest_console,
+    };
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while !api_socket.exists() {
+        if let Some(status) = handle.exit_status() {
+            return Err(FirecrackerError::StartupFailed(status, log));
+        }
+        if Instant::now() >= deadline {
+            return Err(FirecrackerError::StartupTimeout(STARTUP_TIMEOUT));
+        }
+        task::sleep(EXIT_POLL_INTERVAL).await;
+    }
+    info!(pid, "Started firecracker");
+    Ok(handle)
+}
+
+#[test]
+fn api_requests() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    let dir = tempdir::TempDir::new("firecracker").unwrap();
+    let socket = dir.path().join(API_SOCKET);
+    let listener = UnixListener::bind(&socket).unwrap();
+    let server = std::thread::spawn(move || {
+        let mut requests = vec![];
+        for response in ["HTTP/1.1 204 \r\n\r\n", "HTTP/1.1 400 \r\n\r\n{}"] {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0u8; 4096];
+            let read = stream.read(&mut request).unwrap();
+            requests.push(String::from_utf8_lossy(&request[..read]).to_string());
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+        requests
+    });
+
+    let body = r#"{"action_type": "SendCtrlAltDel"}"#;
+    assert!(task::block_on(api_request(&socket, "PUT", "/actions", body)).is_ok());
+    assert!(matches!(
+        task::block_on(api_request(&socket, "PUT", "/actions", body)),
+        Err(FirecrackerError::Api(request, status)) if request == "PUT /actions" && status == "HTTP/1.1 400 "
+    ));
+    let requests = server.join().unwrap();
+    assert!(requests[0].starts_with("PUT /actions HTTP/1.1\r\n"));
+    assert!(requests[0].ends_with(&format!("Content-Length: {}\r\n\r\n{body}", body.len())));
+}
+
+#[test]
+fn vm_config() {
+    let config = VMConfig {
+        boot_source: BootSource {
+            kernel_image_path: PathBuf::from("/ops/kernel.img"),
+            boot_args: None,
+        },
+        drives: vec![Drive {
+            drive_id: "rootfs".to_string(),
+            path_on_host: PathBuf::from("/images/unikernel.img"),
+            is_root_device: false,
+            is_read_only: false,
+        }],
+        network_interfaces: vec![NetworkInterface {
+            iface_id: "eth0".to_string(),
+            guest_mac: "52:54:00:00:00:02".to_string(),
+            host_dev_name: "tap0".to_string(),
+        }],
+        machine_config: MachineConfig {
+            vcpu_count: 2,
+            mem_size_mib: 512,
+            smt: false,
+        },
+    };
+    assert_eq!(
+        serde_json::to_value(config).unwrap(),
+        serde_json::json!({
+            "boot-source": {"kernel_image_path": "/ops/kernel.img"},
+            "drives": [{
+                "drive_id": "rootfs",
+                "path_on_host": "/images/unikernel.img",
+                "is_root_device": false,
+                "is_read_only": false,
+            }],
+            "network-interfaces": [{
+                "iface_id": "eth0",
+                "guest_mac": "52:54:00:00:00:02",
+                "host_dev_name": "tap0",
+            }],
+            "machine-config": {"vcpu_count": 2, "mem_size_mib": 512, "smt": false},
+        })
+    );
 }
@@ -1,15 +1,22 @@
-use std::io::Write;
+use std::fmt::{Display, Formatter};
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
 use serde::Serialize;
-use tempdir::TempDir;
+use thiserror::Error;
 use tracing::info;
 
+use crate::builder::{BuildError, BuildFuture, BuiltImage, ImageBuilder};
+use crate::image::copy_image;
 use crate::network::TapUser;
-use crate::qemu::{LaunchConfiguration, QemuFirmwareConfig};
-use crate::shell::run_shell_command_with_stdin;
-use crate::templates::{Templates, WorkerConfiguration};
+use crate::qemu::{
+    BootSource, LaunchConfiguration, QemuFirmwareConfig, SecurityConfig, SocketAccess,
+};
+use crate::rundir::{RunDir, VmDir};
+use crate::shell::{run_shell_command_with_stdin, ShellError};
+use crate::templates::{TemplateError, Templates, WorkerConfiguration};
 
 #[derive(Debug, Serialize)]
 struct Content {
@@ -31,7 +38,9 @@ struct FlatcarStorageConfig {
 struct FlatcarSystemdUnitConfig {
     name: String,
     enabled: bool,
-    contents: String,
+    // units which ship with flatcar are only enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contents: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -47,7 +56,88 @@ struct FlatcarConfig {
     storage: FlatcarStorageConfig,
 }
 
-async fn run_butane(config: &FlatcarConfig) -> String {
+// Butane config specs for flatcar, newer releases additionally accept newer versions
+const KNOWN_BUTANE_SPECS: &[(&str, &str)] = &[("flatcar", "1.0.0"), ("flatcar", "1.1.0")];
+
+// `variant` and `version` of the generated butane config, e.g. `flatcar:1.1.0` on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ButaneSpec {
+    variant: String,
+    version: String,
+}
+
+impl ButaneSpec {
+    pub(crate) fn new(variant: &str, version: &str) -> Result<Self, String> {
+        if !KNOWN_BUTANE_SPECS.contains(&(variant, version)) {
+            let known = KNOWN_BUTANE_SPECS
+                .iter()
+                .map(|(variant, version)| format!("{variant}:{version}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(format!(
+                "unknown butane spec {variant}:{version}, expected one of {known}"
+            ));
+        }
+        Ok(ButaneSpec {
+            variant: variant.to_string(),
+            version: version.to_string(),
+        })
+    }
+}
+
+impl Default for ButaneSpec {
+    fn default() -> Self {
+        ButaneSpec::new("flatcar", "1.0.0").unwrap()
+    }
+}
+
+impl FromStr for ButaneSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (variant, version) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <variant>:<version>, e.g. flatcar:1.1.0, not {s}"))?;
+        ButaneSpec::new(variant, version)
+    }
+}
+
+impl Display for ButaneSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.variant, self.version)
+    }
+}
+
+// Curated boot debugging profile. Ignition only runs after the kernel booted, so instead of
+// kernel arguments this raises the console log levels which are read later in the boot.
+const VERBOSE_BOOT_FILES: [(&str, &str); 3] = [
+    ("/etc/sysctl.d/10-verbose.conf", "kernel.printk = 8 4 1 7\n"),
+    (
+        "/etc/systemd/system.conf.d/10-verbose.conf",
+        "[Manager]\nLogLevel=debug\nLogTarget=console\nShowStatus=yes\n",
+    ),
+    (
+        "/etc/systemd/journald.conf.d/10-verbose.conf",
+        "[Journal]\nForwardToConsole=yes\nTTYPath=/dev/ttyS0\nMaxLevelConsole=debug\n",
+    ),
+];
+
+// timesyncd only asks the host, which has to serve NTP on the bridge, and the worker waits until
+// the clock is synchronized
+fn clock_sync_files(wc: &WorkerConfiguration) -> [(&'static str, String); 2] {
+    [
+        (
+            "/etc/systemd/timesyncd.conf.d/10-host.conf",
+            format!("[Time]\nNTP={}\nFallbackNTP=\n", wc.host_ip_addr),
+        ),
+        (
+            "/etc/systemd/system/nesWorker.service.d/10-time-sync.conf",
+            "[Unit]\nWants=time-sync.target\nAfter=time-sync.target\n".to_string(),
+        ),
+    ]
+}
+
+async fn run_butane(config: &FlatcarConfig) -> Result<String, ShellError> {
     let data = serde_yaml::to_string(&config).unwrap();
     run_shell_command_with_stdin(
         "docker",
@@ -55,81 +145,407 @@ async fn run_butane(config: &FlatcarConfig) -> String {
         data.as_bytes(),
     )
     .await
-    .expect("could not run docker")
+}
+
+// The stock flatcar image and the butane config which provisions it
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FlatcarImageSpec {
+    butane: FlatcarConfig,
+    base_image: PathBuf,
+}
+
+// Boots a copy of the stock image with the butane config rendered to ignition, which the guest
+// reads from fw_cfg
+#[derive(Debug, Default)]
+pub(crate) struct ButaneBuilder;
+
+impl ImageBuilder<FlatcarImageSpec> for ButaneBuilder {
+    fn build<'a>(&'a self, spec: &'a FlatcarImageSpec, vm_dir: &'a Path) -> BuildFuture<'a> {
+        Box::pin(async move {
+            let image_path = vm_dir.join("flatcar_fresh.iso");
+            let ignition_path = vm_dir.join("ignition.json");
+            let butane_output = run_butane(dbg!(&spec.butane));
+            info!(src = ?spec.base_image, dest = ?image_path, dir = ?vm_dir, "Copy image to tmp directory");
+            copy_image(&spec.base_image, &image_path)
+                .await
+                .map_err(|e| BuildError::IO(e, "copying the flatcar image"))?;
+            let butane_output = butane_output.await.map_err(BuildError::Shell)?;
+            std::fs::write(&ignition_path, butane_output)
+                .map_err(|e| BuildError::IO(e, "writing ignition.json"))?;
+            Ok(BuiltImage {
+                image_path,
+                firmware: vec![QemuFirmwareConfig {
+                    name: "opt/org.flatcar-linux/config".to_string(),
+                    path: ignition_path,
+                }],
+            })
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct Args {
     pub flatcar_fresh_image: PathBuf,
     pub number_of_cores: Option<usize>,
+    pub max_number_of_cores: Option<usize>,
+    pub memory_in_megabytes: Option<usize>,
+    pub security: SecurityConfig,
+    pub tpm: bool,
+    pub socket_dir: Option<PathBuf>,
+    pub run_dir: Option<RunDir>,
+    pub butane: ButaneSpec,
+    // log everything the guest's kernel and systemd have to say to the console
+    pub verbose: bool,
+    // false boots the guest without starting the worker, e.g. for the warm pool
+    pub start_worker: bool,
+    // sync the guest clock with the host before the worker starts
+    pub sync_clock: bool,
+    // builds the image instead of butane
+    pub image_builder: Option<Arc<dyn ImageBuilder<FlatcarImageSpec>>>,
+    // queues of the worker's nic, has to match how the taps were created
+    pub nic_queues: usize,
 }
 
-fn create_configuration(wc: &WorkerConfiguration) -> FlatcarConfig {
-    FlatcarConfig {
-        version: "1.0.0".to_string(),
-        variant: "flatcar".to_string(),
+fn create_configuration(
+    wc: &WorkerConfiguration,
+    spec: &ButaneSpec,
+    verbose: bool,
+    start_worker: bool,
+    sync_clock: bool,
+) -> Result<FlatcarConfig, TemplateError> {
+    let extra_units = wc.extra_units.iter().map(|unit| FlatcarSystemdUnitConfig {
+        name: unit.name.clone(),
+        enabled: unit.enabled,
+        contents: Some(unit.contents.clone()),
+    });
+    let clock_sync_unit = sync_clock.then(|| FlatcarSystemdUnitConfig {
+        name: "systemd-time-wait-sync.service".to_string(),
+        enabled: true,
+        contents: None,
+    });
+    let clock_sync_files =
+        clock_sync_files(wc)
+            .into_iter()
+            .filter(|_| sync_clock)
+            .map(|(path, contents)| FlatcarStorageFileConfig {
+                path: PathBuf::from(path),
+                contents: Content { inline: contents },
+            });
+    let verbose_files = VERBOSE_BOOT_FILES
+        .iter()
+        .filter(|_| verbose)
+        .map(|(path, contents)| FlatcarStorageFileConfig {
+            path: PathBuf::from(path),
+            contents: Content {
+                inline: contents.to_string(),
+            },
+        });
+    // a bond replaces eth0's network, its members are matched by their mac
+    let network_files = match wc.bond {
+        None => vec![(
+            "/etc/systemd/network/00-eth0.network",
+            Templates::network_config(wc)?,
+        )],
+        Some(_) => vec![
+            (
+                "/etc/systemd/network/10-bond0.netdev",
+                Templates::bond_netdev(wc)?,
+            ),
+            (
+                "/etc/systemd/network/20-bond0-members.network",
+                Templates::bond_members(wc)?,
+            ),
+            (
+                "/etc/systemd/network/30-bond0.network",
+                Templates::network_config(wc)?,
+            ),
+        ],
+    };
+    let network_files =
+        network_files
+            .into_iter()
+            .map(|(path, contents)| FlatcarStorageFileConfig {
+                path: PathBuf::from(path),
+                contents: Content { inline: contents },
+            });
+    let extra_files = wc.extra_files.iter().map(|file| FlatcarStorageFileConfig {
+        path: file.path.clone(),
+        contents: Content {
+            inline: file.contents.clone(),
+        },
+    });
+    Ok(FlatcarConfig {
+        version: spec.version.clone(),
+        variant: spec.variant.clone(),
         systemd: FlatcarSystemdConfig {
-            units: vec![FlatcarSystemdUnitConfig {
+            units: std::iter::once(FlatcarSystemdUnitConfig {
                 name: "nesWorker.service".to_string(),
-                enabled: true,
-                contents: Templates::docker_unit(wc),
-            }],
+                enabled: start_worker,
+                contents: Some(Templates::docker_unit(wc)?),
+            })
+            .chain(clock_sync_unit)
+            .chain(extra_units)
+            .collect(),
         },
         storage: FlatcarStorageConfig {
-            files: vec![
-                FlatcarStorageFileConfig {
-                    path: PathBuf::from("/etc/systemd/network/00-eth0.network"),
-                    contents: Content {
-                        inline: Templates::network_config(wc),
-                    },
-                },
-                FlatcarStorageFileConfig {
-                    path: PathBuf::from("/config/worker_config.yaml"),
-                    contents: Content {
-                        inline: Templates::worker_config(wc),
+            files: network_files
+                .chain([
+                    FlatcarStorageFileConfig {
+                        path: PathBuf::from("/config/worker_config.yaml"),
+                        contents: Content {
+                            inline: wc.worker_config_yaml()?,
+                        },
                     },
-                },
-                FlatcarStorageFileConfig {
-                    path: PathBuf::from("/etc/docker/daemon.json"),
-                    contents: Content {
-                        inline: Templates::docker_daemon(wc),
+                    FlatcarStorageFileConfig {
+                        path: PathBuf::from("/etc/docker/daemon.json"),
+                        contents: Content {
+                            inline: Templates::docker_daemon(wc)?,
+                        },
                     },
-                },
-            ],
+                ])
+                .chain(verbose_files)
+                .chain(clock_sync_files)
+                .chain(extra_files)
+                .collect(),
         },
+    })
+}
+
+#[test]
+fn extra_units_and_files() {
+    use crate::templates::{ExtraFile, ExtraUnit};
+
+    let mut wc = WorkerConfiguration {
+        ip_addr: IpAddr::from([10, 0, 0, 1]),
+        host_ip_addr: IpAddr::from([10, 0, 0, 2]),
+        ..Default::default()
+    };
+    let base = create_configuration(&wc, &ButaneSpec::default(), false, true, false).unwrap();
+
+    wc.extra_units.push(ExtraUnit {
+        name: "node-exporter.service".to_string(),
+        contents: "[Service]\nExecStart=/opt/bin/node_exporter\n".to_string(),
+        enabled: false,
+    });
+    wc.extra_files.push(ExtraFile {
+        path: PathBuf::from("/etc/sysctl.d/90-nes.conf"),
+        contents: "net.core.rmem_max=26214400\n".to_string(),
+    });
+    let extended = create_configuration(&wc, &ButaneSpec::default(), false, true, false).unwrap();
+
+    assert_eq!(extended.systemd.units.len(), base.systemd.units.len() + 1);
+    assert_eq!(extended.systemd.units[0].name, "nesWorker.service");
+    let unit = extended.systemd.units.last().unwrap();
+    assert_eq!(unit.name, "node-exporter.service");
+    assert!(!unit.enabled);
+    assert_eq!(extended.storage.files.len(), base.storage.files.len() + 1);
+    let file = extended.storage.files.last().unwrap();
+    assert_eq!(file.path, PathBuf::from("/etc/sysctl.d/90-nes.conf"));
+    assert_eq!(file.contents.inline, "net.core.rmem_max=26214400\n");
+}
+
+#[test]
+fn butane_specs() {
+    let wc = WorkerConfiguration {
+        ip_addr: IpAddr::from([10, 0, 0, 1]),
+        host_ip_addr: IpAddr::from([10, 0, 0, 2]),
+        ..Default::default()
+    };
+    let config = create_configuration(&wc, &ButaneSpec::default(), false, true, false).unwrap();
+    assert_eq!(
+        (config.variant.as_str(), config.version.as_str()),
+        ("flatcar", "1.0.0")
+    );
+
+    let spec = ButaneSpec::new("flatcar", "1.1.0").unwrap();
+    let config = create_configuration(&wc, &spec, false, true, false).unwrap();
+    assert_eq!(config.version, "1.1.0");
+
+    let idle = create_configuration(&wc, &spec, false, false, false).unwrap();
+    assert!(!idle.systemd.units[0].enabled);
+
+    let verbose = create_configuration(&wc, &spec, true, true, false).unwrap();
+    assert!(verbose
+        .storage
+        .files
+        .iter()
+        .any(|file| file.path.as_os_str() == "/etc/sysctl.d/10-verbose.conf"));
+    assert_eq!(
+        verbose.storage.files.len(),
+        config.storage.files.len() + VERBOSE_BOOT_FILES.len()
+    );
+
+    assert!(ButaneSpec::new("flatcar", "2.0.0").is_err());
+    assert!(ButaneSpec::new("fcos", "1.1.0").is_err());
+    assert_eq!("flatcar:1.1.0".parse::<ButaneSpec>().as_ref(), Ok(&spec));
+    assert!("flatcar 1.1.0".parse::<ButaneSpec>().is_err());
+
+    let synced = create_configuration(&wc, &spec, false, true, true).unwrap();
+    let unit = &synced.systemd.units[1];
+    assert_eq!(unit.name, "systemd-time-wait-sync.service");
+    assert!(unit.enabled && unit.contents.is_none());
+    let timesyncd = synced
+        .storage
+        .files
+        .iter()
+        .find(|file| file.path.as_os_str() == "/etc/systemd/timesyncd.conf.d/10-host.conf")
+        .unwrap();
+    assert_eq!(
+        timesyncd.contents.inline,
+        "[Time]\nNTP=10.0.0.2\nFallbackNTP=\n"
+    );
+}
+
+#[test]
+fn bonded_network() {
+    use crate::templates::{BondConfiguration, BondMode};
+
+    let mut wc = WorkerConfiguration {
+        ip_addr: IpAddr::from([10, 0, 0, 3]),
+        host_ip_addr: IpAddr::from([10, 0, 0, 1]),
+        worker_id: 1,
+        bond: Some(BondConfiguration {
+            mode: serde_yaml::from_str("active-backup").unwrap(),
+            mac: "52:54:00:00:00:03".to_string(),
+            member_macs: vec![
+                "52:54:00:00:00:03".to_string(),
+                "52:54:00:00:00:04".to_string(),
+            ],
+        }),
+        ..Default::default()
+    };
+    let config = create_configuration(&wc, &ButaneSpec::default(), false, true, false).unwrap();
+    let file = |path: &str| {
+        config
+            .storage
+            .files
+            .iter()
+            .find(|file| file.path.as_os_str() == path)
+            .map(|file| file.contents.inline.clone())
+    };
+    assert_eq!(file("/etc/systemd/network/00-eth0.network"), None);
+    assert!(file("/etc/systemd/network/10-bond0.netdev")
+        .unwrap()
+        .contains("Mode=active-backup\n"));
+    assert!(file("/etc/systemd/network/20-bond0-members.network")
+        .unwrap()
+        .contains("MACAddress=52:54:00:00:00:03 52:54:00:00:00:04\n"));
+    let network = file("/etc/systemd/network/30-bond0.network").unwrap();
+    assert!(network.contains("Name=bond0\n") && network.contains("Address=10.0.0.3/24\n"));
+
+    assert!(serde_yaml::from_str::<BondMode>("balance-rr").is_ok());
+    assert!(serde_yaml::from_str::<BondMode>("round-robin").is_err());
+    for unsupported in ["802.3ad", "lacp", "balance-xor"] {
+        assert!(serde_yaml::from_str::<BondMode>(unsupported).is_err());
     }
+    wc.bond = None;
+    let config = create_configuration(&wc, &ButaneSpec::default(), false, true, false).unwrap();
+    assert_eq!(
+        config.storage.files[0].path,
+        PathBuf::from("/etc/systemd/network/00-eth0.network")
+    );
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum FlatcarError {
+    #[error("Could not render the butane config")]
+    Template(#[source] TemplateError),
+    #[error("Could not build the image")]
+    Build(#[source] BuildError),
+    #[error("Could not copy the captured disk {1:?}")]
+    CapturedDisk(#[source] std::io::Error, PathBuf),
 }
 
 pub(crate) async fn prepare_launch(
     wc: WorkerConfiguration,
     tap: TapUser,
     args: &Args,
-) -> LaunchConfiguration {
-    let temp_dir = TempDir::new(&format!("worker_{}", wc.worker_id)).unwrap();
-    let image_path = temp_dir.path().join("flatcar_fresh.iso");
-    let ignition_path = temp_dir.path().join("ignition.json");
-    let flatcar_config = create_configuration(&wc);
-    let butane_output = run_butane(dbg!(&flatcar_config));
-    info!(src = ?args.flatcar_fresh_image, dest = ?image_path, tmp= ?temp_dir, "Copy image to tmp directory");
-    std::fs::copy(&args.flatcar_fresh_image, &image_path).expect("Could not copy flatcar image");
-    let butane_output = butane_output.await;
-
-    let mut ignition_file =
-        std::fs::File::create(&ignition_path).expect("Could not create ignition.json");
-    ignition_file
-        .write_all(butane_output.as_ref())
-        .expect("Could not populate ignition.json");
+) -> Result<LaunchConfiguration, FlatcarError> {
+    let vm_dir = VmDir::create(args.run_dir.as_ref(), &format!("worker-{}", wc.worker_id))
+        .expect("Could not create vm directory");
+    let spec = FlatcarImageSpec {
+        butane: create_configuration(
+            &wc,
+            &args.butane,
+            args.verbose,
+            args.start_worker,
+            args.sync_clock,
+        )
+        .map_err(FlatcarError::Template)?,
+        base_image: args.flatcar_fresh_image.clone(),
+    };
+    let built = args
+        .image_builder
+        .as_deref()
+        .unwrap_or(&ButaneBuilder)
+        .build(&spec, vm_dir.path())
+        .await
+        .map_err(FlatcarError::Build)?;
+    Ok(launch_configuration(
+        vm_dir,
+        tap,
+        built.image_path,
+        built.firmware,
+        args,
+    ))
+}
 
+// A worker restored from a captured vm boots a copy of the captured disk, nothing is built. Its
+// guest configured itself from the disk before it was captured.
+pub(crate) async fn restore_launch(
+    worker_id: usize,
+    tap: TapUser,
+    disk: &Path,
+    args: &Args,
+) -> Result<LaunchConfiguration, FlatcarError> {
+    let vm_dir = VmDir::create(args.run_dir.as_ref(), &format!("worker-{worker_id}"))
+        .map_err(|e| FlatcarError::CapturedDisk(e, disk.to_path_buf()))?;
+    let image_path = vm_dir.path().join("restored.img");
+    copy_image(disk, &image_path)
+        .await
+        .map_err(|e| FlatcarError::CapturedDisk(e, disk.to_path_buf()))?;
+    Ok(launch_configuration(vm_dir, tap, image_path, vec![], args))
+}
+
+fn launch_configuration(
+    vm_dir: VmDir,
+    tap: TapUser,
+    image_path: PathBuf,
+    firmware: Vec<QemuFirmwareConfig>,
+    args: &Args,
+) -> LaunchConfiguration {
     LaunchConfiguration {
         tap,
-        image_path,
-        firmware: vec![QemuFirmwareConfig {
-            name: "opt/org.flatcar-linux/config".to_string(),
-            path: temp_dir.path().join("ignition.json"),
-        }],
+        boot: BootSource::Image(image_path),
+        firmware,
+        pflash: None,
         num_cores: args.number_of_cores,
-        memory_in_mega_bytes: Some(512 * 1024),
-        temp_dir,
+        max_num_cores: args.max_number_of_cores,
+        memory_in_mega_bytes: args.memory_in_megabytes,
+        balloon: true,
+        security: args.security.clone(),
+        tpm: args.tpm,
+        socket_dir: args.socket_dir.clone(),
+        cpu_affinity: None,
+        vcpu_reservation: None,
+        cpu_limit: None,
+        machine_properties: vec![],
+        incoming: None,
+        output: None,
+        sync_clock: args.sync_clock,
+        hmp_monitor: false,
+        vnc: None,
+        gdb: None,
+        extra_drives: vec![],
+        disk_queues: None,
+        hugepages: None,
+        qemu_binary: None,
+        vhost: false,
+        nic_queues: args.nic_queues,
+        bond_taps: vec![],
+        socket_access: SocketAccess::default(),
+        vm_dir,
     }
 }
 
@@ -138,11 +554,8 @@ fn should_serialize_properly() {
     let worker_config = WorkerConfiguration {
         ip_addr: IpAddr::from([127, 0, 0, 1]),
         host_ip_addr: IpAddr::from([127, 0, 0, 1]),
-        parent_id: 0,
         worker_id: 1,
-        sources: vec![],
-        log_level: "LOG_INFO",
-        query_processing: Default::default(),
+        ..Default::default()
     };
 
     let config = FlatcarConfig {
@@ -152,7 +565,7 @@ fn should_serialize_properly() {
             units: vec![FlatcarSystemdUnitConfig {
                 name: "nesWorker.service".to_string(),
                 enabled: true,
-                contents: Templates::docker_unit(&worker_config),
+                contents: Some(Templates::docker_unit(&worker_config).unwrap()),
             }],
         },
         storage: FlatcarStorageConfig {
@@ -160,25 +573,25 @@ fn should_serialize_properly() {
                 FlatcarStorageFileConfig {
                     path: PathBuf::from("/etc/systemd/network/00-eth0.network"),
                     contents: Content {
-                        inline: Templates::network_config(&worker_config),
+                        inline: Templates::network_config(&worker_config).unwrap(),
                     },
                 },
                 FlatcarStorageFileConfig {
                     path: PathBuf::from("/config/worker_config.yaml"),
                     contents: Content {
-                        inline: Templates::worker_config(&worker_config),
+                        inline: Templates::worker_config(&worker_config).unwrap(),
                     },
                 },
                 FlatcarStorageFileConfig {
                     path: PathBuf::from("/etc/docker/daemon.json"),
                     contents: Content {
-                        inline: Templates::docker_daemon(&worker_config),
+                        inline: Templates::docker_daemon(&worker_config).unwrap(),
                     },
                 },
             ],
         },
     };
 
-    let output = futures_lite::future::block_on(run_butane(&config));
+    let output = futures_lite::future::block_on(run_butane(&config)).unwrap();
     println!("{output}")
 }
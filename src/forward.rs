@@ -0,0 +1,233 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use futures::future::{AbortHandle, Abortable};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+#[derive(Error, Debug)]
+pub(crate) enum ForwardError {
+    #[error("No free host port left in {0}")]
+    Exhausted(HostPortRange),
+    #[error("Could not listen on host port {1}")]
+    Bind(#[source] std::io::Error, u16),
+    #[error("Invalid host port range: {0}")]
+    InvalidRange(String),
+}
+
+// Inclusive range of host ports, e.g. `20000-20099`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HostPortRange {
+    first: u16,
+    last: u16,
+}
+
+impl FromStr for HostPortRange {
+    type Err = ForwardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ForwardError::InvalidRange(s.to_string());
+        let (first, last) = s.split_once('-').ok_or_else(invalid)?;
+        let first = first.trim().parse::<u16>().map_err(|_| invalid())?;
+        let last = last.trim().parse::<u16>().map_err(|_| invalid())?;
+        if first == 0 || first > last {
+            return Err(invalid());
+        }
+        Ok(HostPortRange { first, last })
+    }
+}
+
+impl Display for HostPortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+// A guest port reachable through a host port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct ForwardedPort {
+    pub(crate) host: u16,
+    pub(crate) guest: u16,
+}
+
+impl Display for ForwardedPort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}->{}", self.host, self.guest)
+    }
+}
+
+#[derive(Debug)]
+struct PortPool {
+    range: HostPortRange,
+    free: BTreeSet<u16>,
+}
+
+// Hands out host ports of a range, each port forwards to at most one guest port
+#[derive(Debug, Clone)]
+pub(crate) struct HostPorts {
+    pool: Arc<Mutex<PortPool>>,
+}
+
+// Forwards connections until dropped, the host port is returned to the pool afterwards
+#[derive(Debug)]
+pub(crate) struct PortForward {
+    port: ForwardedPort,
+    abort: AbortHandle,
+    pool: Arc<Mutex<PortPool>>,
+}
+
+impl PortForward {
+    pub(crate) fn port(&self) -> ForwardedPort {
+        self.port
+    }
+}
+
+impl Drop for PortForward {
+    fn drop(&mut self) {
+        self.abort.abort();
+        self.pool.lock().unwrap().free.insert(self.port.host);
+    }
+}
+
+impl HostPorts {
+    pub(crate) fn new(range: HostPortRange) -> Self {
+        HostPorts {
+            pool: Arc::new(Mutex::new(PortPool {
+                range,
+                free: (range.first..=range.last).collect(),
+            })),
+        }
+    }
+
+    // Listens on the lowest free host port. Ports which are taken by other software are
+    // skipped and not tried again.
+    pub(crate) async fn forward(&self, guest: SocketAddrV4) -> Result<PortForward, ForwardError> {
+        loop {
+            let (range, host) = {
+                let mut pool = self.pool.lock().unwrap();
+                (pool.range, pool.free.pop_first())
+            };
+            let host = host.ok_or(ForwardError::Exhausted(range))?;
+            match TcpListener::bind((Ipv4Addr::UNSPECIFIED, host)).await {
+                Ok(listener) => {
+                    let (abort, registration) = AbortHandle::new_pair();
+                    task::spawn(Abortable::new(accept(listener, guest), registration));
+                    return Ok(PortForward {
+                        port: ForwardedPort {
+                            host,
+                            guest: guest.port(),
+                        },
+                        abort,
+                        pool: self.pool.clone(),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                    warn!(host, "Host port is in use, skipping it");
+                }
+                Err(e) => {
+                    self.pool.lock().unwrap().free.insert(host);
+                    return Err(ForwardError::Bind(e, host));
+                }
+            }
+        }
+    }
+}
+
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Errors like EMFILE persist until connections are closed, accepting again right away would spin
+async fn accept(listener: TcpListener, guest: SocketAddrV4) {
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        let client = match listener.accept().await {
+            Ok((client, _)) => client,
+            Err(e) => {
+                warn!(%e, %guest, ?backoff, "Could not accept forwarded connection");
+                task::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        backoff = ACCEPT_BACKOFF;
+        task::spawn(async move {
+            if let Err(e) = proxy(client, guest).await {
+                debug!(%e, %guest, "Forwarded connection ended");
+            }
+        });
+    }
+}
+
+// Copies both directions until both sides closed their end
+async fn proxy(client: TcpStream, guest: SocketAddrV4) -> std::io::Result<()> {
+    let server = TcpStream::connect(guest).await?;
+    let (mut client_write, mut server_write) = (client.clone(), server.clone());
+    let upstream = async {
+        futures::io::copy(client, &mut server_write).await?;
+        server_write.shutdown(Shutdown::Write)
+    };
+    let downstream = async {
+        futures::io::copy(server, &mut client_write).await?;
+        client_write.shutdown(Shutdown::Write)
+    };
+    let (up, down) = futures::future::join(upstream, downstream).await;
+    up.and(down)
+}
+
+#[test]
+fn forwarded_ports() {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    assert!("20000-19999".parse::<HostPortRange>().is_err());
+    assert!("20000".parse::<HostPortRange>().is_err());
+
+    task::block_on(async {
+        // stands in for the guest
+        let guest = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let guest_addr = match guest.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        task::spawn(async move {
+            let (mut stream, _) = guest.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        // a port which is already taken is skipped
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let taken = listener.local_addr().unwrap().port();
+        let ports = HostPorts::new(HostPortRange {
+            first: taken,
+            last: taken.saturating_add(1),
+        });
+        let forward = ports.forward(guest_addr).await.unwrap();
+        assert_eq!(forward.port().host, taken + 1);
+        assert_eq!(forward.port().guest, guest_addr.port());
+        assert!(matches!(
+            ports.forward(guest_addr).await,
+            Err(ForwardError::Exhausted(_))
+        ));
+
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, taken + 1))
+            .await
+            .unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+
+        // the port is handed out again once the forward is gone
+        drop(forward);
+        assert_eq!(ports.pool.lock().unwrap().free.len(), 1);
+    });
+}
@@ -0,0 +1,116 @@
+use clap::Args;
+use tracing::{info, warn};
+
+use crate::export::InstanceRecord;
+use crate::shell::run_shell_command_with_env;
+
+// Host commands run when a vm changes its state, for integrations which would rather shell out
+// than follow the launcher's log. Every hook runs as `sh -c <command>` with the environment
+//   VML_EVENT      ready, stop or crash
+//   VML_ID         worker id of a worker, node id of a unikernel or kernel
+//   VML_KIND       worker, unikernel or kernel
+//   VML_IP         guest address
+//   VML_MAC        guest mac
+//   VML_TAP        host tap device
+//   VML_PARENT_ID  parent worker, only set for workers
+//   VML_REASON     why the vm is gone, only set for crash
+// A failing hook is logged, the launcher carries on.
+#[derive(Debug, Clone, Default, Args)]
+pub(crate) struct Hooks {
+    /// Host command run once a vm has booted, again after it was restarted
+    #[arg(long)]
+    on_ready: Option<String>,
+    /// Host command run after the launcher stopped a vm
+    #[arg(long)]
+    on_stop: Option<String>,
+    /// Host command run when a vm exited on its own, e.g. killed by the OOM killer
+    #[arg(long)]
+    on_crash: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, strum_macros::Display)]
+pub(crate) enum HookEvent {
+    #[strum(to_string = "ready")]
+    Ready,
+    #[strum(to_string = "stop")]
+    Stop,
+    #[strum(to_string = "crash")]
+    Crash(String),
+}
+
+fn environment(event: &HookEvent, instance: &InstanceRecord) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("VML_EVENT", event.to_string()),
+        ("VML_ID", instance.id.to_string()),
+        ("VML_KIND", instance.kind.to_string()),
+        ("VML_IP", instance.ip.clone()),
+        ("VML_MAC", instance.mac.clone()),
+        ("VML_TAP", instance.tap.clone()),
+    ];
+    if let Some(parent_id) = instance.parent_id {
+        env.push(("VML_PARENT_ID", parent_id.to_string()));
+    }
+    if let HookEvent::Crash(reason) = event {
+        env.push(("VML_REASON", reason.clone()));
+    }
+    env
+}
+
+impl Hooks {
+    fn command(&self, event: &HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Ready => self.on_ready.as_deref(),
+            HookEvent::Stop => self.on_stop.as_deref(),
+            HookEvent::Crash(_) => self.on_crash.as_deref(),
+        }
+    }
+
+    pub(crate) async fn run(&self, event: HookEvent, instance: &InstanceRecord) {
+        let Some(command) = self.command(&event) else {
+            return;
+        };
+        let env = environment(&event, instance);
+        let env = env.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        match run_shell_command_with_env("sh", &vec!["-c", command], env).await {
+            Ok(_) => info!(id = instance.id, %event, "Ran hook"),
+            Err(e) => warn!(id = instance.id, %event, %e, "Hook failed"),
+        }
+    }
+}
+
+#[test]
+fn hook_environment() {
+    let dir = tempdir::TempDir::new("hooks").unwrap();
+    let out = dir.path().join("env");
+    let hooks = Hooks {
+        on_crash: Some(format!(
+            "echo $VML_EVENT $VML_ID $VML_KIND $VML_IP $VML_TAP $VML_PARENT_ID \"$VML_REASON\" > {}",
+            out.display()
+        )),
+        on_stop: Some("exit 3".to_string()),
+        ..Default::default()
+    };
+    let instance = InstanceRecord {
+        id: 2,
+        kind: "worker",
+        parent_id: Some(1),
+        ip: "10.0.0.3".to_string(),
+        mac: "52:54:00:00:00:03".to_string(),
+        tap: "tap2".to_string(),
+        ports: vec![],
+        resources: None,
+        host_ports: vec![],
+    };
+    async_std::task::block_on(async {
+        hooks
+            .run(HookEvent::Crash("killed".to_string()), &instance)
+            .await;
+        // failures and missing hooks are not an error
+        hooks.run(HookEvent::Stop, &instance).await;
+        hooks.run(HookEvent::Ready, &instance).await;
+    });
+    assert_eq!(
+        std::fs::read_to_string(out).unwrap(),
+        "crash 2 worker 10.0.0.3 tap2 1 killed\n"
+    );
+}
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_std::sync::Mutex;
+use async_std::task;
+use nix::ioctl_write_int;
+use nix::sys::ioctl::ioctl_param_type;
+use once_cell::sync::Lazy;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::progress::Progress;
+use crate::shell::{run_shell_command, ShellError};
+
+// Parallel launches of the same topology usually share the base image, so downloads
+// are serialized to only fetch it once.
+static DOWNLOAD_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+// Every worker verifies the same base image, and the session log records its digest once more.
+// A digest stays valid while the file keeps the size and modification time it was hashed with.
+static DIGESTS: Lazy<std::sync::Mutex<HashMap<PathBuf, (FileVersion, String)>>> =
+    Lazy::new(Default::default);
+
+type FileVersion = (u64, SystemTime);
+
+#[derive(Error, Debug)]
+pub(crate) enum ImageError {
+    #[error("Failed to run shell command")]
+    Shell(#[source] ShellError),
+    #[error("FileSystem error: {1}")]
+    FileSystem(#[source] std::io::Error, &'static str),
+    #[error("Homedir error")]
+    HomeDir(#[source] homedir::GetHomeError),
+    #[error("Could not determine home directory")]
+    NoHomeDir,
+    #[error("Unsupported image url: {0}")]
+    UnsupportedUrl(String),
+    #[error("Checksum mismatch for {0:?}: expected {1}, got {2}")]
+    ChecksumMismatch(PathBuf, String, String),
+    #[error("{0:?} does not contain a sha256")]
+    InvalidChecksumFile(PathBuf),
+    #[error("Downloaded image {0} needs --image-sha256 or a {0}.sha256 next to it")]
+    MissingChecksum(String),
+}
+
+type Result<T> = core::result::Result<T, ImageError>;
+
+fn cache_dir() -> Result<PathBuf> {
+    Ok(homedir::get_my_home()
+        .map_err(ImageError::HomeDir)?
+        .ok_or(ImageError::NoHomeDir)?
+        .join(".cache/vmlauncher/images"))
+}
+
+fn cache_file_name(url: &str) -> String {
+    url.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn file_version(path: &Path) -> Result<FileVersion> {
+    let metadata =
+        std::fs::metadata(path).map_err(|e| ImageError::FileSystem(e, "reading image metadata"))?;
+    let modified = metadata
+        .modified()
+        .map_err(|e| ImageError::FileSystem(e, "reading image modification time"))?;
+    Ok((metadata.len(), modified))
+}
+
+fn cached_sha256(path: &Path, version: FileVersion) -> Option<String> {
+    DIGESTS
+        .lock()
+        .unwrap()
+        .get(path)
+        .filter(|(hashed, _)| *hashed == version)
+        .map(|(_, digest)| digest.clone())
+}
+
+pub(crate) async fn sha256(path: &Path) -> Result<String> {
+    let version = file_version(path)?;
+    if let Some(digest) = cached_sha256(path, version) {
+        return Ok(digest);
+    }
+    let output = run_shell_command("sha256sum", &vec![path.to_str().unwrap()])
+        .await
+        .map_err(ImageError::Shell)?;
+    let digest = output
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    DIGESTS
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), (version, digest.clone()));
+    Ok(digest)
+}
+
+async fn verify_sha256(path: &Path, expected: &str) -> Result<()> {
+    let actual = sha256(path).await?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(ImageError::ChecksumMismatch(
+            path.to_owned(),
+            expected.to_string(),
+            actual,
+        ));
+    }
+    Ok(())
+}
+
+// The first word, as written by sha256sum
+fn parse_checksum(content: &str) -> Option<String> {
+    content
+        .split_whitespace()
+        .next()
+        .filter(|sum| sum.len() == 64 && sum.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(str::to_lowercase)
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.sha256", path.display()))
+}
+
+// `<image>.sha256` next to the image, if there is one
+async fn sidecar_checksum(path: &Path) -> Result<Option<String>> {
+    let sidecar = sidecar_path(path);
+    match async_std::fs::read_to_string(&sidecar).await {
+        Ok(content) => parse_checksum(&content)
+            .map(Some)
+            .ok_or(ImageError::InvalidChecksumFile(sidecar)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ImageError::FileSystem(e, "reading checksum file")),
+    }
+}
+
+// `<url>.sha256`, a download without a checksum could be truncated or corrupt
+async fn remote_checksum(url: &str, dir: &Path) -> Result<String> {
+    let partial = dir.join(format!("{}.sha256.part", cache_file_name(url)));
+    let downloaded = download(&format!("{url}.sha256"), &partial).await;
+    let content = async_std::fs::read_to_string(&partial).await;
+    let _ = async_std::fs::remove_file(&partial).await;
+    match (downloaded, content) {
+        (Ok(()), Ok(content)) => parse_checksum(&content)
+            .ok_or_else(|| ImageError::InvalidChecksumFile(PathBuf::from(format!("{url}.sha256")))),
+        _ => Err(ImageError::MissingChecksum(url.to_string())),
+    }
+}
+
+async fn download(url: &str, dest: &Path) -> Result<()> {
+    let dest = dest.to_str().unwrap();
+    match url.split_once("://").map(|(scheme, _)| scheme) {
+        Some("http") | Some("https") => {
+            run_shell_command("curl", &vec!["-fsSL", "-o", dest, url]).await
+        }
+        Some("s3") => run_shell_command("aws", &vec!["s3", "cp", "--quiet", url, dest]).await,
+        _ => return Err(ImageError::UnsupportedUrl(url.to_string())),
+    }
+    .map_err(ImageError::Shell)?;
+    Ok(())
+}
+
+fn is_url(image: &str) -> bool {
+    image.contains("://")
+}
+
+/// Returns a local path for `image`, downloading it into the cache if it is a url. The image is
+/// verified against `expected_sha256`, or the `.sha256` file next to it. Downloads are always
+/// verified.
+pub(crate) async fn resolve_image(image: &str, expected_sha256: Option<&str>) -> Result<PathBuf> {
+    if !is_url(image) {
+        let path = PathBuf::from(image);
+        let expected = match expected_sha256 {
+            Some(expected) => Some(expected.to_string()),
+            None => sidecar_checksum(&path).await?,
+        };
+        if let Some(expected) = expected {
+            verify_sha256(&path, &expected).await?;
+        }
+        return Ok(path);
+    }
+
+    let _guard = DOWNLOAD_LOCK.lock().await;
+    let cache_dir = cache_dir()?;
+    async_std::fs::create_dir_all(&cache_dir)
+        .await
+        .map_err(|e| ImageError::FileSystem(e, "creating image cache dir"))?;
+    let cached = cache_dir.join(cache_file_name(image));
+    // the checksum of a cached download is kept next to it
+    let expected = match expected_sha256 {
+        Some(expected) => expected.to_string(),
+        None => match sidecar_checksum(&cached).await? {
+            Some(expected) => expected,
+            None => remote_checksum(image, &cache_dir).await?,
+        },
+    };
+
+    if cached.exists() {
+        match verify_sha256(&cached, &expected).await {
+            Ok(()) => return Ok(cached),
+            Err(ImageError::ChecksumMismatch(..)) => {
+                warn!(
+                    ?cached,
+                    "Cached image does not match checksum, downloading again"
+                )
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    info!(url = image, dest = ?cached, "Downloading image");
+    let partial = cached.with_extension("part");
+    download(image, &partial).await?;
+    if let Err(e) = verify_sha256(&partial, &expected).await {
+        let _ = async_std::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+    async_std::fs::rename(&partial, &cached)
+        .await
+        .map_err(|e| ImageError::FileSystem(e, "moving downloaded image into cache"))?;
+    async_std::fs::write(sidecar_path(&cached), format!("{expected}\n"))
+        .await
+        .map_err(|e| ImageError::FileSystem(e, "writing checksum file"))?;
+
+    Ok(cached)
+}
+
+ioctl_write_int!(ficlone, 0x94, 9);
+
+fn reflink(src: &File, dest: &File) -> nix::Result<()> {
+    unsafe { ficlone(dest.as_raw_fd(), src.as_raw_fd() as ioctl_param_type) }?;
+    Ok(())
+}
+
+// Copies `src` but skips blocks which are all zeros, leaving holes in `dest`
+fn sparse_copy(mut src: &File, mut dest: &File, progress: &mut Progress) -> std::io::Result<()> {
+    const BLOCK_SIZE: usize = 64 * 1024;
+    let mut buf = vec![0u8; BLOCK_SIZE];
+    let mut len = 0u64;
+    loop {
+        let read = src.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if buf[..read].iter().all(|b| *b == 0) {
+            dest.seek(SeekFrom::Current(read as i64))?;
+        } else {
+            dest.write_all(&buf[..read])?;
+        }
+        len += read as u64;
+        progress.inc(read as u64);
+    }
+    dest.set_len(len)
+}
+
+fn copy_image_blocking(src: &Path, dest: &Path) -> std::io::Result<()> {
+    let src_file = File::open(src)?;
+    let dest_file = File::create(dest)?;
+
+    match reflink(&src_file, &dest_file) {
+        Ok(()) => {
+            debug!(?src, ?dest, "Reflinked image");
+            return Ok(());
+        }
+        Err(e) => debug!(?e, "Reflink not supported, falling back to sparse copy"),
+    }
+
+    let size = src_file.metadata()?.len();
+    let name = src.file_name().unwrap_or_default().to_string_lossy();
+    let mut progress = Progress::bytes(format!("Copying {name}"), size);
+    match sparse_copy(&src_file, &dest_file, &mut progress) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            warn!(?e, "Sparse copy failed, falling back to plain copy");
+            std::fs::copy(src, dest).map(|_| ())
+        }
+    }
+}
+
+/// Copies an image using a copy-on-write reflink if the filesystem supports it,
+/// otherwise with a sparse copy.
+pub(crate) async fn copy_image(src: &Path, dest: &Path) -> std::io::Result<()> {
+    let (src, dest) = (src.to_owned(), dest.to_owned());
+    task::spawn_blocking(move || copy_image_blocking(&src, &dest)).await
+}
+
+#[test]
+fn sparse_copy_preserves_content() {
+    let dir = tempdir::TempDir::new("sparse_copy").unwrap();
+    let src = dir.path().join("src.img");
+    let dest = dir.path().join("dest.img");
+
+    let mut content = vec![0u8; 256 * 1024];
+    content[10] = 1;
+    content[200 * 1024] = 2;
+    content.extend_from_slice(&[0u8; 100]);
+    std::fs::write(&src, &content).unwrap();
+
+    sparse_copy(
+        &File::open(&src).unwrap(),
+        &File::create(&dest).unwrap(),
+        &mut Progress::bytes("src.img".to_string(), content.len() as u64),
+    )
+    .unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), content);
+
+    futures_lite::future::block_on(copy_image(&src, &dest)).unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), content);
+}
+
+#[test]
+fn cached_digests() {
+    use futures_lite::future::block_on;
+    let dir = tempdir::TempDir::new("digest").unwrap();
+    let image = dir.path().join("flatcar.img");
+    std::fs::write(&image, b"flatcar").unwrap();
+    assert_eq!(cached_sha256(&image, file_version(&image).unwrap()), None);
+    let digest = block_on(sha256(&image)).unwrap();
+    assert_eq!(
+        cached_sha256(&image, file_version(&image).unwrap()),
+        Some(digest.clone())
+    );
+
+    // a changed image is hashed again
+    std::fs::write(&image, b"flatcar 2").unwrap();
+    assert_eq!(cached_sha256(&image, file_version(&image).unwrap()), None);
+    assert_ne!(block_on(sha256(&image)).unwrap(), digest);
+}
+
+#[test]
+fn sidecar_checksums() {
+    use futures_lite::future::block_on;
+    let dir = tempdir::TempDir::new("sidecar").unwrap();
+    let image = dir.path().join("flatcar.img");
+    std::fs::write(&image, b"flatcar").unwrap();
+    let image_str = image.to_str().unwrap();
+    // a valid sha256, but not the one of the image
+    let other = "5b2e2e0a1a3ee8b4eb1e0e0bd4a0e3d6b69e2b8b8e05ab8fe6b7d1fd4ed6e0a8";
+    let actual = block_on(sha256(&image)).unwrap();
+
+    assert!(block_on(resolve_image(image_str, None)).is_ok());
+    std::fs::write(sidecar_path(&image), format!("{actual}  flatcar.img\n")).unwrap();
+    assert!(block_on(resolve_image(image_str, None)).is_ok());
+    std::fs::write(sidecar_path(&image), other).unwrap();
+    assert!(matches!(
+        block_on(resolve_image(image_str, None)),
+        Err(ImageError::ChecksumMismatch(..))
+    ));
+    // the flag wins over the sidecar
+    assert!(block_on(resolve_image(image_str, Some(&actual))).is_ok());
+    std::fs::write(sidecar_path(&image), "truncated").unwrap();
+    assert!(matches!(
+        block_on(resolve_image(image_str, None)),
+        Err(ImageError::InvalidChecksumFile(_))
+    ));
+}
+
+#[test]
+fn local_paths_are_not_downloaded() {
+    let path = futures_lite::future::block_on(resolve_image("./flatcar_fresh.iso", None));
+    assert_eq!(path.unwrap(), PathBuf::from("./flatcar_fresh.iso"));
+    assert_eq!(
+        cache_file_name("https://example.com/flatcar.img"),
+        "https___example.com_flatcar.img"
+    );
+}
@@ -0,0 +1,92 @@
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{event, Level};
+
+// `journalctl -o json` with only the fields the launcher looks at
+pub(crate) const JOURNAL_OUTPUT_ARGS: &str = "-o json --output-fields=PRIORITY,MESSAGE";
+
+// journalctl writes every field as a string, messages which are not valid utf-8 as an array of
+// bytes
+#[derive(Debug, Deserialize)]
+struct JournalRecord {
+    #[serde(rename = "PRIORITY")]
+    priority: Option<String>,
+    #[serde(rename = "MESSAGE")]
+    message: Option<Value>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct JournalEntry {
+    pub(crate) level: Level,
+    pub(crate) message: String,
+}
+
+// syslog priorities, 0 (emerg) to 3 (err) are errors
+fn level(priority: u8) -> Level {
+    match priority {
+        0..=3 => Level::ERROR,
+        4 => Level::WARN,
+        5 | 6 => Level::INFO,
+        _ => Level::DEBUG,
+    }
+}
+
+impl JournalEntry {
+    // None for anything which is not a journal record, e.g. boot output before journalctl runs
+    pub(crate) fn parse(line: &str) -> Option<Self> {
+        let record: JournalRecord = serde_json::from_str(line.trim()).ok()?;
+        let message = match record.message? {
+            Value::String(message) => message,
+            Value::Array(bytes) => String::from_utf8_lossy(
+                &bytes
+                    .iter()
+                    .filter_map(|b| b.as_u64().map(|b| b as u8))
+                    .collect::<Vec<_>>(),
+            )
+            .to_string(),
+            _ => return None,
+        };
+        // entries without a priority are logged with the journal's default, info
+        let priority = record.priority.and_then(|p| p.parse().ok()).unwrap_or(6);
+        Some(JournalEntry {
+            level: level(priority),
+            message,
+        })
+    }
+
+    pub(crate) fn emit(&self, node_id: usize) {
+        let message = &self.message;
+        match self.level {
+            Level::ERROR => event!(Level::ERROR, node_id, "{message}"),
+            Level::WARN => event!(Level::WARN, node_id, "{message}"),
+            Level::INFO => event!(Level::INFO, node_id, "{message}"),
+            _ => event!(Level::DEBUG, node_id, "{message}"),
+        }
+    }
+}
+
+#[test]
+fn journal_entries() {
+    assert_eq!(
+        JournalEntry::parse(r#"{"PRIORITY":"3","MESSAGE":"Could not connect to coordinator"}"#),
+        Some(JournalEntry {
+            level: Level::ERROR,
+            message: "Could not connect to coordinator".to_string()
+        })
+    );
+    assert_eq!(
+        JournalEntry::parse("{\"PRIORITY\":\"4\",\"MESSAGE\":[104,105]}\r").unwrap(),
+        JournalEntry {
+            level: Level::WARN,
+            message: "hi".to_string()
+        }
+    );
+    assert_eq!(
+        JournalEntry::parse(r#"{"MESSAGE":"started"}"#)
+            .unwrap()
+            .level,
+        Level::INFO
+    );
+    assert_eq!(JournalEntry::parse("localhost login:"), None);
+    assert_eq!(JournalEntry::parse(r#"{"PRIORITY":"6"}"#), None);
+}
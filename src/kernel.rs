@@ -0,0 +1,64 @@
+use std::path::PathBuf;
+
+use crate::network::TapUser;
+use crate::qemu::{
+    BootSource, DirectKernelBoot, LaunchConfiguration, SecurityConfig, SocketAccess,
+};
+use crate::rundir::{RunDir, VmDir};
+
+const DEFAULT_MEMORY_IN_MEGABYTES: usize = 512;
+
+#[derive(Debug)]
+pub(crate) struct Args {
+    pub(crate) num_cores: Option<usize>,
+    pub(crate) memory_in_megabytes: Option<usize>,
+    pub(crate) security: SecurityConfig,
+    pub(crate) socket_dir: Option<PathBuf>,
+    pub(crate) run_dir: Option<RunDir>,
+}
+
+// A vm which boots a kernel and initrd directly, nothing is built. The kernel has to bring its
+// own root filesystem, e.g. in the initrd, and its console has to be ttyS0 to show up on serial.
+pub(crate) fn prepare_launch(
+    node_id: usize,
+    boot: DirectKernelBoot,
+    tap: TapUser,
+    args: &Args,
+) -> std::io::Result<LaunchConfiguration> {
+    let vm_dir = VmDir::create(args.run_dir.as_ref(), &format!("kernel-{node_id}"))?;
+    Ok(LaunchConfiguration {
+        tap,
+        boot: BootSource::DirectKernel(boot),
+        vm_dir,
+        firmware: vec![],
+        pflash: None,
+        num_cores: args.num_cores,
+        max_num_cores: None,
+        memory_in_mega_bytes: Some(
+            args.memory_in_megabytes
+                .unwrap_or(DEFAULT_MEMORY_IN_MEGABYTES),
+        ),
+        balloon: false,
+        security: args.security.clone(),
+        tpm: false,
+        socket_dir: args.socket_dir.clone(),
+        cpu_affinity: None,
+        vcpu_reservation: None,
+        cpu_limit: None,
+        machine_properties: vec![],
+        incoming: None,
+        output: None,
+        sync_clock: false,
+        hmp_monitor: false,
+        vnc: None,
+        gdb: None,
+        extra_drives: vec![],
+        disk_queues: None,
+        hugepages: None,
+        qemu_binary: None,
+        vhost: false,
+        nic_queues: 1,
+        bond_taps: vec![],
+        socket_access: SocketAccess::default(),
+    })
+}
@@ -1,60 +1,504 @@
 use async_std::future::{self, timeout, TimeoutError};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::future::Future;
 use std::io::stdin;
-use std::net::{IpAddr, Ipv4Addr};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
 use std::pin::{pin, Pin};
+use std::str::FromStr;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::nanos::RunConfig;
-use crate::nes::{
-    Format, Source, TCPSourceConfig, TCPSourceConfigBuilder,
-    WorkerQueryProcessingConfigurationBuilder,
-};
+use crate::nes::{Format, LogicalSource, Source, TCPSourceConfig, TCPSourceConfigBuilder};
 use async_std::task;
 use async_std::task::JoinHandle;
 use camino::Utf8PathBuf;
-use clap::{Args, Parser, Subcommand};
-use futures::FutureExt;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use inquire::error::InquireResult;
 use inquire::{CustomType, InquireError};
 use ipnet::Ipv4Net;
 use itertools::Itertools;
-use serde::Deserialize;
+use nix::sys::termios::{self, SetArg};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::network::{network_cleanup, network_setup, NetworkConfig};
+use crate::builder::CommandBuilder;
+use crate::cpus::{CpuAssignment, IsolatedCpus, VcpuBudget, VcpuReservation};
+use crate::env::{validate_env, Backends};
+use crate::export::{ExportFormat, InstanceRecord};
+use crate::firecracker::{start_firecracker, FirecrackerError};
+use crate::flatcar::ButaneSpec;
+use crate::forward::{HostPortRange, HostPorts, PortForward};
+use crate::hooks::{HookEvent, Hooks};
+use crate::journal::JOURNAL_OUTPUT_ARGS;
+use crate::network::{
+    network_adopt, network_cleanup, network_setup, network_setup_segment, validate_segments,
+    KernelNetworkBackend, NetworkBackend, NetworkConfig, NetworkError, ReservedRange, TapUser,
+};
+use crate::oom::OomWatcher;
+use crate::profile::{ProfileRegistry, ResourceProfile};
 use crate::qemu::{
-    serial, serial_with_command, start_qemu, QemuError, QemuProcessHandle, SerialError,
+    serial_capture, start_qemu_with_retries, wait_for_serial_marker, CapturedVm, CommandOutput,
+    DirectKernelBoot, GdbStub, LaunchConfiguration, LaunchSummary, MachineProperty, PflashConfig,
+    QemuError, QemuProcessHandle, SecurityConfig, SerialConsole, SerialError, SerialOptions,
+    SerialSink, SocketAccess, StopTimeouts, VirtioDrive, VncDisplay, DEFAULT_SERIAL_BUFFER_SIZE,
 };
-use crate::templates::WorkerConfiguration;
+use crate::rundir::RunDir;
+use crate::session::{SessionHeader, SessionLog};
+use crate::templates::{
+    BondConfiguration, BondMode, CoordinatorConfiguration, CoordinatorTimeouts, ExtraFile,
+    ExtraUnit, Templates, WorkerConfigFile, WorkerConfiguration, WorkerPorts,
+};
+use crate::topology::Topology;
+use crate::vm::{VmError, VmHandle};
 
+mod arp;
+mod builder;
+mod cgroup;
+mod cpus;
+mod env;
+mod export;
+mod firecracker;
 mod flatcar;
+mod forward;
+mod hooks;
+mod image;
+mod journal;
+mod kernel;
 mod nanos;
 mod nes;
 mod network;
+mod oom;
+mod profile;
+mod progress;
 mod qemu;
+mod readiness;
+mod reset;
+mod rundir;
+mod session;
 mod shell;
 mod templates;
-// mod firecracker;
+mod topology;
+mod vm;
 
 #[derive(Parser)]
 struct ProgramArgs {
     #[arg(short = 'k')]
     keep_bridge_alive: bool,
+    #[clap(flatten)]
+    launch_options: LaunchOptions,
     #[clap(subcommand)]
     command: VMLauncherCommand,
 }
 
+#[derive(Debug, Clone, Args)]
+struct LaunchOptions {
+    /// Namespaces the bridges, taps, qemu processes and run dir, so several topologies can run
+    /// on one host and reset-host only removes the resources of this one
+    #[arg(long = "topology-name")]
+    topology: Option<Topology>,
+    /// Seconds a worker may take to boot before it is stopped and considered failed
+    #[arg(long, default_value_t = 300)]
+    boot_timeout: u64,
+    /// Regex matching the console line which tells that a worker booted, e.g.
+    /// 'nesWorker.*started'
+    #[arg(long, default_value = WORKER_BOOT_MARKER, value_parser = Regex::new)]
+    boot_marker: Regex,
+    /// Seconds a vm gets to shut down after the power button before qemu is quit, e.g. 30 for
+    /// stateful workers which flush on shutdown
+    #[arg(long, default_value_t = 2)]
+    shutdown_grace_period: u64,
+    /// Seconds qemu gets to exit after it was quit, before it is sent SIGTERM
+    #[arg(long, default_value_t = 2)]
+    quit_timeout: u64,
+    /// Seconds qemu gets to exit after SIGTERM, before it is killed
+    #[arg(long, default_value_t = 5)]
+    terminate_timeout: u64,
+    /// Flatcar base image, either a local path or an http(s):// or s3:// url
+    #[arg(long, default_value = "./flatcar_fresh.iso")]
+    flatcar_image: String,
+    /// Expected sha256 of the flatcar base image, instead of the `<image>.sha256` file next to
+    /// it. Downloaded images are only used with either of them
+    #[arg(long)]
+    image_sha256: Option<String>,
+    /// Launch qemu with the seccomp sandbox enabled
+    #[arg(long)]
+    sandbox: bool,
+    /// Drop qemu privileges to this user after startup
+    #[arg(long)]
+    run_as: Option<String>,
+    /// Resource profile for workers which do not name one (small, medium, large)
+    #[arg(long)]
+    profile: Option<String>,
+    #[arg(skip)]
+    profiles: ProfileRegistry,
+    /// Watch the host kernel log to tell if a vm was killed by the OOM killer
+    #[arg(long)]
+    watch_oom: bool,
+    /// Refuse to run more than this many vms at the same time
+    #[arg(long, default_value_t = 32)]
+    max_workers: usize,
+    /// Gateway address of the default bridge, defaults to the first host of the ip range
+    #[arg(long)]
+    gateway: Option<Ipv4Addr>,
+    /// Addresses which are never assigned to a vm, e.g. a coordinator running on the host.
+    /// Either a single address, a range like 10.0.0.100-10.0.0.150 or a cidr block
+    #[arg(long = "reserve")]
+    reserved_ips: Vec<ReservedRange>,
+    /// Attach workers to this existing bridge, e.g. virbr0, instead of creating one. Its subnet
+    /// is used for the workers and the bridge is left in place on exit
+    #[arg(long)]
+    existing_bridge: Option<String>,
+    /// Attach an emulated TPM (requires swtpm) to workers
+    #[arg(long)]
+    tpm: bool,
+    /// Directory for the qemu unix sockets, e.g. /run/vmlauncher, with a subdirectory per
+    /// --topology name. Defaults to the vm's temp dir, which may exceed the socket path limit on
+    /// long TMPDIRs
+    #[arg(long)]
+    socket_dir: Option<PathBuf>,
+    /// Put every vm's files into a named subdirectory, e.g. `<dir>/worker-3/`, instead of its own
+    /// random temp dir. The vm directories are removed when the launcher exits
+    #[arg(long = "run-dir")]
+    run_dir_root: Option<PathBuf>,
+    #[arg(skip)]
+    run_dir: Option<RunDir>,
+    /// Additionally append each vm's console output to `<dir>/serial-<id>.log`, a restarted vm
+    /// keeps the output of its previous run
+    #[arg(long)]
+    serial_log_dir: Option<PathBuf>,
+    /// Do not echo the console output of the vms to stdout, boot detection and
+    /// --serial-log-dir still see every line
+    #[arg(long)]
+    quiet: bool,
+    /// Follow the worker journal as json and log each entry at its priority, so guest errors
+    /// and warnings stand out
+    #[arg(long)]
+    journal_levels: bool,
+    /// Boot flatcar workers with debug log levels for the kernel, systemd and journald, all
+    /// written to the serial console
+    #[arg(long)]
+    guest_verbose: bool,
+    /// Keep the guest clocks in sync with the host for time based windows. All vms get an rtc
+    /// which follows the host clock. Flatcar workers additionally sync with NTP from the host's
+    /// bridge address before the worker starts, so the host has to serve NTP there. Nanos
+    /// unikernels only read the rtc at boot.
+    #[arg(long)]
+    sync_guest_clock: bool,
+    /// Do not open the human monitor socket `monitor.socket` next to `qmp.socket`, which scripts
+    /// still use and is opened by default
+    #[arg(long)]
+    no_hmp_monitor: bool,
+    /// Wait at most this many seconds for the NES worker of every vm to accept connections on
+    /// its rpc port, before a script runs its exec, add source and stop commands
+    #[arg(long)]
+    ready_timeout: Option<u64>,
+    /// Permissions of the serial, monitor and qmp sockets in octal, e.g. 660 together with
+    /// --socket-group. Anyone who can connect may stop the vm
+    #[arg(long, default_value = "600", value_parser = parse_socket_mode)]
+    socket_mode: u32,
+    /// Group the serial, monitor and qmp sockets are handed to
+    #[arg(long)]
+    socket_group: Option<String>,
+    /// Serve the console of every worker and unikernel over vnc, `[host]:<display>`. A vm gets
+    /// the display plus its id, e.g. worker 2 is on :3 with --vnc :1. Pool vms have no display
+    #[arg(long)]
+    vnc: Option<VncDisplay>,
+    /// Freeze every unikernel until gdb attaches to its stub on this port plus its node id, e.g.
+    /// node 2 on 1236 with --gdb 1234. Workers only get a stub with `gdb` in the script or when
+    /// asked for one interactively
+    #[arg(long)]
+    gdb: Option<u16>,
+    /// What unikernels run on, flatcar workers always run on qemu
+    #[arg(long, value_enum, default_value_t = Hypervisor::Qemu)]
+    hypervisor: Hypervisor,
+    /// Kernel firecracker boots unikernels with, e.g. ~/.ops/<version>/kernel.img
+    #[arg(long, required_if_eq("hypervisor", "firecracker"))]
+    firecracker_kernel: Option<PathBuf>,
+    /// Serve the boot disk with this many virtio-blk queues from a dedicated iothread, at most
+    /// one per vcpu. Off by default
+    #[arg(long)]
+    disk_queues: Option<usize>,
+    /// Back guest memory with hugepages from this hugetlbfs mount, e.g. /dev/hugepages. Enough
+    /// pages for every vm have to be reserved beforehand
+    #[arg(long)]
+    hugepages: Option<PathBuf>,
+    /// Qemu to run the vms with, e.g. a locally built one. `qemu-system-x86_64` from PATH by
+    /// default
+    #[arg(long, env = "VMLAUNCHER_QEMU_BIN")]
+    qemu_binary: Option<PathBuf>,
+    /// Do not use vhost-net for the nics, which is used by default if /dev/vhost-net can be
+    /// opened
+    #[arg(long)]
+    no_vhost: bool,
+    /// Queues of every nic, e.g. the vcpus of the workers so each one handles its own traffic.
+    /// More than one creates multiqueue taps. Single queue by default
+    #[arg(long, default_value_t = 1)]
+    nic_queues: usize,
+    /// Pin every vm to its own cores out of the host's isolated cpus (isolcpus=)
+    #[arg(long)]
+    use_isolated_cpus: bool,
+    #[arg(skip)]
+    isolated_cpus: Option<IsolatedCpus>,
+    /// Refuse to launch a vm if the vcpus of all vms would exceed this many times the host's
+    /// online cpus, e.g. 2.0
+    #[arg(long)]
+    max_vcpu_overcommit: Option<f64>,
+    /// Only warn when --max-vcpu-overcommit is exceeded
+    #[arg(long, requires = "max_vcpu_overcommit")]
+    force_overcommit: bool,
+    #[arg(skip)]
+    vcpu_budget: Option<VcpuBudget>,
+    /// OVMF firmware code, boots workers and unikernel images via UEFI
+    #[arg(long, requires = "ovmf_vars")]
+    ovmf_code: Option<PathBuf>,
+    /// OVMF variable store template, copied for every vm
+    #[arg(long, requires = "ovmf_code")]
+    ovmf_vars: Option<PathBuf>,
+    /// Size of the serial read buffer, longer console lines are split
+    #[arg(long, default_value_t = DEFAULT_SERIAL_BUFFER_SIZE)]
+    serial_buffer_size: usize,
+    /// Stop reading the console of a vm which did not write anything within this many
+    /// seconds, 0 waits forever
+    #[arg(long, default_value_t = 120)]
+    serial_no_output_timeout: u64,
+    /// Prefix console lines with the UTC time they were read, `[2024-01-01T12:00:00Z][id] line`
+    #[arg(long)]
+    serial_timestamps: bool,
+    /// Extra -machine property for every vm, e.g. kernel-irqchip=split. Can be repeated
+    #[arg(long = "machine-property")]
+    machine_properties: Vec<MachineProperty>,
+    /// Grow the source and global buffer pools with the number of sources of a worker,
+    /// unless the worker sets them explicitly
+    #[arg(long)]
+    scale_buffers: bool,
+    /// Print the merged configuration of every worker as yaml before launching it
+    #[arg(long)]
+    print_effective_config: bool,
+    /// Retry a vm launch this many times if qemu fails because a tap, port or kvm is busy
+    #[arg(long, default_value_t = 0)]
+    launch_retries: usize,
+    /// Host ports for workers which expose their data and rpc port, e.g. 20000-20099
+    #[arg(long)]
+    host_port_range: Option<HostPortRange>,
+    #[arg(skip)]
+    host_ports: Option<HostPorts>,
+    /// Keep this many flatcar vms booted ahead of time on the default bridge. Workers with the
+    /// default resources take one of them instead of booting. Pool vms do not count towards
+    /// --max-workers
+    #[arg(long, default_value_t = 0)]
+    warm_pool_size: usize,
+    #[arg(skip)]
+    warm_pool: Option<WarmPool>,
+    #[clap(flatten)]
+    hooks: Hooks,
+    /// Record every command the launcher executes to this file, so the session can be reproduced
+    /// with replay. Interactive restarts, migrations and reconfigurations are not recorded, a
+    /// replay brings the vms up as they were launched.
+    #[arg(long)]
+    session_log: Option<PathBuf>,
+    #[arg(skip)]
+    session: Option<Arc<SessionLog>>,
+    /// Check the consistency of the ip allocation after every tap that is created or released,
+    /// and abort on the first violation
+    #[arg(long, default_value_t = false)]
+    debug_assert_state: bool,
+    /// Variant and version of the generated butane config, newer flatcar releases accept newer
+    /// versions, e.g. flatcar:1.1.0
+    #[arg(long, default_value = "flatcar:1.0.0", value_parser = ButaneSpec::from_str)]
+    butane_spec: ButaneSpec,
+}
+
+fn parse_socket_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("{mode} is not an octal file mode"))
+}
+
+impl LaunchOptions {
+    fn topology(&self) -> Topology {
+        self.topology.clone().unwrap_or_default()
+    }
+
+    // A copy which records to --session-log, if it is set
+    fn with_session(&self, header: SessionHeader) -> Result<LaunchOptions, Error> {
+        let mut options = self.clone();
+        if let Some(path) = self.session_log.as_ref() {
+            let session = SessionLog::create(path, &header).map_err(Error::Session)?;
+            options.session = Some(Arc::new(session));
+        }
+        Ok(options)
+    }
+
+    fn stop_timeouts(&self) -> StopTimeouts {
+        StopTimeouts {
+            grace_period: Duration::from_secs(self.shutdown_grace_period),
+            quit: Duration::from_secs(self.quit_timeout),
+            terminate: Duration::from_secs(self.terminate_timeout),
+        }
+    }
+
+    fn socket_access(&self) -> SocketAccess {
+        SocketAccess {
+            mode: self.socket_mode,
+            group: self.socket_group.clone(),
+        }
+    }
+
+    fn security(&self) -> SecurityConfig {
+        SecurityConfig {
+            sandbox: self.sandbox,
+            run_as: self.run_as.clone(),
+        }
+    }
+
+    fn check_capacity(&self, running: usize, additional: usize) -> Result<(), Error> {
+        if running + additional > self.max_workers {
+            return Err(Error::TooManyWorkers(
+                running + additional,
+                self.max_workers,
+            ));
+        }
+        Ok(())
+    }
+
+    fn assign_cpus(&self, cores: usize) -> Result<Option<CpuAssignment>, Error> {
+        self.isolated_cpus
+            .as_ref()
+            .map(|cpus| cpus.assign(cores))
+            .transpose()
+            .map_err(Error::Cpus)
+    }
+
+    fn reserve_vcpus(&self, vcpus: usize) -> Result<Option<VcpuReservation>, Error> {
+        self.vcpu_budget
+            .as_ref()
+            .map(|budget| budget.reserve(vcpus))
+            .transpose()
+            .map_err(Error::Cpus)
+    }
+
+    fn serial_options(&self) -> SerialOptions {
+        SerialOptions {
+            buffer_size: self.serial_buffer_size,
+            no_output_timeout: Some(Duration::from_secs(self.serial_no_output_timeout))
+                .filter(|timeout| !timeout.is_zero()),
+            timestamps: self.serial_timestamps,
+        }
+    }
+
+    // The vm's own copy of the OVMF variable store, with --ovmf-code and --ovmf-vars
+    async fn pflash(&self, vm_dir: &Path) -> Result<Option<PflashConfig>, Error> {
+        let Some((code, vars)) = self.ovmf_code.as_ref().zip(self.ovmf_vars.as_ref()) else {
+            return Ok(None);
+        };
+        let pflash = PflashConfig::prepare(code, vars, vm_dir)
+            .await
+            .map_err(Error::Qemu)?;
+        Ok(Some(pflash))
+    }
+
+    // A vm frozen until gdb continues it stays silent for as long as the debugging session takes
+    fn vm_serial_options(&self, handle: &QemuProcessHandle) -> SerialOptions {
+        let mut serial_options = self.serial_options();
+        if handle.gdb().is_some_and(|gdb| gdb.wait) {
+            serial_options.no_output_timeout = None;
+        }
+        serial_options
+    }
+
+    fn worker_serial_command(&self) -> String {
+        match self.journal_levels {
+            true => format!("{WORKER_SERIAL_COMMAND} {JOURNAL_OUTPUT_ARGS}\n"),
+            false => format!("{WORKER_SERIAL_COMMAND}\n"),
+        }
+    }
+
+    // `journal` for consoles which run the worker serial command
+    fn serial_sinks(&self, id: usize, journal: bool) -> Result<Vec<SerialSink>, Error> {
+        let mut sinks = vec![match (self.quiet, journal && self.journal_levels) {
+            (true, _) => SerialSink::Discard,
+            (false, true) => SerialSink::Journal(id),
+            (false, false) => SerialSink::Stdout(id),
+        }];
+        if let Some(dir) = self.serial_log_dir.as_ref() {
+            std::fs::create_dir_all(dir).map_err(Error::IO)?;
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(dir.join(format!("serial-{id}.log")))
+                .map_err(Error::IO)?;
+            sinks.push(SerialSink::File(file));
+        }
+        Ok(sinks)
+    }
+
+    fn oom_watcher(&self) -> Option<OomWatcher> {
+        if !self.watch_oom {
+            return None;
+        }
+        match OomWatcher::start() {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                error!(%e, "Not watching for OOM kills");
+                None
+            }
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum VMLauncherCommand {
     Interactive(InteractiveArgs),
     Script(ScriptArgs),
-    Test,
+    Test(TestArgs),
+    /// Check that the external tools are installed and recent enough
+    ValidateEnv(ValidateEnvArgs),
+    /// Remove the bridges, taps and qemu processes a crashed launcher left behind
+    ResetHost(ResetHostArgs),
+    /// Run the commands recorded with --session-log again, on the same images and binaries
+    Replay(ReplayArgs),
+}
+
+#[derive(Debug, Args)]
+struct ReplayArgs {
+    session_log: PathBuf,
+    /// Address space of the default bridge, the recorded one by default
+    #[arg(short = 'n')]
+    ip_range: Option<Ipv4Net>,
+}
+
+#[derive(Debug, Args)]
+struct ResetHostArgs {
+    /// Only list what would be removed
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+struct ValidateEnvArgs {
+    /// Only require the tools used by this script
+    script: Option<Utf8PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct TestArgs {
+    // Record the network operations instead of creating real devices
+    #[arg(long)]
+    mock: bool,
+    /// Feed a recorded console log through the worker boot detection instead
+    #[arg(long)]
+    serial_replay: Option<PathBuf>,
 }
 
 #[derive(Debug, Args)]
@@ -65,9 +509,18 @@ struct InteractiveArgs {
 
 #[derive(Debug, Args)]
 struct ScriptArgs {
+    /// Address space of the default bridge, required unless --existing-bridge is given
     #[arg(short = 'n')]
-    ip_range: Ipv4Net,
+    ip_range: Option<Ipv4Net>,
     config: Option<Utf8PathBuf>,
+    /// Write the topology to this file once all commands ran
+    #[arg(long)]
+    export: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+    export_format: ExportFormat,
+    /// Write a coordinator config declaring the script's logical sources to this file
+    #[arg(long)]
+    coordinator_config: Option<PathBuf>,
 }
 
 #[derive(Error, Debug)]
@@ -76,8 +529,28 @@ enum Error {
     Inquire(#[source] InquireError),
     #[error("Qemu Error")]
     Nanos(#[source] nanos::NanosError),
+    #[error("Flatcar Error")]
+    Flatcar(#[source] flatcar::FlatcarError),
+    #[error("Could not resolve image")]
+    Image(#[source] image::ImageError),
+    #[error("Network Error")]
+    Network(#[source] network::NetworkError),
+    #[error("Export Error")]
+    Export(#[source] export::ExportError),
+    #[error("Reset Error")]
+    Reset(#[source] reset::ResetError),
+    #[error("Cpu assignment Error")]
+    Cpus(#[source] cpus::CpuError),
+    #[error("Resource profile Error")]
+    Profile(#[source] profile::ProfileError),
     #[error("Qemu Error")]
     Qemu(#[source] QemuError),
+    #[error("Firecracker Error")]
+    Firecracker(#[source] FirecrackerError),
+    #[error("Vm Error")]
+    Vm(#[source] VmError),
+    #[error("{0} is only supported for qemu vms")]
+    QemuOnly(&'static str),
     #[error("Qemu Error while listening to serial")]
     QemuSerial(#[source] SerialError),
     #[error("Qemu Error while doing io")]
@@ -86,9 +559,67 @@ enum Error {
     ScriptFileNotFound(#[source] std::io::Error, Utf8PathBuf),
     #[error("Qemu Error while doing io")]
     Deserialization(#[source] serde_yaml::Error),
+    #[error("Instance {0} was killed by the OOM killer: {1}")]
+    KilledByOom(usize, String),
+    #[error("Instance {0} is no longer running")]
+    InstanceGone(usize),
+    #[error("Refusing to run {0} vms, the limit is {1} (see --max-workers)")]
+    TooManyWorkers(usize, usize),
+    #[error("Invalid launch dependencies: {0}")]
+    InvalidDependencies(String),
+    #[error("Instance {0} is launched while it is still running")]
+    AlreadyRunning(usize),
+    #[error("Captured vm {0:?} has an invalid mac address {1}")]
+    InvalidCapturedMac(PathBuf, String),
+    #[error("No instance with id {0}")]
+    UnknownInstance(usize),
+    #[error("Worker {0} already uses port {1}")]
+    PortInUse(usize, u16),
+    #[error("Worker {0} runs a complete worker config file, its sources can not be changed")]
+    RawWorkerConfig(usize),
+    #[error("Could not print the effective configuration")]
+    EffectiveConfig(#[source] serde_yaml::Error),
+    #[error("Worker config file {1:?} is not valid yaml")]
+    InvalidWorkerConfigFile(#[source] serde_yaml::Error, PathBuf),
+    #[error("Template Error")]
+    Template(#[source] templates::TemplateError),
+    #[error("Either an ip range (-n) or --existing-bridge is required")]
+    NoIpRange,
+    #[error("Worker {0} exposes its ports, which requires --host-port-range")]
+    NoHostPortRange(usize),
+    #[error("The gdb port {0} plus node id {1} is not a valid port")]
+    GdbPortOutOfRange(u16, usize),
+    #[error("Could not forward a host port")]
+    Forward(#[source] forward::ForwardError),
+    #[error("Instance {0} is not a flatcar worker")]
+    NotAWorker(usize),
+    #[error("`{1}` failed on instance {0} with exit status {2}")]
+    GuestCommandFailed(usize, String, i32),
+    #[error("Worker {0} bonds {1} taps, a bond needs at least 2")]
+    InvalidBond(usize, usize),
+    #[error("Not ready: {}", .0.iter().map(|(id, e)| format!("{id} ({e})")).join(", "))]
+    NotReady(Vec<(usize, readiness::ReadinessError)>),
+    #[error("Session log Error")]
+    Session(#[source] session::SessionError),
+    #[error("Worker did not boot within {0:?}. Last serial output:\n{}", .1.join("\n"))]
+    BootTimeout(Duration, Vec<String>),
 }
 
-#[derive(Deserialize)]
+// A kernel booted without a disk image, e.g. to test a kernel build without ops or butane
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AddKernelArgs {
+    node_id: usize,
+    // kernel, initrd and append
+    #[serde(flatten)]
+    boot: DirectKernelBoot,
+    vcpus: Option<usize>,
+    memory_in_megabytes: Option<usize>,
+    segment: Option<String>,
+    gdb: Option<GdbStub>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct AddUnikernelArgs {
     node_id: usize,
@@ -96,6 +627,30 @@ struct AddUnikernelArgs {
     path_to_binary: String,
     args: Vec<String>,
     ip: Option<Ipv4Addr>,
+    segment: Option<String>,
+    #[serde(flatten)]
+    ports: WorkerPorts,
+    // host directory the unikernel's /output is copied to when it stops
+    #[serde(default)]
+    output_dir: Option<PathBuf>,
+    // host command which builds the image instead of ops, see builder.rs
+    image_builder: Option<String>,
+    // e.g. `gdb: {port: 1234, wait: true}`, see GdbStub for attaching
+    gdb: Option<GdbStub>,
+}
+
+// Skipped for a vm without a gdb stub
+fn inquire_gdb_stub() -> Result<Option<GdbStub>, InquireError> {
+    let Some(port) = inquire::CustomType::<u16>::new("Gdb stub port?")
+        .with_help_message("Skip for no gdb stub")
+        .prompt_skippable()?
+    else {
+        return Ok(None);
+    };
+    let wait = inquire::Confirm::new("Freeze until gdb continues?")
+        .with_default(false)
+        .prompt()?;
+    Ok(Some(GdbStub { port, wait }))
 }
 
 impl AddUnikernelArgs {
@@ -119,24 +674,39 @@ impl AddUnikernelArgs {
                 .map(|s| s.to_string())
                 .collect(),
             ip: inquire::CustomType::<Ipv4Addr>::new("ip ?").prompt_skippable()?,
+            segment: None,
+            ports: WorkerPorts::default(),
+            output_dir: inquire::Text::new("output directory?")
+                .prompt_skippable()?
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+            image_builder: None,
+            gdb: inquire_gdb_stub()?,
         })
     }
 }
 
 async fn add_unikernel(
     nc: NetworkConfig,
+    tap: TapUser,
+    options: &LaunchOptions,
     args: AddUnikernelArgs,
-) -> Result<(QemuProcessHandle, Result<(), Error>), Error> {
+) -> LaunchResult {
+    let elf_binary = image::resolve_image(&args.path_to_binary, None)
+        .await
+        .map_err(Error::Image)?;
+    let rpc_port = args.ports.rpc_port;
     let wc = nanos::UnikernelWorkerConfig {
         node_id: args.node_id,
         query_id: args.query_id,
-        elf_binary: Utf8PathBuf::from(args.path_to_binary),
+        elf_binary: Utf8PathBuf::from_path_buf(elf_binary).unwrap(),
         args: Some(args.args.join(" ")),
         ip: args.ip,
+        ports: args.ports,
+        output_dir: args.output_dir,
     };
 
-    let tap = nc.get_tap();
-    let lc = nanos::prepare_launch(
+    let mut lc = nanos::prepare_launch(
         wc,
         tap,
         &nanos::Args {
@@ -148,51 +718,565 @@ async fn add_unikernel(
                 gateway: nc.host_ip(),
             },
             use_docker: false,
+            security: options.security(),
+            socket_dir: options.socket_dir.clone(),
+            run_dir: options.run_dir.clone(),
+            image_builder: args
+                .image_builder
+                .clone()
+                .map(|command| Arc::new(CommandBuilder { command }) as _),
         },
     )
     .await
     .map_err(Error::Nanos)?;
+    lc.pflash = options.pflash(lc.vm_dir.path()).await?;
+    lc.sync_clock = options.sync_guest_clock;
+    lc.hmp_monitor = !options.no_hmp_monitor;
+    lc.socket_access = options.socket_access();
+    lc.disk_queues = options.disk_queues;
+    lc.hugepages = options.hugepages.clone();
+    lc.qemu_binary = options.qemu_binary.clone();
+    lc.vhost = !options.no_vhost && qemu::vhost_net_available();
+    lc.nic_queues = options.nic_queues;
+    lc.vnc = options
+        .vnc
+        .as_ref()
+        .map(|vnc| vnc.for_instance(args.node_id));
+    lc.gdb = match (args.gdb, options.gdb) {
+        (Some(gdb), _) => Some(gdb),
+        (None, Some(port)) => Some(GdbStub {
+            port: u16::try_from(args.node_id)
+                .ok()
+                .and_then(|id| port.checked_add(id))
+                .ok_or(Error::GdbPortOutOfRange(port, args.node_id))?,
+            wait: true,
+        }),
+        (None, None) => None,
+    };
+    lc.vcpu_reservation = options.reserve_vcpus(lc.vcpus())?;
+    lc.cpu_affinity = options.assign_cpus(lc.vcpus())?;
+    lc.machine_properties = options.machine_properties.clone();
 
-    info!("Starting Qemu");
-    let handle = start_qemu(lc).await.map_err(Error::Qemu)?;
-    let serial_socket = handle.serial_path();
-    Ok((
+    let (handle, console): (Box<dyn VmHandle>, _) = match options.hypervisor {
+        Hypervisor::Qemu => {
+            info!("Starting Qemu");
+            let handle = start_qemu_with_retries(lc, options.launch_retries)
+                .await
+                .map_err(Error::Qemu)?;
+            let console = SerialConsole::connect(
+                handle.serial_path(),
+                options.serial_sinks(args.node_id, false)?,
+                options.vm_serial_options(&handle),
+            )
+            .await
+            .map_err(Error::QemuSerial)?;
+            (Box::new(handle), console)
+        }
+        Hypervisor::Firecracker => {
+            info!("Starting Firecracker");
+            let kernel = options.firecracker_kernel.clone().unwrap();
+            let lc = firecracker::LaunchConfiguration::from_qemu(lc, kernel)
+                .map_err(Error::Firecracker)?;
+            let mut handle = start_firecracker(lc).await.map_err(Error::Firecracker)?;
+            let console = SerialConsole::attach(
+                handle.take_console().unwrap(),
+                options.serial_sinks(args.node_id, false)?,
+                options.serial_options(),
+            );
+            (Box::new(handle), console)
+        }
+    };
+    let mut instance = Instance {
+        id: args.node_id,
+        kind: "unikernel",
         handle,
-        serial(serial_socket, args.node_id)
+        serial: None,
+        console,
+        worker_config: None,
+        resources: None,
+        ports: vec![],
+        forwards: vec![],
+        serial_command: None,
+        stop_timeouts: options.stop_timeouts(),
+        guest_ip: None,
+        rpc_port: Some(rpc_port),
+        image: None,
+    };
+    instance.log_gdb_stub();
+    instance.spawn_serial();
+    Ok(instance)
+}
+
+async fn add_kernel(tap: TapUser, options: &LaunchOptions, args: AddKernelArgs) -> LaunchResult {
+    let mut lc = kernel::prepare_launch(
+        args.node_id,
+        args.boot,
+        tap,
+        &kernel::Args {
+            num_cores: args.vcpus,
+            memory_in_megabytes: args.memory_in_megabytes,
+            security: options.security(),
+            socket_dir: options.socket_dir.clone(),
+            run_dir: options.run_dir.clone(),
+        },
+    )
+    .map_err(Error::IO)?;
+    lc.sync_clock = options.sync_guest_clock;
+    lc.hmp_monitor = !options.no_hmp_monitor;
+    lc.socket_access = options.socket_access();
+    lc.hugepages = options.hugepages.clone();
+    lc.qemu_binary = options.qemu_binary.clone();
+    lc.vhost = !options.no_vhost && qemu::vhost_net_available();
+    lc.nic_queues = options.nic_queues;
+    lc.vnc = options
+        .vnc
+        .as_ref()
+        .map(|vnc| vnc.for_instance(args.node_id));
+    lc.gdb = args.gdb;
+    lc.vcpu_reservation = options.reserve_vcpus(lc.vcpus())?;
+    lc.cpu_affinity = options.assign_cpus(lc.vcpus())?;
+    lc.machine_properties = options.machine_properties.clone();
+
+    info!("Starting Qemu");
+    let handle = start_qemu_with_retries(lc, options.launch_retries)
+        .await
+        .map_err(Error::Qemu)?;
+    let console = SerialConsole::connect(
+        handle.serial_path(),
+        options.serial_sinks(args.node_id, false)?,
+        options.vm_serial_options(&handle),
+    )
+    .await
+    .map_err(Error::QemuSerial)?;
+    let mut instance = Instance {
+        id: args.node_id,
+        kind: "kernel",
+        handle: Box::new(handle),
+        serial: None,
+        console,
+        worker_config: None,
+        resources: None,
+        ports: vec![],
+        forwards: vec![],
+        serial_command: None,
+        stop_timeouts: options.stop_timeouts(),
+        guest_ip: None,
+        rpc_port: None,
+        image: None,
+    };
+    instance.log_gdb_stub();
+    instance.spawn_serial();
+    Ok(instance)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Hypervisor {
+    Qemu,
+    Firecracker,
+}
+
+struct Instance {
+    id: usize,
+    // worker, unikernel or kernel, what the instance was launched as
+    kind: &'static str,
+    handle: Box<dyn VmHandle>,
+    // reads the console, stopped together with the instance
+    serial: Option<JoinHandle<Result<(), Error>>>,
+    console: SerialConsole,
+    // only set for flatcar workers
+    worker_config: Option<WorkerConfiguration>,
+    resources: Option<ResourceProfile>,
+    ports: Vec<u16>,
+    // host ports forwarded to the guest, released with the instance
+    forwards: Vec<PortForward>,
+    // follows the guest's log on the console, run again after every exec
+    serial_command: Option<String>,
+    // passed to the handle's stop
+    stop_timeouts: StopTimeouts,
+    // the address the host has seen the guest use, once it has seen it
+    guest_ip: Option<Ipv4Addr>,
+    // the NES worker accepts connections on it once it is up, None for pool vms
+    rpc_port: Option<u16>,
+    // the flatcar image and its sha256, only hashed when the session is recorded
+    image: Option<(PathBuf, String)>,
+}
+
+impl Instance {
+    async fn stop(&mut self) -> Result<(), Error> {
+        if let Some(serial) = self.serial.take() {
+            serial.cancel().await;
+        }
+        self.handle
+            .stop(self.stop_timeouts)
             .await
-            .map_err(|e| Error::QemuSerial(e)),
-    ))
+            .map_err(Error::Vm)
+    }
+
+    // Looks the guest's mac up in the host's neighbour table, the guest is only assumed to use
+    // the reserved address until it sent something to the host
+    fn discover_ip(&mut self) {
+        let tap = self.handle.tap();
+        let Some(ip) = arp::guest_ip(&tap.mac().to_string()) else {
+            return;
+        };
+        if ip != *tap.ip() && self.guest_ip != Some(ip) {
+            warn!(id = self.id, reserved = %tap.ip(), discovered = %ip, "Guest does not use its reserved ip");
+        }
+        self.guest_ip = Some(ip);
+    }
+
+    fn ip(&self) -> Ipv4Addr {
+        self.guest_ip.unwrap_or(*self.handle.tap().ip())
+    }
+
+    fn record(&self) -> InstanceRecord {
+        let tap = self.handle.tap();
+        InstanceRecord {
+            id: self.id,
+            kind: self.kind,
+            parent_id: self.worker_config.as_ref().map(|wc| wc.parent_id),
+            ip: self.ip().to_string(),
+            mac: tap.mac().to_string(),
+            tap: tap.device(),
+            ports: self.ports.clone(),
+            resources: self.resources.clone(),
+            host_ports: self.forwards.iter().map(PortForward::port).collect(),
+        }
+    }
+
+    async fn check_alive(&self, oom: Option<&OomWatcher>) -> Result<(), Error> {
+        if self.handle.is_running().await.map_err(Error::Vm)? {
+            return Ok(());
+        }
+        match oom
+            .zip(self.handle.pid())
+            .and_then(|(oom, pid)| oom.killed_by_oom(pid))
+        {
+            Some(kill) => Err(Error::KilledByOom(self.id, kill)),
+            None => Err(Error::InstanceGone(self.id)),
+        }
+    }
+
+    fn log_gdb_stub(&self) {
+        if let Some(gdb) = self.handle.gdb() {
+            info!(id = self.id, gdb = %gdb, connect = %gdb.connect_command(), "Gdb stub listening");
+        }
+    }
+
+    fn spawn_serial(&mut self) {
+        let console = self.console.clone();
+        let id = self.id;
+        self.serial = Some(task::spawn(async move {
+            let result = console.read().await;
+            if let Err(e) = result.as_ref() {
+                warn!(id, %e, "Stopped reading serial console");
+            }
+            result.map_err(Error::QemuSerial)
+        }));
+    }
+
+    // A restarted qemu listens on a new serial socket, firecracker keeps its console. The output
+    // goes to the same sinks as before, appended to the serial log.
+    async fn reconnect_serial(&mut self, options: &LaunchOptions) -> Result<(), Error> {
+        let sinks = options.serial_sinks(self.id, self.worker_config.is_some())?;
+        match self.handle.as_qemu() {
+            Some(qemu) => {
+                self.console = SerialConsole::connect(
+                    qemu.serial_path(),
+                    sinks,
+                    options.vm_serial_options(qemu),
+                )
+                .await
+                .map_err(Error::QemuSerial)?;
+            }
+            None => self.console.set_sinks(sinks),
+        }
+        self.spawn_serial();
+        Ok(())
+    }
+
+    // Runs `command` on the guest console and returns its output. The output also shows up in
+    // the serial log, which interrupts the worker's journal until the command is done.
+    async fn exec_in_guest(
+        &mut self,
+        command: &str,
+        terminator: Option<&str>,
+        timeout: Duration,
+    ) -> Result<CommandOutput, Error> {
+        let output = serial_capture(&self.console, command, terminator, timeout).await;
+        if let Some(serial_command) = self.serial_command.as_ref() {
+            self.console
+                .write(serial_command)
+                .await
+                .map_err(Error::QemuSerial)?;
+        }
+        output.map_err(Error::QemuSerial)
+    }
+
+    // Runs the commands one after another, up to the first one which fails
+    async fn exec_all_in_guest(
+        &mut self,
+        commands: impl IntoIterator<Item = String>,
+    ) -> Result<(), Error> {
+        for command in commands {
+            let output = self
+                .exec_in_guest(&command, None, STAGE_COMMAND_TIMEOUT)
+                .await?;
+            if output.exit_status != Some(0) {
+                return Err(Error::GuestCommandFailed(
+                    self.id,
+                    command,
+                    output.exit_status.unwrap_or(-1),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Deploys the worker config with one more source and restarts the worker, which only reads
+    // its sources at startup. The instance is only updated once the worker was restarted.
+    async fn add_source(&mut self, args: &AddSourceArgs) -> Result<(), Error> {
+        let Some(wc) = self.worker_config.as_ref() else {
+            return Err(Error::NotAWorker(self.id));
+        };
+        if matches!(wc.config_file, WorkerConfigFile::Raw(_)) {
+            return Err(Error::RawWorkerConfig(self.id));
+        }
+        check_source_port(self.id, &self.ports, &wc.ports, args.port)?;
+
+        let mut wc = wc.clone();
+        let logical_source_name = args.logical_source_name.as_deref().unwrap_or("bid");
+        wc.sources.push(tcp_source(
+            args.port,
+            logical_source_name,
+            &format!("{logical_source_name}_phy_{}", wc.sources.len()),
+            args.num_source_threads,
+            args.source_affinity,
+        ));
+        let config = wc.worker_config_yaml().map_err(Error::Template)?;
+        let commands = stage_file_commands(WORKER_CONFIG_PATH, &config)
+            .into_iter()
+            .chain(std::iter::once(
+                "sudo systemctl restart nesWorker".to_string(),
+            ));
+        self.exec_all_in_guest(commands).await?;
+        info!(worker_id = self.id, port = args.port, "Added source");
+        self.worker_config = Some(wc);
+        self.ports.push(args.port);
+        Ok(())
+    }
+
+    // Reads the worker configuration back from the guest and compares it to the deployed one
+    async fn diff_config(&mut self) -> Result<Vec<String>, Error> {
+        let Some(wc) = self.worker_config.as_ref() else {
+            return Err(Error::NotAWorker(self.id));
+        };
+        let expected = wc.worker_config_yaml().map_err(Error::Template)?;
+        let actual = self
+            .exec_in_guest(
+                &format!("cat {WORKER_CONFIG_PATH}"),
+                None,
+                Duration::from_secs(10),
+            )
+            .await?
+            .output();
+
+        Ok(diff_lines(&expected, &actual))
+    }
+}
+
+fn find_instance(instances: &mut [Instance], id: usize) -> Result<&mut Instance, Error> {
+    instances
+        .iter_mut()
+        .find(|i| i.id == id)
+        .ok_or(Error::UnknownInstance(id))
+}
+
+async fn run_and_capture(
+    instances: &mut [Instance],
+    id: usize,
+    command: &str,
+    terminator: Option<&str>,
+    timeout: Duration,
+) -> Result<CommandOutput, Error> {
+    find_instance(instances, id)?
+        .exec_in_guest(command, terminator, timeout)
+        .await
+}
+
+impl Display for Instance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "[{}] {} {}",
+            self.id,
+            self.kind,
+            self.handle
+        ))?;
+        if self.ip() != *self.handle.tap().ip() {
+            write!(f, " (guest uses {})", self.ip())?;
+        }
+        if !self.forwards.is_empty() {
+            let ports = self
+                .forwards
+                .iter()
+                .map(|f| f.port().to_string())
+                .join(", ");
+            f.write_fmt(format_args!(", Forwarded: {ports}"))?;
+        }
+        if let Some(gdb) = self.handle.gdb() {
+            write!(f, ", Gdb: {gdb}")?;
+        }
+        Ok(())
+    }
+}
+
+// Line based diff of `expected` and `actual`, prefixing lines with ' ', '-' or '+'
+fn diff_lines(expected: &str, actual: &str) -> Vec<String> {
+    let expected = expected.lines().collect::<Vec<_>>();
+    let actual = actual.lines().collect::<Vec<_>>();
+
+    // longest common subsequence table
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            diff.push(format!(" {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if i < expected.len() && (j == actual.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(format!("-{}", expected[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", actual[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[test]
+fn test_diff_lines() {
+    assert_eq!(diff_lines("a\nb\nc", "a\nb\nc"), vec![" a", " b", " c"]);
+    assert_eq!(
+        diff_lines("a\nb\nc", "a\nx\nc\nd"),
+        vec![" a", "-b", "+x", " c", "+d"]
+    );
+}
+
+// Removes instances whose vm is no longer running, and refreshes the addresses of the others
+fn reap_dead_instances(
+    instances: &mut Vec<Instance>,
+    oom: Option<&OomWatcher>,
+    hooks: &Hooks,
+) -> Vec<Instance> {
+    let mut dead = vec![];
+    let mut index = 0;
+    while index < instances.len() {
+        match task::block_on(instances[index].check_alive(oom)) {
+            Ok(()) => {
+                instances[index].discover_ip();
+                index += 1
+            }
+            Err(e) => {
+                error!(%e, "Instance died");
+                let mut instance = instances.remove(index);
+                if let Err(e) = task::block_on(instance.stop()) {
+                    error!(%instance, ?e, "Could not clean up dead instance");
+                }
+                task::block_on(hooks.run(HookEvent::Crash(e.to_string()), &instance.record()));
+                dead.push(instance);
+            }
+        }
+    }
+    dead
+}
+
+#[derive(Debug, Clone, Copy, strum_macros::Display)]
+enum InstanceState {
+    #[strum(to_string = "running")]
+    Running,
+    #[strum(to_string = "stopped")]
+    Stopped,
 }
 
 struct ProcessOption<'a> {
     index: usize,
-    qph: &'a mut QemuProcessHandle,
+    state: InstanceState,
+    instance: &'a mut Instance,
 }
 
 impl Display for ProcessOption<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}", self.qph))
+        f.write_fmt(format_args!(
+            "{} #{} {}",
+            self.state, self.index, self.instance
+        ))
     }
 }
 
-fn run_stop<'a>(
-    instances: &mut Vec<QemuProcessHandle>,
-) -> Result<Vec<QemuProcessHandle>, (Vec<QemuProcessHandle>, Error)> {
-    let options = instances
+// Every space separated term has to appear somewhere, e.g. "worker 10.0.0.1"
+fn matches_all_terms<T>(input: &str, _: &T, display: &str, _: usize) -> bool {
+    let display = display.to_lowercase();
+    input
+        .split_whitespace()
+        .all(|term| display.contains(&term.to_lowercase()))
+}
+
+#[test]
+fn test_matches_all_terms() {
+    let display = "Running #0 [3] worker TapDevice: tap3, Ip: 10.0.0.4";
+    assert!(matches_all_terms("", &(), display, 0));
+    assert!(matches_all_terms("WORKER 10.0.0.4", &(), display, 0));
+    assert!(matches_all_terms("[3]", &(), display, 0));
+    assert!(!matches_all_terms("unikernel 10.0.0.4", &(), display, 0));
+}
+
+// What the hypervisor is actually doing, which tells crashed instances apart from stopped ones
+fn vm_status(instance: &Instance) -> String {
+    task::block_on(instance.handle.status())
+        .map_or_else(|e| format!("status unknown ({e})"), |s| s.to_string())
+}
+
+fn process_options(instances: &mut [Instance], state: InstanceState) -> Vec<ProcessOption<'_>> {
+    instances
         .iter_mut()
         .enumerate()
-        .map(|(i, o)| ProcessOption { index: i, qph: o })
-        .collect();
+        .map(|(i, o)| ProcessOption {
+            index: i,
+            state,
+            instance: o,
+        })
+        .collect()
+}
+
+fn run_stop(
+    instances: &mut Vec<Instance>,
+    hooks: &Hooks,
+) -> Result<Vec<Instance>, (Vec<Instance>, Error)> {
+    let options = process_options(instances, InstanceState::Running);
 
     let options = inquire::MultiSelect::new("Stop machines?", options)
+        .with_filter(&matches_all_terms)
         .prompt()
         .map_err(|e| (vec![], Error::Inquire(e)))?;
 
     let mut indexes_to_remove = vec![];
     let mut first_error: Option<Error> = None;
     for option in options {
-        match task::block_on(option.qph.stop()).map_err(Error::Qemu) {
+        match task::block_on(option.instance.stop()) {
             Ok(_) => {
+                task::block_on(hooks.run(HookEvent::Stop, &option.instance.record()));
                 indexes_to_remove.push(option.index);
             }
             Err(e) => {
@@ -214,105 +1298,1011 @@ fn run_stop<'a>(
     }
 }
 
-#[derive(Deserialize)]
+fn export_instances(
+    instances: &[Instance],
+    format: ExportFormat,
+    out: &std::path::Path,
+) -> Result<(), Error> {
+    let records = instances.iter().map(Instance::record).collect::<Vec<_>>();
+    export::export(&records, format, out).map_err(Error::Export)?;
+    info!(?out, "Exported {} instances", records.len());
+    Ok(())
+}
+
+fn run_export(instances: &[Instance]) -> Result<(), Error> {
+    let format = inquire::Select::new("Format?", vec!["json", "csv"])
+        .prompt()
+        .map_err(Error::Inquire)?;
+    let out = inquire::Text::new("Output file?")
+        .with_default(&format!("topology.{format}"))
+        .prompt()
+        .map_err(Error::Inquire)?;
+    let format = match format {
+        "csv" => ExportFormat::Csv,
+        _ => ExportFormat::Json,
+    };
+    export_instances(instances, format, &PathBuf::from(out))
+}
+
+fn run_diff_config(instances: &mut [Instance]) -> Result<(), Error> {
+    let options = process_options(instances, InstanceState::Running);
+    let option = inquire::Select::new("Diff config of machine?", options)
+        .prompt()
+        .map_err(Error::Inquire)?;
+
+    let diff = task::block_on(option.instance.diff_config())?;
+    if diff.iter().all(|l| l.starts_with(' ')) {
+        println!("Configuration matches");
+    } else {
+        for line in diff {
+            println!("{line}");
+        }
+    }
+    Ok(())
+}
+
+const MIGRATION_TIMEOUT: Duration = Duration::from_secs(300);
+
+fn run_migrate(instances: &mut [Instance]) -> Result<(), Error> {
+    let options = process_options(instances, InstanceState::Running);
+    let option = inquire::Select::new("Migrate machine?", options)
+        .prompt()
+        .map_err(Error::Inquire)?;
+    let out = inquire::Text::new("State file?")
+        .with_default(&format!("worker-{}.state", option.instance.id))
+        .prompt()
+        .map_err(Error::Inquire)?;
+    let out = std::path::absolute(out).map_err(Error::IO)?;
+
+    let captured = task::block_on(
+        option
+            .instance
+            .handle
+            .as_qemu()
+            .ok_or(Error::QemuOnly("Migration"))?
+            .capture_to_file(&out, MIGRATION_TIMEOUT),
+    )
+    .map_err(Error::Qemu)?;
+    info!(?out, disk = ?captured.disk, ip = %captured.ip, mac = %captured.mac, "Captured vm state, the vm is paused");
+    Ok(())
+}
+
+fn run_snapshot(instances: &mut [Instance]) -> Result<(), Error> {
+    let options = process_options(instances, InstanceState::Running);
+    let option = inquire::Select::new("Snapshot machine?", options)
+        .prompt()
+        .map_err(Error::Inquire)?;
+    let action = inquire::Select::new("Action?", vec!["save", "load"])
+        .prompt()
+        .map_err(Error::Inquire)?;
+    let name = inquire::Text::new("Snapshot name?")
+        .with_default("snapshot")
+        .prompt()
+        .map_err(Error::Inquire)?;
+
+    let handle = option
+        .instance
+        .handle
+        .as_qemu()
+        .ok_or(Error::QemuOnly("Snapshotting"))?;
+    match action {
+        "save" => task::block_on(handle.savevm(&name)),
+        _ => task::block_on(handle.loadvm(&name)),
+    }
+    .map_err(Error::Qemu)?;
+    info!(id = option.instance.id, name, action, "Snapshot");
+    Ok(())
+}
+
+fn run_add_source(instances: &mut [Instance]) -> Result<(), Error> {
+    let options = process_options(instances, InstanceState::Running);
+    let option = inquire::Select::new("Add source to worker?", options)
+        .prompt()
+        .map_err(Error::Inquire)?;
+    let next_port = option.instance.ports.iter().max().map_or(8071, |p| p + 1);
+    let args = AddSourceArgs {
+        worker_id: option.instance.id,
+        port: inquire::CustomType::<u16>::new("Port?")
+            .with_default(next_port)
+            .prompt()
+            .map_err(Error::Inquire)?,
+        logical_source_name: inquire::Text::new("Logical source?")
+            .with_default("bid")
+            .prompt_skippable()
+            .map_err(Error::Inquire)?,
+        num_source_threads: None,
+        source_affinity: None,
+    };
+    task::block_on(option.instance.add_source(&args))
+}
+
+fn run_reconfigure(instances: &mut [Instance]) -> Result<(), Error> {
+    let options = process_options(instances, InstanceState::Running);
+    let option = inquire::Select::new("Reconfigure machine?", options)
+        .prompt()
+        .map_err(Error::Inquire)?;
+
+    let handle = option
+        .instance
+        .handle
+        .as_qemu_mut()
+        .ok_or(Error::QemuOnly("Reconfiguring"))?;
+    let cores = inquire::CustomType::<usize>::new("Number of vcpus?")
+        .with_default(handle.number_of_cores())
+        .prompt()
+        .map_err(Error::Inquire)?;
+    task::block_on(handle.set_number_of_cores(cores)).map_err(Error::Qemu)?;
+
+    if let Some(memory) = inquire::CustomType::<usize>::new("Memory balloon target in MB?")
+        .prompt_skippable()
+        .map_err(Error::Inquire)?
+    {
+        task::block_on(handle.set_memory_target(memory)).map_err(Error::Qemu)?;
+    }
+
+    Ok(())
+}
+
+// A worker with several nics on its network, bonded in the guest
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BondArgs {
+    taps: usize,
+    mode: BondMode,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct AddWorkerArgs {
     worker_id: usize,
-    number_of_worker_threads: usize,
     number_of_sources: usize,
+    boot_timeout: Option<u64>,
+    // seconds, overrides --shutdown-grace-period
+    shutdown_grace_period: Option<u64>,
+    max_cores: Option<usize>,
+    profile: Option<String>,
+    segment: Option<String>,
+    // workers which have to be up before this one is started
+    depends_on: Option<Vec<usize>>,
+    num_source_threads: Option<usize>,
+    // cores the sources are pinned to, assigned round-robin
+    source_affinity: Option<Vec<usize>>,
+    // explicit settings override the profile
+    #[serde(flatten)]
+    resources: ResourceProfile,
+    #[serde(flatten)]
+    ports: WorkerPorts,
+    #[serde(flatten)]
+    timeouts: CoordinatorTimeouts,
+    // complete worker_config.yaml which replaces the rendered one
+    worker_config_file: Option<PathBuf>,
+    // added to the flatcar guest next to the worker's own unit and config
+    #[serde(default)]
+    extra_units: Vec<ExtraUnit>,
+    #[serde(default)]
+    extra_files: Vec<ExtraFile>,
+    // forward the data and rpc port from host ports out of --host-port-range
+    #[serde(default)]
+    expose: bool,
+    // state captured with migrate, the worker resumes from it on the disk, address and mac
+    // captured with it instead of booting
+    incoming: Option<PathBuf>,
+    bond: Option<BondArgs>,
+    // attached after the flatcar image, e.g. a scratch disk
+    #[serde(default)]
+    drives: Vec<VirtioDrive>,
+    // host command which builds the image instead of butane, see builder.rs
+    image_builder: Option<String>,
+    // e.g. `gdb: {port: 1234, wait: true}`, see GdbStub for attaching
+    gdb: Option<GdbStub>,
+    // host cpus the vcpus are pinned to, e.g. the cores of one numa node, instead of the
+    // isolated cpus of --use-isolated-cpus
+    cpu_affinity: Option<Vec<usize>>,
 }
 
 impl AddWorkerArgs {
-    pub fn inquire() -> Result<Self, InquireError> {
+    pub fn inquire(profiles: &ProfileRegistry) -> Result<Self, InquireError> {
         let worker_id = inquire::CustomType::<usize>::new("WorkerId?").prompt()?;
-        let number_of_worker_threads =
-            inquire::CustomType::<usize>::new("Number of Worker Threads?").prompt()?;
+        let profile =
+            inquire::Select::new("Resource profile?", profiles.names()).prompt_skippable()?;
+        let number_of_worker_threads = if profile.is_some() {
+            inquire::CustomType::<usize>::new("Number of Worker Threads?").prompt_skippable()?
+        } else {
+            Some(inquire::CustomType::<usize>::new("Number of Worker Threads?").prompt()?)
+        };
+        let memory_in_megabytes = inquire::CustomType::<usize>::new("Memory in MB?")
+            .with_help_message("Skip for the profile's memory")
+            .prompt_skippable()?;
+        let vcpus = inquire::CustomType::<usize>::new("Vcpus?")
+            .with_help_message("Skip for one vcpu per worker thread")
+            .prompt_skippable()?;
         let number_of_sources = inquire::CustomType::<usize>::new("with source?")
             .with_default(0)
             .prompt()?;
+        let max_cores =
+            inquire::CustomType::<usize>::new("Max vcpus for hotplug?").prompt_skippable()?;
+        let gdb = inquire_gdb_stub()?;
         Ok(Self {
             worker_id,
-            number_of_worker_threads,
             number_of_sources,
+            boot_timeout: None,
+            shutdown_grace_period: None,
+            max_cores,
+            profile,
+            segment: None,
+            depends_on: None,
+            num_source_threads: None,
+            source_affinity: None,
+            resources: ResourceProfile {
+                memory_in_megabytes,
+                vcpus,
+                number_of_worker_threads,
+                ..Default::default()
+            },
+            ports: WorkerPorts::default(),
+            timeouts: CoordinatorTimeouts::default(),
+            worker_config_file: None,
+            extra_units: vec![],
+            extra_files: vec![],
+            incoming: None,
+            expose: false,
+            bond: None,
+            drives: vec![],
+            image_builder: None,
+            gdb,
+            cpu_affinity: None,
         })
     }
 }
 
-async fn add_worker(
-    nc: NetworkConfig,
-    args: AddWorkerArgs,
-) -> Result<(QemuProcessHandle, Result<(), Error>), Error> {
-    let tap = nc.get_tap();
-    let worker_id = args.worker_id;
+// The file is staged as is, it only has to be valid yaml
+fn load_worker_config_file(path: &Path) -> Result<WorkerConfigFile, Error> {
+    let content = std::fs::read_to_string(path).map_err(Error::IO)?;
+    serde_yaml::from_str::<serde_yaml::Value>(&content)
+        .map_err(|e| Error::InvalidWorkerConfigFile(e, path.to_path_buf()))?;
+    Ok(WorkerConfigFile::Raw(content))
+}
 
-    let sources = (0..args.number_of_sources)
-        .map(|i| {
-            TCPSourceConfigBuilder::default()
-                .format(Format::NES(8))
-                .socket_port(8071 + i as u16)
-                .logical_source_name("bid".to_string())
-                .physical_source_name(format!("bid_phy_{i}"))
-                .flush_interval(std::time::Duration::from_millis(1))
-                .build()
-                .unwrap()
-                .into()
-        })
-        .collect::<Vec<_>>();
+// Everything a worker is launched with, after layering defaults, profile, flags and script
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EffectiveConfig<'a> {
+    worker_id: usize,
+    profile: Option<String>,
+    resources: &'a ResourceProfile,
+    worker_configuration: &'a WorkerConfiguration,
+    launch: LaunchSummary,
+}
 
-    let worker_config = WorkerConfiguration {
-        host_ip_addr: IpAddr::from(nc.host_ip()),
-        ip_addr: IpAddr::from(*tap.ip()),
-        worker_id: args.worker_id,
-        parent_id: args.worker_id - 1,
-        sources,
-        log_level: "LOG_INFO",
-        query_processing: WorkerQueryProcessingConfigurationBuilder::default()
-            .number_of_worker_threads(args.number_of_worker_threads)
-            .buffer_size(8192)
-            .number_of_source_buffers(32)
-            .total_number_of_buffers(2000000)
-            .number_of_buffers_per_thread(128)
-            .build()
-            .unwrap()
-            .into(),
-    };
-    let wc = worker_config;
-    let args = flatcar::Args {
-        flatcar_fresh_image: PathBuf::from("./flatcar_fresh.iso"),
-        number_of_cores: Some(args.number_of_worker_threads),
-    };
-    let lc = flatcar::prepare_launch(wc, tap, &args).await;
-    let handle = qemu::start_qemu(lc).await.map_err(Error::Qemu)?;
-    let serial_socket = handle.serial_path();
-    task::sleep(Duration::from_secs(20)).await;
-    Ok((
-        handle,
-        serial_with_command("journalctl -u nesWorker -f\n", serial_socket, worker_id)
+// Everything qemu needs to boot a flatcar worker, and the image it boots from with its sha256
+// if the session is recorded
+async fn prepare_flatcar_launch(
+    wc: WorkerConfiguration,
+    tap: TapUser,
+    resources: &ResourceProfile,
+    max_cores: Option<usize>,
+    start_worker: bool,
+    image_builder: Option<&str>,
+    options: &LaunchOptions,
+) -> Result<(LaunchConfiguration, Option<(PathBuf, String)>), Error> {
+    let flatcar_fresh_image =
+        image::resolve_image(&options.flatcar_image, options.image_sha256.as_deref())
             .await
-            .map_err(|e| Error::QemuSerial(e)),
-    ))
+            .map_err(Error::Image)?;
+    let image = match options.session {
+        Some(_) => {
+            let sha256 = image::sha256(&flatcar_fresh_image)
+                .await
+                .map_err(Error::Image)?;
+            Some((flatcar_fresh_image.clone(), sha256))
+        }
+        None => None,
+    };
+    let args = flatcar_args(
+        flatcar_fresh_image,
+        resources,
+        max_cores,
+        start_worker,
+        image_builder,
+        options,
+    );
+    let mut lc = flatcar::prepare_launch(wc, tap, &args)
+        .await
+        .map_err(Error::Flatcar)?;
+    configure_flatcar_launch(&mut lc, resources, options).await?;
+    Ok((lc, image))
 }
 
-fn interactive_main(args: InteractiveArgs, keep_bridge_alive: bool) -> Result<(), Error> {
-    let gateway_ip = args
-        .ip_range
-        .or_else(|| {
-            CustomType::<Ipv4Net>::new("Ip Address Space")
-                .with_default("10.0.0.0/24".parse::<Ipv4Net>().unwrap())
-                .with_error_message("Please type a valid Ipv4 cidr notation")
-                .prompt()
-                .ok()
-        })
-        .unwrap();
+// Boots a copy of the disk of a captured vm, to resume it from its migrated state
+async fn restore_flatcar_launch(
+    worker_id: usize,
+    tap: TapUser,
+    disk: &Path,
+    resources: &ResourceProfile,
+    max_cores: Option<usize>,
+    options: &LaunchOptions,
+) -> Result<LaunchConfiguration, Error> {
+    let args = flatcar_args(
+        disk.to_path_buf(),
+        resources,
+        max_cores,
+        true,
+        None,
+        options,
+    );
+    let mut lc = flatcar::restore_launch(worker_id, tap, disk, &args)
+        .await
+        .map_err(Error::Flatcar)?;
+    configure_flatcar_launch(&mut lc, resources, options).await?;
+    Ok(lc)
+}
+
+fn flatcar_args(
+    flatcar_fresh_image: PathBuf,
+    resources: &ResourceProfile,
+    max_cores: Option<usize>,
+    start_worker: bool,
+    image_builder: Option<&str>,
+    options: &LaunchOptions,
+) -> flatcar::Args {
+    flatcar::Args {
+        flatcar_fresh_image,
+        number_of_cores: resources.vcpus(),
+        memory_in_megabytes: resources.memory_in_megabytes,
+        max_number_of_cores: max_cores,
+        security: options.security(),
+        tpm: options.tpm,
+        socket_dir: options.socket_dir.clone(),
+        run_dir: options.run_dir.clone(),
+        butane: options.butane_spec.clone(),
+        verbose: options.guest_verbose,
+        start_worker,
+        sync_clock: options.sync_guest_clock,
+        image_builder: image_builder.map(|command| {
+            Arc::new(CommandBuilder {
+                command: command.to_string(),
+            }) as _
+        }),
+        nic_queues: options.nic_queues,
+    }
+}
+
+async fn configure_flatcar_launch(
+    lc: &mut LaunchConfiguration,
+    resources: &ResourceProfile,
+    options: &LaunchOptions,
+) -> Result<(), Error> {
+    lc.pflash = options.pflash(lc.vm_dir.path()).await?;
+    lc.vcpu_reservation = options.reserve_vcpus(lc.vcpus())?;
+    lc.cpu_limit = resources.cpu_limit();
+    lc.machine_properties = options.machine_properties.clone();
+    lc.hmp_monitor = !options.no_hmp_monitor;
+    lc.socket_access = options.socket_access();
+    lc.disk_queues = options.disk_queues;
+    lc.hugepages = options.hugepages.clone();
+    lc.qemu_binary = options.qemu_binary.clone();
+    lc.vhost = !options.no_vhost && qemu::vhost_net_available();
+    Ok(())
+}
+
+async fn forward_ports(
+    instance: &mut Instance,
+    host_ports: &HostPorts,
+    guest_ports: &[u16],
+) -> Result<(), Error> {
+    for guest_port in guest_ports {
+        let guest = SocketAddrV4::new(*instance.handle.tap().ip(), *guest_port);
+        let forward = host_ports.forward(guest).await.map_err(Error::Forward)?;
+        info!(worker_id = instance.id, port = %forward.port(), "Forwarding host port");
+        instance.forwards.push(forward);
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct WarmPoolState {
+    ready: Vec<Instance>,
+    booting: HashMap<usize, JoinHandle<()>>,
+    next_boot: usize,
+    // the default network and what the pool vms are launched with, once started
+    launch: Option<(NetworkConfig, Arc<LaunchOptions>, ResourceProfile)>,
+}
+
+// Flatcar vms which boot ahead of time with the worker unit disabled. Claiming one stages the
+// worker's config and starts the unit, the pool refills in the background.
+#[derive(Clone)]
+struct WarmPool {
+    size: usize,
+    state: Arc<Mutex<WarmPoolState>>,
+}
+
+impl std::fmt::Debug for WarmPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WarmPool")
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WarmPool {
+    fn new(size: usize) -> Self {
+        WarmPool {
+            size,
+            state: Default::default(),
+        }
+    }
+
+    fn start(&self, nc: &NetworkConfig, options: &LaunchOptions) -> Result<(), Error> {
+        let resources = options
+            .profiles
+            .resolve(options.profile.as_deref(), &ResourceProfile::default())
+            .map_err(Error::Profile)?;
+        let mut options = options.clone();
+        // the boots must not keep the pool alive
+        options.warm_pool = None;
+        self.state.lock().unwrap().launch = Some((nc.clone(), Arc::new(options), resources));
+        self.refill();
+        Ok(())
+    }
+
+    // Boots vms until the pool is full again. Failed boots are not retried until the next claim.
+    fn refill(&self) {
+        let mut state = self.state.lock().unwrap();
+        let Some((nc, options, resources)) = state.launch.clone() else {
+            return;
+        };
+        while state.ready.len() + state.booting.len() < self.size {
+            let index = state.next_boot;
+            state.next_boot += 1;
+            let (nc, options, resources) = (nc.clone(), options.clone(), resources.clone());
+            let pool = self.clone();
+            let boot = task::spawn(async move {
+                let result = boot_warm_vm(nc, &options, &resources).await;
+                let mut state = pool.state.lock().unwrap();
+                state.booting.remove(&index);
+                match result {
+                    Ok(instance) => {
+                        info!(ip = %instance.handle.tap().ip(), "Warm vm is ready");
+                        state.ready.push(instance);
+                    }
+                    Err(e) => error!(?e, "Could not boot warm vm"),
+                }
+            });
+            state.booting.insert(index, boot);
+        }
+    }
+
+    // Only workers on the default network which need nothing at boot time the pool vms lack
+    fn fits(&self, resources: &ResourceProfile, args: &AddWorkerArgs) -> bool {
+        let state = self.state.lock().unwrap();
+        let Some((_, _, pool_resources)) = state.launch.as_ref() else {
+            return false;
+        };
+        resources.number_of_worker_threads == pool_resources.number_of_worker_threads
+            && resources.vcpus() == pool_resources.vcpus()
+            && resources.memory_in_megabytes == pool_resources.memory_in_megabytes
+            && resources.cpu_limit() == pool_resources.cpu_limit()
+            && args.max_cores.is_none()
+            && args.segment.is_none()
+            && args.incoming.is_none()
+            && args.extra_units.is_empty()
+            && args.extra_files.is_empty()
+            && args.bond.is_none()
+            && args.drives.is_empty()
+            && args.image_builder.is_none()
+            && args.gdb.is_none()
+            && args.cpu_affinity.is_none()
+    }
+
+    fn take(&self) -> Option<Instance> {
+        let instance = self.state.lock().unwrap().ready.pop();
+        if instance.is_some() {
+            self.refill();
+        }
+        instance
+    }
+
+    async fn shutdown(&self) {
+        let boots = {
+            let mut state = self.state.lock().unwrap();
+            state.launch = None;
+            state
+                .booting
+                .drain()
+                .map(|(_, boot)| boot)
+                .collect::<Vec<_>>()
+        };
+        // a cancelled boot stops its vm when the handle is dropped
+        for boot in boots {
+            boot.cancel().await;
+        }
+        let mut ready = std::mem::take(&mut self.state.lock().unwrap().ready);
+        // pool vms were never handed out, so there is nobody to notify
+        stop_all(&mut ready, &Hooks::default()).await;
+    }
+}
+
+// A booted flatcar vm on the default network with a placeholder worker config
+async fn boot_warm_vm(
+    nc: NetworkConfig,
+    options: &LaunchOptions,
+    resources: &ResourceProfile,
+) -> LaunchResult {
+    let tap = nc.get_tap().map_err(Error::Network)?;
+    let wc = WorkerConfiguration {
+        host_ip_addr: IpAddr::from(nc.host_ip()),
+        ip_addr: IpAddr::from(*tap.ip()),
+        query_processing: resources.query_processing().into(),
+        ..Default::default()
+    };
+    let (mut lc, image) =
+        prepare_flatcar_launch(wc, tap, resources, None, false, None, options).await?;
+    lc.cpu_affinity = options.assign_cpus(lc.vcpus())?;
+    let handle = qemu::start_qemu_with_retries(lc, options.launch_retries)
+        .await
+        .map_err(Error::Qemu)?;
+    // nothing is echoed until the vm is claimed
+    let console = SerialConsole::connect(
+        handle.serial_path(),
+        vec![SerialSink::Discard],
+        options.serial_options(),
+    )
+    .await
+    .map_err(Error::QemuSerial)?;
+    let boot_lines = console.subscribe();
+    let mut instance = Instance {
+        id: 0,
+        kind: "worker",
+        handle: Box::new(handle),
+        serial: None,
+        console,
+        worker_config: None,
+        resources: None,
+        ports: vec![],
+        forwards: vec![],
+        serial_command: None,
+        stop_timeouts: options.stop_timeouts(),
+        guest_ip: None,
+        rpc_port: None,
+        image,
+    };
+    instance.spawn_serial();
+    let boot_timeout = Duration::from_secs(options.boot_timeout);
+    match wait_for_serial_marker(boot_lines, &options.boot_marker, Some(boot_timeout)).await {
+        Ok(()) => Ok(instance),
+        Err(SerialError::BootTimeout(last_lines)) => {
+            Err(Error::BootTimeout(boot_timeout, last_lines))
+        }
+        Err(e) => Err(Error::QemuSerial(e)),
+    }
+}
+
+const STAGED_FILE: &str = "/tmp/vmlauncher-staged";
+const WORKER_CONFIG_PATH: &str = "/config/worker_config.yaml";
+const STAGE_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Shell commands which write `contents` to `path` over the guest console. printf escapes keep
+// every line well below the tty's line limit, whatever the file contains.
+fn stage_file_commands(path: &str, contents: &str) -> Vec<String> {
+    std::iter::once(format!(": > {STAGED_FILE}"))
+        .chain(contents.as_bytes().chunks(256).map(|chunk| {
+            let escaped = chunk.iter().map(|b| format!("\\x{b:02x}")).join("");
+            format!("printf '{escaped}' >> {STAGED_FILE}")
+        }))
+        .chain(std::iter::once(format!(
+            "sudo install -m 644 {STAGED_FILE} {path}"
+        )))
+        .collect()
+}
+
+#[test]
+fn test_stage_file_commands() {
+    assert_eq!(
+        stage_file_commands("/config/worker_config.yaml", "a: '%1'\n"),
+        vec![
+            ": > /tmp/vmlauncher-staged",
+            "printf '\\x61\\x3a\\x20\\x27\\x25\\x31\\x27\\x0a' >> /tmp/vmlauncher-staged",
+            "sudo install -m 644 /tmp/vmlauncher-staged /config/worker_config.yaml",
+        ]
+    );
+    assert_eq!(stage_file_commands("/f", &"x".repeat(300)).len(), 4);
+}
+
+// Turns a warm vm into the worker, the worker starts with the staged config
+async fn claim_warm_vm(
+    mut instance: Instance,
+    worker_config: WorkerConfiguration,
+    resources: ResourceProfile,
+    ports: Vec<u16>,
+    options: &LaunchOptions,
+) -> LaunchResult {
+    let worker_id = worker_config.worker_id;
+    info!(worker_id, ip = %instance.handle.tap().ip(), "Using warm vm");
+    instance.id = worker_id;
+    let config = worker_config
+        .worker_config_yaml()
+        .map_err(Error::Template)?;
+    let commands = stage_file_commands(WORKER_CONFIG_PATH, &config)
+        .into_iter()
+        .chain(std::iter::once(
+            "sudo systemctl start nesWorker".to_string(),
+        ));
+    instance.exec_all_in_guest(commands).await?;
+
+    instance
+        .console
+        .set_sinks(options.serial_sinks(worker_id, true)?);
+    instance.rpc_port = Some(worker_config.ports.rpc_port);
+    instance.worker_config = Some(worker_config);
+    instance.resources = Some(resources);
+    instance.ports = ports;
+    instance.serial_command = Some(options.worker_serial_command());
+    instance
+        .console
+        .write(&options.worker_serial_command())
+        .await
+        .map_err(Error::QemuSerial)?;
+    Ok(instance)
+}
+
+// Sources of flatcar workers receive NES binary tuples over TCP on `port`
+fn tcp_source(
+    port: u16,
+    logical_source_name: &str,
+    physical_source_name: &str,
+    threads: Option<usize>,
+    affinity: Option<usize>,
+) -> Source {
+    let mut builder = TCPSourceConfigBuilder::default();
+    builder
+        .format(Format::NES(8))
+        .socket_port(port)
+        .logical_source_name(logical_source_name.to_string())
+        .physical_source_name(physical_source_name.to_string())
+        .flush_interval(std::time::Duration::from_millis(1));
+    if let Some(threads) = threads {
+        builder.num_source_threads(threads);
+    }
+    if let Some(core) = affinity {
+        builder.source_affinity(core);
+    }
+    builder.build().unwrap().into()
+}
+
+// A new source can neither reuse one of the worker's source ports nor its own ports
+fn check_source_port(
+    worker_id: usize,
+    source_ports: &[u16],
+    worker_ports: &WorkerPorts,
+    port: u16,
+) -> Result<(), Error> {
+    let worker_ports = [
+        worker_ports.data_port,
+        worker_ports.rpc_port,
+        worker_ports.coordinator_port,
+    ];
+    if source_ports.contains(&port) || worker_ports.contains(&port) {
+        return Err(Error::PortInUse(worker_id, port));
+    }
+    Ok(())
+}
+
+#[test]
+fn source_ports() {
+    let ports = WorkerPorts::default();
+    assert!(check_source_port(1, &[8071, 8072], &ports, 8073).is_ok());
+    assert!(matches!(
+        check_source_port(1, &[8071, 8072], &ports, 8072),
+        Err(Error::PortInUse(1, 8072))
+    ));
+    assert!(check_source_port(1, &[], &ports, ports.data_port).is_err());
+
+    let args: AddSourceArgs =
+        serde_yaml::from_str("workerId: 2\nport: 8075\nlogicalSourceName: auction").unwrap();
+    assert_eq!(args.logical_source_name.as_deref(), Some("auction"));
+    assert_eq!(args.num_source_threads, None);
+}
+
+const WORKER_BOOT_MARKER: &str = "login:";
+const WORKER_SERIAL_COMMAND: &str = "journalctl -u nesWorker -f";
+
+async fn add_worker(
+    nc: NetworkConfig,
+    tap: TapUser,
+    options: &LaunchOptions,
+    args: AddWorkerArgs,
+) -> LaunchResult {
+    let worker_id = args.worker_id;
+    let profile = args.profile.clone().or(options.profile.clone());
+    let resources = options
+        .profiles
+        .resolve(profile.as_deref(), &args.resources)
+        .map_err(Error::Profile)?;
+    let resources = if options.scale_buffers {
+        resources.scaled_for_sources(args.number_of_sources, &args.resources)
+    } else {
+        resources
+    };
+    let boot_timeout = Duration::from_secs(args.boot_timeout.unwrap_or(options.boot_timeout));
+    let mut stop_timeouts = options.stop_timeouts();
+    if let Some(grace_period) = args.shutdown_grace_period {
+        stop_timeouts.grace_period = Duration::from_secs(grace_period);
+    }
+    let host_ports = match (args.expose, options.host_ports.as_ref()) {
+        (false, _) => None,
+        (true, Some(host_ports)) => Some(host_ports),
+        (true, None) => return Err(Error::NoHostPortRange(worker_id)),
+    };
+    let exposed_ports = [args.ports.data_port, args.ports.rpc_port];
+    if let Some(bond) = args.bond.as_ref().filter(|bond| bond.taps < 2) {
+        return Err(Error::InvalidBond(worker_id, bond.taps));
+    }
+    // a restored guest still uses the address and mac it had when it was captured
+    let (tap, captured) = match args.incoming.as_deref() {
+        Some(state) => {
+            let captured = CapturedVm::load(state).map_err(Error::Qemu)?;
+            let mac = captured.mac.parse().map_err(|_| {
+                Error::InvalidCapturedMac(state.to_path_buf(), captured.mac.clone())
+            })?;
+            drop(tap);
+            let tap = nc.claim_tap(captured.ip, mac).map_err(Error::Network)?;
+            info!(worker_id, captured = %captured.tap, tap = %tap.device(), ip = %captured.ip, "Restoring captured vm");
+            (tap, Some(captured))
+        }
+        None => (tap, None),
+    };
+    let warm = options
+        .warm_pool
+        .as_ref()
+        .filter(|pool| pool.fits(&resources, &args))
+        .and_then(WarmPool::take);
+    let ip = warm
+        .as_ref()
+        .map_or(*tap.ip(), |instance| *instance.handle.tap().ip());
+
+    let ports = (0..args.number_of_sources)
+        .map(|i| 8071 + i as u16)
+        .collect::<Vec<_>>();
+    let sources = ports
+        .iter()
+        .enumerate()
+        .map(|(i, port)| {
+            let affinity = args
+                .source_affinity
+                .as_ref()
+                .filter(|c| !c.is_empty())
+                .map(|cores| cores[i % cores.len()]);
+            tcp_source(
+                *port,
+                "bid",
+                &format!("bid_phy_{i}"),
+                args.num_source_threads,
+                affinity,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // the guest bonds its tap with further taps on the same network
+    let bond_taps = match args.bond.as_ref() {
+        Some(bond) => nc.reserve(bond.taps - 1).map_err(Error::Network)?,
+        None => vec![],
+    };
+    let bond = args.bond.as_ref().map(|bond| BondConfiguration {
+        mode: bond.mode,
+        mac: tap.mac().to_string(),
+        member_macs: std::iter::once(&tap)
+            .chain(&bond_taps)
+            .map(|t| t.mac().to_string())
+            .collect(),
+    });
+
+    let worker_config = WorkerConfiguration {
+        host_ip_addr: IpAddr::from(nc.host_ip()),
+        ip_addr: IpAddr::from(ip),
+        worker_id: args.worker_id,
+        parent_id: args.worker_id - 1,
+        sources,
+        log_level: "LOG_INFO",
+        query_processing: resources.query_processing().into(),
+        ports: args.ports,
+        timeouts: args.timeouts,
+        config_file: match args.worker_config_file.as_ref() {
+            Some(path) => load_worker_config_file(path)?,
+            None => WorkerConfigFile::Rendered,
+        },
+        extra_units: args.extra_units.clone(),
+        extra_files: args.extra_files.clone(),
+        bond,
+    };
+    if let Some(instance) = warm {
+        drop(tap);
+        let mut instance =
+            claim_warm_vm(instance, worker_config, resources, ports, options).await?;
+        instance.stop_timeouts = stop_timeouts;
+        if let Some(host_ports) = host_ports {
+            forward_ports(&mut instance, host_ports, &exposed_ports).await?;
+        }
+        return Ok(instance);
+    }
 
-    let bridges = network_setup(gateway_ip);
+    let rpc_port = worker_config.ports.rpc_port;
+    let (mut lc, image) = match captured.as_ref() {
+        Some(captured) => {
+            let lc = restore_flatcar_launch(
+                worker_id,
+                tap,
+                &captured.disk,
+                &resources,
+                args.max_cores,
+                options,
+            )
+            .await?;
+            (lc, None)
+        }
+        None => {
+            prepare_flatcar_launch(
+                worker_config.clone(),
+                tap,
+                &resources,
+                args.max_cores,
+                true,
+                args.image_builder.as_deref(),
+                options,
+            )
+            .await?
+        }
+    };
+    lc.cpu_affinity = match args.cpu_affinity.filter(|cpus| !cpus.is_empty()) {
+        Some(cpus) => Some(CpuAssignment::fixed(cpus)),
+        None => options.assign_cpus(lc.vcpus())?,
+    };
+    lc.incoming = args.incoming;
+    lc.vnc = options.vnc.as_ref().map(|vnc| vnc.for_instance(worker_id));
+    lc.gdb = args.gdb;
+    lc.bond_taps = bond_taps;
+    lc.extra_drives = args.drives;
+    let restored = lc.incoming.is_some();
+    if options.print_effective_config {
+        let effective = EffectiveConfig {
+            worker_id,
+            profile,
+            resources: &resources,
+            worker_configuration: &worker_config,
+            launch: lc.summary(),
+        };
+        let yaml = serde_yaml::to_string(&effective).map_err(Error::EffectiveConfig)?;
+        println!("---\n{yaml}");
+    }
+    let handle = qemu::start_qemu_with_retries(lc, options.launch_retries)
+        .await
+        .map_err(Error::Qemu)?;
+    let console = SerialConsole::connect(
+        handle.serial_path(),
+        options.serial_sinks(worker_id, true)?,
+        options.vm_serial_options(&handle),
+    )
+    .await
+    .map_err(Error::QemuSerial)?;
+    let boot_lines = console.subscribe();
+    let mut instance = Instance {
+        id: worker_id,
+        kind: "worker",
+        handle: Box::new(handle),
+        serial: None,
+        console,
+        worker_config: Some(worker_config),
+        resources: Some(resources),
+        ports,
+        forwards: vec![],
+        serial_command: Some(options.worker_serial_command()),
+        stop_timeouts,
+        guest_ip: None,
+        rpc_port: Some(rpc_port),
+        image,
+    };
+    if let Some(host_ports) = host_ports {
+        forward_ports(&mut instance, host_ports, &exposed_ports).await?;
+    }
+    instance.log_gdb_stub();
+    instance.spawn_serial();
+    // a restored worker is already past its boot and running the serial command
+    if restored {
+        return Ok(instance);
+    }
+    // a worker frozen until gdb continues it boots whenever the debugging session lets it
+    let marker_timeout = Some(boot_timeout).filter(|_| !args.gdb.is_some_and(|gdb| gdb.wait));
+    match wait_for_serial_marker(boot_lines, &options.boot_marker, marker_timeout).await {
+        Ok(()) => {}
+        Err(SerialError::BootTimeout(last_lines)) => {
+            if let Err(e) = instance.stop().await {
+                error!(?e, "Could not stop worker which did not boot");
+            }
+            return Err(Error::BootTimeout(boot_timeout, last_lines));
+        }
+        Err(e) => return Err(Error::QemuSerial(e)),
+    }
+    instance
+        .console
+        .write(&options.worker_serial_command())
+        .await
+        .map_err(Error::QemuSerial)?;
+    Ok(instance)
+}
+
+fn interactive_main(
+    args: InteractiveArgs,
+    options: &LaunchOptions,
+    keep_bridge_alive: bool,
+) -> Result<(), Error> {
+    let stop = install_shutdown_handler();
+    let oom = options.oom_watcher();
+    let backend: Arc<dyn NetworkBackend> =
+        Arc::new(KernelNetworkBackend::with_nic_queues(options.nic_queues));
+    let bridges = match options.existing_bridge.as_deref() {
+        Some(bridge) => network_adopt(
+            backend,
+            &options.topology(),
+            bridge,
+            options.gateway,
+            &options.reserved_ips,
+        ),
+        None => {
+            let gateway_ip = args
+                .ip_range
+                .or_else(|| {
+                    CustomType::<Ipv4Net>::new("Ip Address Space")
+                        .with_default("10.0.0.0/24".parse::<Ipv4Net>().unwrap())
+                        .with_error_message("Please type a valid Ipv4 cidr notation")
+                        .prompt()
+                        .ok()
+                })
+                .unwrap();
+            network_setup(
+                backend,
+                &options.topology(),
+                gateway_ip,
+                options.gateway,
+                &options.reserved_ips,
+            )
+        }
+    }
+    .map_err(Error::Network)?
+    .with_state_checks(options.debug_assert_state);
+    let options = &options.with_session(SessionHeader {
+        ip_range: bridges.ip_net(),
+        segments: BTreeMap::new(),
+    })?;
+    if let Some(pool) = options.warm_pool.as_ref() {
+        pool.start(&bridges, options)?;
+    }
     {
-        let mut serials = vec![];
         let mut qemu_instances = vec![];
         let mut stopped_instances = vec![];
         loop {
-            let actions = vec!["stop", "add worker", "ps", "uk", "exit", "restart"];
-            match inquire::Select::new("", actions).prompt() {
+            if is_stopped(&stop) {
+                break;
+            }
+            // dead vms can be brought back with restart
+            stopped_instances.extend(reap_dead_instances(
+                &mut qemu_instances,
+                oom.as_ref(),
+                &options.hooks,
+            ));
+            let actions = vec![
+                "stop",
+                "add worker",
+                "ps",
+                "uk",
+                "exit",
+                "restart",
+                "reconfigure",
+                "add-source",
+                "diff-config",
+                "export",
+                "migrate",
+                "snapshot",
+            ];
+            match prompt_until_stopped(&stop, || inquire::Select::new("", actions).prompt()) {
                 Err(inquire::InquireError::OperationCanceled) => continue,
                 Err(inquire::InquireError::OperationInterrupted) => break,
                 Err(e) => {
@@ -320,60 +2310,128 @@ fn interactive_main(args: InteractiveArgs, keep_bridge_alive: bool) -> Result<()
                     break;
                 }
                 Ok(action) => match action {
-                    "uk" => match AddUnikernelArgs::inquire()
-                        .map_err(Error::Inquire)
-                        .and_then(|args| task::block_on(add_unikernel(bridges.clone(), args)))
-                    {
-                        Ok((qh, serial)) => {
-                            qemu_instances.push(qh);
-                            serials.push(serial);
+                    "uk" => match options
+                        .check_capacity(qemu_instances.len(), 1)
+                        .and_then(|_| AddUnikernelArgs::inquire().map_err(Error::Inquire))
+                        .and_then(|args| {
+                            let command = ScriptCommands::AddUnikernel(args.clone());
+                            bridges.get_tap().map_err(Error::Network).and_then(|tap| {
+                                task::block_on(add_unikernel(bridges.clone(), tap, options, args))
+                                    .map(|instance| (command, instance))
+                            })
+                        }) {
+                        Ok((command, instance)) => {
+                            task::block_on(launched(options, command, &instance));
+                            qemu_instances.push(instance);
                         }
                         Err(e) => {
                             error!(?e, "Could not create worker");
                         }
                     },
                     "ps" => {
-                        for qh in &qemu_instances {
-                            println!("{qh}")
+                        for option in process_options(&mut qemu_instances, InstanceState::Running) {
+                            let status = vm_status(option.instance);
+                            println!("{option}, {status} ({})", option.instance.console.stats())
                         }
-                    }
-                    "restart" => match run_restart(&mut stopped_instances).as_mut() {
-                        Ok(started) => {
-                            qemu_instances.append(started);
+                        for option in
+                            process_options(&mut stopped_instances, InstanceState::Stopped)
+                        {
+                            let status = vm_status(option.instance);
+                            println!("{option}, {status}")
                         }
-                        Err((started, err)) => {
-                            qemu_instances.append(started);
-                            error!(%err, "Could not start all instances")
+                    }
+                    "restart" => {
+                        match run_restart(&mut stopped_instances, options, qemu_instances.len())
+                            .as_mut()
+                        {
+                            Ok(started) => {
+                                qemu_instances.append(started);
+                            }
+                            Err((started, err)) => {
+                                qemu_instances.append(started);
+                                error!(%err, "Could not start all instances")
+                            }
                         }
-                    },
-                    "stop" => match run_stop(&mut qemu_instances).as_mut() {
-                        Ok(removed) => {
-                            stopped_instances.append(removed);
+                    }
+                    "stop" => {
+                        let (mut removed, result) =
+                            match run_stop(&mut qemu_instances, &options.hooks) {
+                                Ok(removed) => (removed, Ok(())),
+                                Err((removed, err)) => (removed, Err(err)),
+                            };
+                        for instance in &removed {
+                            let command = ScriptCommands::Stop(StopArgs { id: instance.id });
+                            task::block_on(record(options, &command, &[]));
                         }
-                        Err((removed, err)) => {
-                            stopped_instances.append(removed);
+                        stopped_instances.append(&mut removed);
+                        if let Err(err) = result {
                             error!(%err, "Could not remove all instances")
                         }
-                    },
+                    }
+                    "reconfigure" => {
+                        if let Err(e) = run_reconfigure(&mut qemu_instances) {
+                            error!(%e, "Could not reconfigure instance")
+                        }
+                    }
+                    "export" => {
+                        if let Err(e) = run_export(&qemu_instances) {
+                            error!(%e, "Could not export topology")
+                        }
+                    }
+                    "add-source" => {
+                        if let Err(e) = run_add_source(&mut qemu_instances) {
+                            error!(%e, "Could not add source")
+                        }
+                    }
+                    "diff-config" => {
+                        if let Err(e) = run_diff_config(&mut qemu_instances) {
+                            error!(%e, "Could not diff config")
+                        }
+                    }
+                    "migrate" => {
+                        if let Err(e) = run_migrate(&mut qemu_instances) {
+                            error!(%e, "Could not migrate instance")
+                        }
+                    }
+                    "snapshot" => {
+                        if let Err(e) = run_snapshot(&mut qemu_instances) {
+                            error!(%e, "Could not snapshot instance")
+                        }
+                    }
                     "exit" => {
                         break;
                     }
-                    "add worker" => match AddWorkerArgs::inquire()
-                        .map_err(Error::Inquire)
-                        .and_then(|args| task::block_on(add_worker(bridges.clone(), args)))
-                    {
-                        Ok((qh, serial)) => {
-                            qemu_instances.push(qh);
-                        }
-                        Err(e) => {
-                            error!(?e, "Could not create worker");
+                    "add worker" => {
+                        match options
+                            .check_capacity(qemu_instances.len(), 1)
+                            .and_then(|_| {
+                                AddWorkerArgs::inquire(&options.profiles).map_err(Error::Inquire)
+                            })
+                            .and_then(|args| {
+                                let command = ScriptCommands::AddWorker(Box::new(args.clone()));
+                                bridges.get_tap().map_err(Error::Network).and_then(|tap| {
+                                    task::block_on(add_worker(bridges.clone(), tap, options, args))
+                                        .map(|instance| (command, instance))
+                                })
+                            }) {
+                            Ok((command, instance)) => {
+                                task::block_on(launched(options, command, &instance));
+                                qemu_instances.push(instance);
+                            }
+                            Err(e) => {
+                                error!(?e, "Could not create worker");
+                            }
                         }
-                    },
+                    }
                     _ => unreachable!(),
                 },
             }
         }
         info!("Stopping");
+        task::block_on(stop_all(&mut qemu_instances, &options.hooks));
+    }
+    if let Some(pool) = options.warm_pool.as_ref() {
+        task::block_on(pool.shutdown());
     }
 
     if !keep_bridge_alive {
@@ -385,62 +2443,661 @@ fn interactive_main(args: InteractiveArgs, keep_bridge_alive: bool) -> Result<()
 
 #[derive(Deserialize)]
 struct Script {
+    // additional or replaced resource profiles
+    #[serde(default)]
+    profiles: HashMap<String, ResourceProfile>,
+    // named address spaces, each gets its own bridge
+    #[serde(default)]
+    segments: BTreeMap<String, Ipv4Net>,
+    // schemas of the sources, for the coordinator
+    #[serde(default, rename = "logicalSources")]
+    logical_sources: Vec<LogicalSource>,
     commands: Vec<ScriptCommands>,
+    // brings launches up one after the other, a replay follows the order of the session log
+    #[serde(skip)]
+    in_order: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 enum ScriptCommands {
-    AddWorker(AddWorkerArgs),
+    AddWorker(Box<AddWorkerArgs>),
     AddUnikernel(AddUnikernelArgs),
+    AddKernel(AddKernelArgs),
+    Exec(ExecArgs),
+    AddSource(AddSourceArgs),
+    Stop(StopArgs),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StopArgs {
+    id: usize,
+}
+
+// Another TCP source on a running flatcar worker
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AddSourceArgs {
+    worker_id: usize,
+    port: u16,
+    // bid by default, like the sources the worker was launched with
+    logical_source_name: Option<String>,
+    num_source_threads: Option<usize>,
+    source_affinity: Option<usize>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecArgs {
+    worker_id: usize,
+    command: String,
+    // seconds
+    timeout: Option<u64>,
+    // stop capturing at the first line containing this, for commands which keep running
+    terminator: Option<String>,
+    // fail the script if the command exits with a non-zero status
+    #[serde(default)]
+    check: bool,
+}
+
+impl ScriptCommands {
+    fn id(&self) -> usize {
+        match self {
+            ScriptCommands::AddWorker(args) => args.worker_id,
+            ScriptCommands::AddUnikernel(args) => args.node_id,
+            ScriptCommands::AddKernel(args) => args.node_id,
+            ScriptCommands::Exec(args) => args.worker_id,
+            ScriptCommands::AddSource(args) => args.worker_id,
+            ScriptCommands::Stop(args) => args.id,
+        }
+    }
+    // everything else runs once the launches before it in the script are up
+    fn is_launch(&self) -> bool {
+        matches!(
+            self,
+            ScriptCommands::AddWorker(_)
+                | ScriptCommands::AddUnikernel(_)
+                | ScriptCommands::AddKernel(_)
+        )
+    }
+    fn depends_on(&self) -> Option<&[usize]> {
+        match self {
+            ScriptCommands::AddWorker(args) => args.depends_on.as_deref(),
+            _ => None,
+        }
+    }
+    fn segment(&self) -> Option<&str> {
+        match self {
+            ScriptCommands::AddWorker(args) => args.segment.as_deref(),
+            ScriptCommands::AddUnikernel(args) => args.segment.as_deref(),
+            ScriptCommands::AddKernel(args) => args.segment.as_deref(),
+            ScriptCommands::Exec(_) | ScriptCommands::AddSource(_) | ScriptCommands::Stop(_) => {
+                None
+            }
+        }
+    }
+}
+
+// Machines without a segment are attached to the default bridge
+fn segment_network<'a>(
+    default: &'a NetworkConfig,
+    segments: &'a BTreeMap<String, NetworkConfig>,
+    segment: Option<&str>,
+) -> Result<&'a NetworkConfig, Error> {
+    match segment {
+        None => Ok(default),
+        Some(name) => segments
+            .get(name)
+            .ok_or_else(|| Error::Network(NetworkError::UnknownSegment(name.to_string()))),
+    }
+}
+
+// Fails if a launch depends on an unknown instance or dependencies form a cycle
+fn check_dependencies(
+    launches: &[(usize, Vec<usize>)],
+    running: &HashSet<usize>,
+) -> Result<(), Error> {
+    let known = launches
+        .iter()
+        .map(|(id, _)| *id)
+        .chain(running.iter().cloned())
+        .collect::<HashSet<_>>();
+    for (id, depends_on) in launches {
+        if let Some(unknown) = depends_on.iter().find(|d| !known.contains(d)) {
+            return Err(Error::InvalidDependencies(format!(
+                "{id} depends on unknown instance {unknown}"
+            )));
+        }
+    }
+
+    let mut done = running.clone();
+    let mut remaining = launches.iter().collect::<Vec<_>>();
+    while !remaining.is_empty() {
+        let (startable, waiting): (Vec<_>, Vec<_>) = remaining
+            .into_iter()
+            .partition(|(_, depends_on)| depends_on.iter().all(|d| done.contains(d)));
+        if startable.is_empty() {
+            return Err(Error::InvalidDependencies(format!(
+                "dependency cycle between {:?}",
+                waiting.iter().map(|(id, _)| id).collect::<Vec<_>>()
+            )));
+        }
+        done.extend(startable.iter().map(|(id, _)| *id));
+        remaining = waiting;
+    }
+    Ok(())
+}
+
+#[test]
+fn serial_logs() {
+    use std::io::Write;
+    let dir = tempdir::TempDir::new("serial").unwrap();
+    let args = ProgramArgs::try_parse_from([
+        "vml",
+        "--serial-log-dir",
+        dir.path().to_str().unwrap(),
+        "validate-env",
+    ])
+    .unwrap();
+    // a restart opens the log again
+    for run in ["crashed", "restarted"] {
+        let mut sinks = args.launch_options.serial_sinks(3, false).unwrap();
+        let Some(SerialSink::File(file)) = sinks.last_mut() else {
+            panic!("no log file sink");
+        };
+        writeln!(file, "{run}").unwrap();
+    }
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("serial-3.log")).unwrap(),
+        "crashed\nrestarted\n"
+    );
 }
 
-type RunResult = Result<(QemuProcessHandle, Result<(), Error>), Error>;
-fn run_commands_stop_at_first_error(
+#[test]
+fn uefi_firmware() {
+    let args = ProgramArgs::try_parse_from([
+        "vml",
+        "--ovmf-code",
+        "CODE.fd",
+        "--ovmf-vars",
+        "VARS.fd",
+        "validate-env",
+    ])
+    .unwrap();
+    assert_eq!(
+        args.launch_options.ovmf_code,
+        Some(PathBuf::from("CODE.fd"))
+    );
+    assert_eq!(
+        args.launch_options.ovmf_vars,
+        Some(PathBuf::from("VARS.fd"))
+    );
+    assert!(
+        ProgramArgs::try_parse_from(["vml", "--ovmf-code", "CODE.fd", "validate-env"]).is_err()
+    );
+}
+
+#[test]
+fn test_check_dependencies() {
+    let running = HashSet::from([1]);
+    assert!(check_dependencies(&[(2, vec![1]), (3, vec![2]), (4, vec![2])], &running).is_ok());
+    assert!(check_dependencies(&[(2, vec![5])], &running).is_err());
+    assert!(check_dependencies(&[(2, vec![3]), (3, vec![2])], &running).is_err());
+}
+
+const GUEST_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+
+type LaunchResult = Result<Instance, Error>;
+
+const LIVENESS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Handles SIGINT, SIGTERM and SIGHUP. Whatever waits for the signal has to clean up, further
+// signals do not cut that short.
+fn install_shutdown_handler() -> Arc<(Mutex<bool>, Condvar)> {
+    let pair = Arc::new((Mutex::new(false), Condvar::new()));
+    let pair2 = Arc::clone(&pair);
+    ctrlc::set_handler(move || {
+        let (lock, cvar) = &*pair2;
+        let mut stop = lock.lock().unwrap();
+        if *stop {
+            warn!("Received another termination signal, still shutting down");
+            return;
+        }
+        info!("Received termination signal, shutting down");
+        *stop = true;
+        cvar.notify_all();
+    })
+    .expect("Error settings termination handler");
+    pair
+}
+
+// Runs the prompt on its own thread, so a termination signal cancels it while it waits for
+// input. A cancelled prompt keeps waiting, the terminal is switched back out of raw mode for it.
+fn prompt_until_stopped<T: Send + 'static>(
+    stop: &Arc<(Mutex<bool>, Condvar)>,
+    prompt: impl FnOnce() -> InquireResult<T> + Send + 'static,
+) -> InquireResult<T> {
+    let terminal = termios::tcgetattr(stdin()).ok();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let pair = Arc::clone(stop);
+    std::thread::spawn(move || {
+        // the receiver is gone if the prompt was cancelled
+        let _ = sender.send(prompt());
+        let (lock, cvar) = &*pair;
+        let _stopped = lock.lock().unwrap();
+        cvar.notify_all();
+    });
+
+    let (lock, cvar) = &**stop;
+    let mut result = None;
+    let _stopped = cvar
+        .wait_while(lock.lock().unwrap(), |stopped| {
+            result = receiver.try_recv().ok();
+            !*stopped && result.is_none()
+        })
+        .unwrap();
+    result.unwrap_or_else(|| {
+        if let Some(terminal) = terminal {
+            if let Err(e) = termios::tcsetattr(stdin(), SetArg::TCSANOW, &terminal) {
+                warn!(%e, "Could not restore the terminal");
+            }
+        }
+        Err(InquireError::OperationInterrupted)
+    })
+}
+
+fn is_stopped(stop: &Arc<(Mutex<bool>, Condvar)>) -> bool {
+    *stop.as_ref().0.lock().unwrap()
+}
+
+// Runs the commands in script order. Consecutive launches are brought up together, ordered by
+// their dependencies, unless `in_order` is set. Every other command waits for the launches before
+// it and runs before the launches after it.
+async fn run_commands_stop_at_first_error(
     bridges: &NetworkConfig,
-    qemu_instances: &mut Vec<QemuProcessHandle>,
-    serials: &mut Vec<JoinHandle<Result<(), Error>>>,
+    segments: &BTreeMap<String, NetworkConfig>,
+    options: &LaunchOptions,
+    qemu_instances: &mut Vec<Instance>,
     commands: Vec<ScriptCommands>,
+    in_order: bool,
     stop: Arc<(Mutex<bool>, Condvar)>,
 ) -> Result<(), Error> {
-    let mut startup_tasks: Vec<Pin<Box<dyn Future<Output = RunResult>>>> = vec![];
-    for command in commands {
-        match command {
-            ScriptCommands::AddWorker(args) => {
-                startup_tasks.push(Box::pin(add_worker(bridges.clone(), args)));
-                sleep(Duration::from_secs(10));
+    let launches = commands
+        .iter()
+        .filter(|c| c.is_launch())
+        .collect::<Vec<_>>();
+    options.check_capacity(qemu_instances.len(), launches.len())?;
+
+    let mut ready = qemu_instances.iter().map(|i| i.id).collect::<HashSet<_>>();
+    check_script_dependencies(&commands, in_order, &ready)?;
+
+    // reserve all addresses up front, so the topology is not brought up partially
+    let mut taps = HashMap::new();
+    for (segment, count) in launches
+        .iter()
+        .map(|c| c.segment().map(str::to_string))
+        .counts()
+    {
+        let network = segment_network(bridges, segments, segment.as_deref())?;
+        let reserved = network.reserve(count).map_err(Error::Network)?;
+        taps.insert(segment, reserved.into_iter());
+    }
+
+    for batch in script_batches(&commands, in_order) {
+        if is_stopped(&stop) {
+            break;
+        }
+        if batch[0].is_launch() {
+            let launches = batch
+                .iter()
+                .map(|command| {
+                    let segment = command.segment().map(str::to_string);
+                    let network = segment_network(bridges, segments, segment.as_deref())?.clone();
+                    let tap = taps.get_mut(&segment).and_then(|t| t.next()).unwrap();
+                    Ok((command.clone(), network, tap))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            launch_all(options, qemu_instances, launches, &mut ready, &stop).await?;
+            continue;
+        }
+        let command = &batch[0];
+        run_command(options, qemu_instances, command).await?;
+        if let ScriptCommands::Stop(args) = command {
+            ready.remove(&args.id);
+        }
+    }
+
+    if is_stopped(&stop) {
+        info!("Script interrupted");
+    }
+    Ok(())
+}
+
+// Consecutive launches form one batch, unless they are brought up in order. Every other command
+// is a batch of its own.
+fn script_batches(
+    commands: &[ScriptCommands],
+    in_order: bool,
+) -> impl Iterator<Item = &[ScriptCommands]> {
+    commands.chunk_by(move |a, b| !in_order && a.is_launch() && b.is_launch())
+}
+
+// A launch can only depend on instances which are up before it or launched together with it,
+// the commands in between run before the launches after them. Ids of running instances can only
+// be launched again once they are stopped.
+fn check_script_dependencies(
+    commands: &[ScriptCommands],
+    in_order: bool,
+    running: &HashSet<usize>,
+) -> Result<(), Error> {
+    let mut known = running.clone();
+    for batch in script_batches(commands, in_order) {
+        if let ScriptCommands::Stop(args) = &batch[0] {
+            known.remove(&args.id);
+        }
+        if !batch[0].is_launch() {
+            continue;
+        }
+        let batch = batch
+            .iter()
+            .map(|c| (c.id(), c.depends_on().unwrap_or_default().to_vec()))
+            .collect::<Vec<_>>();
+        if let Some(id) = batch.iter().map(|(id, _)| *id).duplicates().next() {
+            return Err(Error::AlreadyRunning(id));
+        }
+        if let Some((id, _)) = batch.iter().find(|(id, _)| known.contains(id)) {
+            return Err(Error::AlreadyRunning(*id));
+        }
+        check_dependencies(&batch, &known)?;
+        known.extend(batch.iter().map(|(id, _)| *id));
+    }
+    Ok(())
+}
+
+#[test]
+fn script_order() {
+    let commands = |yaml: &str| serde_yaml::from_str::<Vec<ScriptCommands>>(yaml).unwrap();
+    let launch = indoc::indoc! {"
+        - {type: AddWorker, workerId: 1, numberOfSources: 0}
+        - {type: Exec, workerId: 1, command: mount /dev/vdb /scratch}
+        - {type: AddWorker, workerId: 2, numberOfSources: 0, dependsOn: [1]}
+    "};
+    assert!(check_script_dependencies(&commands(launch), false, &HashSet::new()).is_ok());
+    // the exec in between would have to run before worker 2 is up
+    let reversed = indoc::indoc! {"
+        - {type: AddWorker, workerId: 2, numberOfSources: 0, dependsOn: [1]}
+        - {type: Exec, workerId: 2, command: uptime}
+        - {type: AddWorker, workerId: 1, numberOfSources: 0}
+    "};
+    assert!(check_script_dependencies(&commands(reversed), false, &HashSet::new()).is_err());
+
+    // an id is only free again once its instance is stopped
+    let relaunch = indoc::indoc! {"
+        - {type: AddWorker, workerId: 1, numberOfSources: 0}
+        - {type: AddWorker, workerId: 1, numberOfSources: 0}
+    "};
+    assert!(matches!(
+        check_script_dependencies(&commands(relaunch), true, &HashSet::new()),
+        Err(Error::AlreadyRunning(1))
+    ));
+    assert!(matches!(
+        check_script_dependencies(&commands(launch), false, &HashSet::from([2])),
+        Err(Error::AlreadyRunning(2))
+    ));
+    let restart = indoc::indoc! {"
+        - {type: AddWorker, workerId: 1, numberOfSources: 0}
+        - {type: Stop, id: 1}
+        - {type: AddWorker, workerId: 1, numberOfSources: 0}
+    "};
+    assert!(check_script_dependencies(&commands(restart), true, &HashSet::new()).is_ok());
+}
+
+const STAGGER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Brings up the launches on their networks and reserved taps, each once its dependencies are
+// up, and waits for the NES workers among them to accept connections
+async fn launch_all(
+    options: &LaunchOptions,
+    qemu_instances: &mut Vec<Instance>,
+    launches: Vec<(ScriptCommands, NetworkConfig, TapUser)>,
+    ready: &mut HashSet<usize>,
+    stop: &Arc<(Mutex<bool>, Condvar)>,
+) -> Result<(), Error> {
+    let failed = Cell::new(false);
+    let cancelled = || failed.get() || is_stopped(stop);
+
+    let mut pending = vec![];
+    let mut launched_commands = HashMap::new();
+    let mut start_delay = Duration::ZERO;
+    for (command, network, tap) in launches {
+        let id = command.id();
+        let depends_on = command.depends_on().map(<[usize]>::to_vec);
+        // workers without declared dependencies are staggered, hoping their parent is up by then
+        let delay = match depends_on {
+            None if matches!(command, ScriptCommands::AddWorker(_)) => {
+                let delay = start_delay;
+                start_delay += Duration::from_secs(10);
+                delay
             }
+            _ => Duration::ZERO,
+        };
+        launched_commands.insert(id, command.clone());
+        let launch: Pin<Box<dyn Future<Output = LaunchResult>>> = match command {
+            ScriptCommands::AddWorker(args) => Box::pin(add_worker(network, tap, options, *args)),
             ScriptCommands::AddUnikernel(args) => {
-                startup_tasks.push(Box::pin(add_unikernel(bridges.clone(), args)));
+                Box::pin(add_unikernel(network, tap, options, args))
+            }
+            ScriptCommands::AddKernel(args) => Box::pin(add_kernel(tap, options, args)),
+            ScriptCommands::Exec(_) | ScriptCommands::AddSource(_) | ScriptCommands::Stop(_) => {
+                unreachable!("only launches are started here")
+            }
+        };
+        pending.push((id, depends_on.unwrap_or_default(), delay, launch));
+    }
+
+    let mut in_flight = FuturesUnordered::new();
+    let mut first_error = None;
+    let mut started = vec![];
+    loop {
+        // start every launch whose dependencies are up
+        if !cancelled() {
+            let (startable, waiting): (Vec<_>, Vec<_>) = pending
+                .into_iter()
+                .partition(|(_, depends_on, _, _)| depends_on.iter().all(|d| ready.contains(d)));
+            pending = waiting;
+            for (id, _, delay, launch) in startable {
+                let cancelled = &cancelled;
+                in_flight.push(async move {
+                    // a stop during the stagger cancels the launch right away
+                    let start = Instant::now() + delay;
+                    loop {
+                        if cancelled() {
+                            return (id, None);
+                        }
+                        let remaining = start.saturating_duration_since(Instant::now());
+                        if remaining.is_zero() {
+                            break;
+                        }
+                        task::sleep(remaining.min(STAGGER_POLL_INTERVAL)).await;
+                    }
+                    (id, Some(launch.await))
+                });
             }
         }
-        let has_stopped = stop.as_ref().0.lock().unwrap();
-        if *has_stopped {
-            info!("Script interrupted");
-            return Ok(());
+
+        let Some((id, result)) = in_flight.next().await else {
+            break;
+        };
+        match result {
+            None => info!(id, "Launch cancelled before it was started"),
+            Some(Ok(instance)) => {
+                ready.insert(id);
+                started.push(id);
+                if let Some(command) = launched_commands.remove(&id) {
+                    launched(options, command, &instance).await;
+                }
+                qemu_instances.push(instance);
+            }
+            Some(Err(e)) => {
+                error!(?e, "Launch failed, cancelling remaining launches");
+                failed.set(true);
+                first_error.get_or_insert(e);
+            }
         }
     }
 
-    let mut all_done = futures::future::join_all(startup_tasks).fuse();
-    let result;
-    loop {
-        let has_stopped = stop.as_ref().0.lock().unwrap();
-        if *has_stopped {
-            info!("Script interrupted");
+    if !pending.is_empty() {
+        info!(
+            count = pending.len(),
+            "Launches cancelled before their dependencies were up"
+        );
+    }
+
+    if let Some(e) = first_error {
+        return Err(e);
+    }
+
+    if let Some(timeout) = options.ready_timeout.filter(|_| !is_stopped(stop)) {
+        let vms = qemu_instances
+            .iter()
+            .filter(|i| started.contains(&i.id))
+            .filter_map(|i| Some((i.id, SocketAddrV4::new(i.ip(), i.rpc_port?))))
+            .collect::<Vec<_>>();
+        readiness::wait_all_ready(&vms, Duration::from_secs(timeout))
+            .await
+            .map_err(Error::NotReady)?;
+    }
+    Ok(())
+}
+
+// Runs an exec, add source or stop command of a script
+async fn run_command(
+    options: &LaunchOptions,
+    qemu_instances: &mut Vec<Instance>,
+    command: &ScriptCommands,
+) -> Result<(), Error> {
+    let args = match command {
+        ScriptCommands::Exec(args) => args,
+        ScriptCommands::AddSource(args) => {
+            find_instance(qemu_instances, args.worker_id)?
+                .add_source(args)
+                .await?;
+            record(options, command, &[]).await;
             return Ok(());
         }
-        futures::select! {
-            a = all_done => {result = a; break;},
-            default => continue,
+        ScriptCommands::Stop(args) => {
+            let index = qemu_instances
+                .iter()
+                .position(|i| i.id == args.id)
+                .ok_or(Error::UnknownInstance(args.id))?;
+            let mut instance = qemu_instances.remove(index);
+            instance.stop().await?;
+            options.hooks.run(HookEvent::Stop, &instance.record()).await;
+            record(options, command, &[]).await;
+            return Ok(());
         }
+        _ => unreachable!("launches are started by launch_all"),
+    };
+    let timeout = args.timeout.map_or(GUEST_EXEC_TIMEOUT, Duration::from_secs);
+    let output = run_and_capture(
+        qemu_instances,
+        args.worker_id,
+        &args.command,
+        args.terminator.as_deref(),
+        timeout,
+    )
+    .await?;
+    for line in &output.lines {
+        println!("[{}] {}", args.worker_id, line);
+    }
+    if let Some(status) = output.exit_status {
+        println!("[{}] exit status {status}", args.worker_id);
+        if args.check && status != 0 {
+            return Err(Error::GuestCommandFailed(
+                args.worker_id,
+                args.command.clone(),
+                status,
+            ));
+        }
+    }
+    record(options, command, &[]).await;
+    Ok(())
+}
+
+// Appends the command to the session log, if there is one. A command which can not be recorded
+// already ran, so this is only logged.
+async fn record(options: &LaunchOptions, command: &ScriptCommands, files: &[&Path]) {
+    if options.session.is_none() {
+        return;
+    }
+    match session::checksums(files).await {
+        Ok(files) => record_checksummed(options, command, files),
+        Err(e) => error!(%e, "Could not record command in the session log"),
     }
+}
 
-    println!("{:?}", result);
+fn record_checksummed(
+    options: &LaunchOptions,
+    command: &ScriptCommands,
+    files: BTreeMap<PathBuf, String>,
+) {
+    let Some(session) = options.session.as_ref() else {
+        return;
+    };
+    if let Err(e) = session.record(command, files) {
+        error!(%e, "Could not record command in the session log");
+    }
+}
 
-    Ok(())
+// Runs the ready hook and records the launch with the settings the instance ended up with, so
+// a replay does not depend on the profiles of the original session
+async fn launched(options: &LaunchOptions, command: ScriptCommands, instance: &Instance) {
+    options
+        .hooks
+        .run(HookEvent::Ready, &instance.record())
+        .await;
+    if options.session.is_none() {
+        return;
+    }
+    match command {
+        ScriptCommands::AddWorker(mut args) => {
+            args.resources = instance.resources.clone().unwrap_or_default();
+            args.profile = None;
+            let files = instance.image.iter().cloned().collect();
+            record_checksummed(options, &ScriptCommands::AddWorker(args), files);
+        }
+        ScriptCommands::AddUnikernel(ref args) => {
+            let binary = PathBuf::from(&args.path_to_binary);
+            record(options, &command, &[&binary]).await;
+        }
+        ScriptCommands::AddKernel(ref args) => {
+            let files = std::iter::once(&args.boot.kernel)
+                .chain(args.boot.initrd.iter())
+                .map(PathBuf::as_path)
+                .collect::<Vec<_>>();
+            record(options, &command, &files).await;
+        }
+        command => record(options, &command, &[]).await,
+    }
 }
 
-fn script_main(args: ScriptArgs, keep_bridge_alive: bool) -> Result<(), Error> {
+// Every vm waits up to its grace period, so they are stopped at the same time
+async fn stop_all(qemu_instances: &mut Vec<Instance>, hooks: &Hooks) {
+    let stops = qemu_instances.drain(..).map(|mut instance| async move {
+        match instance.stop().await {
+            Ok(()) => hooks.run(HookEvent::Stop, &instance.record()).await,
+            Err(e) => error!(%instance, ?e, "Could not stop instance"),
+        }
+    });
+    futures::future::join_all(stops).await;
+}
+
+fn script_main(
+    args: ScriptArgs,
+    options: &LaunchOptions,
+    keep_bridge_alive: bool,
+) -> Result<(), Error> {
     let file: Box<dyn std::io::Read + 'static> = if let Some(ref path) = args.config {
         Box::from(File::open(path).map_err(|e| Error::ScriptFileNotFound(e, path.clone()))?)
     } else {
@@ -448,99 +3105,378 @@ fn script_main(args: ScriptArgs, keep_bridge_alive: bool) -> Result<(), Error> {
     };
 
     let script: Script = serde_yaml::from_reader(file).map_err(Error::Deserialization)?;
+    run_script(script, &args, options, keep_bridge_alive)
+}
 
-    let pair = Arc::new((Mutex::new(false), Condvar::new()));
-    let pair2 = Arc::clone(&pair);
-    ctrlc::set_handler(move || {
-        let (lock, cvar) = &*pair2;
-        let mut stop = lock.lock().unwrap();
-        *stop = true;
-        cvar.notify_one();
-    })
-    .expect("Error settings ctrl-c handler");
+fn run_script(
+    script: Script,
+    args: &ScriptArgs,
+    options: &LaunchOptions,
+    keep_bridge_alive: bool,
+) -> Result<(), Error> {
+    let mut options = options.clone();
+    options.profiles.extend(script.profiles);
+    let options = &options;
+
+    if let Some(path) = args.coordinator_config.as_ref() {
+        let coordinator_config = CoordinatorConfiguration {
+            logical_sources: script.logical_sources,
+        };
+        let rendered =
+            Templates::coordinator_config(&coordinator_config).map_err(Error::Template)?;
+        std::fs::write(path, rendered).map_err(Error::IO)?;
+    }
+
+    let pair = install_shutdown_handler();
+    let oom = options.oom_watcher();
 
-    let bridges = network_setup(args.ip_range);
+    let backend: Arc<dyn NetworkBackend> =
+        Arc::new(KernelNetworkBackend::with_nic_queues(options.nic_queues));
+    let bridges = match (options.existing_bridge.as_deref(), args.ip_range) {
+        (Some(bridge), _) => network_adopt(
+            backend.clone(),
+            &options.topology(),
+            bridge,
+            options.gateway,
+            &options.reserved_ips,
+        ),
+        (None, Some(ip_range)) => network_setup(
+            backend.clone(),
+            &options.topology(),
+            ip_range,
+            options.gateway,
+            &options.reserved_ips,
+        ),
+        (None, None) => return Err(Error::NoIpRange),
+    }
+    .map_err(Error::Network)?
+    .with_state_checks(options.debug_assert_state);
+
+    let address_spaces = script
+        .segments
+        .iter()
+        .map(|(name, ip_net)| (name.as_str(), *ip_net))
+        .chain(std::iter::once(("default", bridges.ip_net())))
+        .collect::<Vec<_>>();
+    validate_segments(&address_spaces).map_err(Error::Network)?;
+    let segments = script
+        .segments
+        .iter()
+        .enumerate()
+        .map(|(i, (name, ip_net))| {
+            network_setup_segment(
+                backend.clone(),
+                &options.topology().bridge(i + 1),
+                &options.topology().segment_tap_prefix(i + 1),
+                *ip_net,
+                None,
+                &options.reserved_ips,
+            )
+            .map(|nc| {
+                (
+                    name.clone(),
+                    nc.with_state_checks(options.debug_assert_state),
+                )
+            })
+        })
+        .collect::<Result<BTreeMap<_, _>, _>>()
+        .map_err(Error::Network)?;
+    let options = &options.with_session(SessionHeader {
+        ip_range: bridges.ip_net(),
+        segments: script.segments.clone(),
+    })?;
+    if let Some(pool) = options.warm_pool.as_ref() {
+        pool.start(&bridges, options)?;
+    }
     {
         let mut qemu_instances = vec![];
-        let mut serials = vec![];
-        let result = run_commands_stop_at_first_error(
+        let result = task::block_on(run_commands_stop_at_first_error(
             &bridges,
+            &segments,
+            options,
             &mut qemu_instances,
-            &mut serials,
             script.commands,
+            script.in_order,
             pair.clone(),
-        );
+        ));
 
         match result {
             Ok(_) => {
+                if let Some(out) = args.export.as_ref() {
+                    qemu_instances.iter_mut().for_each(Instance::discover_ip);
+                    if let Err(e) = export_instances(&qemu_instances, args.export_format, out) {
+                        error!(%e, "Could not export topology");
+                    }
+                }
                 info!("Commands run successful, waiting for Ctr-C");
                 let (lock, cvar) = &*pair;
-                let mut stopped = lock.lock().unwrap();
-                // As long as the value inside the `Mutex<bool>` is `false`, we wait.
-                while !*stopped {
-                    stopped = cvar.wait(stopped).unwrap();
+                loop {
+                    // As long as the value inside the `Mutex<bool>` is `false`, we wait.
+                    let (stopped, _) = cvar
+                        .wait_timeout_while(lock.lock().unwrap(), LIVENESS_CHECK_INTERVAL, |s| !*s)
+                        .unwrap();
+                    if *stopped {
+                        break;
+                    }
+                    drop(stopped);
+                    reap_dead_instances(&mut qemu_instances, oom.as_ref(), &options.hooks);
                 }
             }
             Err(e) => {
                 error!("Commands failed: {:?}", e)
             }
         }
+
+        info!("Stopping {} instances", qemu_instances.len());
+        task::block_on(stop_all(&mut qemu_instances, &options.hooks));
+    }
+    if let Some(pool) = options.warm_pool.as_ref() {
+        task::block_on(pool.shutdown());
     }
 
     if !keep_bridge_alive {
         task::block_on(network_cleanup(bridges));
+        for segment in segments.into_values() {
+            task::block_on(network_cleanup(segment));
+        }
     }
 
     Ok(())
 }
 
-fn run_test() {
-    let bridge =
-        network::userbridge::Bridge::new("bridge2", "10.0.0.0/24".parse::<Ipv4Net>().unwrap())
-            .unwrap();
+fn run_test(args: TestArgs) -> Result<(), Error> {
+    if let Some(log) = args.serial_replay {
+        return task::block_on(replay_boot_log(log));
+    }
+    let mock = Arc::new(network::mock::MockNetworkBackend::default());
+    let backend: Arc<dyn NetworkBackend> = if args.mock {
+        mock.clone()
+    } else {
+        Arc::new(KernelNetworkBackend::default())
+    };
+
     {
-        let tap = network::usertap::Tap::new("tap56").unwrap();
-        bridge.add_tap(&tap).unwrap();
-        sleep(std::time::Duration::from_secs(10));
+        let nc = network_setup_segment(
+            backend,
+            "bridge2",
+            "tap",
+            "10.0.0.0/24".parse::<Ipv4Net>().unwrap(),
+            None,
+            &[],
+        )
+        .map_err(Error::Network)?;
+        let taps = nc.reserve(2).map_err(Error::Network)?;
+        for tap in &taps {
+            info!(device = tap.device(), ip = %tap.ip(), mac = %tap.mac(), "Created tap");
+        }
+        if !args.mock {
+            sleep(std::time::Duration::from_secs(10));
+        }
+    }
+
+    for operation in mock.operations() {
+        println!("{operation:?}");
+    }
+    Ok(())
+}
+
+// Returns false if a resource could not be removed
+fn run_reset_host(args: ResetHostArgs, options: &LaunchOptions) -> Result<bool, Error> {
+    let reset = task::block_on(reset::reset_host(
+        &options.topology(),
+        options.qemu_binary.as_deref(),
+        args.dry_run,
+    ))
+    .map_err(Error::Reset)?;
+    let verb = if args.dry_run {
+        "would remove"
+    } else {
+        "removed"
+    };
+    if reset.removed.is_empty() && reset.failed.is_empty() {
+        println!("nothing to clean up");
     }
-    sleep(std::time::Duration::from_secs(10));
+    for resource in reset.removed {
+        println!("{verb} {resource}");
+    }
+    for (resource, e) in reset.failed.iter() {
+        match std::error::Error::source(e) {
+            Some(cause) => println!("could not remove {resource}: {cause}"),
+            None => println!("could not remove {resource}"),
+        }
+    }
+    Ok(reset.failed.is_empty())
+}
+
+async fn replay_boot_log(log: PathBuf) -> Result<(), Error> {
+    let console = SerialConsole::replay(log, vec![], SerialOptions::default());
+    let lines = console.subscribe();
+    let reader = task::spawn(console.read());
+    let marker = Regex::new(WORKER_BOOT_MARKER).unwrap();
+    let booted = wait_for_serial_marker(lines, &marker, Some(Duration::from_secs(60))).await;
+    // a replay always ends with the log
+    let _ = reader.await;
+    match booted {
+        Ok(()) => println!("worker booted"),
+        Err(e) => println!("worker did not boot: {e}"),
+    }
+    Ok(())
+}
+
+// Returns false if a required tool is missing or too old
+fn run_validate_env(args: ValidateEnvArgs, options: &LaunchOptions) -> Result<bool, Error> {
+    let (workers, unikernels) = match args.script.as_ref() {
+        Some(path) => {
+            let file = File::open(path).map_err(|e| Error::ScriptFileNotFound(e, path.clone()))?;
+            let script: Script = serde_yaml::from_reader(file).map_err(Error::Deserialization)?;
+            (
+                script
+                    .commands
+                    .iter()
+                    .any(|c| matches!(c, ScriptCommands::AddWorker(_))),
+                script
+                    .commands
+                    .iter()
+                    .any(|c| matches!(c, ScriptCommands::AddUnikernel(_))),
+            )
+        }
+        // interactive sessions can launch both
+        None => (true, true),
+    };
+
+    let backends = Backends {
+        workers,
+        unikernels,
+        tpm: options.tpm,
+        isolated_cpus: options.use_isolated_cpus,
+        image_checksum: options.image_sha256.is_some(),
+        image_scheme: options
+            .flatcar_image
+            .split_once("://")
+            .map(|(scheme, _)| scheme.to_string()),
+        qemu_binary: options.qemu_binary.clone(),
+    };
+    let reports = task::block_on(validate_env(&backends));
+    for report in &reports {
+        println!("{report}");
+    }
+    Ok(reports.iter().all(|r| r.is_ok()))
 }
 
 fn main() {
-    let args = ProgramArgs::parse();
+    let mut args = ProgramArgs::parse();
     tracing_subscriber::fmt::init();
+    if let Err(e) = Templates::self_test() {
+        error!(?e, "Template self test failed");
+        std::process::exit(1);
+    }
+    if args.launch_options.use_isolated_cpus {
+        args.launch_options.isolated_cpus =
+            Some(IsolatedCpus::from_sysfs().expect("Could not read isolated cpus"));
+    }
+    if let Some(ratio) = args.launch_options.max_vcpu_overcommit {
+        args.launch_options.vcpu_budget = Some(
+            VcpuBudget::from_sysfs(ratio, args.launch_options.force_overcommit)
+                .expect("Could not set up the vcpu overcommit limit"),
+        );
+    }
+    if let Some(range) = args.launch_options.host_port_range {
+        args.launch_options.host_ports = Some(HostPorts::new(range));
+    }
+    if args.launch_options.warm_pool_size > 0 {
+        args.launch_options.warm_pool = Some(WarmPool::new(args.launch_options.warm_pool_size));
+    }
+    if let Some(dir) = args.launch_options.socket_dir.as_ref() {
+        args.launch_options.socket_dir = Some(args.launch_options.topology().namespaced_dir(dir));
+    }
+    if let Some(root) = args.launch_options.run_dir_root.as_ref() {
+        let root = args.launch_options.topology().namespaced_dir(root);
+        args.launch_options.run_dir =
+            Some(RunDir::create(&root).expect("Could not create run directory"));
+    }
 
     match args.command {
         VMLauncherCommand::Interactive(ia) => {
-            interactive_main(ia, args.keep_bridge_alive).expect("Interactive Failed")
+            interactive_main(ia, &args.launch_options, args.keep_bridge_alive)
+                .expect("Interactive Failed")
         }
         VMLauncherCommand::Script(sa) => {
-            script_main(sa, args.keep_bridge_alive).expect("Script Failed")
+            script_main(sa, &args.launch_options, args.keep_bridge_alive).expect("Script Failed")
         }
-        VMLauncherCommand::Test => {
-            run_test();
+        VMLauncherCommand::Test(ta) => run_test(ta).expect("Test Failed"),
+        VMLauncherCommand::ValidateEnv(va) => {
+            if !run_validate_env(va, &args.launch_options).expect("Validating environment failed") {
+                std::process::exit(1);
+            }
+        }
+        VMLauncherCommand::ResetHost(ra) => {
+            if !run_reset_host(ra, &args.launch_options).expect("Resetting host failed") {
+                std::process::exit(1);
+            }
+        }
+        VMLauncherCommand::Replay(ra) => {
+            replay_main(ra, &args.launch_options, args.keep_bridge_alive).expect("Replay Failed")
         }
     };
 }
 
-fn run_restart<'a>(
-    stopped_instances: &mut Vec<QemuProcessHandle>,
-) -> Result<Vec<QemuProcessHandle>, (Vec<QemuProcessHandle>, Error)> {
-    let options = stopped_instances
-        .iter_mut()
-        .enumerate()
-        .map(|(i, o)| ProcessOption { index: i, qph: o })
-        .collect();
+// Runs a recorded session like a script. Every command runs in recorded order, a launch only
+// starts once the launch before it is up.
+fn replay_main(
+    args: ReplayArgs,
+    options: &LaunchOptions,
+    keep_bridge_alive: bool,
+) -> Result<(), Error> {
+    let (header, entries) =
+        session::read::<ScriptCommands>(&args.session_log).map_err(Error::Session)?;
+    task::block_on(session::check_files(&entries)).map_err(Error::Session)?;
+    info!(commands = entries.len(), "Replaying session");
+    let script = Script {
+        profiles: HashMap::new(),
+        segments: header.segments,
+        logical_sources: vec![],
+        commands: entries.into_iter().map(|entry| entry.command).collect(),
+        in_order: true,
+    };
+    let script_args = ScriptArgs {
+        ip_range: Some(args.ip_range.unwrap_or(header.ip_range)),
+        config: None,
+        export: None,
+        export_format: ExportFormat::Json,
+        coordinator_config: None,
+    };
+    run_script(script, &script_args, options, keep_bridge_alive)
+}
+
+fn run_restart(
+    stopped_instances: &mut Vec<Instance>,
+    launch_options: &LaunchOptions,
+    running: usize,
+) -> Result<Vec<Instance>, (Vec<Instance>, Error)> {
+    let options = process_options(stopped_instances, InstanceState::Stopped);
 
     let options = inquire::MultiSelect::new("Restart machines?", options)
+        .with_filter(&matches_all_terms)
         .prompt()
         .map_err(|e| (vec![], Error::Inquire(e)))?;
+    launch_options
+        .check_capacity(running, options.len())
+        .map_err(|e| (vec![], e))?;
 
     let mut indexes_to_remove = vec![];
     let mut first_error: Option<Error> = None;
     for option in options {
-        match task::block_on(option.qph.restart()).map_err(Error::Qemu) {
-            Ok(_) => {
+        let restarted = task::block_on(async {
+            option.instance.handle.restart().await.map_err(Error::Vm)?;
+            option.instance.reconnect_serial(launch_options).await?;
+            launch_options
+                .hooks
+                .run(HookEvent::Ready, &option.instance.record())
+                .await;
+            Ok(())
+        });
+        match restarted {
+            Ok(()) => {
                 indexes_to_remove.push(option.index);
             }
             Err(e) => {
@@ -1,21 +1,27 @@
 use crate::nanos::NanosError::HomeDir;
+use async_std::sync::{Mutex, MutexGuardArc};
 use camino::Utf8PathBuf;
 use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io::Write;
 use std::net::Ipv4Addr;
-use std::path::PathBuf;
-use tempdir::TempDir;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
 use thiserror::Error;
 use tracing::{error, info};
 use tracing_subscriber::fmt::format;
 use which::which;
 
+use crate::builder::{BuildError, BuildFuture, BuiltImage, ImageBuilder};
+use crate::image::copy_image;
 use crate::network::TapUser;
-use crate::qemu::LaunchConfiguration;
+use crate::progress::with_spinner;
+use crate::qemu::{BootSource, LaunchConfiguration, OutputDirectory, SecurityConfig, SocketAccess};
+use crate::rundir::{RunDir, VmDir};
 use crate::shell;
 use crate::shell::{run_shell_command, run_shell_command_with_env, ShellError};
-use crate::templates::WorkerConfiguration;
+use crate::templates::{WorkerConfiguration, WorkerPorts};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "PascalCase")]
@@ -28,6 +34,15 @@ pub struct Args {
     pub(crate) debugflags: Vec<String>,
     pub(crate) run_config: RunConfig,
     pub(crate) use_docker: bool,
+    #[serde(skip)]
+    pub(crate) security: SecurityConfig,
+    #[serde(skip)]
+    pub(crate) socket_dir: Option<PathBuf>,
+    #[serde(skip)]
+    pub(crate) run_dir: Option<RunDir>,
+    // builds the image instead of ops
+    #[serde(skip)]
+    pub(crate) image_builder: Option<Arc<dyn ImageBuilder<UnikernelImageSpec>>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -36,6 +51,33 @@ pub struct RunConfig {
     pub(crate) gateway: Ipv4Addr,
 }
 
+// 9p share the worker's output directory is mounted from, and where it appears in the guest
+const OUTPUT_MOUNT_TAG: &str = "output";
+const OUTPUT_GUEST_PATH: &str = "/output";
+
+// The ops config of a single worker, the shared args plus the worker's own mounts
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct OpsConfig<'a> {
+    #[serde(flatten)]
+    args: &'a Args,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    mounts: BTreeMap<&'static str, &'static str>,
+}
+
+impl<'a> OpsConfig<'a> {
+    fn new(args: &'a Args, worker_configuration: &UnikernelWorkerConfig) -> Self {
+        OpsConfig {
+            args,
+            mounts: worker_configuration
+                .output_dir
+                .iter()
+                .map(|_| (OUTPUT_MOUNT_TAG, OUTPUT_GUEST_PATH))
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UnikernelWorkerConfig {
     pub query_id: usize,
@@ -43,6 +85,9 @@ pub struct UnikernelWorkerConfig {
     pub elf_binary: Utf8PathBuf,
     pub args: Option<String>,
     pub ip: Option<Ipv4Addr>,
+    pub ports: WorkerPorts,
+    // host directory the worker's /output is collected into once the vm exits
+    pub output_dir: Option<PathBuf>,
 }
 
 impl UnikernelWorkerConfig {
@@ -63,6 +108,8 @@ pub(crate) enum NanosError {
     HomeDir(#[source] homedir::GetHomeError),
     #[error("Usage error: {0}")]
     UsageError(String),
+    #[error("Could not build the image")]
+    Build(#[source] BuildError),
 }
 
 pub(crate) async fn prepare_launch(
@@ -71,27 +118,26 @@ pub(crate) async fn prepare_launch(
     args: &Args,
 ) -> Result<LaunchConfiguration, NanosError> {
     let image_name = worker_configuration.image_name();
-    let temp_dir = tempdir::TempDir::new(&image_name)
-        .map_err(|e| NanosError::FileSystem(e, "Creating Tempdir"))?;
-    let dest_image_path = temp_dir.path().join(".ops/images").join(&image_name);
-
-    async_std::fs::create_dir_all(dest_image_path.parent().unwrap())
-        .await
-        .map_err(|e| NanosError::FileSystem(e, "creating ops image dir"))?;
+    let vm_dir = VmDir::create(args.run_dir.as_ref(), &image_name)
+        .map_err(|e| NanosError::FileSystem(e, "Creating vm directory"))?;
 
     let ip_string = worker_configuration
         .ip
         .as_ref()
         .unwrap_or(tap.ip())
         .to_string();
-    let nanos_config_file = temp_dir.path().join("nanos_config.json");
+    let nanos_config_file = vm_dir.path().join("nanos_config.json");
 
     let mut file = fs::File::create(&nanos_config_file)
         .map_err(|e| NanosError::FileSystem(e, "Creating Config"))?;
-    serde_json::to_string(args).unwrap();
+    let ops_config = OpsConfig::new(args, &worker_configuration);
 
-    file.write_all(serde_json::to_string(args).unwrap().as_bytes())
+    file.write_all(serde_json::to_string(&ops_config).unwrap().as_bytes())
         .map_err(|e| NanosError::FileSystem(e, "Writing Config"))?;
+    if worker_configuration.output_dir.is_some() {
+        fs::create_dir_all(OutputDirectory::staging(vm_dir.path()))
+            .map_err(|e| NanosError::FileSystem(e, "Creating output directory"))?;
+    }
 
     if !worker_configuration.elf_binary.is_file() {
         return Err(NanosError::UsageError(format!(
@@ -99,81 +145,240 @@ pub(crate) async fn prepare_launch(
             worker_configuration.elf_binary.as_str()
         )));
     }
-    let (binary_name, config_file) = if args.use_docker {
-        let elf_binary_filename = worker_configuration.elf_binary.file_name().unwrap();
-        (
-            Utf8PathBuf::from("/input/").join(elf_binary_filename),
-            Utf8PathBuf::from("/config/")
-                .join(nanos_config_file.file_name().unwrap().to_str().unwrap()),
-        )
-    } else {
-        (
-            worker_configuration.elf_binary.clone(),
-            Utf8PathBuf::from_path_buf(nanos_config_file).unwrap(),
-        )
-    };
-
-    let mut ops_args = vec![
-        "build",
-        binary_name.as_str(),
-        "-c",
-        config_file.as_str(),
-        "--ip-address",
-        &ip_string,
-        "-i",
-        &image_name,
-    ];
 
+    let mut ops_args = vec![];
     if let Some(args) = worker_configuration.args.as_ref() {
         if !args.is_empty() {
-            for arg in args.split(' ') {
-                ops_args.push("--args");
-                ops_args.push(arg);
-            }
+            ops_args.extend(args.split(' ').map(str::to_string));
         }
     }
-
-    if args.use_docker {
-        ops_build_using_docker(ops_args, &worker_configuration, &temp_dir).await?
-    } else {
-        ops_build_using_local(ops_args, &worker_configuration, &dest_image_path).await?
-    }
+    ops_args.extend(worker_configuration.ports.as_args());
+    let spec = UnikernelImageSpec {
+        image_name: image_name.clone(),
+        elf_binary: worker_configuration.elf_binary.clone().into(),
+        config_file: nanos_config_file,
+        ip: ip_string,
+        args: ops_args,
+    };
+    let ops = OpsBuilder {
+        use_docker: args.use_docker,
+    };
+    let builder = args.image_builder.as_deref().unwrap_or(&ops);
+    let built = with_spinner(
+        format!("Building {image_name}"),
+        builder.build(&spec, vm_dir.path()),
+    )
+    .await
+    .map_err(NanosError::Build)?;
 
     Ok(LaunchConfiguration {
         tap,
-        image_path: dest_image_path,
-        temp_dir,
-        firmware: vec![],
+        boot: BootSource::Image(built.image_path),
+        vm_dir,
+        firmware: built.firmware,
+        pflash: None,
         num_cores: Some(1),
+        max_num_cores: None,
         memory_in_mega_bytes: Some(512),
+        balloon: false,
+        security: args.security.clone(),
+        tpm: false,
+        socket_dir: args.socket_dir.clone(),
+        cpu_affinity: None,
+        vcpu_reservation: None,
+        cpu_limit: None,
+        machine_properties: vec![],
+        incoming: None,
+        output: worker_configuration
+            .output_dir
+            .map(|destination| OutputDirectory {
+                mount_tag: OUTPUT_MOUNT_TAG.to_string(),
+                destination,
+            }),
+        sync_clock: false,
+        hmp_monitor: false,
+        vnc: None,
+        gdb: None,
+        extra_drives: vec![],
+        disk_queues: None,
+        hugepages: None,
+        qemu_binary: None,
+        vhost: false,
+        nic_queues: 1,
+        bond_taps: vec![],
+        socket_access: SocketAccess::default(),
     })
 }
 
+static IMAGE_BUILDS: LazyLock<std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+// Builds of the same image write the same file in ~/.ops/images, so they have to take turns
+async fn lock_image_build(image_name: &str) -> MutexGuardArc<()> {
+    let lock = IMAGE_BUILDS
+        .lock()
+        .unwrap()
+        .entry(image_name.to_string())
+        .or_default()
+        .clone();
+    lock.lock_arc().await
+}
+
 async fn ops_build_using_local(
     ops_args: Vec<&str>,
-    config: &UnikernelWorkerConfig,
-    dest_image: &PathBuf,
-) -> Result<(), NanosError> {
+    image_name: &str,
+    dest_image: &Path,
+) -> Result<(), BuildError> {
+    // held until the image is copied out of the ops cache
+    let _build = lock_image_build(image_name).await;
     run_shell_command("ops", &ops_args)
         .await
-        .map_err(NanosError::Shell)?;
+        .map_err(BuildError::Shell)?;
 
     let source_image = homedir::get_my_home()
         .unwrap()
         .unwrap()
-        .join(format!(".ops/images/{}.img", config.image_name()));
+        .join(format!(".ops/images/{image_name}.img"));
 
-    async_std::fs::copy(source_image, dest_image)
+    copy_image(&source_image, dest_image)
         .await
-        .map_err(|e| NanosError::FileSystem(e, "copying image"))?;
+        .map_err(|e| BuildError::IO(e, "copying image"))?;
 
     Ok(())
 }
 
-async fn ops_build_using_docker(
-    p0: Vec<&str>,
-    p1: &UnikernelWorkerConfig,
-    p2: &TempDir,
-) -> Result<(), NanosError> {
+async fn ops_build_using_docker(p0: Vec<&str>, p1: &str, p2: &Path) -> Result<(), BuildError> {
     todo!()
 }
+
+// The unikernel of a single worker and the ops config it is built with
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UnikernelImageSpec {
+    image_name: String,
+    elf_binary: PathBuf,
+    config_file: PathBuf,
+    ip: String,
+    // passed to the unikernel
+    args: Vec<String>,
+}
+
+// `ops build`, either with a local ops or in its container
+#[derive(Debug)]
+pub(crate) struct OpsBuilder {
+    use_docker: bool,
+}
+
+impl ImageBuilder<UnikernelImageSpec> for OpsBuilder {
+    fn build<'a>(&'a self, spec: &'a UnikernelImageSpec, vm_dir: &'a Path) -> BuildFuture<'a> {
+        Box::pin(async move {
+            let dest_image_path = vm_dir.join(".ops/images").join(&spec.image_name);
+            async_std::fs::create_dir_all(dest_image_path.parent().unwrap())
+                .await
+                .map_err(|e| BuildError::IO(e, "creating ops image dir"))?;
+
+            let elf_binary = Utf8PathBuf::from_path_buf(spec.elf_binary.clone()).unwrap();
+            let (binary_name, config_file) = if self.use_docker {
+                let elf_binary_filename = elf_binary.file_name().unwrap();
+                (
+                    Utf8PathBuf::from("/input/").join(elf_binary_filename),
+                    Utf8PathBuf::from("/config/")
+                        .join(spec.config_file.file_name().unwrap().to_str().unwrap()),
+                )
+            } else {
+                (
+                    elf_binary.clone(),
+                    Utf8PathBuf::from_path_buf(spec.config_file.clone()).unwrap(),
+                )
+            };
+
+            let mut ops_args = vec![
+                "build",
+                binary_name.as_str(),
+                "-c",
+                config_file.as_str(),
+                "--ip-address",
+                &spec.ip,
+                "-i",
+                &spec.image_name,
+            ];
+            for arg in &spec.args {
+                ops_args.push("--args");
+                ops_args.push(arg);
+            }
+
+            if self.use_docker {
+                ops_build_using_docker(ops_args, &spec.image_name, vm_dir).await?
+            } else {
+                ops_build_using_local(ops_args, &spec.image_name, &dest_image_path).await?
+            }
+            Ok(BuiltImage {
+                image_path: dest_image_path,
+                firmware: vec![],
+            })
+        })
+    }
+}
+
+#[test]
+fn output_mount() {
+    let args = Args {
+        klibs: vec![],
+        kernel: None,
+        klib_dir: None,
+        debugflags: vec![],
+        run_config: RunConfig {
+            gateway: Ipv4Addr::new(10, 0, 0, 1),
+        },
+        use_docker: false,
+        security: SecurityConfig::default(),
+        socket_dir: None,
+        run_dir: None,
+        image_builder: None,
+    };
+    let mut wc = UnikernelWorkerConfig {
+        query_id: 1,
+        node_id: 2,
+        elf_binary: Utf8PathBuf::from("/bin/unikernel"),
+        args: None,
+        ip: None,
+        ports: WorkerPorts::default(),
+        output_dir: None,
+    };
+    let config = serde_json::to_value(OpsConfig::new(&args, &wc)).unwrap();
+    assert_eq!(config["RunConfig"]["Gateway"], "10.0.0.1");
+    assert!(config.get("Mounts").is_none());
+
+    wc.output_dir = Some(PathBuf::from("/tmp/results"));
+    let config = serde_json::to_value(OpsConfig::new(&args, &wc)).unwrap();
+    assert_eq!(config["Mounts"], serde_json::json!({"output": "/output"}));
+}
+
+#[test]
+fn concurrent_builds_of_the_same_image_take_turns() {
+    let dir = tempdir::TempDir::new("image_build").unwrap();
+    let image = dir.path().join("unikernel_1_1.img");
+    // stands in for ops writing the image and copying it out of the cache
+    let build = |content: u8| {
+        let image = image.clone();
+        async move {
+            let _build = lock_image_build("unikernel_1_1").await;
+            for _ in 0..10 {
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&image)
+                    .and_then(|mut f| f.write_all(&[content]))
+                    .unwrap();
+                async_std::task::yield_now().await;
+            }
+            let written = fs::read(&image).unwrap();
+            fs::remove_file(&image).unwrap();
+            written
+        }
+    };
+
+    let (a, b) = async_std::task::block_on(futures::future::join(build(1), build(2)));
+    assert_eq!(a, vec![1; 10]);
+    assert_eq!(b, vec![2; 10]);
+}
@@ -1,13 +1,13 @@
 use derive_builder::Builder;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct ConfigItem {
     key: &'static str,
     value: String,
 }
 
-#[derive(Serialize, Default)]
+#[derive(Serialize, Default, Clone)]
 pub(crate) struct WorkerQueryProcessingConfigurationInternal {
     config: Vec<ConfigItem>,
 }
@@ -66,7 +66,7 @@ impl Into<WorkerQueryProcessingConfigurationInternal> for WorkerQueryProcessingC
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub(crate) struct Source {
     source_type: &'static str,
     logical_source_name: String,
@@ -97,6 +97,11 @@ pub(crate) struct TCPSourceConfig {
     flush_interval: std::time::Duration,
     #[builder(default = "Format::CSV")]
     format: Format,
+    #[builder(default = "None")]
+    num_source_threads: Option<usize>,
+    // core the source thread is pinned to
+    #[builder(default = "None")]
+    source_affinity: Option<usize>,
 }
 
 impl Into<Source> for TCPSourceConfig {
@@ -151,6 +156,19 @@ impl Into<Source> for TCPSourceConfig {
             ]),
         };
 
+        if let Some(threads) = self.num_source_threads {
+            config.push(ConfigItem {
+                key: "numSourceThreads",
+                value: threads.to_string(),
+            });
+        }
+        if let Some(core) = self.source_affinity {
+            config.push(ConfigItem {
+                key: "sourceAffinity",
+                value: core.to_string(),
+            });
+        }
+
         Source {
             source_type: "TCP_SOURCE",
             physical_source_name: self
@@ -161,3 +179,56 @@ impl Into<Source> for TCPSourceConfig {
         }
     }
 }
+
+#[test]
+fn source_threads_and_affinity() {
+    let source: Source = TCPSourceConfigBuilder::default()
+        .logical_source_name("bid".to_string())
+        .socket_port(8071)
+        .num_source_threads(2)
+        .source_affinity(3)
+        .build()
+        .unwrap()
+        .into();
+
+    let config = source
+        .config
+        .iter()
+        .map(|item| (item.key, item.value.as_str()))
+        .collect::<Vec<_>>();
+    assert!(config.contains(&("numSourceThreads", "2")));
+    assert!(config.contains(&("sourceAffinity", "3")));
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum FieldType {
+    Int8,
+    Int16,
+    Int32,
+    Int64,
+    Uint8,
+    Uint16,
+    Uint32,
+    Uint64,
+    Float32,
+    Float64,
+    Boolean,
+    Char,
+    Text,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub(crate) struct SchemaField {
+    pub(crate) name: String,
+    #[serde(rename = "type")]
+    pub(crate) field_type: FieldType,
+}
+
+// Schema of the sources' logicalSourceName, declared by the coordinator
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LogicalSource {
+    pub(crate) logical_source_name: String,
+    pub(crate) fields: Vec<SchemaField>,
+}
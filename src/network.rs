@@ -1,64 +1,249 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::net::{IpAddr, Ipv4Addr};
-use std::ops::Deref;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex};
 use std::{cell::RefCell, sync};
 
 use async_std::task;
 
 use crate::network::userbridge::UserBridgeError;
+use crate::network::usertap::UserTapError;
 use ipnet::{IpSub, Ipv4AddrRange, Ipv4Net};
 use itertools::Itertools;
 use macaddr::MacAddr;
 use rand::random;
-use tracing::{instrument, warn, Level};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{debug, instrument, warn, Level};
 
 use crate::shell::{run_shell_command, ShellError};
+use crate::topology::Topology;
 mod common;
+pub(crate) mod mock;
 pub(crate) mod userbridge;
 pub(crate) mod usertap;
 
-#[derive(Debug, Clone)]
+// Creates and deletes the bridge and tap devices. Everything above this (allocation, naming,
+// releasing taps on drop) is shared, so it can be exercised without CAP_NET_ADMIN.
+pub(crate) trait NetworkBackend: std::fmt::Debug + Send + Sync {
+    // `address` is the host's address on the bridge, the gateway, with the prefix of its subnet
+    fn create_bridge(&self, name: &str, address: Ipv4Net) -> Result<(), NetworkError>;
+    // Makes an existing bridge available to attach taps to, returns its address and prefix
+    fn adopt_bridge(&self, name: &str) -> Result<Ipv4Net, NetworkError>;
+    fn create_tap(&self, name: &str) -> Result<(), NetworkError>;
+    fn attach_tap(&self, bridge: &str, tap: &str) -> Result<(), NetworkError>;
+    fn delete_tap(&self, name: &str);
+    fn delete_bridge(&self, name: &str);
+}
+
+// Real devices, they are removed when dropped
+#[derive(Debug, Default)]
+pub(crate) struct KernelNetworkBackend {
+    bridges: Mutex<HashMap<String, userbridge::Bridge>>,
+    taps: Mutex<HashMap<String, usertap::Tap>>,
+    // taps are created with IFF_MULTI_QUEUE, every vm has to use multiqueue nics
+    multi_queue: bool,
+}
+
+impl KernelNetworkBackend {
+    // Taps for nics with `nic_queues` queues
+    pub(crate) fn with_nic_queues(nic_queues: usize) -> Self {
+        KernelNetworkBackend {
+            multi_queue: nic_queues > 1,
+            ..Default::default()
+        }
+    }
+}
+
+impl NetworkBackend for KernelNetworkBackend {
+    fn create_bridge(&self, name: &str, address: Ipv4Net) -> Result<(), NetworkError> {
+        let bridge = userbridge::Bridge::new(name, address)
+            .map_err(|e| NetworkError::CreateBridge(e, name.to_string()))?;
+        self.bridges
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), bridge);
+        Ok(())
+    }
+
+    fn adopt_bridge(&self, name: &str) -> Result<Ipv4Net, NetworkError> {
+        let bridge = userbridge::Bridge::existing(name)
+            .map_err(|e| NetworkError::ExistingBridge(e, name.to_string()))?;
+        let address = bridge
+            .address()
+            .map_err(|e| NetworkError::ExistingBridge(e, name.to_string()))?;
+        self.bridges
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), bridge);
+        Ok(address)
+    }
+
+    fn create_tap(&self, name: &str) -> Result<(), NetworkError> {
+        let tap = usertap::Tap::new(name, self.multi_queue)
+            .map_err(|e| NetworkError::Tap(e, name.to_string()))?;
+        self.taps.lock().unwrap().insert(name.to_string(), tap);
+        Ok(())
+    }
+
+    fn attach_tap(&self, bridge: &str, tap: &str) -> Result<(), NetworkError> {
+        let bridges = self.bridges.lock().unwrap();
+        let taps = self.taps.lock().unwrap();
+        let bridge = bridges
+            .get(bridge)
+            .ok_or_else(|| NetworkError::UnknownDevice(bridge.to_string()))?;
+        let device = taps
+            .get(tap)
+            .ok_or_else(|| NetworkError::UnknownDevice(tap.to_string()))?;
+        bridge
+            .add_tap(device)
+            .map_err(|e| NetworkError::Bridge(e, tap.to_string()))
+    }
+
+    fn delete_tap(&self, name: &str) {
+        self.taps.lock().unwrap().remove(name);
+    }
+
+    fn delete_bridge(&self, name: &str) {
+        self.bridges.lock().unwrap().remove(name);
+    }
+}
+
+#[derive(Debug)]
 struct Bridge {
-    bridge: Arc<RwLock<userbridge::Bridge>>,
-    ip_addr: Ipv4Net,
+    name: String,
+    backend: Arc<dyn NetworkBackend>,
+    ip_net: Ipv4Net,
+    // an existing bridge the launcher only attaches its taps to
+    adopted: bool,
 }
 
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        if !self.adopted {
+            self.backend.delete_bridge(&self.name);
+        }
+    }
+}
+
+#[instrument(level = tracing::Level::DEBUG)]
+pub(crate) fn network_setup(
+    backend: Arc<dyn NetworkBackend>,
+    topology: &Topology,
+    ip_net: Ipv4Net,
+    gateway: Option<Ipv4Addr>,
+    reserved: &[ReservedRange],
+) -> Result<NetworkConfig, NetworkError> {
+    network_setup_segment(
+        backend,
+        &topology.bridge(0),
+        &topology.tap_prefix(),
+        ip_net,
+        gateway,
+        reserved,
+    )
+}
+
+// Creates a bridge with its own address space. Tap devices are named `{tap_prefix}{id}`,
+// so the prefix has to be unique per segment.
+// The gateway (first host by default) and any reserved address or range within `ip_net` are
+// never handed out to a tap device.
 #[instrument(level = tracing::Level::DEBUG)]
-pub(crate) fn network_setup(ip_net: Ipv4Net) -> NetworkConfig {
-    return NetworkConfig {
-        bridges: Bridge::create_bridge("tbr0", ip_net).unwrap(),
-        ip_allocator: sync::Arc::new(sync::RwLock::new(IpAddressAllocator::new(
-            Ipv4AddrRange::new(
-                ip_net.hosts().skip(1).next().unwrap(),
-                ip_net.hosts().last().unwrap(),
-            ),
-        ))),
-    };
+pub(crate) fn network_setup_segment(
+    backend: Arc<dyn NetworkBackend>,
+    bridge_name: &str,
+    tap_prefix: &str,
+    ip_net: Ipv4Net,
+    gateway: Option<Ipv4Addr>,
+    reserved: &[ReservedRange],
+) -> Result<NetworkConfig, NetworkError> {
+    let gateway = gateway.unwrap_or_else(|| ip_net.hosts().next().unwrap());
+    let ip_allocator = IpAddressAllocator::with_reserved(ip_net, gateway, reserved)?;
+
+    Ok(NetworkConfig {
+        bridges: Arc::new(Bridge::create_bridge(backend, bridge_name, ip_net, gateway)?),
+        tap_prefix: tap_prefix.to_string(),
+        gateway,
+        ip_allocator: sync::Arc::new(sync::RwLock::new(ip_allocator)),
+        check_state: false,
+    })
+}
+
+// Joins a bridge which is managed outside of the launcher, e.g. virbr0. Its subnet is used for
+// the taps and its address is the gateway unless one is given. The bridge is left in place
+// when the network is dropped.
+#[instrument(level = tracing::Level::DEBUG)]
+pub(crate) fn network_adopt(
+    backend: Arc<dyn NetworkBackend>,
+    topology: &Topology,
+    bridge_name: &str,
+    gateway: Option<Ipv4Addr>,
+    reserved: &[ReservedRange],
+) -> Result<NetworkConfig, NetworkError> {
+    let address = backend.adopt_bridge(bridge_name)?;
+    let ip_net = address.trunc();
+    let gateway = gateway.unwrap_or(address.addr());
+    let mut ip_allocator = IpAddressAllocator::with_reserved(ip_net, gateway, reserved)?;
+    // the host keeps its address on the bridge, even with a different gateway
+    ip_allocator.reserve(&ReservedRange::from(address.addr()));
+
+    Ok(NetworkConfig {
+        bridges: Arc::new(Bridge {
+            name: bridge_name.to_string(),
+            backend,
+            ip_net,
+            adopted: true,
+        }),
+        tap_prefix: topology.tap_prefix(),
+        gateway,
+        ip_allocator: sync::Arc::new(sync::RwLock::new(ip_allocator)),
+        check_state: false,
+    })
+}
+
+// Segments share the host routing table, so their address spaces must be disjoint
+pub(crate) fn validate_segments(segments: &[(&str, Ipv4Net)]) -> Result<(), NetworkError> {
+    for ((a_name, a), (b_name, b)) in segments.iter().tuple_combinations() {
+        if a.contains(&b.network()) || b.contains(&a.network()) {
+            return Err(NetworkError::OverlappingSegments(
+                a_name.to_string(),
+                b_name.to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn overlapping_segments() {
+    let a = ("a", "10.1.0.0/24".parse().unwrap());
+    let b = ("b", "10.2.0.0/24".parse().unwrap());
+    let c = ("c", "10.1.0.128/25".parse().unwrap());
+    assert!(validate_segments(&[a, b]).is_ok());
+    assert!(validate_segments(&[a, b, c]).is_err());
 }
 
 #[tracing::instrument(level = tracing::Level::DEBUG)]
 pub(crate) async fn network_cleanup(nc: NetworkConfig) {}
 
 impl Bridge {
-    fn host_ip(&self) -> Ipv4Addr {
-        self.ip_addr.hosts().next().unwrap()
+    fn register_tap_device(&self, tap: &Tap) -> Result<(), NetworkError> {
+        self.backend.attach_tap(&self.name, &tap.name)
     }
-    fn register_tap_device(&self, tap: &Tap) {
-        self.bridge
-            .write()
-            .unwrap()
-            .add_tap(tap.tap.read().unwrap().deref())
-            .unwrap();
-    }
-
-    fn create_bridge(name: &str, ip_net: Ipv4Net) -> Result<Bridge, UserBridgeError> {
-        let bridge = Arc::new(RwLock::new(userbridge::Bridge::new(name, ip_net)?));
 
+    fn create_bridge(
+        backend: Arc<dyn NetworkBackend>,
+        name: &str,
+        ip_net: Ipv4Net,
+        gateway: Ipv4Addr,
+    ) -> Result<Bridge, NetworkError> {
+        let address = Ipv4Net::new(gateway, ip_net.prefix_len()).unwrap();
+        backend.create_bridge(name, address)?;
         Ok(Bridge {
-            bridge,
-            ip_addr: ip_net,
+            name: name.to_string(),
+            backend,
+            ip_net,
+            adopted: false,
         })
     }
 }
@@ -67,19 +252,27 @@ impl Bridge {
 struct Tap {
     pub(crate) ip_addr: Ipv4Addr,
     pub(crate) mac_addr: MacAddr,
-    tap: Arc<RwLock<usertap::Tap>>,
+    name: String,
 }
 
 impl Tap {
-    fn create(name: String, ip_addr: Ipv4Addr) -> Self {
-        Tap {
+    fn create(
+        backend: &dyn NetworkBackend,
+        name: String,
+        ip_addr: Ipv4Addr,
+        mac_addr: MacAddr,
+    ) -> Result<Self, NetworkError> {
+        backend.create_tap(&name)?;
+        Ok(Tap {
             ip_addr,
-            mac_addr: MacAddr::from([0x0, 0x60, 0x2f, random(), random(), random()]),
-            tap: Arc::new(RwLock::new(usertap::Tap::new(&name).unwrap())),
-        }
+            mac_addr,
+            name,
+        })
     }
+}
 
-    fn destroy(self) {}
+fn random_mac() -> MacAddr {
+    MacAddr::from([0x0, 0x60, 0x2f, random(), random(), random()])
 }
 
 #[tracing::instrument(level = tracing::Level::DEBUG, err(level = tracing::Level::INFO))]
@@ -118,10 +311,56 @@ async fn run_tunctl_command(command: &str, args: &Vec<&str>) -> Result<String, S
     .await
 }
 
+// Addresses which are never handed out, given as `10.0.0.5`, `10.0.0.100-10.0.0.150` or
+// `10.0.0.192/26`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReservedRange {
+    first: Ipv4Addr,
+    last: Ipv4Addr,
+}
+
+impl From<Ipv4Addr> for ReservedRange {
+    fn from(ip: Ipv4Addr) -> Self {
+        ReservedRange {
+            first: ip,
+            last: ip,
+        }
+    }
+}
+
+impl FromStr for ReservedRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |ip: &str| {
+            Ipv4Addr::from_str(ip.trim()).map_err(|e| format!("invalid address {ip}: {e}"))
+        };
+        if let Some((first, last)) = s.split_once('-') {
+            let (first, last) = (parse(first)?, parse(last)?);
+            if first > last {
+                return Err(format!("{first} is after {last}"));
+            }
+            Ok(ReservedRange { first, last })
+        } else if s.contains('/') {
+            let net = Ipv4Net::from_str(s).map_err(|e| format!("invalid network {s}: {e}"))?;
+            Ok(ReservedRange {
+                first: net.network(),
+                last: net.broadcast(),
+            })
+        } else {
+            parse(s).map(ReservedRange::from)
+        }
+    }
+}
+
 #[derive(Debug)]
 struct IpAddressAllocator {
     ip: Ipv4AddrRange,
+    // disjoint, inclusive (start, end) ranges of free ids, ordered by start
     free: BTreeSet<(usize, usize)>,
+    // inclusive (start, end) ranges of ids which are neither allocated nor freed
+    reserved: BTreeSet<(usize, usize)>,
+    allocated: BTreeSet<usize>,
 }
 
 impl IpAddressAllocator {
@@ -132,8 +371,62 @@ impl IpAddressAllocator {
     pub fn new(address_range: Ipv4AddrRange) -> Self {
         Self {
             ip: address_range,
-            free: BTreeSet::from([(Self::max(address_range) - 1, 0)]),
+            free: BTreeSet::from([(0, Self::max(address_range) - 1)]),
+            reserved: BTreeSet::new(),
+            allocated: BTreeSet::new(),
+        }
+    }
+    // Allocates from all hosts of `ip_net` except for the gateway and reserved addresses.
+    // Reserved addresses outside of `ip_net` are ignored.
+    fn with_reserved(
+        ip_net: Ipv4Net,
+        gateway: Ipv4Addr,
+        reserved: &[ReservedRange],
+    ) -> Result<Self, NetworkError> {
+        if !ip_net.contains(&gateway) {
+            return Err(NetworkError::AddressOutsideNetwork(gateway, ip_net));
+        }
+        let mut allocator = Self::new(Ipv4AddrRange::new(
+            ip_net.hosts().next().unwrap(),
+            ip_net.hosts().last().unwrap(),
+        ));
+        for range in std::iter::once(&ReservedRange::from(gateway)).chain(reserved) {
+            allocator.reserve(range);
         }
+        Ok(allocator)
+    }
+    // Removes the part of `range` within the allocator's addresses from the free set for good
+    fn reserve(&mut self, range: &ReservedRange) {
+        let first = <Ipv4AddrRange as Iterator>::min(self.ip).unwrap();
+        let last = <Ipv4AddrRange as Iterator>::max(self.ip).unwrap();
+        if range.last < first || range.first > last {
+            return;
+        }
+        let (first, last) = (
+            self.to_id(range.first.max(first)),
+            self.to_id(range.last.min(last)),
+        );
+        let overlapping = self
+            .free
+            .iter()
+            .filter(|&&(start, end)| start <= last && first <= end)
+            .cloned()
+            .collect::<Vec<_>>();
+        for (start, end) in overlapping {
+            self.free.remove(&(start, end));
+            if start < first {
+                self.free.insert((start, first - 1));
+            }
+            if last < end {
+                self.free.insert((last + 1, end));
+            }
+        }
+        self.reserved.insert((first, last));
+    }
+    fn is_reserved(&self, id: usize) -> bool {
+        self.reserved
+            .iter()
+            .any(|&(start, end)| (start..=end).contains(&id))
     }
     fn to_id(&self, ip_net: Ipv4Addr) -> usize {
         let host = <Ipv4AddrRange as Iterator>::min(self.ip).unwrap();
@@ -142,11 +435,49 @@ impl IpAddressAllocator {
     fn to_ip(&self, value: usize) -> Ipv4Addr {
         self.ip.clone().nth(value).expect("Could not assign ip")
     }
+    // Either allocates all `n` addresses or none of them
+    pub fn allocate_many(&mut self, n: usize) -> Option<Vec<Ipv4Addr>> {
+        let mut allocated = Vec::with_capacity(n);
+        for _ in 0..n {
+            match self.allocate() {
+                Some(ip) => allocated.push(ip),
+                None => {
+                    for ip in allocated {
+                        self.free(ip);
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(allocated)
+    }
+    // Allocates exactly `ip`, if it is free
+    pub fn allocate_ip(&mut self, ip: Ipv4Addr) -> Option<Ipv4Addr> {
+        if ip < <Ipv4AddrRange as Iterator>::min(self.ip).unwrap() {
+            return None;
+        }
+        let id = self.to_id(ip);
+        let (start, end) = self
+            .free
+            .iter()
+            .find(|&&(start, end)| (start..=end).contains(&id))
+            .cloned()?;
+        self.free.remove(&(start, end));
+        if start < id {
+            self.free.insert((start, id - 1));
+        }
+        if id < end {
+            self.free.insert((id + 1, end));
+        }
+        self.allocated.insert(id);
+        Some(ip)
+    }
     pub fn allocate(&mut self) -> Option<Ipv4Addr> {
-        if let Some((end, start)) = self.free.pop_first() {
+        if let Some((start, end)) = self.free.pop_first() {
             if start != end {
-                self.free.insert((end, start + 1));
+                self.free.insert((start + 1, end));
             }
+            self.allocated.insert(start);
             Some(self.to_ip(start))
         } else {
             None
@@ -158,11 +489,11 @@ impl IpAddressAllocator {
             .free
             .iter()
             .cloned()
-            .coalesce(|(a_end, a_start), (b_end, b_start)| {
-                if a_end + 1 == b_start {
-                    Ok((b_end, a_start))
+            .coalesce(|(a_start, a_end), (b_start, b_end)| {
+                if a_end + 1 >= b_start {
+                    Ok((a_start, a_end.max(b_end)))
                 } else {
-                    Err(((a_end, a_start), (b_end, b_start)))
+                    Err(((a_start, a_end), (b_start, b_end)))
                 }
             })
             .collect();
@@ -170,10 +501,25 @@ impl IpAddressAllocator {
     pub fn free(&mut self, ip_net: Ipv4Addr) {
         let host = <Ipv4AddrRange as Iterator>::min(self.ip).unwrap();
         let id = ip_net.saturating_sub(host) as usize;
-        assert!(id <= Self::max(self.ip));
+        assert!(id < Self::max(self.ip));
+        if self.is_reserved(id) {
+            warn!(%ip_net, "Reserved ip address can not be freed");
+            return;
+        }
+        if self.is_free(id) {
+            warn!(%ip_net, "Ip address was freed twice");
+            return;
+        }
+        self.allocated.remove(&id);
         self.free.insert((id, id));
         self.compact();
-        println!("After Compaction {:?}", self.free);
+        debug!(free = ?self.free, "After Compaction");
+    }
+    fn is_free(&self, id: usize) -> bool {
+        self.free
+            .range(..=(id, usize::MAX))
+            .next_back()
+            .is_some_and(|&(_, end)| id <= end)
     }
 }
 
@@ -211,10 +557,243 @@ fn ip_allocation() {
     assert_eq!(allocator.allocate(), Some("10.0.0.6".parse().unwrap()));
 }
 
+#[test]
+fn ip_allocation_many() {
+    let mut allocator = IpAddressAllocator::new(Ipv4AddrRange::new(
+        "10.0.0.2".parse().unwrap(),
+        "10.0.0.6".parse().unwrap(),
+    ));
+
+    assert_eq!(allocator.allocate(), Some("10.0.0.2".parse().unwrap()));
+    assert_eq!(allocator.allocate_many(5), None);
+    assert_eq!(
+        allocator.allocate_many(4),
+        Some(vec![
+            "10.0.0.3".parse().unwrap(),
+            "10.0.0.4".parse().unwrap(),
+            "10.0.0.5".parse().unwrap(),
+            "10.0.0.6".parse().unwrap(),
+        ])
+    );
+    assert_eq!(allocator.allocate(), None);
+}
+
+#[test]
+fn reserved_addresses_are_not_allocated() {
+    let reserved = ["10.0.0.4".parse().unwrap(), "10.1.0.1".parse().unwrap()];
+    let mut allocator = IpAddressAllocator::with_reserved(
+        "10.0.0.0/29".parse().unwrap(),
+        "10.0.0.1".parse().unwrap(),
+        &reserved,
+    )
+    .unwrap();
+
+    let mut allocated = vec![];
+    while let Some(ip) = allocator.allocate() {
+        allocated.push(ip.to_string());
+    }
+    assert_eq!(
+        allocated,
+        vec!["10.0.0.2", "10.0.0.3", "10.0.0.5", "10.0.0.6"]
+    );
+
+    assert!(IpAddressAllocator::with_reserved(
+        "10.0.0.0/29".parse().unwrap(),
+        "10.0.1.1".parse().unwrap(),
+        &[],
+    )
+    .is_err());
+}
+
+#[test]
+fn reserved_ranges_are_not_allocated() {
+    let reserved = ["10.0.0.3-10.0.0.5", "10.0.0.8/30", "10.0.0.254-10.0.1.20"]
+        .map(|r| r.parse::<ReservedRange>().unwrap());
+    let mut allocator = IpAddressAllocator::with_reserved(
+        "10.0.0.0/24".parse().unwrap(),
+        "10.0.0.1".parse().unwrap(),
+        &reserved,
+    )
+    .unwrap();
+
+    let mut allocated = vec![];
+    while let Some(ip) = allocator.allocate() {
+        allocated.push(ip.to_string());
+    }
+    // 254 hosts without the gateway, 3 + 4 reserved addresses and 10.0.0.254
+    assert_eq!(allocated.len(), 245);
+    assert_eq!(
+        allocated[..4],
+        ["10.0.0.2", "10.0.0.6", "10.0.0.7", "10.0.0.12"]
+    );
+    assert_eq!(allocated.last().unwrap(), "10.0.0.253");
+
+    // freeing a reserved address does not make it available
+    allocator.free("10.0.0.4".parse().unwrap());
+    allocator.free("10.0.0.1".parse().unwrap());
+    assert_eq!(allocator.allocate(), None);
+    allocator.free("10.0.0.6".parse().unwrap());
+    assert_eq!(allocator.allocate(), Some("10.0.0.6".parse().unwrap()));
+
+    assert!("10.0.0.5-10.0.0.3".parse::<ReservedRange>().is_err());
+    assert!("10.0.0.300".parse::<ReservedRange>().is_err());
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn ip_allocation_matches_model(ops in proptest::collection::vec(proptest::option::of(0usize..16), 0..200)) {
+        let mut allocator = IpAddressAllocator::new(Ipv4AddrRange::new(
+            "10.0.0.2".parse().unwrap(),
+            "10.0.0.17".parse().unwrap(),
+        ));
+        let mut allocated = BTreeSet::new();
+
+        // None allocates, Some(i) frees the i-th allocated address if there is one
+        for op in ops {
+            match op {
+                None => match allocator.allocate() {
+                    Some(ip) => proptest::prop_assert!(allocated.insert(allocator.to_id(ip))),
+                    None => proptest::prop_assert_eq!(allocated.len(), 16),
+                },
+                Some(i) => {
+                    if let Some(&id) = allocated.iter().nth(i % allocated.len().max(1)) {
+                        allocated.remove(&id);
+                        allocator.free(allocator.to_ip(id));
+                    }
+                }
+            }
+
+            let free_ids = allocator
+                .free
+                .iter()
+                .flat_map(|&(start, end)| start..=end)
+                .collect::<BTreeSet<_>>();
+            let expected = (0..16).filter(|id| !allocated.contains(id)).collect::<BTreeSet<_>>();
+            proptest::prop_assert_eq!(free_ids, expected);
+
+            // ranges are fully coalesced
+            for ((_, a_end), (b_start, _)) in allocator.free.iter().tuple_windows() {
+                proptest::prop_assert!(a_end + 1 < *b_start);
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum NetworkError {
+    #[error("Not enough free ip addresses to reserve {0} tap devices")]
+    OutOfIps(usize),
+    #[error("Could not create tap device {1}")]
+    Tap(#[source] UserTapError, String),
+    #[error("Could not attach tap device {1} to bridge")]
+    Bridge(#[source] UserBridgeError, String),
+    #[error("Could not create bridge {1}")]
+    CreateBridge(#[source] UserBridgeError, String),
+    #[error("Address {0} is not part of {1}")]
+    AddressOutsideNetwork(Ipv4Addr, Ipv4Net),
+    #[error("Address {0} is not free")]
+    AddressInUse(Ipv4Addr),
+    #[error("Network segments {0} and {1} overlap")]
+    OverlappingSegments(String, String),
+    #[error("Unknown network segment: {0}")]
+    UnknownSegment(String),
+    #[error("Unknown network device: {0}")]
+    UnknownDevice(String),
+    #[error("Could not use existing bridge {1}")]
+    ExistingBridge(#[source] UserBridgeError, String),
+    #[error("Inconsistent network state: {0}")]
+    InconsistentState(String),
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct NetworkConfig {
-    bridges: Bridge,
+    bridges: Arc<Bridge>,
+    tap_prefix: String,
+    gateway: Ipv4Addr,
     ip_allocator: std::sync::Arc<sync::RwLock<IpAddressAllocator>>,
+    // check the invariants after every allocation and release, see --debug-assert-state
+    check_state: bool,
+}
+
+// The complete allocation state of a network at one point in time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NetworkSnapshot {
+    bridge: String,
+    ip_net: Ipv4Net,
+    gateway: Ipv4Addr,
+    // inclusive ranges
+    free: Vec<(Ipv4Addr, Ipv4Addr)>,
+    reserved: Vec<(Ipv4Addr, Ipv4Addr)>,
+    // address to tap device
+    allocated: BTreeMap<Ipv4Addr, String>,
+}
+
+impl NetworkSnapshot {
+    // Every address is free, reserved or allocated to a tap, never more than one of them, and
+    // the gateway is never handed out
+    pub(crate) fn check(&self) -> Result<(), NetworkError> {
+        let within = |ranges: &[(Ipv4Addr, Ipv4Addr)], ip: &Ipv4Addr| {
+            ranges
+                .iter()
+                .any(|(first, last)| (first..=last).contains(&ip))
+        };
+        let inconsistent = |reason: String| Err(NetworkError::InconsistentState(reason));
+        for (first, last) in self.free.iter().chain(&self.reserved) {
+            if !self.ip_net.contains(first) || !self.ip_net.contains(last) || first > last {
+                return inconsistent(format!("{first}-{last} is not part of {}", self.ip_net));
+            }
+        }
+        for ((a_first, a_last), (b_first, b_last)) in self.free.iter().tuple_windows() {
+            if a_last >= b_first {
+                return inconsistent(format!(
+                    "free ranges {a_first}-{a_last} and {b_first}-{b_last} overlap"
+                ));
+            }
+        }
+        let free_and_reserved = self.free.iter().cartesian_product(&self.reserved);
+        for ((f_first, f_last), (r_first, r_last)) in free_and_reserved {
+            if f_first <= r_last && r_first <= f_last {
+                return inconsistent(format!(
+                    "{f_first}-{f_last} and {r_first}-{r_last} are both free and reserved"
+                ));
+            }
+        }
+        for ip in self.allocated.keys() {
+            if !self.ip_net.contains(ip) {
+                return inconsistent(format!("{ip} is not part of {}", self.ip_net));
+            }
+            if within(&self.free, ip) {
+                return inconsistent(format!("{ip} is both free and allocated"));
+            }
+            if within(&self.reserved, ip) {
+                return inconsistent(format!("{ip} is both reserved and allocated"));
+            }
+        }
+        let first_host = self.ip_net.hosts().next();
+        if first_host.is_some_and(|first| first <= self.gateway)
+            && !within(&self.reserved, &self.gateway)
+        {
+            return inconsistent(format!("gateway {} is not reserved", self.gateway));
+        }
+        Ok(())
+    }
+
+    // Taps which were added and removed since `self`
+    pub(crate) fn changes(&self, later: &NetworkSnapshot) -> Vec<String> {
+        let removed = self
+            .allocated
+            .iter()
+            .filter(|(ip, _)| !later.allocated.contains_key(ip))
+            .map(|(ip, tap)| format!("-{tap} ({ip})"));
+        let added = later
+            .allocated
+            .iter()
+            .filter(|(ip, _)| !self.allocated.contains_key(ip))
+            .map(|(ip, tap)| format!("+{tap} ({ip})"));
+        removed.chain(added).collect()
+    }
 }
 
 #[derive(Debug)]
@@ -225,7 +804,7 @@ pub(crate) struct TapUser {
 
 impl TapUser {
     pub fn device(&self) -> String {
-        self.tap.as_ref().unwrap().tap.read().unwrap().deref().name.to_string()
+        self.tap.as_ref().unwrap().name.clone()
     }
     pub fn mac(&self) -> &MacAddr {
         &self.tap.as_ref().unwrap().mac_addr
@@ -245,24 +824,308 @@ impl Drop for TapUser {
 
 impl NetworkConfig {
     pub(crate) fn host_ip(&self) -> Ipv4Addr {
-        self.bridges.ip_addr.hosts().next().unwrap()
+        self.gateway
     }
-    pub fn get_tap(&self) -> TapUser {
-        let ip = self
+    pub(crate) fn ip_net(&self) -> Ipv4Net {
+        self.bridges.ip_net
+    }
+    // Applies to all clones made afterwards
+    pub(crate) fn with_state_checks(mut self, check_state: bool) -> Self {
+        self.check_state = check_state;
+        self
+    }
+    pub(crate) fn snapshot(&self) -> NetworkSnapshot {
+        let allocator = self.ip_allocator.read().unwrap();
+        let ips = |ranges: &BTreeSet<(usize, usize)>| {
+            ranges
+                .iter()
+                .map(|&(start, end)| (allocator.to_ip(start), allocator.to_ip(end)))
+                .collect()
+        };
+        NetworkSnapshot {
+            bridge: self.bridges.name.clone(),
+            ip_net: self.bridges.ip_net,
+            gateway: self.gateway,
+            free: ips(&allocator.free),
+            reserved: ips(&allocator.reserved),
+            allocated: allocator
+                .allocated
+                .iter()
+                .map(|&id| (allocator.to_ip(id), format!("{}{id}", self.tap_prefix)))
+                .collect(),
+        }
+    }
+    // Panics if a change leaves the network inconsistent, only with state checks enabled
+    fn checked<T>(&self, change: impl FnOnce() -> T) -> T {
+        if !self.check_state {
+            return change();
+        }
+        let before = self.snapshot();
+        let result = change();
+        let after = self.snapshot();
+        debug!(changes = ?before.changes(&after), "Network state changed");
+        if let Err(e) = after.check() {
+            panic!("{e}: {after:?}");
+        }
+        result
+    }
+    pub fn get_tap(&self) -> Result<TapUser, NetworkError> {
+        self.checked(|| {
+            let ip = self
+                .ip_allocator
+                .write()
+                .unwrap()
+                .allocate()
+                .ok_or(NetworkError::OutOfIps(1))?;
+            self.create_tap_user(ip, random_mac()).inspect_err(|_| {
+                self.ip_allocator.write().unwrap().free(ip);
+            })
+        })
+    }
+    // The address and mac of a vm restored from a capture, the guest still uses them
+    pub fn claim_tap(&self, ip: Ipv4Addr, mac: MacAddr) -> Result<TapUser, NetworkError> {
+        self.checked(|| {
+            self.ip_allocator
+                .write()
+                .unwrap()
+                .allocate_ip(ip)
+                .ok_or(NetworkError::AddressInUse(ip))?;
+            self.create_tap_user(ip, mac).inspect_err(|_| {
+                self.ip_allocator.write().unwrap().free(ip);
+            })
+        })
+    }
+    // Reserves `n` tap devices, releasing all of them if any one cannot be created.
+    pub fn reserve(&self, n: usize) -> Result<Vec<TapUser>, NetworkError> {
+        self.checked(|| self.reserve_unchecked(n))
+    }
+    fn reserve_unchecked(&self, n: usize) -> Result<Vec<TapUser>, NetworkError> {
+        let ips = self
             .ip_allocator
             .write()
             .unwrap()
-            .allocate()
-            .expect("Out of ips");
+            .allocate_many(n)
+            .ok_or(NetworkError::OutOfIps(n))?;
+
+        let mut taps = Vec::with_capacity(n);
+        let mut ips = ips.into_iter();
+        while let Some(ip) = ips.next() {
+            match self.create_tap_user(ip, random_mac()) {
+                Ok(tap) => taps.push(tap),
+                Err(e) => {
+                    // taps which were already created release their ip when dropped
+                    let mut allocator = self.ip_allocator.write().unwrap();
+                    for ip in std::iter::once(ip).chain(ips) {
+                        allocator.free(ip);
+                    }
+                    drop(allocator);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(taps)
+    }
+    fn create_tap_user(&self, ip: Ipv4Addr, mac: MacAddr) -> Result<TapUser, NetworkError> {
         let id = self.ip_allocator.read().unwrap().to_id(ip);
-        let tap = Tap::create(format!("tap{id}"), ip);
-        self.bridges.register_tap_device(&tap);
-        TapUser {
+        let name = format!("{}{id}", self.tap_prefix);
+        let tap = Tap::create(self.bridges.backend.as_ref(), name, ip, mac)?;
+        if let Err(e) = self.bridges.register_tap_device(&tap) {
+            self.bridges.backend.delete_tap(&tap.name);
+            return Err(e);
+        }
+        Ok(TapUser {
             config: self.clone(),
             tap: Some(tap),
-        }
+        })
     }
     async fn release_tap(&self, tap: Tap) {
-        self.ip_allocator.write().unwrap().free(tap.ip_addr);
+        self.checked(|| {
+            self.bridges.backend.delete_tap(&tap.name);
+            self.ip_allocator.write().unwrap().free(tap.ip_addr);
+        })
+    }
+}
+
+#[test]
+fn tap_lifecycle() {
+    use mock::{MockNetworkBackend, NetworkOperation::*};
+
+    let backend = Arc::new(MockNetworkBackend::default());
+    let nc = network_setup_segment(
+        backend.clone(),
+        "tbr9",
+        "tap9_",
+        "10.0.0.0/29".parse().unwrap(),
+        None,
+        &[],
+    )
+    .unwrap();
+    let taps = nc.reserve(2).unwrap();
+    assert_eq!(taps[0].device(), "tap9_1");
+    assert_eq!(taps[0].ip(), &"10.0.0.2".parse::<Ipv4Addr>().unwrap());
+    assert!(nc.reserve(5).is_err());
+    drop(taps);
+    // released addresses, and therefore tap names, are reused
+    assert_eq!(nc.get_tap().unwrap().device(), "tap9_1");
+    drop(nc);
+
+    let bridge = || "tbr9".to_string();
+    assert_eq!(
+        backend.operations(),
+        vec![
+            BridgeCreated(bridge(), "10.0.0.1/29".parse().unwrap()),
+            TapCreated("tap9_1".to_string()),
+            TapAttached(bridge(), "tap9_1".to_string()),
+            TapCreated("tap9_2".to_string()),
+            TapAttached(bridge(), "tap9_2".to_string()),
+            TapDeleted("tap9_1".to_string()),
+            TapDeleted("tap9_2".to_string()),
+            TapCreated("tap9_1".to_string()),
+            TapAttached(bridge(), "tap9_1".to_string()),
+            TapDeleted("tap9_1".to_string()),
+            BridgeDeleted(bridge()),
+        ]
+    );
+}
+
+#[test]
+fn claimed_tap() {
+    use mock::MockNetworkBackend;
+
+    let nc = network_setup_segment(
+        Arc::new(MockNetworkBackend::default()),
+        "tbr9",
+        "tap9_",
+        "10.0.0.0/29".parse().unwrap(),
+        None,
+        &[],
+    )
+    .unwrap();
+    let mac = MacAddr::from([0x0, 0x60, 0x2f, 1, 2, 3]);
+    let ip = "10.0.0.3".parse::<Ipv4Addr>().unwrap();
+    let tap = nc.claim_tap(ip, mac).unwrap();
+    assert_eq!(tap.device(), "tap9_2");
+    assert_eq!(tap.mac(), &mac);
+    assert!(matches!(
+        nc.claim_tap(ip, mac),
+        Err(NetworkError::AddressInUse(_))
+    ));
+    assert!(nc.claim_tap("10.0.0.1".parse().unwrap(), mac).is_err());
+    // the addresses around it are still handed out
+    assert_eq!(nc.get_tap().unwrap().ip(), &"10.0.0.2".parse::<Ipv4Addr>().unwrap());
+    drop(tap);
+    assert!(nc.claim_tap(ip, mac).is_ok());
+}
+
+#[test]
+fn adopted_bridge() {
+    use mock::{MockNetworkBackend, NetworkOperation::*};
+
+    let backend = Arc::new(MockNetworkBackend::with_bridge(
+        "virbr0",
+        "192.168.122.1/29".parse().unwrap(),
+    ));
+    let topology = Topology::default();
+    assert!(network_adopt(backend.clone(), &topology, "virbr1", None, &[]).is_err());
+
+    let nc = network_adopt(backend.clone(), &topology, "virbr0", None, &[]).unwrap();
+    assert_eq!(nc.ip_net(), "192.168.122.0/29".parse::<Ipv4Net>().unwrap());
+    assert_eq!(nc.host_ip(), "192.168.122.1".parse::<Ipv4Addr>().unwrap());
+    let tap = nc.get_tap().unwrap();
+    assert_eq!(tap.ip(), &"192.168.122.2".parse::<Ipv4Addr>().unwrap());
+    drop(tap);
+    drop(nc);
+
+    // taps are attached and removed, the bridge stays
+    assert_eq!(
+        backend.operations(),
+        vec![
+            BridgeAdopted("virbr0".to_string()),
+            TapCreated("tap1".to_string()),
+            TapAttached("virbr0".to_string(), "tap1".to_string()),
+            TapDeleted("tap1".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn network_snapshots() {
+    use mock::MockNetworkBackend;
+
+    let nc = network_setup_segment(
+        Arc::new(MockNetworkBackend::default()),
+        "tbr9",
+        "tap9_",
+        "10.0.0.0/29".parse().unwrap(),
+        None,
+        &[ReservedRange::from("10.0.0.6".parse::<Ipv4Addr>().unwrap())],
+    )
+    .unwrap()
+    .with_state_checks(true);
+    let empty = nc.snapshot();
+    empty.check().unwrap();
+    let tap = nc.get_tap().unwrap();
+    let snapshot = nc.snapshot();
+    assert_eq!(
+        serde_json::to_value(&snapshot).unwrap(),
+        serde_json::json!({
+            "bridge": "tbr9",
+            "ipNet": "10.0.0.0/29",
+            "gateway": "10.0.0.1",
+            "free": [["10.0.0.3", "10.0.0.5"]],
+            "reserved": [["10.0.0.1", "10.0.0.1"], ["10.0.0.6", "10.0.0.6"]],
+            "allocated": {"10.0.0.2": "tap9_1"},
+        })
+    );
+    assert_eq!(empty.changes(&snapshot), vec!["+tap9_1 (10.0.0.2)"]);
+    drop(tap);
+    assert_eq!(nc.snapshot(), empty);
+
+    let mut broken = snapshot.clone();
+    broken.free = vec![("10.0.0.2".parse().unwrap(), "10.0.0.5".parse().unwrap())];
+    assert!(broken.check().is_err());
+    let mut broken = snapshot;
+    broken.reserved.remove(0);
+    assert!(broken.check().is_err());
+    // a reserved address in the middle of a free range
+    let mut broken = empty;
+    broken.reserved.push(("10.0.0.4".parse().unwrap(), "10.0.0.4".parse().unwrap()));
+    assert!(broken.check().is_err());
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn network_state_stays_consistent(ops in proptest::collection::vec(proptest::option::of(0usize..8), 0..100)) {
+        let nc = network_setup_segment(
+            Arc::new(mock::MockNetworkBackend::default()),
+            "tbr9",
+            "tap9_",
+            "10.0.0.0/28".parse().unwrap(),
+            None,
+            &[ReservedRange::from("10.0.0.9".parse::<Ipv4Addr>().unwrap())],
+        )
+        .unwrap()
+        .with_state_checks(true);
+        let mut taps = vec![];
+
+        // None takes a tap, Some(i) releases the i-th tap if there is one
+        for op in ops {
+            match op {
+                None => match nc.get_tap() {
+                    Ok(tap) => taps.push(tap),
+                    Err(_) => proptest::prop_assert_eq!(taps.len(), 12),
+                },
+                Some(i) if !taps.is_empty() => drop(taps.remove(i % taps.len())),
+                Some(_) => {}
+            }
+            let snapshot = nc.snapshot();
+            proptest::prop_assert!(snapshot.check().is_ok());
+            let held = taps
+                .iter()
+                .map(|tap| (*tap.ip(), tap.device()))
+                .collect::<BTreeMap<_, _>>();
+            proptest::prop_assert_eq!(snapshot.allocated, held);
+        }
     }
 }
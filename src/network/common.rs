@@ -21,6 +21,8 @@ ioctl_readwrite_bad!(get_if_index, 0x8933, c_int);
 ioctl_write_ptr_bad!(set_if_addr, 0x8916, c_int);
 ioctl_write_ptr_bad!(set_if_flags, 0x8914, c_int);
 ioctl_readwrite_bad!(get_if_flags, 0x8913, c_int);
+ioctl_readwrite_bad!(get_if_addr, 0x8915, c_int);
+ioctl_readwrite_bad!(get_if_netmask, 0x891b, c_int);
 #[derive(Error, Debug)]
 pub enum CommonError {
     #[error("Name is to long")]
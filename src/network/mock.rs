@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use ipnet::Ipv4Net;
+use tracing::debug;
+
+use crate::network::{NetworkBackend, NetworkError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NetworkOperation {
+    BridgeCreated(String, Ipv4Net),
+    BridgeAdopted(String),
+    TapCreated(String),
+    TapAttached(String, String),
+    TapDeleted(String),
+    BridgeDeleted(String),
+}
+
+// Does not touch any device, only records what would have happened
+#[derive(Debug, Default)]
+pub(crate) struct MockNetworkBackend {
+    operations: Mutex<Vec<NetworkOperation>>,
+    // bridges which already exist on the pretend host, with their address
+    existing_bridges: HashMap<String, Ipv4Net>,
+}
+
+impl MockNetworkBackend {
+    #[cfg(test)]
+    pub(crate) fn with_bridge(name: &str, address: Ipv4Net) -> Self {
+        MockNetworkBackend {
+            existing_bridges: HashMap::from([(name.to_string(), address)]),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn operations(&self) -> Vec<NetworkOperation> {
+        self.operations.lock().unwrap().clone()
+    }
+
+    fn record(&self, operation: NetworkOperation) {
+        debug!(?operation, "Mock network operation");
+        self.operations.lock().unwrap().push(operation);
+    }
+}
+
+impl NetworkBackend for MockNetworkBackend {
+    fn create_bridge(&self, name: &str, address: Ipv4Net) -> Result<(), NetworkError> {
+        self.record(NetworkOperation::BridgeCreated(name.to_string(), address));
+        Ok(())
+    }
+
+    fn adopt_bridge(&self, name: &str) -> Result<Ipv4Net, NetworkError> {
+        let address = *self
+            .existing_bridges
+            .get(name)
+            .ok_or_else(|| NetworkError::UnknownDevice(name.to_string()))?;
+        self.record(NetworkOperation::BridgeAdopted(name.to_string()));
+        Ok(address)
+    }
+
+    fn create_tap(&self, name: &str) -> Result<(), NetworkError> {
+        self.record(NetworkOperation::TapCreated(name.to_string()));
+        Ok(())
+    }
+
+    fn attach_tap(&self, bridge: &str, tap: &str) -> Result<(), NetworkError> {
+        self.record(NetworkOperation::TapAttached(
+            bridge.to_string(),
+            tap.to_string(),
+        ));
+        Ok(())
+    }
+
+    fn delete_tap(&self, name: &str) {
+        self.record(NetworkOperation::TapDeleted(name.to_string()));
+    }
+
+    fn delete_bridge(&self, name: &str) {
+        self.record(NetworkOperation::BridgeDeleted(name.to_string()));
+    }
+}
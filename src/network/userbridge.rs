@@ -24,7 +24,8 @@ use tracing::error;
 use users::get_current_uid;
 
 use crate::network::common::{
-    add_br, add_if, create_ifreq, del_br, get_if_flags, set_if_addr, set_if_flags, CommonError,
+    add_br, add_if, create_ifreq, del_br, get_if_addr, get_if_flags, get_if_netmask, set_if_addr,
+    set_if_flags, CommonError,
 };
 use crate::network::userbridge::UserBridgeError::{CouldNotAttachTap, CouldNotCreateBridge};
 use crate::network::usertap::{Tap, UserTapError};
@@ -32,20 +33,16 @@ use crate::network::usertap::{Tap, UserTapError};
 #[derive(Debug)]
 pub(crate) struct Bridge {
     name: String,
+    // bridges managed by someone else are left in place
+    owned: bool,
 }
 
 impl Drop for Bridge {
     fn drop(&mut self) {
-        if let Err(e) = self
-            .is_up()
-            .and_then(|up| {
-                if up {
-                    return self.down();
-                }
-                Ok(())
-            })
-            .and_then(|_| self.delete())
-        {
+        if !self.owned {
+            return;
+        }
+        if let Err(e) = self.down_and_delete() {
             error!("Could not delete bridge {}: {}", self.name, e);
         }
     }
@@ -79,6 +76,8 @@ pub(crate) enum UserBridgeError {
     Socket(nix::Error, &'static str),
     #[error("Ioctl error when {1}: {0}")]
     Ioctl(nix::Error, &'static str),
+    #[error("Bridge has an invalid netmask {0}")]
+    InvalidNetmask(Ipv4Addr),
 }
 #[repr(C)]
 #[derive(bytemuck::NoUninit, Clone, Copy)]
@@ -103,7 +102,7 @@ impl Bridge {
     }
 
     pub fn delete(&mut self) -> Result<()> {
-        let cstring = CString::new(self.name.clone()).unwrap();
+        let cstring = CString::new(self.name.clone()).map_err(UserBridgeError::FFINullError)?;
         let bridge_fd = nix::sys::socket::socket(
             AddressFamily::Unix,
             SockType::Stream,
@@ -182,7 +181,8 @@ impl Bridge {
 
         Ok(())
     }
-    pub fn new(name: &str, ipv4addr: Ipv4Net) -> Result<Self> {
+    // `address` is the host's address on the bridge, e.g. 10.0.0.1/24
+    pub fn new(name: &str, address: Ipv4Net) -> Result<Self> {
         Self::check_caps()?;
         let bridge_fd = nix::sys::socket::socket(
             AddressFamily::Unix,
@@ -198,15 +198,70 @@ impl Bridge {
         unsafe { add_br(bridge_fd.as_raw_fd(), cstring.as_ptr() as *const c_long) }
             .map_err(|e| UserBridgeError::CouldNotCreateBridge(e, "AddBridge IOCTL"))?;
 
-        let bridge = Bridge { name };
+        let bridge = Bridge { name, owned: true };
 
-        bridge.set_ip(ipv4addr.network())?;
+        bridge.set_ip(address.addr())?;
 
         bridge.set_flags((IFF_UP | IFF_BROADCAST | IFF_RUNNING | IFF_MULTICAST) as c_short)?;
 
         Ok(bridge)
     }
 
+    // Deletes an existing bridge, e.g. one left behind by a crash
+    pub(crate) fn remove(name: &str) -> Result<()> {
+        let mut bridge = Bridge {
+            name: name.to_string(),
+            owned: false,
+        };
+        bridge.down_and_delete()
+    }
+
+    fn down_and_delete(&mut self) -> Result<()> {
+        if self.is_up()? {
+            self.down()?;
+        }
+        self.delete()
+    }
+
+    // Uses a bridge which is managed outside of the launcher, it is not deleted on drop
+    pub(crate) fn existing(name: &str) -> Result<Self> {
+        let bridge = Bridge {
+            name: name.to_string(),
+            owned: false,
+        };
+        // fails if there is no such device
+        bridge.get_flags()?;
+        Ok(bridge)
+    }
+
+    fn get_ipv4(
+        &self,
+        ioctl: unsafe fn(c_int, *mut c_int) -> nix::Result<c_int>,
+        context: &'static str,
+    ) -> Result<Ipv4Addr> {
+        let fd = nix::sys::socket::socket(
+            AddressFamily::Inet,
+            SockType::Datagram,
+            SockFlag::empty(),
+            None,
+        )
+        .map_err(|e| UserBridgeError::Socket(e, "Opening Unix Socket"))?;
+
+        let mut req = create_ifreq(&self.name)?;
+        unsafe { ioctl(fd.as_raw_fd(), &mut req as *mut ifreq as *mut c_int) }
+            .map_err(|e| UserBridgeError::Ioctl(e, context))?;
+
+        let sai: SockaddrIn = unsafe { mem::transmute(req.ifr_ifru.ifru_addr) };
+        Ok(Ipv4Addr::from(sai.sin_addr.s_addr.to_ne_bytes()))
+    }
+
+    // The bridge's own address together with the prefix of its subnet
+    pub(crate) fn address(&self) -> Result<Ipv4Net> {
+        let addr = self.get_ipv4(get_if_addr, "Get IF ADDR Ioctl")?;
+        let netmask = self.get_ipv4(get_if_netmask, "Get IF NETMASK Ioctl")?;
+        Ipv4Net::with_netmask(addr, netmask).map_err(|_| UserBridgeError::InvalidNetmask(netmask))
+    }
+
     pub fn add_tap(&self, tap: &Tap) -> Result<()> {
         let index = tap
             .get_index()
@@ -1,7 +1,7 @@
 use std::ffi::{CStr, FromBytesUntilNulError};
 use std::os::fd::{AsRawFd, OwnedFd};
 
-use libc::{c_int, c_short, ifreq, IFF_TAP};
+use libc::{c_int, c_short, ifreq, IFF_MULTI_QUEUE, IFF_TAP};
 use nix::sys::ioctl::ioctl_param_type;
 use nix::sys::socket::{AddressFamily, SockFlag, SockType};
 use thiserror::Error;
@@ -15,16 +15,14 @@ use crate::network::common::{
 #[derive(Debug)]
 pub(crate) struct Tap {
     pub(crate) name: String,
+    // qemu has to open every queue of the tap with IFF_MULTI_QUEUE as well
+    multi_queue: bool,
 }
 
 impl Drop for Tap {
     fn drop(&mut self) {
         info!("Dropping Tap: {}", self.name);
-        if let Err(e) = Self::get_tun_device(&self.name).and_then(|f| {
-            unsafe { tun_set_persist(f.as_raw_fd(), 0) }
-                .map_err(|e| UserTapError::CouldNotGetIndex(e, "Unpersisting"))?;
-            Ok(())
-        }) {
+        if let Err(e) = Self::unpersist(&self.name, self.multi_queue) {
             error!("Could not close tap device: {e:?}");
         }
     }
@@ -55,21 +53,38 @@ pub(crate) enum UserTapError {
 type Result<T> = core::result::Result<T, UserTapError>;
 
 impl Tap {
-    pub fn new(name: &str) -> Result<Self> {
+    pub fn new(name: &str, multi_queue: bool) -> Result<Self> {
         Self::check_caps()?;
-        let device = OwnedFd::from(Self::get_tun_device(name)?);
+        let device = OwnedFd::from(Self::get_tun_device(name, multi_queue)?);
         println!("fd: {}", device.as_raw_fd());
 
         let current_user = get_current_uid();
-        unsafe { tun_set_owner(device.as_raw_fd(), current_user as ioctl_param_type) }.unwrap();
+        unsafe { tun_set_owner(device.as_raw_fd(), current_user as ioctl_param_type) }
+            .map_err(|e| UserTapError::CouldNotGetIndex(e, "Setting owner"))?;
         unsafe { tun_set_persist(device.as_raw_fd(), 1) }
             .map_err(|e| UserTapError::CouldNotGetIndex(e, "Persisting"))?;
 
         Ok(Self {
             name: name.to_string(),
+            multi_queue,
         })
     }
 
+    // Removes an existing persistent tap, e.g. one left behind by a crash
+    pub(crate) fn remove(name: &str) -> Result<()> {
+        Self::unpersist(name, false)
+    }
+
+    fn unpersist(name: &str, multi_queue: bool) -> Result<()> {
+        // a tap left behind may have been created with either flag, the kernel only attaches to
+        // it with the matching one
+        let device = Self::get_tun_device(name, multi_queue)
+            .or_else(|_| Self::get_tun_device(name, !multi_queue))?;
+        unsafe { tun_set_persist(device.as_raw_fd(), 0) }
+            .map_err(|e| UserTapError::CouldNotGetIndex(e, "Unpersisting"))?;
+        Ok(())
+    }
+
     pub(crate) fn get_index(&self) -> Result<c_int> {
         let mut req = create_ifreq(&self.name)?;
         let fd = nix::sys::socket::socket(
@@ -87,7 +102,7 @@ impl Tap {
         Ok(index)
     }
 
-    fn get_tun_device(name: &str) -> Result<std::fs::File> {
+    fn get_tun_device(name: &str, multi_queue: bool) -> Result<std::fs::File> {
         let device_path = std::path::PathBuf::from("/dev/net/tun");
 
         if !device_path.exists() {
@@ -109,7 +124,10 @@ impl Tap {
         }
 
         let mut req = create_ifreq(name)?;
-        req.ifr_ifru.ifru_flags = IFF_TAP as c_short;
+        req.ifr_ifru.ifru_flags = match multi_queue {
+            true => (IFF_TAP | IFF_MULTI_QUEUE) as c_short,
+            false => IFF_TAP as c_short,
+        };
 
         let _ = unsafe { tun_set_iff(device.as_raw_fd(), &req as *const ifreq as *const c_int) }
             .map_err(UserTapError::CouldNotCreateTap)?;
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+use tracing::{error, warn};
+
+const KERNEL_LOG: &str = "/dev/kmsg";
+
+#[derive(Error, Debug)]
+pub(crate) enum OomError {
+    #[error("Could not open kernel log {1}")]
+    IO(#[source] std::io::Error, &'static str),
+}
+
+// Tails the host kernel log and remembers which pids were killed by the OOM killer
+#[derive(Debug, Clone)]
+pub(crate) struct OomWatcher {
+    kills: Arc<Mutex<HashMap<usize, String>>>,
+}
+
+impl OomWatcher {
+    pub(crate) fn start() -> Result<Self, OomError> {
+        let mut kmsg = File::open(KERNEL_LOG).map_err(|e| OomError::IO(e, KERNEL_LOG))?;
+        // only kills that happen from now on are of interest, older pids may have been reused
+        kmsg.seek(SeekFrom::End(0))
+            .map_err(|e| OomError::IO(e, KERNEL_LOG))?;
+
+        let kills = Arc::new(Mutex::new(HashMap::new()));
+        let watcher = OomWatcher {
+            kills: kills.clone(),
+        };
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(kmsg);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match reader.read_line(&mut line) {
+                    Ok(0) => return,
+                    Ok(_) => {
+                        if let Some(pid) = parse_oom_kill(&line) {
+                            warn!(pid, "OOM killer killed process");
+                            kills
+                                .lock()
+                                .unwrap()
+                                .insert(pid, line.trim_end().to_string());
+                        }
+                    }
+                    // the ring buffer overwrote records we did not read yet
+                    Err(e) if e.kind() == ErrorKind::BrokenPipe => continue,
+                    Err(e) => {
+                        error!(?e, "Stopped watching kernel log for OOM kills");
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(watcher)
+    }
+
+    pub(crate) fn killed_by_oom(&self, pid: usize) -> Option<String> {
+        self.kills.lock().unwrap().get(&pid).cloned()
+    }
+}
+
+// Matches "Out of memory: Killed process 1234 (qemu-system-x86) ..." and the memory
+// cgroup variant of the same message
+fn parse_oom_kill(line: &str) -> Option<usize> {
+    let (_, rest) = line.split_once("Killed process ")?;
+    rest.split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[test]
+fn parses_oom_kill_messages() {
+    assert_eq!(
+        parse_oom_kill(
+            "3,1234,5678,-;Out of memory: Killed process 4242 (qemu-system-x86) total-vm:1kB"
+        ),
+        Some(4242)
+    );
+    assert_eq!(
+        parse_oom_kill("3,1,2,-;Memory cgroup out of memory: Killed process 17 (qemu-system-x86)"),
+        Some(17)
+    );
+    assert_eq!(
+        parse_oom_kill("6,1,2,-;tap3: entered promiscuous mode"),
+        None
+    );
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cgroup::CpuLimit;
+use crate::nes::{WorkerQueryProcessingConfiguration, WorkerQueryProcessingConfigurationBuilder};
+
+#[derive(Error, Debug)]
+pub(crate) enum ProfileError {
+    #[error("Unknown resource profile: {0}")]
+    UnknownProfile(String),
+}
+
+// Every field is optional, so profiles and explicit worker settings can be layered
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ResourceProfile {
+    #[serde(alias = "memoryMb")]
+    pub(crate) memory_in_megabytes: Option<usize>,
+    // vcpus of the vm, one per worker thread unless set
+    pub(crate) vcpus: Option<usize>,
+    pub(crate) number_of_worker_threads: Option<usize>,
+    pub(crate) buffer_size: Option<usize>,
+    pub(crate) total_number_of_buffers: Option<usize>,
+    pub(crate) number_of_source_buffers: Option<usize>,
+    pub(crate) number_of_buffers_per_thread: Option<usize>,
+    // percent of a single core the whole vm may use
+    pub(crate) cpu_max_percent: Option<usize>,
+    pub(crate) cpu_weight: Option<usize>,
+}
+
+impl ResourceProfile {
+    // Used for anything neither the profile nor the worker specifies
+    fn worker_defaults() -> Self {
+        ResourceProfile {
+            memory_in_megabytes: Some(512 * 1024),
+            vcpus: None,
+            number_of_worker_threads: Some(8),
+            buffer_size: Some(8192),
+            total_number_of_buffers: Some(2000000),
+            number_of_source_buffers: Some(32),
+            number_of_buffers_per_thread: Some(128),
+            cpu_max_percent: None,
+            cpu_weight: None,
+        }
+    }
+
+    // Fields set in `overrides` take precedence
+    pub(crate) fn merge(&self, overrides: &ResourceProfile) -> ResourceProfile {
+        ResourceProfile {
+            memory_in_megabytes: overrides.memory_in_megabytes.or(self.memory_in_megabytes),
+            vcpus: overrides.vcpus.or(self.vcpus),
+            number_of_worker_threads: overrides
+                .number_of_worker_threads
+                .or(self.number_of_worker_threads),
+            buffer_size: overrides.buffer_size.or(self.buffer_size),
+            total_number_of_buffers: overrides
+                .total_number_of_buffers
+                .or(self.total_number_of_buffers),
+            number_of_source_buffers: overrides
+                .number_of_source_buffers
+                .or(self.number_of_source_buffers),
+            number_of_buffers_per_thread: overrides
+                .number_of_buffers_per_thread
+                .or(self.number_of_buffers_per_thread),
+            cpu_max_percent: overrides.cpu_max_percent.or(self.cpu_max_percent),
+            cpu_weight: overrides.cpu_weight.or(self.cpu_weight),
+        }
+    }
+
+    // Grows the buffer pools with the number of sources, so they don't stall waiting for
+    // buffers. Values never shrink and fields set in `explicit` are kept as they are:
+    //   sourceBuffers = max(sourceBuffers, 32 + 4 * sources)
+    //   totalBuffers = max(totalBuffers, 2 * (sources * sourceBuffers + threads * buffersPerThread))
+    pub(crate) fn scaled_for_sources(
+        &self,
+        sources: usize,
+        explicit: &ResourceProfile,
+    ) -> ResourceProfile {
+        let defaults = Self::worker_defaults();
+        let source_buffers = self
+            .number_of_source_buffers
+            .or(defaults.number_of_source_buffers)
+            .unwrap();
+        let source_buffers = explicit
+            .number_of_source_buffers
+            .unwrap_or(source_buffers.max(32 + 4 * sources));
+
+        let threads = self
+            .number_of_worker_threads
+            .or(defaults.number_of_worker_threads)
+            .unwrap();
+        let per_thread = self
+            .number_of_buffers_per_thread
+            .or(defaults.number_of_buffers_per_thread)
+            .unwrap();
+        let total = self
+            .total_number_of_buffers
+            .or(defaults.total_number_of_buffers)
+            .unwrap();
+        let total = explicit
+            .total_number_of_buffers
+            .unwrap_or(total.max(2 * (sources * source_buffers + threads * per_thread)));
+
+        ResourceProfile {
+            number_of_source_buffers: Some(source_buffers),
+            total_number_of_buffers: Some(total),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn vcpus(&self) -> Option<usize> {
+        self.vcpus.or(self.number_of_worker_threads)
+    }
+
+    // Only vms which set a limit get their own cgroup
+    pub(crate) fn cpu_limit(&self) -> Option<CpuLimit> {
+        (self.cpu_max_percent.is_some() || self.cpu_weight.is_some()).then_some(CpuLimit {
+            max_percent: self.cpu_max_percent,
+            weight: self.cpu_weight,
+        })
+    }
+
+    pub(crate) fn query_processing(&self) -> WorkerQueryProcessingConfiguration {
+        let mut builder = WorkerQueryProcessingConfigurationBuilder::default();
+        if let Some(threads) = self.number_of_worker_threads {
+            builder.number_of_worker_threads(threads);
+        }
+        if let Some(buffer_size) = self.buffer_size {
+            builder.buffer_size(buffer_size);
+        }
+        if let Some(buffers) = self.total_number_of_buffers {
+            builder.total_number_of_buffers(buffers);
+        }
+        if let Some(buffers) = self.number_of_source_buffers {
+            builder.number_of_source_buffers(buffers);
+        }
+        if let Some(buffers) = self.number_of_buffers_per_thread {
+            builder.number_of_buffers_per_thread(buffers);
+        }
+        builder.build().unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ProfileRegistry {
+    profiles: HashMap<String, ResourceProfile>,
+}
+
+impl Default for ProfileRegistry {
+    fn default() -> Self {
+        let profiles = [
+            (
+                "small",
+                ResourceProfile {
+                    memory_in_megabytes: Some(4 * 1024),
+                    number_of_worker_threads: Some(2),
+                    buffer_size: Some(4096),
+                    total_number_of_buffers: Some(65536),
+                    number_of_source_buffers: Some(16),
+                    number_of_buffers_per_thread: Some(64),
+                    ..Default::default()
+                },
+            ),
+            (
+                "medium",
+                ResourceProfile {
+                    memory_in_megabytes: Some(32 * 1024),
+                    number_of_worker_threads: Some(8),
+                    buffer_size: Some(8192),
+                    total_number_of_buffers: Some(500000),
+                    number_of_source_buffers: Some(32),
+                    number_of_buffers_per_thread: Some(128),
+                    ..Default::default()
+                },
+            ),
+            (
+                "large",
+                ResourceProfile {
+                    memory_in_megabytes: Some(512 * 1024),
+                    number_of_worker_threads: Some(16),
+                    buffer_size: Some(8192),
+                    total_number_of_buffers: Some(2000000),
+                    number_of_source_buffers: Some(32),
+                    number_of_buffers_per_thread: Some(128),
+                    ..Default::default()
+                },
+            ),
+        ]
+        .into_iter()
+        .map(|(name, profile)| (name.to_string(), profile))
+        .collect();
+
+        ProfileRegistry { profiles }
+    }
+}
+
+impl ProfileRegistry {
+    // Profiles from the script config replace built-in profiles of the same name
+    pub(crate) fn extend(&mut self, profiles: HashMap<String, ResourceProfile>) {
+        self.profiles.extend(profiles);
+    }
+
+    pub(crate) fn names(&self) -> Vec<String> {
+        let mut names = self.profiles.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    // defaults < named profile < explicit worker settings
+    pub(crate) fn resolve(
+        &self,
+        name: Option<&str>,
+        overrides: &ResourceProfile,
+    ) -> Result<ResourceProfile, ProfileError> {
+        let base = ResourceProfile::worker_defaults();
+        let base = match name {
+            Some(name) => base.merge(
+                self.profiles
+                    .get(name)
+                    .ok_or_else(|| ProfileError::UnknownProfile(name.to_string()))?,
+            ),
+            None => base,
+        };
+        Ok(base.merge(overrides))
+    }
+}
+
+#[test]
+fn explicit_fields_override_profile() {
+    let registry = ProfileRegistry::default();
+    let resolved = registry
+        .resolve(
+            Some("small"),
+            &ResourceProfile {
+                number_of_worker_threads: Some(4),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    assert_eq!(resolved.number_of_worker_threads, Some(4));
+    assert_eq!(resolved.memory_in_megabytes, Some(4 * 1024));
+    assert_eq!(resolved.vcpus(), Some(4));
+    assert!(registry.resolve(Some("huge"), &resolved).is_err());
+
+    let explicit: ResourceProfile = serde_yaml::from_str("memoryMb: 2048\nvcpus: 2").unwrap();
+    let resolved = registry.resolve(Some("large"), &explicit).unwrap();
+    assert_eq!(resolved.memory_in_megabytes, Some(2048));
+    assert_eq!(resolved.vcpus(), Some(2));
+    assert_eq!(resolved.number_of_worker_threads, Some(16));
+}
+
+#[test]
+fn buffers_scale_with_sources() {
+    let resolved = ResourceProfile {
+        number_of_worker_threads: Some(2),
+        number_of_buffers_per_thread: Some(64),
+        number_of_source_buffers: Some(16),
+        total_number_of_buffers: Some(1024),
+        ..Default::default()
+    };
+
+    let scaled = resolved.scaled_for_sources(100, &ResourceProfile::default());
+    assert_eq!(scaled.number_of_source_buffers, Some(32 + 4 * 100));
+    assert_eq!(
+        scaled.total_number_of_buffers,
+        Some(2 * (100 * 432 + 2 * 64))
+    );
+
+    // few sources keep the configured pools
+    let scaled = resolved.scaled_for_sources(1, &ResourceProfile::default());
+    assert_eq!(scaled.number_of_source_buffers, Some(36));
+    assert_eq!(scaled.total_number_of_buffers, Some(1024));
+
+    let explicit = ResourceProfile {
+        number_of_source_buffers: Some(16),
+        ..Default::default()
+    };
+    let scaled = resolved.scaled_for_sources(100, &explicit);
+    assert_eq!(scaled.number_of_source_buffers, Some(16));
+    assert_eq!(
+        scaled.total_number_of_buffers,
+        Some(2 * (100 * 16 + 2 * 64))
+    );
+}
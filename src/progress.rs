@@ -0,0 +1,102 @@
+use std::future::Future;
+use std::io::IsTerminal;
+use std::pin::pin;
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use once_cell::sync::Lazy;
+use tracing::info;
+
+// Parallel launches each get their own line instead of overwriting each other
+static BARS: Lazy<MultiProgress> = Lazy::new(MultiProgress::new);
+// Without a terminal, progress is logged at most this often
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+fn is_interactive() -> bool {
+    std::io::stderr().is_terminal()
+}
+
+// Progress of a long running operation with a known size. Drawn as a bar on a terminal,
+// logged periodically otherwise.
+pub(crate) enum Progress {
+    Bar(ProgressBar),
+    Log {
+        message: String,
+        total: u64,
+        done: u64,
+        last_log: Instant,
+    },
+}
+
+impl Progress {
+    pub(crate) fn bytes(message: String, total: u64) -> Self {
+        if !is_interactive() {
+            return Progress::Log {
+                message,
+                total,
+                done: 0,
+                last_log: Instant::now(),
+            };
+        }
+        let bar = BARS.add(ProgressBar::new(total));
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{msg} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+            )
+            .unwrap()
+            .progress_chars("=> "),
+        );
+        bar.set_message(message);
+        Progress::Bar(bar)
+    }
+
+    pub(crate) fn inc(&mut self, n: u64) {
+        match self {
+            Progress::Bar(bar) => bar.inc(n),
+            Progress::Log {
+                message,
+                total,
+                done,
+                last_log,
+            } => {
+                *done += n;
+                if last_log.elapsed() >= LOG_INTERVAL {
+                    *last_log = Instant::now();
+                    info!("{message}: {} of {}", HumanBytes(*done), HumanBytes(*total));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Progress::Bar(bar) = self {
+            bar.finish_and_clear();
+            BARS.remove(bar);
+        }
+    }
+}
+
+// Shows a spinner while `f` runs, e.g. around an image build which reports no progress itself
+pub(crate) async fn with_spinner<F: Future>(message: String, f: F) -> F::Output {
+    let mut f = pin!(f);
+    if is_interactive() {
+        let spinner = BARS.add(ProgressBar::new_spinner());
+        spinner.set_style(ProgressStyle::with_template("{spinner} {msg} ({elapsed})").unwrap());
+        spinner.set_message(message);
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        let output = f.await;
+        spinner.finish_and_clear();
+        BARS.remove(&spinner);
+        return output;
+    }
+
+    let start = Instant::now();
+    loop {
+        match async_std::future::timeout(LOG_INTERVAL, &mut f).await {
+            Ok(output) => return output,
+            Err(_) => info!("{message}: still running after {:?}", start.elapsed()),
+        }
+    }
+}
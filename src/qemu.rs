@@ -1,38 +1,367 @@
-use async_std::io::{ReadExt, WriteExt};
+use async_std::channel::{Receiver, Sender};
+use async_std::io::prelude::BufReadExt;
+use async_std::io::{BufReader, ReadExt, WriteExt};
 use async_std::os::unix::net::UnixStream;
 use async_std::{io, task};
+use chrono::{DateTime, SecondsFormat, Utc};
 use rand::random;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
 use std::fmt::{Display, Formatter};
 use std::fs::Permissions;
 use std::future::Future;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Write};
+use std::net::Ipv4Addr;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::process::ExitStatus;
-use std::str::from_utf8;
-use std::time::Duration;
+use std::process::{ExitStatus, Output};
+use std::str::{from_utf8, FromStr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use strum_macros::Display;
-use tempdir::TempDir;
 use thiserror::Error;
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, warn};
 
+use crate::cgroup::{Cgroup, CgroupError, CpuLimit};
+use crate::cpus::{CpuAssignment, VcpuReservation};
+use crate::image::copy_image;
+use crate::journal::JournalEntry;
 use crate::network::TapUser;
 use crate::qemu::MachineType::Q35;
+use crate::rundir::VmDir;
 use crate::shell::{self, ShellError};
-use crate::shell::{run_command_without_output, run_shell_command};
+use crate::shell::{run_command_with_output, run_command_without_output, run_shell_command};
+use crate::vm::{VmError, VmFuture, VmHandle};
 
 #[derive(Debug)]
 pub struct LaunchConfiguration {
     pub(crate) tap: TapUser,
-    pub(crate) image_path: PathBuf,
-    pub(crate) temp_dir: TempDir,
+    pub(crate) boot: BootSource,
+    pub(crate) vm_dir: VmDir,
     pub(crate) firmware: Vec<QemuFirmwareConfig>,
+    // UEFI boot instead of the default bios
+    pub(crate) pflash: Option<PflashConfig>,
     pub(crate) num_cores: Option<usize>,
+    pub(crate) max_num_cores: Option<usize>,
     pub(crate) memory_in_mega_bytes: Option<usize>,
+    pub(crate) balloon: bool,
+    pub(crate) security: SecurityConfig,
+    // emulated TPM 2.0 backed by swtpm
+    pub(crate) tpm: bool,
+    // sockets are placed in `socket_dir/<tap>/` instead of the vm dir
+    pub(crate) socket_dir: Option<PathBuf>,
+    // host cpus all qemu threads are pinned to
+    pub(crate) cpu_affinity: Option<CpuAssignment>,
+    // counted against the vcpu overcommit limit while the vm exists
+    pub(crate) vcpu_reservation: Option<VcpuReservation>,
+    // cgroup cpu.max/cpu.weight for the qemu process
+    pub(crate) cpu_limit: Option<CpuLimit>,
+    // appended to -machine, e.g. kernel-irqchip=split
+    pub(crate) machine_properties: Vec<MachineProperty>,
+    // state captured with `migrate_to_file`, restored instead of booting the image
+    pub(crate) incoming: Option<PathBuf>,
+    // writable share the guest leaves its results in
+    pub(crate) output: Option<OutputDirectory>,
+    // the rtc follows the host clock and catches up on missed ticks
+    pub(crate) sync_clock: bool,
+    // the human monitor socket for existing scripts, the launcher itself only uses QMP
+    pub(crate) hmp_monitor: bool,
+    // `-vnc` display with a vga device, for looking at a guest which does not boot
+    pub(crate) vnc: Option<String>,
+    // gdbstub for debugging a guest crash, only ever set for single vms
+    pub(crate) gdb: Option<GdbStub>,
+    // attached after the image, e.g. a scratch disk for the worker's state
+    pub(crate) extra_drives: Vec<VirtioDrive>,
+    // virtio-blk queues, served by a dedicated iothread instead of qemu's main loop. virtio-net
+    // can not use an iothread, it scales with `nic_queues` instead
+    pub(crate) disk_queues: Option<usize>,
+    // hugetlbfs mount guest memory is allocated from, e.g. /dev/hugepages. The pages have to be
+    // reserved on the host beforehand, qemu fails to start if there are not enough
+    pub(crate) hugepages: Option<PathBuf>,
+    // e.g. a locally built qemu, `qemu-system-x86_64` from PATH if unset
+    pub(crate) qemu_binary: Option<PathBuf>,
+    // moves the nic's data path into the host kernel, see `vhost_net_available`
+    pub(crate) vhost: bool,
+    // rx/tx queue pairs of every nic, more than one needs taps created with IFF_MULTI_QUEUE
+    pub(crate) nic_queues: usize,
+    // further nics of a bonded worker, on the same network as `tap`
+    pub(crate) bond_taps: Vec<TapUser>,
+    // who besides the launcher's user may connect to the serial, monitor and qmp sockets
+    pub(crate) socket_access: SocketAccess,
 }
 
-const QEMU_BINARY: &str = "qemu-system-x86_64";
+// vms are named after their tap, so leftover processes can be found after a crash
+pub(crate) const QEMU_NAME_PREFIX: &str = "nes-";
+const MONITOR_SOCKET: &str = "monitor.socket";
+const QMP_SOCKET: &str = "qmp.socket";
+const SERIAL_SOCKET: &str = "serial.socket";
+const SWTPM_SOCKET: &str = "swtpm.socket";
+// sun_path is 108 bytes including the terminating nul
+const MAX_SOCKET_PATH_LENGTH: usize = 107;
+
+impl LaunchConfiguration {
+    fn socket_path(&self, name: &str) -> PathBuf {
+        match self.socket_dir.as_ref() {
+            Some(dir) => dir.join(self.tap.device()).join(name),
+            None => self.vm_dir.path().join(name),
+        }
+    }
+
+    fn validate_socket_paths(&self) -> Result<()> {
+        for name in [MONITOR_SOCKET, QMP_SOCKET, SERIAL_SOCKET, SWTPM_SOCKET] {
+            let path = self.socket_path(name);
+            if path.as_os_str().len() > MAX_SOCKET_PATH_LENGTH {
+                return Err(QemuError::SocketPathTooLong(path));
+            }
+        }
+        Ok(())
+    }
+
+    fn qemu_binary(&self) -> &str {
+        self.qemu_binary
+            .as_ref()
+            .map_or(QEMU_BINARY, |path| path.to_str().unwrap())
+    }
+
+    // Fails before anything was set up for the vm, a missing binary is not retried
+    fn validate_qemu_binary(&self) -> Result<()> {
+        shell::find_binary(self.qemu_binary())
+            .map(|_| ())
+            .map_err(QemuError::Shell)
+    }
+
+    fn validate_hugepages(&self) -> Result<()> {
+        match self.hugepages.as_ref() {
+            Some(path) if !path.is_dir() => Err(QemuError::MissingHugepages(path.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_boot(&self) -> Result<()> {
+        let files = match &self.boot {
+            BootSource::Image(path) => vec![path],
+            BootSource::DirectKernel(boot) => std::iter::once(&boot.kernel)
+                .chain(boot.initrd.iter())
+                .collect(),
+        };
+        match files.into_iter().find(|path| !path.is_file()) {
+            Some(path) => Err(QemuError::MissingBootFile(path.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn validate_extra_drives(&self) -> Result<()> {
+        match self.extra_drives.iter().find(|d| !d.path.exists()) {
+            Some(drive) => Err(QemuError::MissingDrive(drive.path.clone())),
+            None => Ok(()),
+        }
+    }
+
+    // More queues than vcpus only add overhead
+    fn validate_disk_queues(&self) -> Result<()> {
+        match self.disk_queues {
+            Some(queues) if !(1..=self.vcpus()).contains(&queues) => {
+                Err(QemuError::DiskQueues(queues, self.vcpus()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // A property given twice, or one the launcher sets itself, would make qemu pick one
+    fn validate_machine_properties(&self) -> Result<()> {
+        let mut keys = vec![];
+        for property in &self.machine_properties {
+            if RESERVED_MACHINE_PROPERTIES.contains(&property.key.as_str())
+                || keys.contains(&&property.key)
+            {
+                return Err(QemuError::MachineProperty(property.key.clone()));
+            }
+            keys.push(&property.key);
+        }
+        Ok(())
+    }
+
+    // vcpus the vm boots with
+    pub(crate) fn vcpus(&self) -> usize {
+        self.num_cores.unwrap_or(DEFAULT_NUMBER_OF_CORES)
+    }
+
+    // What qemu is going to be started with, defaults filled in
+    pub(crate) fn summary(&self) -> LaunchSummary {
+        let num_cores = self.vcpus();
+        LaunchSummary {
+            boot: self.boot.clone(),
+            vm_dir: self.vm_dir.path().to_owned(),
+            tap: self.tap.device(),
+            ip: self.tap.ip().to_string(),
+            mac: self.tap.mac().to_string(),
+            bond_taps: self.bond_taps.iter().map(|t| t.device()).collect(),
+            num_cores,
+            max_num_cores: self.max_num_cores.map(|m| m.max(num_cores)),
+            memory_in_megabytes: self
+                .memory_in_mega_bytes
+                .unwrap_or(DEFAULT_MEMORY_IN_MEGABYTES),
+            balloon: self.balloon,
+            uefi: self.pflash.is_some(),
+            tpm: self.tpm,
+            sandbox: self.security.sandbox,
+            run_as: self.security.run_as.clone(),
+            socket_dir: self.socket_path(""),
+            cpu_affinity: self.cpu_affinity.as_ref().map(|cpus| cpus.cpu_list()),
+            cpu_limit: self.cpu_limit,
+            machine_properties: self
+                .machine_properties
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            incoming: self.incoming.clone(),
+            vnc: self.vnc.clone(),
+            gdb: self.gdb,
+            extra_drives: self.extra_drives.clone(),
+            disk_queues: self.disk_queues,
+            hugepages: self.hugepages.clone(),
+            vhost: self.vhost,
+            nic_queues: self.nic_queues,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LaunchSummary {
+    boot: BootSource,
+    vm_dir: PathBuf,
+    tap: String,
+    ip: String,
+    mac: String,
+    bond_taps: Vec<String>,
+    num_cores: usize,
+    max_num_cores: Option<usize>,
+    memory_in_megabytes: usize,
+    balloon: bool,
+    uefi: bool,
+    tpm: bool,
+    sandbox: bool,
+    run_as: Option<String>,
+    socket_dir: PathBuf,
+    cpu_affinity: Option<String>,
+    cpu_limit: Option<CpuLimit>,
+    machine_properties: Vec<String>,
+    incoming: Option<PathBuf>,
+    vnc: Option<String>,
+    gdb: Option<GdbStub>,
+    extra_drives: Vec<VirtioDrive>,
+    disk_queues: Option<usize>,
+    hugepages: Option<PathBuf>,
+    vhost: bool,
+    nic_queues: usize,
+}
+
+// set by the launcher itself
+const RESERVED_MACHINE_PROPERTIES: [&str; 2] = ["type", "accel"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MachineProperty {
+    key: String,
+    value: String,
+}
+
+impl FromStr for MachineProperty {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !value.is_empty() && !s.contains(',') => {
+                Ok(MachineProperty {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+            }
+            _ => Err(format!("expected a single key=value, got {s}")),
+        }
+    }
+}
+
+impl Display for MachineProperty {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+// `[host]:<display>` as taken by -vnc. Every vm gets its own display, offset by its id, so a
+// whole topology can be watched with one flag.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VncDisplay {
+    host: String,
+    display: u16,
+}
+
+impl FromStr for VncDisplay {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s
+            .rsplit_once(':')
+            .map(|(host, display)| (host, display.parse()))
+        {
+            Some((host, Ok(display))) if !host.contains(',') => Ok(VncDisplay {
+                host: host.to_string(),
+                display,
+            }),
+            _ => Err(format!("expected [host]:<display>, e.g. :1, got {s}")),
+        }
+    }
+}
+
+impl VncDisplay {
+    // The display of the vm with the given worker or node id, vnc port 5900 + display
+    pub(crate) fn for_instance(&self, id: usize) -> String {
+        format!("{}:{}", self.host, self.display as usize + id)
+    }
+}
+
+// `-gdb tcp::<port>`, listening on all host addresses. With `wait` the vcpus are frozen (-S) until
+// the debugger continues them, so nothing boots and the boot timeout has to allow for the
+// session. Attach with the symbols of the guest kernel:
+//   nanos:   gdb <unikernel binary> -ex 'target remote :<port>', the kernel symbols are in
+//            ~/.ops/<version>/kernel.img, add them with `add-symbol-file`
+//   flatcar: gdb vmlinux -ex 'target remote :<port>', the vmlinux with debug symbols of the
+//            flatcar release the image was built from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GdbStub {
+    pub(crate) port: u16,
+    #[serde(default)]
+    pub(crate) wait: bool,
+}
+
+impl GdbStub {
+    // qemu listens on every host address
+    pub(crate) fn connect_command(&self) -> String {
+        format!("gdb -ex 'target remote localhost:{}'", self.port)
+    }
+}
+
+impl Display for GdbStub {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tcp::{}", self.port)?;
+        if self.wait {
+            write!(f, " (frozen until continued)")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) const QEMU_BINARY: &str = "qemu-system-x86_64";
+const SWTPM_BINARY: &str = "swtpm";
+const TASKSET_BINARY: &str = "taskset";
+const DEFAULT_NUMBER_OF_CORES: usize = 8;
+const DEFAULT_MEMORY_IN_MEGABYTES: usize = 16000;
 
 trait QemuCommandLineArgs {
     fn as_args(&self) -> impl Iterator<Item = String>;
@@ -54,6 +383,108 @@ impl QemuCommandLineArgs for QemuFirmwareConfig {
     }
 }
 
+// Anyone who can connect to the monitor can stop the vm, so the sockets are only accessible to
+// the launcher's user, which qemu runs as, unless a group is given
+#[derive(Debug, Clone)]
+pub(crate) struct SocketAccess {
+    pub(crate) mode: u32,
+    pub(crate) group: Option<String>,
+}
+
+impl Default for SocketAccess {
+    fn default() -> Self {
+        SocketAccess {
+            mode: 0o600,
+            group: None,
+        }
+    }
+}
+
+impl SocketAccess {
+    async fn apply(&self, path: &Path) -> Result<()> {
+        if let Some(group) = self.group.as_ref() {
+            run_shell_command("chgrp", &vec![group, path.to_str().unwrap()])
+                .await
+                .map_err(QemuError::Shell)?;
+        }
+        async_std::fs::set_permissions(path, Permissions::from_mode(self.mode))
+            .await
+            .map_err(|e| QemuError::IO(e, "changing socket permissions"))
+    }
+}
+
+#[test]
+fn socket_access() {
+    use std::os::unix::fs::MetadataExt;
+    let dir = tempdir::TempDir::new("sockets").unwrap();
+    let socket = dir.path().join("qmp.socket");
+    std::fs::write(&socket, b"").unwrap();
+
+    task::block_on(SocketAccess::default().apply(&socket)).unwrap();
+    assert_eq!(std::fs::metadata(&socket).unwrap().mode() & 0o777, 0o600);
+
+    let gid = std::fs::metadata(&socket).unwrap().gid();
+    let access = SocketAccess {
+        mode: 0o660,
+        group: Some(gid.to_string()),
+    };
+    task::block_on(access.apply(&socket)).unwrap();
+    let metadata = std::fs::metadata(&socket).unwrap();
+    assert_eq!((metadata.mode() & 0o777, metadata.gid()), (0o660, gid));
+}
+
+// OVMF firmware code and variable store. The vars are written by the guest, so every vm
+// gets its own copy.
+#[derive(Debug, Clone)]
+pub(crate) struct PflashConfig {
+    code_path: PathBuf,
+    vars_path: PathBuf,
+}
+
+impl PflashConfig {
+    pub(crate) async fn prepare(
+        code_path: &Path,
+        vars_path: &Path,
+        temp_dir: &Path,
+    ) -> Result<Self> {
+        for path in [code_path, vars_path] {
+            if !path.is_file() {
+                return Err(QemuError::MissingFirmware(path.to_owned()));
+            }
+        }
+        let vm_vars_path = temp_dir.join("OVMF_VARS.fd");
+        async_std::fs::copy(vars_path, &vm_vars_path)
+            .await
+            .map_err(|e| QemuError::IO(e, "copying OVMF vars"))?;
+        async_std::fs::set_permissions(&vm_vars_path, Permissions::from_mode(0o644))
+            .await
+            .map_err(|e| QemuError::IO(e, "making OVMF vars writable"))?;
+
+        Ok(PflashConfig {
+            code_path: code_path.to_owned(),
+            vars_path: vm_vars_path,
+        })
+    }
+}
+
+impl QemuCommandLineArgs for PflashConfig {
+    fn as_args(&self) -> impl Iterator<Item = String> {
+        [
+            "-drive".to_string(),
+            format!(
+                "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                self.code_path.to_str().unwrap()
+            ),
+            "-drive".to_string(),
+            format!(
+                "if=pflash,format=raw,unit=1,file={}",
+                self.vars_path.to_str().unwrap()
+            ),
+        ]
+        .into_iter()
+    }
+}
+
 struct MountedFilesystem {
     mount_tag: String,
     readonly: bool,
@@ -77,15 +508,178 @@ impl QemuCommandLineArgs for MountedFilesystem {
     }
 }
 
+// A kernel qemu loads itself, without a disk image or bootloader, e.g. to test a kernel build
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DirectKernelBoot {
+    pub(crate) kernel: PathBuf,
+    pub(crate) initrd: Option<PathBuf>,
+    // kernel command line, e.g. "console=ttyS0"
+    pub(crate) append: Option<String>,
+}
+
+impl QemuCommandLineArgs for DirectKernelBoot {
+    fn as_args(&self) -> impl Iterator<Item = String> {
+        [
+            "-kernel".to_string(),
+            self.kernel.to_str().unwrap().to_string(),
+        ]
+        .into_iter()
+        .chain(
+            self.initrd
+                .iter()
+                .flat_map(|initrd| ["-initrd".to_string(), initrd.to_str().unwrap().to_string()]),
+        )
+        .chain(
+            self.append
+                .iter()
+                .flat_map(|append| ["-append".to_string(), append.clone()]),
+        )
+    }
+}
+
+// What a vm boots from, flatcar and unikernels boot from their disk image
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum BootSource {
+    Image(PathBuf),
+    DirectKernel(DirectKernelBoot),
+}
+
+impl BootSource {
+    pub(crate) fn image(&self) -> Option<&PathBuf> {
+        match self {
+            BootSource::Image(path) => Some(path),
+            BootSource::DirectKernel(_) => None,
+        }
+    }
+}
+
+// Block device of the vm, the boot image is the first one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct VirtioDrive {
+    pub(crate) path: PathBuf,
+    #[serde(default)]
+    pub(crate) readonly: bool,
+}
+
+impl VirtioDrive {
+    // -drive options apart from the interface
+    fn drive_options(&self) -> String {
+        format!(
+            "file={}{}",
+            self.path.to_str().unwrap(),
+            if self.readonly { ",readonly=on" } else { "" }
+        )
+    }
+}
+
+// A writable 9p share backed by `<vm dir>/output`. Its contents are copied to `destination`
+// whenever the vm is stopped, the vm dir itself is removed with the vm.
+#[derive(Debug, Clone)]
+pub(crate) struct OutputDirectory {
+    pub(crate) mount_tag: String,
+    pub(crate) destination: PathBuf,
+}
+
+impl OutputDirectory {
+    pub(crate) fn staging(vm_dir: &Path) -> PathBuf {
+        vm_dir.join("output")
+    }
+
+    fn collect(&self, vm_dir: &Path) -> Result<usize> {
+        copy_dir(&Self::staging(vm_dir), &self.destination)
+            .map_err(|e| QemuError::IO(e, "collecting output"))
+    }
+}
+
+// Recursively, returns the number of files copied
+fn copy_dir(from: &Path, to: &Path) -> io::Result<usize> {
+    std::fs::create_dir_all(to)?;
+    let mut files = 0;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            files += copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+            files += 1;
+        }
+    }
+    Ok(files)
+}
+
+#[test]
+fn collected_output() {
+    let vm = tempdir::TempDir::new("vm").unwrap();
+    let results = tempdir::TempDir::new("results").unwrap();
+    let staging = OutputDirectory::staging(vm.path());
+    std::fs::create_dir_all(staging.join("q1")).unwrap();
+    std::fs::write(staging.join("summary.csv"), "latency\n12\n").unwrap();
+    std::fs::write(staging.join("q1/throughput.csv"), "42\n").unwrap();
+
+    let output = OutputDirectory {
+        mount_tag: "output".to_string(),
+        destination: results.path().join("node-1"),
+    };
+    assert_eq!(output.collect(vm.path()).unwrap(), 2);
+    assert_eq!(
+        std::fs::read_to_string(results.path().join("node-1/q1/throughput.csv")).unwrap(),
+        "42\n"
+    );
+    // collecting again after a restart replaces the earlier files
+    std::fs::write(staging.join("summary.csv"), "latency\n10\n").unwrap();
+    assert_eq!(output.collect(vm.path()).unwrap(), 2);
+    assert_eq!(
+        std::fs::read_to_string(results.path().join("node-1/summary.csv")).unwrap(),
+        "latency\n10\n"
+    );
+}
+
+struct QemuTpm {
+    socket_path: PathBuf,
+}
+
+impl QemuCommandLineArgs for QemuTpm {
+    fn as_args(&self) -> impl Iterator<Item = String> {
+        [
+            "-chardev".to_string(),
+            format!(
+                "socket,id=chrtpm,path={}",
+                self.socket_path.to_str().unwrap()
+            ),
+            "-tpmdev".to_string(),
+            "emulator,id=tpm0,chardev=chrtpm".to_string(),
+            "-device".to_string(),
+            "tpm-tis,tpmdev=tpm0".to_string(),
+        ]
+        .into_iter()
+    }
+}
+
 struct QemuConfig<'tap> {
     name: Option<String>,
     memory_in_megabytes: Option<usize>,
     number_of_cores: Option<usize>,
+    max_number_of_cores: Option<usize>,
     rng_device: bool,
-    tap: Option<&'tap TapUser>,
+    balloon_device: bool,
+    taps: Vec<&'tap TapUser>,
+    tpm: Option<QemuTpm>,
     firmware: Vec<QemuFirmwareConfig>,
-    virtio_drives: Vec<PathBuf>,
+    pflash: Option<PflashConfig>,
+    kernel: Option<DirectKernelBoot>,
+    virtio_drives: Vec<VirtioDrive>,
+    // multiqueue drives attached to the io0 iothread
+    disk_queues: Option<usize>,
     mounted_filesystems: Vec<MountedFilesystem>,
+    rtc_host_clock: bool,
+    // backs all of the guest memory, which becomes a single numa node
+    hugepages: Option<PathBuf>,
+    vhost: bool,
+    nic_queues: usize,
 }
 
 fn bool_option(b: bool) -> Option<()> {
@@ -98,17 +692,34 @@ fn bool_option(b: bool) -> Option<()> {
 
 impl QemuCommandLineArgs for QemuConfig<'_> {
     fn as_args(&self) -> impl Iterator<Item = String> {
-        self.virtio_drives
+        self.disk_queues
             .iter()
-            .flat_map(|f| {
-                [
-                    "-drive".to_string(),
-                    format!("if=virtio,file={}", f.to_str().unwrap()),
-                ]
-                .into_iter()
-            })
+            .flat_map(|_| ["-object".to_string(), "iothread,id=io0".to_string()])
+            .chain(self.virtio_drives.iter().enumerate().flat_map(
+                |(i, f)| match self.disk_queues {
+                    None => vec![
+                        "-drive".to_string(),
+                        format!("if=virtio,{}", f.drive_options()),
+                    ],
+                    Some(queues) => vec![
+                        "-drive".to_string(),
+                        format!("if=none,id=disk{i},{}", f.drive_options()),
+                        "-device".to_string(),
+                        format!("virtio-blk-pci,drive=disk{i},iothread=io0,num-queues={queues}"),
+                    ],
+                },
+            ))
             .chain(self.mounted_filesystems.iter().flat_map(|f| f.as_args()))
             .chain(self.firmware.iter().flat_map(|f| f.as_args()))
+            .chain(self.pflash.iter().flat_map(|p| p.as_args()))
+            .chain(self.kernel.iter().flat_map(|k| k.as_args()))
+            .chain(self.tpm.iter().flat_map(|t| t.as_args()))
+            .chain(bool_option(self.rtc_host_clock).into_iter().flat_map(|_| {
+                [
+                    "-rtc".to_string(),
+                    "base=utc,clock=host,driftfix=slew".to_string(),
+                ]
+            }))
             .chain(bool_option(self.rng_device).into_iter().flat_map(|_| {
                 [
                     "-object",
@@ -131,22 +742,49 @@ impl QemuCommandLineArgs for QemuConfig<'_> {
                     .map(|m| ["-m".to_string(), format!("{m}m")])
                     .flat_map(|a| a.into_iter()),
             )
+            .chain(
+                self.memory_in_megabytes
+                    .iter()
+                    .zip(self.hugepages.iter())
+                    .flat_map(|(m, path)| {
+                        [
+                            "-object".to_string(),
+                            format!(
+                                "memory-backend-file,id=mem,size={m}m,mem-path={},share=on",
+                                path.display()
+                            ),
+                            "-numa".to_string(),
+                            "node,memdev=mem".to_string(),
+                        ]
+                    }),
+            )
+            .chain(bool_option(self.balloon_device).into_iter().flat_map(|_| {
+                ["-device".to_string(), "virtio-balloon-pci".to_string()].into_iter()
+            }))
             .chain(
                 self.number_of_cores
                     .iter()
-                    .map(|c| ["-smp".to_string(), format!("{c}")])
+                    .map(|c| match self.max_number_of_cores {
+                        // one core per socket, so additional vcpus can be hotplugged by socket-id
+                        Some(m) => [
+                            "-smp".to_string(),
+                            format!("cpus={c},maxcpus={m},sockets={m},cores=1,threads=1"),
+                        ],
+                        None => ["-smp".to_string(), format!("{c}")],
+                    })
                     .flat_map(|a| a.into_iter()),
             )
             .chain(
-                self.tap
+                self.taps
                     .iter()
-                    .map(|t| {
+                    .enumerate()
+                    .map(|(i, t)| {
                         info!(interface_name = t.device(), mac = %t.mac(), "Attaching Tap Device");
                         [
                             "-netdev".to_string(),
-                            format!("tap,id=eth0,ifname={},script=no,downscript=no", t.device()),
+                            tap_netdev(i, &t.device(), self.vhost, self.nic_queues),
                             "-device".to_string(),
-                            format!("virtio-net-pci,netdev=eth0,mac={}", t.mac()),
+                            nic_device(i, &t.mac().to_string(), self.nic_queues),
                         ]
                     })
                     .flat_map(|a| a.into_iter()),
@@ -154,11 +792,71 @@ impl QemuCommandLineArgs for QemuConfig<'_> {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct SecurityConfig {
+    // enables the seccomp filter (-sandbox)
+    pub(crate) sandbox: bool,
+    // drop to this user once qemu has opened kvm and the tap device
+    pub(crate) run_as: Option<String>,
+}
+
+impl SecurityConfig {
+    fn validate(&self, tap: &TapUser) -> Result<()> {
+        let Some(user) = self.run_as.as_ref() else {
+            return Ok(());
+        };
+        if users::get_user_by_name(user).is_none() {
+            return Err(QemuError::Security(format!("user {user} does not exist")));
+        }
+        // qemu needs the privileges to setuid, /dev/kvm and the tap are opened before dropping them
+        if users::get_effective_uid() != 0 {
+            return Err(QemuError::Security(format!(
+                "-runas {user} requires the launcher to run as root"
+            )));
+        }
+        if !PathBuf::from("/dev/net/tun").exists() || !PathBuf::from("/dev/kvm").exists() {
+            return Err(QemuError::Security(format!(
+                "kvm or tun is not available for tap {}",
+                tap.device()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl QemuCommandLineArgs for SecurityConfig {
+    fn as_args(&self) -> impl Iterator<Item = String> {
+        let mut options = vec![];
+        if self.sandbox {
+            // spawn stays allowed: the filter is installed before qemu forks for -daemonize.
+            // setuid is needed for -runas which happens after the filter is installed.
+            let elevate_privileges = if self.run_as.is_some() {
+                "allow"
+            } else {
+                "deny"
+            };
+            options.push("-sandbox".to_string());
+            options.push(format!(
+                "on,obsolete=deny,elevateprivileges={elevate_privileges},resourcecontrol=deny"
+            ));
+        }
+        if let Some(user) = self.run_as.as_ref() {
+            options.push("-runas".to_string());
+            options.push(user.clone());
+        }
+        options.into_iter()
+    }
+}
+
 struct QemuRunMode {
     monitor: Option<QemuMonitor>,
+    qmp: Option<QemuMonitor>,
     serial: Option<QemuSerial>,
     display: bool,
+    vnc: Option<String>,
+    gdb: Option<GdbStub>,
     daemonize_pidfile: Option<PathBuf>,
+    incoming: Option<PathBuf>,
 }
 
 impl QemuCommandLineArgs for QemuRunMode {
@@ -167,6 +865,11 @@ impl QemuCommandLineArgs for QemuRunMode {
             .iter()
             .map(|m| m.as_args())
             .flat_map(|s| s.into_iter())
+            .chain(
+                self.qmp
+                    .iter()
+                    .flat_map(|m| ["-qmp".to_string(), m.socket_arg()].into_iter()),
+            )
             .chain(
                 self.serial
                     .iter()
@@ -180,12 +883,225 @@ impl QemuCommandLineArgs for QemuRunMode {
                     .flat_map(|s| s.into_iter().map(|s| s.to_string())),
             )
             .chain(
-                bool_option(!self.display)
+                bool_option(!self.display && self.vnc.is_none())
                     .into_iter()
                     .map(|_| ["-display", "none", "-vga", "none"])
                     .flat_map(|s| s.into_iter().map(|s| s.to_string())),
             )
+            // keeps the default vga device, which is what the vnc server shows
+            .chain(
+                self.vnc
+                    .iter()
+                    .flat_map(|spec| ["-vnc".to_string(), spec.clone()]),
+            )
+            .chain(self.gdb.iter().flat_map(|gdb| {
+                ["-gdb".to_string(), format!("tcp::{}", gdb.port)]
+                    .into_iter()
+                    .chain(gdb.wait.then(|| "-S".to_string()))
+            }))
+            .chain(
+                self.incoming
+                    .iter()
+                    .flat_map(|path| ["-incoming".to_string(), exec_uri("cat", path)]),
+            )
+    }
+}
+
+#[test]
+fn disk_queues() {
+    let mut qc = QemuConfig {
+        name: None,
+        memory_in_megabytes: None,
+        number_of_cores: None,
+        max_number_of_cores: None,
+        rng_device: false,
+        balloon_device: false,
+        taps: vec![],
+        tpm: None,
+        firmware: vec![],
+        pflash: None,
+        kernel: None,
+        virtio_drives: vec![VirtioDrive {
+            path: PathBuf::from("/vm/disk.img"),
+            readonly: false,
+        }],
+        disk_queues: None,
+        mounted_filesystems: vec![],
+        rtc_host_clock: false,
+        hugepages: None,
+        vhost: false,
+        nic_queues: 1,
+    };
+    assert_eq!(
+        qc.as_args().collect::<Vec<_>>(),
+        vec!["-drive", "if=virtio,file=/vm/disk.img"]
+    );
+    qc.disk_queues = Some(4);
+    assert_eq!(
+        qc.as_args().collect::<Vec<_>>(),
+        vec![
+            "-object",
+            "iothread,id=io0",
+            "-drive",
+            "if=none,id=disk0,file=/vm/disk.img",
+            "-device",
+            "virtio-blk-pci,drive=disk0,iothread=io0,num-queues=4"
+        ]
+    );
+
+    qc.disk_queues = None;
+    qc.virtio_drives
+        .push(serde_yaml::from_str("path: /data/scratch.img\nreadonly: true").unwrap());
+    assert_eq!(
+        qc.as_args().collect::<Vec<_>>(),
+        vec![
+            "-drive",
+            "if=virtio,file=/vm/disk.img",
+            "-drive",
+            "if=virtio,file=/data/scratch.img,readonly=on"
+        ]
+    );
+}
+
+fn tap_netdev(index: usize, device: &str, vhost: bool, queues: usize) -> String {
+    let mut netdev = format!("tap,id=eth{index},ifname={device},script=no,downscript=no");
+    if vhost {
+        netdev.push_str(",vhost=on");
     }
+    if queues > 1 {
+        netdev.push_str(&format!(",queues={queues}"));
+    }
+    netdev
+}
+
+// A multiqueue nic needs an msi-x vector per rx and tx queue, plus one for config changes and
+// one for the control queue
+fn nic_device(index: usize, mac: &str, queues: usize) -> String {
+    let device = format!("virtio-net-pci,netdev=eth{index},mac={mac}");
+    match queues {
+        0 | 1 => device,
+        queues => format!("{device},mq=on,vectors={}", 2 * queues + 2),
+    }
+}
+
+const VHOST_NET_DEVICE: &str = "/dev/vhost-net";
+static VHOST_NET: LazyLock<bool> = LazyLock::new(|| {
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(VHOST_NET_DEVICE)
+    {
+        Ok(_) => true,
+        Err(e) => {
+            warn!(device = VHOST_NET_DEVICE, %e, "vhost-net is not available, nics are slower");
+            false
+        }
+    }
+});
+
+// qemu opens the device for every nic with vhost=on and fails to start if it can't, checked
+// once as it does not come and go
+pub(crate) fn vhost_net_available() -> bool {
+    *VHOST_NET
+}
+
+#[test]
+fn tap_netdevs() {
+    assert_eq!(
+        tap_netdev(0, "tap3", false, 1),
+        "tap,id=eth0,ifname=tap3,script=no,downscript=no"
+    );
+    assert_eq!(
+        tap_netdev(1, "tap4", true, 1),
+        "tap,id=eth1,ifname=tap4,script=no,downscript=no,vhost=on"
+    );
+    assert_eq!(
+        tap_netdev(0, "tap3", true, 4),
+        "tap,id=eth0,ifname=tap3,script=no,downscript=no,vhost=on,queues=4"
+    );
+    assert_eq!(
+        nic_device(0, "52:54:00:00:00:03", 1),
+        "virtio-net-pci,netdev=eth0,mac=52:54:00:00:00:03"
+    );
+    assert_eq!(
+        nic_device(0, "52:54:00:00:00:03", 4),
+        "virtio-net-pci,netdev=eth0,mac=52:54:00:00:00:03,mq=on,vectors=10"
+    );
+}
+
+#[test]
+fn hugepages() {
+    let qc = QemuConfig {
+        name: None,
+        memory_in_megabytes: Some(2048),
+        number_of_cores: None,
+        max_number_of_cores: None,
+        rng_device: false,
+        balloon_device: false,
+        taps: vec![],
+        tpm: None,
+        firmware: vec![],
+        pflash: None,
+        kernel: None,
+        virtio_drives: vec![],
+        disk_queues: None,
+        mounted_filesystems: vec![],
+        rtc_host_clock: false,
+        hugepages: Some(PathBuf::from("/dev/hugepages")),
+        vhost: false,
+        nic_queues: 1,
+    };
+    assert_eq!(
+        qc.as_args().collect::<Vec<_>>(),
+        vec![
+            "-m",
+            "2048m",
+            "-object",
+            "memory-backend-file,id=mem,size=2048m,mem-path=/dev/hugepages,share=on",
+            "-numa",
+            "node,memdev=mem"
+        ]
+    );
+}
+
+#[test]
+fn direct_kernel_boot() {
+    let mut boot = DirectKernelBoot {
+        kernel: PathBuf::from("/boot/vmlinuz"),
+        initrd: None,
+        append: None,
+    };
+    assert_eq!(
+        boot.as_args().collect::<Vec<_>>(),
+        vec!["-kernel", "/boot/vmlinuz"]
+    );
+    boot.initrd = Some(PathBuf::from("/boot/initrd.img"));
+    boot.append = Some("console=ttyS0 root=/dev/vda".to_string());
+    assert_eq!(
+        boot.as_args().collect::<Vec<_>>(),
+        vec![
+            "-kernel",
+            "/boot/vmlinuz",
+            "-initrd",
+            "/boot/initrd.img",
+            "-append",
+            "console=ttyS0 root=/dev/vda"
+        ]
+    );
+    assert_eq!(BootSource::DirectKernel(boot).image(), None);
+    assert_eq!(
+        serde_json::to_value(BootSource::Image(PathBuf::from("/vm/disk.img"))).unwrap(),
+        serde_json::json!({"image": "/vm/disk.img"})
+    );
+}
+
+// Migration through a shell command, which works with every qemu version and image format
+fn exec_uri(command: &str, path: &Path) -> String {
+    format!("exec:{command} {}", shell_quote(&path.to_string_lossy()))
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
 }
 
 #[derive(Display)]
@@ -196,6 +1112,7 @@ enum MachineType {
 
 struct QemuVirtualizationMode {
     machine: Option<MachineType>,
+    properties: Vec<MachineProperty>,
     cpu: Option<String>,
     accel: Option<String>,
 }
@@ -203,9 +1120,20 @@ struct QemuVirtualizationMode {
 impl QemuCommandLineArgs for QemuVirtualizationMode {
     fn as_args(&self) -> impl Iterator<Item = String> {
         let mut options = vec![];
-        if let Some(machine_type) = self.machine.as_ref() {
+        // everything goes into a single -machine, instead of relying on qemu merging them
+        let machine = self
+            .machine
+            .iter()
+            .map(|machine_type| format!("type={machine_type}"))
+            .chain(self.accel.iter().map(|accel| {
+                assert_eq!(accel, "kvm");
+                format!("accel={accel}")
+            }))
+            .chain(self.properties.iter().map(|p| p.to_string()))
+            .collect::<Vec<_>>();
+        if !machine.is_empty() {
             options.push("-machine".to_string());
-            options.push(machine_type.to_string());
+            options.push(machine.join(","));
         }
 
         if let Some(machine_type) = self.cpu.as_ref() {
@@ -216,13 +1144,6 @@ impl QemuCommandLineArgs for QemuVirtualizationMode {
             options.push("host".to_string());
         }
 
-        if let Some(accel) = self.accel.as_ref() {
-            assert_eq!(accel, "kvm");
-            options.push("-enable-kvm".to_string());
-            options.push("-machine".to_string());
-            options.push("accel=kvm".to_string());
-        }
-
         options.into_iter()
     }
 }
@@ -231,16 +1152,18 @@ struct QemuMonitor {
     monitor_socket_path: PathBuf,
 }
 
+impl QemuMonitor {
+    fn socket_arg(&self) -> String {
+        format!(
+            "unix:{},server,nowait",
+            self.monitor_socket_path.to_str().unwrap()
+        )
+    }
+}
+
 impl QemuCommandLineArgs for QemuMonitor {
     fn as_args(&self) -> impl Iterator<Item = String> {
-        [
-            "-monitor".to_string(),
-            format!(
-                "unix:{},server,nowait",
-                self.monitor_socket_path.to_str().unwrap()
-            ),
-        ]
-        .into_iter()
+        ["-monitor".to_string(), self.socket_arg()].into_iter()
     }
 }
 
@@ -263,204 +1186,1227 @@ impl QemuCommandLineArgs for QemuSerial {
 
 fn create_qemu_arguments(lc: &LaunchConfiguration) -> Vec<String> {
     let qr = QemuRunMode {
-        monitor: Some(QemuMonitor {
-            monitor_socket_path: lc.temp_dir.path().join("monitor.socket"),
+        monitor: lc.hmp_monitor.then(|| QemuMonitor {
+            monitor_socket_path: lc.socket_path(MONITOR_SOCKET),
+        }),
+        qmp: Some(QemuMonitor {
+            monitor_socket_path: lc.socket_path(QMP_SOCKET),
         }),
         serial: Some(QemuSerial {
-            serial_socket_path: lc.temp_dir.path().join("serial.socket"),
+            serial_socket_path: lc.socket_path(SERIAL_SOCKET),
         }),
         display: false,
-        daemonize_pidfile: Some(lc.temp_dir.path().join("pidfile")),
+        vnc: lc.vnc.clone(),
+        gdb: lc.gdb,
+        daemonize_pidfile: Some(lc.vm_dir.path().join("pidfile")),
+        incoming: lc.incoming.clone(),
     };
 
     let qv = QemuVirtualizationMode {
         machine: Some(Q35),
+        properties: lc.machine_properties.clone(),
         cpu: None,
         accel: Some("kvm".to_string()),
     };
 
     let qc = QemuConfig {
-        name: None,
-        memory_in_megabytes: Some(lc.memory_in_mega_bytes.unwrap_or(16000)),
-        number_of_cores: Some(lc.num_cores.unwrap_or(8)),
+        name: Some(format!("{QEMU_NAME_PREFIX}{}", lc.tap.device())),
+        memory_in_megabytes: Some(
+            lc.memory_in_mega_bytes
+                .unwrap_or(DEFAULT_MEMORY_IN_MEGABYTES),
+        ),
+        number_of_cores: Some(lc.num_cores.unwrap_or(DEFAULT_NUMBER_OF_CORES)),
+        max_number_of_cores: lc
+            .max_num_cores
+            .map(|m| m.max(lc.num_cores.unwrap_or(DEFAULT_NUMBER_OF_CORES))),
         rng_device: true,
-        tap: Some(&lc.tap),
+        balloon_device: lc.balloon,
+        taps: std::iter::once(&lc.tap).chain(&lc.bond_taps).collect(),
+        tpm: lc.tpm.then(|| QemuTpm {
+            socket_path: lc.socket_path(SWTPM_SOCKET),
+        }),
         firmware: lc.firmware.clone(),
-        virtio_drives: vec![lc.image_path.clone()],
-        mounted_filesystems: vec![MountedFilesystem {
+        pflash: lc.pflash.clone(),
+        kernel: match &lc.boot {
+            BootSource::Image(_) => None,
+            BootSource::DirectKernel(boot) => Some(boot.clone()),
+        },
+        virtio_drives: lc
+            .boot
+            .image()
+            .map(|path| VirtioDrive {
+                path: path.clone(),
+                readonly: false,
+            })
+            .into_iter()
+            .chain(lc.extra_drives.iter().cloned())
+            .collect(),
+        disk_queues: lc.disk_queues,
+        mounted_filesystems: std::iter::once(MountedFilesystem {
             mount_tag: "config-2".to_string(),
             readonly: true,
-            path: lc.temp_dir.path().to_owned(),
-        }],
+            path: lc.vm_dir.path().to_owned(),
+        })
+        .chain(lc.output.iter().map(|output| MountedFilesystem {
+            mount_tag: output.mount_tag.clone(),
+            readonly: false,
+            path: OutputDirectory::staging(lc.vm_dir.path()),
+        }))
+        .collect(),
+        rtc_host_clock: lc.sync_clock,
+        hugepages: lc.hugepages.clone(),
+        vhost: lc.vhost,
+        nic_queues: lc.nic_queues,
     };
 
     qr.as_args()
         .chain(qv.as_args())
         .chain(qc.as_args())
+        .chain(lc.security.as_args())
         .collect()
 }
 
 #[derive(Debug)]
-pub struct QemuProcessHandle {
-    lc: Option<LaunchConfiguration>,
+pub struct QemuProcessHandle {
+    lc: Option<LaunchConfiguration>,
+    // remembered at launch, the pidfile may be gone once qemu died
+    pid: Option<usize>,
+    cgroup: Option<Cgroup>,
+}
+
+// Where tools like socat or virsh attach to a running vm, in `--socket-dir` if it is set
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VmSockets {
+    pub(crate) monitor: PathBuf,
+    pub(crate) qmp: PathBuf,
+    pub(crate) serial: PathBuf,
+}
+
+struct PidNoLongerExists {
+    pid: usize,
+    ps_process_future: Option<Pin<Box<dyn Future<Output = io::Result<ExitStatus>>>>>,
+}
+
+impl QemuProcessHandle {
+    fn monitor_path(&self) -> PathBuf {
+        self.lc
+            .as_ref()
+            .expect("invalid state")
+            .socket_path(MONITOR_SOCKET)
+    }
+    fn qmp_path(&self) -> PathBuf {
+        self.lc
+            .as_ref()
+            .expect("invalid state")
+            .socket_path(QMP_SOCKET)
+    }
+    pub(crate) fn sockets(&self) -> VmSockets {
+        VmSockets {
+            monitor: self.monitor_path(),
+            qmp: self.qmp_path(),
+            serial: self.serial_path(),
+        }
+    }
+    pub(crate) fn tap(&self) -> &TapUser {
+        &self.lc.as_ref().expect("invalid state").tap
+    }
+    pub(crate) fn gdb(&self) -> Option<GdbStub> {
+        self.lc.as_ref().expect("invalid state").gdb
+    }
+    pub fn serial_path(&self) -> PathBuf {
+        self.lc
+            .as_ref()
+            .expect("invalid state")
+            .socket_path(SERIAL_SOCKET)
+    }
+    fn pid_file_path(&self) -> PathBuf {
+        self.lc
+            .as_ref()
+            .expect("invalid state")
+            .vm_dir
+            .path()
+            .join("pidfile")
+    }
+
+    #[instrument]
+    pub(crate) async fn restart(&mut self) -> Result<()> {
+        let mut started = start_qemu(self.lc.take().unwrap()).await?;
+        self.pid = started.pid;
+        self.cgroup = started.cgroup.take();
+        self.lc = started.lc.take();
+        Ok(())
+    }
+    fn swtpm_pid_file_path(&self) -> PathBuf {
+        self.lc
+            .as_ref()
+            .expect("invalid state")
+            .vm_dir
+            .path()
+            .join("swtpm.pid")
+    }
+    // Powers the guest down, quits qemu once the grace period is over and terminates and kills it
+    // if that does not help either, see StopTimeouts
+    #[instrument]
+    pub(crate) async fn stop(&self, timeouts: StopTimeouts) -> Result<()> {
+        let result = self.stop_qemu(timeouts).await;
+        self.stop_swtpm().await?;
+        self.remove_socket_dir().await?;
+        self.collect_output()?;
+        if let Some(cgroup) = self.cgroup.as_ref() {
+            cgroup.remove().map_err(QemuError::Cgroup)?;
+        }
+        result
+    }
+    fn collect_output(&self) -> Result<()> {
+        let lc = self.lc.as_ref().expect("invalid state");
+        if let Some(output) = lc.output.as_ref() {
+            let files = output.collect(lc.vm_dir.path())?;
+            info!(files, destination = ?output.destination, "Collected vm output");
+        }
+        Ok(())
+    }
+    async fn remove_socket_dir(&self) -> Result<()> {
+        let lc = self.lc.as_ref().expect("invalid state");
+        if lc.socket_dir.is_none() {
+            return Ok(());
+        }
+        match async_std::fs::remove_dir_all(lc.socket_path("")).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(QemuError::IO(e, "removing socket directory"))
+            }
+            _ => Ok(()),
+        }
+    }
+    // swtpm terminates once qemu disconnects, this covers qemu never connecting
+    async fn stop_swtpm(&self) -> Result<()> {
+        if !self.lc.as_ref().expect("invalid state").tpm {
+            return Ok(());
+        }
+        let pid_file = self.swtpm_pid_file_path();
+        let pid = match async_std::fs::read_to_string(&pid_file).await {
+            Ok(pid) => pid,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(QemuError::IO(e, "reading swtpm pidfile")),
+        };
+        run_command_without_output("kill", vec![pid.trim()])
+            .await
+            .map_err(QemuError::Shell)?;
+        async_std::fs::remove_file(pid_file)
+            .await
+            .map_err(|e| QemuError::IO(e, "removing swtpm pidfile"))
+    }
+    // Asks the guest to power off and waits for it, a paused or unresponsive vm is quit and
+    // eventually killed
+    async fn stop_qemu(&self, timeouts: StopTimeouts) -> Result<()> {
+        if !self.is_running().await? {
+            return Ok(());
+        }
+
+        let pid = self.get_pid().await?;
+
+        match QmpClient::connect(&self.qmp_path()).await {
+            Ok(mut qmp) => {
+                match qmp.query_status().await {
+                    Ok(status) if status.running && !timeouts.grace_period.is_zero() => {
+                        if qmp.system_powerdown().await.is_ok() {
+                            if wait_for_exit(pid, timeouts.grace_period).await? {
+                                return Ok(());
+                            }
+                            warn!(pid, "Vm did not power down, quitting qemu");
+                        }
+                    }
+                    // a paused guest does not react to the power button
+                    Ok(status) => info!(pid, status = status.status, "Quitting qemu"),
+                    Err(e) => warn!(%e, "Could not query the vm status"),
+                }
+                if let Err(e) = qmp.quit().await {
+                    warn!(%e, "Could not quit qemu");
+                }
+            }
+            Err(e) => warn!(%e, "Could not connect to qmp"),
+        }
+        if wait_for_exit(pid, timeouts.quit).await? {
+            return Ok(());
+        }
+        terminate(pid, timeouts.terminate).await?;
+        // a killed qemu leaves its pidfile behind, which would look like a crash
+        match async_std::fs::remove_file(self.pid_file_path()).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(QemuError::IO(e, "removing pidfile")),
+            _ => Ok(()),
+        }
+    }
+    // Runs a human monitor command over QMP
+    async fn monitor_command(&self, command: &str) -> Result<String> {
+        let reply = QmpClient::connect(&self.qmp_path())
+            .await?
+            .human_monitor_command(command)
+            .await?;
+
+        if let Some(error) = reply.lines().find(|l| l.starts_with("Error")) {
+            return Err(QemuError::Monitor(command.to_string(), error.to_string()));
+        }
+
+        Ok(reply)
+    }
+
+    pub(crate) fn number_of_cores(&self) -> usize {
+        self.lc
+            .as_ref()
+            .expect("invalid state")
+            .num_cores
+            .unwrap_or(DEFAULT_NUMBER_OF_CORES)
+    }
+
+    #[instrument]
+    pub(crate) async fn set_number_of_cores(&mut self, cores: usize) -> Result<()> {
+        let current = self.number_of_cores();
+        let max = self.lc.as_ref().expect("invalid state").max_num_cores;
+        if cores < current || cores > max.unwrap_or(current) {
+            return Err(QemuError::CpuHotplug(
+                current,
+                cores,
+                max.unwrap_or(current),
+            ));
+        }
+
+        for id in current..cores {
+            self.monitor_command(&format!(
+                "device_add host-x86_64-cpu,id=cpu{id},socket-id={id},core-id=0,thread-id=0"
+            ))
+            .await?;
+            self.lc.as_mut().expect("invalid state").num_cores = Some(id + 1);
+        }
+
+        Ok(())
+    }
+
+    #[instrument]
+    pub(crate) async fn set_memory_target(&self, memory_in_mega_bytes: usize) -> Result<()> {
+        if !self.lc.as_ref().expect("invalid state").balloon {
+            return Err(QemuError::NoBalloon());
+        }
+        self.monitor_command(&format!("balloon {memory_in_mega_bytes}"))
+            .await?;
+        Ok(())
+    }
+
+    // Captures the complete vm state to a file, restorable with `incoming` on any host with the
+    // same image. The vm stays paused afterwards.
+    #[instrument]
+    pub(crate) async fn migrate_to_file(&self, path: &Path, timeout: Duration) -> Result<()> {
+        // the monitor unescapes quoted strings before the shell sees them
+        let uri = exec_uri("cat >", path)
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        self.monitor_command(&format!("migrate -d \"{uri}\""))
+            .await?;
+        let wait_for_migration = async {
+            loop {
+                let info = self.monitor_command("info migrate").await?;
+                match migration_status(&info) {
+                    Some(status) if status.starts_with("completed") => return Ok(()),
+                    Some(status)
+                        if status.starts_with("failed") || status.starts_with("cancelled") =>
+                    {
+                        return Err(QemuError::Migration(status.to_string()))
+                    }
+                    _ => task::sleep(MIGRATION_POLL_INTERVAL).await,
+                }
+            }
+        };
+
+        match async_std::future::timeout(timeout, wait_for_migration).await {
+            Ok(r) => r,
+            Err(_) => {
+                if let Err(e) = self.monitor_command("migrate_cancel").await {
+                    warn!(%e, "Could not cancel migration");
+                }
+                Err(QemuError::MigrationTimeout(timeout))
+            }
+        }
+    }
+
+    // Migrates the vm to `state` and copies the disk it is paused with next to it, a restore
+    // needs both and the network identity of the vm
+    #[instrument]
+    pub(crate) async fn capture_to_file(
+        &self,
+        state: &Path,
+        timeout: Duration,
+    ) -> Result<CapturedVm> {
+        let lc = self.lc.as_ref().expect("invalid state");
+        let image = lc.boot.image().ok_or(QemuError::CaptureWithoutImage)?;
+        self.migrate_to_file(state, timeout).await?;
+        let captured = CapturedVm {
+            disk: CapturedVm::sidecar(state, "disk"),
+            tap: lc.tap.device(),
+            ip: *lc.tap.ip(),
+            mac: lc.tap.mac().to_string(),
+        };
+        copy_image(image, &captured.disk)
+            .await
+            .map_err(|e| QemuError::CapturedVm(e, "copy the disk of", state.to_path_buf()))?;
+        captured.save(state)?;
+        Ok(captured)
+    }
+
+    // qemu keeps internal snapshots in the images, every writable one has to be qcow2
+    fn check_snapshot_support(&self) -> Result<()> {
+        let lc = self.lc.as_ref().expect("invalid state");
+        let writable = lc.boot.image().into_iter().chain(
+            lc.extra_drives
+                .iter()
+                .filter(|d| !d.readonly)
+                .map(|d| &d.path),
+        );
+        for path in writable {
+            if !is_qcow2(path)? {
+                return Err(QemuError::SnapshotUnsupported(path.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    // Snapshots the running vm including its memory under `name`, replacing an older one
+    #[instrument]
+    pub(crate) async fn savevm(&self, name: &str) -> Result<()> {
+        self.check_snapshot_support()?;
+        self.monitor_command(&format!("savevm {name}")).await?;
+        Ok(())
+    }
+
+    // Resets the vm to the snapshot `name`, it keeps running from there
+    #[instrument]
+    pub(crate) async fn loadvm(&self, name: &str) -> Result<()> {
+        self.check_snapshot_support()?;
+        self.monitor_command(&format!("loadvm {name}")).await?;
+        Ok(())
+    }
+
+    async fn get_pid(&self) -> Result<usize> {
+        read_pid_file(&self.pid_file_path()).await
+    }
+    pub(crate) async fn is_running(&self) -> Result<bool> {
+        Ok(matches!(self.status().await?, VmStatus::Running { .. }))
+    }
+    pub(crate) async fn status(&self) -> Result<VmStatus> {
+        vm_status(&self.pid_file_path()).await
+    }
+}
+
+const QCOW2_MAGIC: &[u8; 4] = b"QFI\xfb";
+
+fn is_qcow2(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file =
+        std::fs::File::open(path).map_err(|e| QemuError::IO(e, "opening image for snapshot"))?;
+    match std::io::Read::read_exact(&mut file, &mut magic) {
+        Ok(()) => Ok(&magic == QCOW2_MAGIC),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(QemuError::IO(e, "reading image header")),
+    }
+}
+
+#[test]
+fn qcow2_detection() {
+    let dir = tempdir::TempDir::new("snapshot").unwrap();
+    let qcow2 = dir.path().join("flatcar.img");
+    let raw = dir.path().join("nanos.img");
+    let empty = dir.path().join("empty.img");
+    std::fs::write(&qcow2, b"QFI\xfb\0\0\0\x03").unwrap();
+    std::fs::write(&raw, [0u8; 512]).unwrap();
+    std::fs::write(&empty, b"").unwrap();
+    assert!(is_qcow2(&qcow2).unwrap());
+    assert!(!is_qcow2(&raw).unwrap());
+    assert!(!is_qcow2(&empty).unwrap());
+    assert!(is_qcow2(&dir.path().join("missing.img")).is_err());
+}
+
+async fn read_pid_file(path: &Path) -> Result<usize> {
+    let mut buf = vec![0; 64];
+    let read_len = match async_std::fs::File::open(path).await {
+        Ok(mut f) => f
+            .read(&mut buf)
+            .await
+            .map_err(|e| QemuError::IO(e, "reading pidfile"))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(QemuError::NotRunning());
+        }
+        Err(e) => {
+            return Err(QemuError::IO(e, "opening pidfile"));
+        }
+    };
+
+    assert!(read_len > 0);
+    let pid_slice = from_utf8(&buf[0..read_len - 1]).map_err(QemuError::PidFileNonUtf)?;
+    pid_slice
+        .parse::<usize>()
+        .map_err(QemuError::PidFileNonNumeric)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VmStatus {
+    Running { pid: usize },
+    Stopped,
+    // qemu removes its pidfile when it exits, unless it was killed
+    Crashed,
+}
+
+impl Display for VmStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmStatus::Running { pid } => write!(f, "running (pid {pid})"),
+            VmStatus::Stopped => write!(f, "stopped"),
+            VmStatus::Crashed => write!(f, "crashed"),
+        }
+    }
+}
+
+async fn vm_status(pid_file: &Path) -> Result<VmStatus> {
+    let pid = match read_pid_file(pid_file).await {
+        Ok(pid) => pid,
+        Err(QemuError::NotRunning()) => return Ok(VmStatus::Stopped),
+        Err(e) => return Err(e),
+    };
+    let running = run_command_without_output("ps", vec!["-p", &pid.to_string()])
+        .await
+        .map_err(QemuError::Shell)?;
+    Ok(if running {
+        VmStatus::Running { pid }
+    } else {
+        VmStatus::Crashed
+    })
+}
+
+#[test]
+fn vm_statuses() {
+    let dir = tempdir::TempDir::new("status").unwrap();
+    let pid_file = dir.path().join("pidfile");
+    assert_eq!(
+        task::block_on(vm_status(&pid_file)).unwrap(),
+        VmStatus::Stopped
+    );
+
+    let pid = std::process::id() as usize;
+    std::fs::write(&pid_file, format!("{pid}\n")).unwrap();
+    assert_eq!(
+        task::block_on(vm_status(&pid_file)).unwrap(),
+        VmStatus::Running { pid }
+    );
+
+    let mut exited = std::process::Command::new("true").spawn().unwrap();
+    exited.wait().unwrap();
+    std::fs::write(&pid_file, format!("{}\n", exited.id())).unwrap();
+    assert_eq!(
+        task::block_on(vm_status(&pid_file)).unwrap(),
+        VmStatus::Crashed
+    );
+}
+
+impl Display for QemuProcessHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "TapDevice: {}, Ip: {}",
+            self.lc.as_ref().unwrap().tap.device(),
+            self.lc.as_ref().unwrap().tap.ip()
+        ))
+    }
+}
+
+impl VmHandle for QemuProcessHandle {
+    fn tap(&self) -> &TapUser {
+        QemuProcessHandle::tap(self)
+    }
+    fn pid(&self) -> Option<usize> {
+        self.pid
+    }
+    fn gdb(&self) -> Option<GdbStub> {
+        QemuProcessHandle::gdb(self)
+    }
+    fn status(&self) -> VmFuture<'_, VmStatus> {
+        Box::pin(async move { QemuProcessHandle::status(self).await.map_err(VmError::Qemu) })
+    }
+    fn stop(&self, timeouts: StopTimeouts) -> VmFuture<'_, ()> {
+        Box::pin(async move {
+            QemuProcessHandle::stop(self, timeouts)
+                .await
+                .map_err(VmError::Qemu)
+        })
+    }
+    fn restart(&mut self) -> VmFuture<'_, ()> {
+        Box::pin(async move {
+            QemuProcessHandle::restart(self)
+                .await
+                .map_err(VmError::Qemu)
+        })
+    }
+    fn as_qemu(&self) -> Option<&QemuProcessHandle> {
+        Some(self)
+    }
+    fn as_qemu_mut(&mut self) -> Option<&mut QemuProcessHandle> {
+        Some(self)
+    }
+}
+
+impl Drop for QemuProcessHandle {
+    fn drop(&mut self) {
+        if self.lc.is_some() {
+            info!("Stopping Qemu");
+            if let Err(e) = task::block_on(self.stop(StopTimeouts::ON_DROP)) {
+                error!("Failed to stop qemu: {e:?}");
+            }
+        }
+    }
+}
+
+// How long `stop` waits for qemu to exit after each step: the guest gets `grace_period` after the
+// power button, then qemu is quit over QMP, sent SIGTERM after `quit` and killed `terminate`
+// later. A zero grace period skips the power button, a zero terminate timeout kills right away.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StopTimeouts {
+    pub(crate) grace_period: Duration,
+    pub(crate) quit: Duration,
+    pub(crate) terminate: Duration,
+}
+
+impl StopTimeouts {
+    // Dropped handles are quit and killed without waiting for the guest, as they always were
+    pub(crate) const ON_DROP: StopTimeouts = StopTimeouts {
+        grace_period: Duration::ZERO,
+        quit: Duration::from_secs(2),
+        terminate: Duration::ZERO,
+    };
+}
+
+impl Default for StopTimeouts {
+    fn default() -> Self {
+        StopTimeouts {
+            grace_period: DEFAULT_POWERDOWN_GRACE_PERIOD,
+            quit: Duration::from_secs(2),
+            terminate: Duration::from_secs(5),
+        }
+    }
+}
+
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Whether the process exited within `timeout`
+async fn wait_for_exit(pid: usize, timeout: Duration) -> Result<bool> {
+    let wait = async {
+        while run_command_without_output("ps", vec!["-p", &pid.to_string()]).await? {
+            task::sleep(EXIT_POLL_INTERVAL).await;
+        }
+        Ok(())
+    };
+    match async_std::future::timeout(timeout, wait).await {
+        Ok(r) => r.map(|_| true).map_err(QemuError::Shell),
+        Err(_) => Ok(false),
+    }
+}
+
+// SIGTERM lets qemu flush its disks and remove its pidfile, SIGKILL follows if it does not exit
+async fn terminate(pid: usize, timeout: Duration) -> Result<()> {
+    if !timeout.is_zero() {
+        warn!(pid, "Qemu did not quit, terminating it");
+        run_command_without_output("kill", vec!["-TERM", &pid.to_string()])
+            .await
+            .map_err(QemuError::Shell)?;
+        if wait_for_exit(pid, timeout).await? {
+            return Ok(());
+        }
+    }
+    warn!(pid, "Qemu did not terminate, killing it");
+    let killed = run_command_without_output("kill", vec!["-KILL", &pid.to_string()])
+        .await
+        .map_err(QemuError::Shell)?;
+    if killed {
+        Ok(())
+    } else {
+        Err(QemuError::CouldNotKill("kill failed"))
+    }
+}
+
+#[test]
+fn terminate_escalation() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Command;
+    let spawn = |script: &str| {
+        let mut child = Command::new("sh").args(["-c", script]).spawn().unwrap();
+        let pid = child.id() as usize;
+        // signals sent before the trap is set would end the shell
+        while !std::fs::read_to_string(format!("/proc/{pid}/comm")).is_ok_and(|c| c == "sleep\n") {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // reaped right away, `ps` would still see a zombie
+        (pid, std::thread::spawn(move || child.wait().unwrap()))
+    };
+
+    let (pid, exit) = spawn("exec sleep 30");
+    task::block_on(terminate(pid, Duration::from_secs(5))).unwrap();
+    assert_eq!(exit.join().unwrap().signal(), Some(15));
+
+    let (pid, exit) = spawn("trap '' TERM; exec sleep 30");
+    task::block_on(terminate(pid, Duration::from_millis(500))).unwrap();
+    assert_eq!(exit.join().unwrap().signal(), Some(9));
+}
+
+// how long a guest gets to shut down after the power button was pressed, unless the caller of
+// `stop` asks for a different grace period
+pub(crate) const DEFAULT_POWERDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+const QMP_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct QmpStatus {
+    pub(crate) status: String,
+    pub(crate) running: bool,
+}
+
+// QEMU machine protocol client, connected to the `-qmp` socket of a vm. Commands are executed
+// one at a time, asynchronous events in between are skipped.
+pub(crate) struct QmpClient {
+    socket: UnixStream,
+    replies: BufReader<UnixStream>,
+}
+
+impl QmpClient {
+    pub(crate) async fn connect(path: &Path) -> Result<Self> {
+        let socket = UnixStream::connect(path)
+            .await
+            .map_err(|e| QemuError::IO(e, "connecting to qmp"))?;
+        let mut client = QmpClient {
+            replies: BufReader::new(socket.clone()),
+            socket,
+        };
+        let greeting = client.read_message().await?;
+        if greeting.get("QMP").is_none() {
+            return Err(QemuError::Qmp(
+                "greeting".to_string(),
+                format!("unexpected message {greeting}"),
+            ));
+        }
+        client.execute("qmp_capabilities", None).await?;
+        Ok(client)
+    }
+
+    async fn read_message(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        let read = io::timeout(QMP_TIMEOUT, self.replies.read_line(&mut line))
+            .await
+            .map_err(|e| QemuError::IO(e, "reading from qmp"))?;
+        if read == 0 {
+            return Err(QemuError::IO(
+                ErrorKind::UnexpectedEof.into(),
+                "reading from qmp",
+            ));
+        }
+        serde_json::from_str(&line)
+            .map_err(|e| QemuError::Qmp("reply".to_string(), format!("invalid json: {e}")))
+    }
+
+    // The `return` value of the command, or its error description
+    pub(crate) async fn execute(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        self.socket
+            .write_all(format!("{request}\n").as_bytes())
+            .await
+            .map_err(|e| QemuError::IO(e, "writing to qmp"))?;
+        loop {
+            let mut reply = self.read_message().await?;
+            if let Some(value) = reply.get_mut("return") {
+                return Ok(value.take());
+            }
+            if let Some(error) = reply.get("error") {
+                let description = error["desc"].as_str().unwrap_or("unknown error");
+                return Err(QemuError::Qmp(command.to_string(), description.to_string()));
+            }
+            if reply.get("event").is_none() {
+                return Err(QemuError::Qmp(
+                    command.to_string(),
+                    format!("unexpected message {reply}"),
+                ));
+            }
+        }
+    }
+
+    pub(crate) async fn query_status(&mut self) -> Result<QmpStatus> {
+        let status = self.execute("query-status", None).await?;
+        serde_json::from_value(status)
+            .map_err(|e| QemuError::Qmp("query-status".to_string(), e.to_string()))
+    }
+
+    // Presses the power button, the guest decides whether to shut down
+    pub(crate) async fn system_powerdown(&mut self) -> Result<()> {
+        self.execute("system_powerdown", None).await.map(|_| ())
+    }
+
+    pub(crate) async fn quit(&mut self) -> Result<()> {
+        match self.execute("quit", None).await {
+            // qemu may exit before its reply is read
+            Err(QemuError::IO(e, _)) if e.kind() == ErrorKind::UnexpectedEof => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    pub(crate) async fn human_monitor_command(&mut self, command_line: &str) -> Result<String> {
+        let reply = self
+            .execute(
+                "human-monitor-command",
+                Some(json!({ "command-line": command_line })),
+            )
+            .await?;
+        Ok(reply.as_str().unwrap_or_default().to_string())
+    }
+}
+
+#[test]
+fn qmp_client() {
+    use async_std::os::unix::net::UnixListener;
+    use async_std::stream::StreamExt;
+
+    let dir = tempdir::TempDir::new("qmp").unwrap();
+    let path = dir.path().join(QMP_SOCKET);
+    task::block_on(async {
+        let listener = UnixListener::bind(&path).await.unwrap();
+        let server = task::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut requests = BufReader::new(socket.clone()).lines();
+            let send = |message: &str| {
+                let message = format!("{message}\r\n");
+                let mut socket = socket.clone();
+                async move { socket.write_all(message.as_bytes()).await.unwrap() }
+            };
+            send(r#"{"QMP": {"version": {}, "capabilities": []}}"#).await;
+            let mut received = vec![];
+            while let Some(Ok(request)) = requests.next().await {
+                let request: Value = serde_json::from_str(&request).unwrap();
+                let command = request["execute"].as_str().unwrap().to_string();
+                match command.as_str() {
+                    "query-status" => {
+                        send(r#"{"event": "RESUME", "timestamp": {}}"#).await;
+                        send(r#"{"return": {"status": "running", "singlestep": false, "running": true}}"#)
+                            .await
+                    }
+                    "human-monitor-command" => {
+                        send(r#"{"return": "Error: Device 'cpu1' not found\r\n"}"#).await
+                    }
+                    "system_powerdown" => {
+                        send(r#"{"error": {"class": "GenericError", "desc": "not supported"}}"#)
+                            .await
+                    }
+                    "quit" => {
+                        received.push(command);
+                        break;
+                    }
+                    _ => send(r#"{"return": {}}"#).await,
+                }
+                received.push(command);
+            }
+            socket.shutdown(std::net::Shutdown::Both).unwrap();
+            received
+        });
+
+        let mut qmp = QmpClient::connect(&path).await.unwrap();
+        let status = qmp.query_status().await.unwrap();
+        assert!(status.running);
+        assert_eq!(status.status, "running");
+        assert_eq!(
+            qmp.human_monitor_command("device_del cpu1").await.unwrap(),
+            "Error: Device 'cpu1' not found\r\n"
+        );
+        assert!(matches!(
+            qmp.system_powerdown().await,
+            Err(QemuError::Qmp(command, description))
+                if command == "system_powerdown" && description == "not supported"
+        ));
+        qmp.quit().await.unwrap();
+        assert_eq!(
+            server.await,
+            vec![
+                "qmp_capabilities",
+                "query-status",
+                "human-monitor-command",
+                "system_powerdown",
+                "quit"
+            ]
+        );
+    });
+}
+
+const MIGRATION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// What a restore needs besides the migrated state: the disk as the vm was paused with it and the
+// network identity the guest configured, kept next to the state file
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CapturedVm {
+    pub(crate) disk: PathBuf,
+    pub(crate) tap: String,
+    pub(crate) ip: Ipv4Addr,
+    pub(crate) mac: String,
+}
+
+impl CapturedVm {
+    fn sidecar(state: &Path, extension: &str) -> PathBuf {
+        let mut path = state.as_os_str().to_owned();
+        path.push(".");
+        path.push(extension);
+        PathBuf::from(path)
+    }
+
+    fn save(&self, state: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).unwrap();
+        std::fs::write(Self::sidecar(state, "json"), json)
+            .map_err(|e| QemuError::CapturedVm(e, "write", state.to_path_buf()))
+    }
+
+    pub(crate) fn load(state: &Path) -> Result<Self> {
+        let json = std::fs::read(Self::sidecar(state, "json"))
+            .map_err(|e| QemuError::CapturedVm(e, "read", state.to_path_buf()))?;
+        serde_json::from_slice(&json)
+            .map_err(|e| QemuError::CapturedVm(e.into(), "parse", state.to_path_buf()))
+    }
+}
+
+// `Migration status: failed (Unable to write to command)` in the reply to `info migrate`
+fn migration_status(info: &str) -> Option<&str> {
+    info.lines()
+        .find_map(|l| l.trim().strip_prefix("Migration status:"))
+        .map(str::trim)
+}
+
+#[test]
+fn migration() {
+    let info = "info migrate\r\nglobals:\r\nstore-global-state: on\r\n\
+                Migration status: active\r\ntotal time: 1043 ms\r\n(qemu) ";
+    assert_eq!(migration_status(info), Some("active"));
+    assert_eq!(migration_status("info migrate\r\n(qemu) "), None);
+
+    let qr = QemuRunMode {
+        monitor: None,
+        qmp: None,
+        serial: None,
+        display: true,
+        vnc: None,
+        gdb: None,
+        daemonize_pidfile: None,
+        incoming: Some(PathBuf::from("/tmp/worker's 1.state")),
+    };
+    assert_eq!(
+        qr.as_args().collect::<Vec<_>>(),
+        vec!["-incoming", "exec:cat '/tmp/worker'\\''s 1.state'"]
+    );
+
+    let dir = tempdir::TempDir::new("capture").unwrap();
+    let state = dir.path().join("worker-1.state");
+    let captured = CapturedVm {
+        disk: CapturedVm::sidecar(&state, "disk"),
+        tap: "tap1".to_string(),
+        ip: Ipv4Addr::new(10, 0, 0, 2),
+        mac: "00:60:2F:01:02:03".to_string(),
+    };
+    assert_eq!(captured.disk, dir.path().join("worker-1.state.disk"));
+    captured.save(&state).unwrap();
+    assert_eq!(CapturedVm::load(&state).unwrap(), captured);
+}
+
+#[test]
+fn vnc_displays() {
+    let vnc: VncDisplay = ":1".parse().unwrap();
+    assert_eq!(vnc.for_instance(3), ":4");
+    let vnc: VncDisplay = "0.0.0.0:10".parse().unwrap();
+    assert_eq!(vnc.for_instance(0), "0.0.0.0:10");
+    assert!("1".parse::<VncDisplay>().is_err());
+    assert!(":1,password=on".parse::<VncDisplay>().is_err());
+
+    let qr = QemuRunMode {
+        monitor: None,
+        qmp: None,
+        serial: None,
+        display: false,
+        vnc: Some(":4".to_string()),
+        gdb: None,
+        daemonize_pidfile: None,
+        incoming: None,
+    };
+    assert_eq!(qr.as_args().collect::<Vec<_>>(), vec!["-vnc", ":4"]);
+}
+
+#[test]
+fn gdb_stub() {
+    let mut qr = QemuRunMode {
+        monitor: None,
+        qmp: None,
+        serial: None,
+        display: true,
+        vnc: None,
+        gdb: Some(GdbStub {
+            port: 1234,
+            wait: false,
+        }),
+        daemonize_pidfile: None,
+        incoming: None,
+    };
+    assert_eq!(qr.as_args().collect::<Vec<_>>(), vec!["-gdb", "tcp::1234"]);
+    qr.gdb = Some(GdbStub {
+        port: 1234,
+        wait: true,
+    });
+    assert_eq!(
+        qr.as_args().collect::<Vec<_>>(),
+        vec!["-gdb", "tcp::1234", "-S"]
+    );
+    assert_eq!(
+        qr.gdb.unwrap().to_string(),
+        "tcp::1234 (frozen until continued)"
+    );
+    assert_eq!(
+        qr.gdb.unwrap().connect_command(),
+        "gdb -ex 'target remote localhost:1234'"
+    );
+}
+
+// Where the lines read from a guest console end up
+#[derive(Debug)]
+pub(crate) enum SerialSink {
+    // prefixed with the instance id
+    Stdout(usize),
+    File(std::fs::File),
+    Channel(Sender<String>),
+    // takes every line without echoing it, keeps the console read when nothing else does
+    Discard,
+    // journalctl json records are logged at their priority, other lines printed like Stdout
+    Journal(usize),
+}
+
+impl SerialSink {
+    // Returns false once the sink cannot take any more lines. Subscribers always get the bare
+    // line, the timestamp only prefixes what is printed and logged.
+    fn write_line(&mut self, line: &str, timestamp: Option<&str>) -> bool {
+        let prefix = timestamp.map(|t| format!("[{t}]")).unwrap_or_default();
+        match self {
+            SerialSink::Stdout(node_id) => {
+                println!("{}[{}] {}", prefix, node_id, line);
+                true
+            }
+            SerialSink::File(file) => match timestamp {
+                Some(_) => writeln!(file, "{prefix} {line}").is_ok(),
+                None => writeln!(file, "{line}").is_ok(),
+            },
+            SerialSink::Channel(sender) => sender.try_send(line.to_string()).is_ok(),
+            SerialSink::Discard => true,
+            SerialSink::Journal(node_id) => {
+                match JournalEntry::parse(line) {
+                    Some(entry) => entry.emit(*node_id),
+                    None => println!("{}[{}] {}", prefix, node_id, line),
+                }
+                true
+            }
+        }
+    }
+}
+
+// A single connection to the guest console. One reader hands every line to all sinks, so
+// the live stream, a log file and readiness checks don't compete for the output.
+#[derive(Debug, Clone)]
+pub(crate) struct SerialConsole {
+    source: Arc<dyn SerialSource>,
+    sinks: Arc<Mutex<Vec<SerialSink>>>,
+    options: SerialOptions,
+    stats: Arc<SerialStats>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SerialOptions {
+    // lines longer than this are split
+    pub(crate) buffer_size: usize,
+    // give up if the guest did not write a single byte within this time, e.g. because the
+    // image has its serial console disabled
+    pub(crate) no_output_timeout: Option<Duration>,
+    // prefix printed and logged lines with the UTC time their newline was read, so the output
+    // of several vms can be ordered
+    pub(crate) timestamps: bool,
+}
+
+impl Default for SerialOptions {
+    fn default() -> Self {
+        SerialOptions {
+            buffer_size: DEFAULT_SERIAL_BUFFER_SIZE,
+            no_output_timeout: None,
+            timestamps: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SerialStats {
+    bytes_read: AtomicU64,
+    since: Instant,
 }
 
-struct PidNoLongerExists {
-    pid: usize,
-    ps_process_future: Option<Pin<Box<dyn Future<Output = io::Result<ExitStatus>>>>>,
+impl SerialStats {
+    // average since the console was connected
+    pub(crate) fn bytes_per_second(&self) -> f64 {
+        self.bytes_read.load(Ordering::Relaxed) as f64 / self.since.elapsed().as_secs_f64()
+    }
 }
 
-impl QemuProcessHandle {
-    fn monitor_path(&self) -> PathBuf {
-        self.lc
-            .as_ref()
-            .expect("invalid state")
-            .temp_dir
-            .path()
-            .join("monitor.socket")
+impl Display for SerialStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "serial: {} bytes, {:.1} B/s",
+            self.bytes_read.load(Ordering::Relaxed),
+            self.bytes_per_second()
+        )
     }
-    pub fn serial_path(&self) -> PathBuf {
-        self.lc
-            .as_ref()
-            .expect("invalid state")
-            .temp_dir
-            .path()
-            .join("serial.socket")
+}
+
+pub(crate) const DEFAULT_SERIAL_BUFFER_SIZE: usize = 4096;
+
+type SerialReader = Box<dyn io::Read + Unpin + Send>;
+type SerialWriter = Box<dyn io::Write + Unpin + Send>;
+
+// Where the console output comes from and input goes to
+pub(crate) trait SerialSource: std::fmt::Debug + Send + Sync {
+    fn reader(&self) -> io::Result<SerialReader>;
+    fn writer(&self) -> io::Result<SerialWriter>;
+}
+
+impl SerialSource for UnixStream {
+    fn reader(&self) -> io::Result<SerialReader> {
+        Ok(Box::new(self.clone()))
     }
-    fn pid_file_path(&self) -> PathBuf {
-        self.lc
-            .as_ref()
-            .expect("invalid state")
-            .temp_dir
-            .path()
-            .join("pidfile")
+    fn writer(&self) -> io::Result<SerialWriter> {
+        Ok(Box::new(self.clone()))
     }
+}
 
-    #[instrument]
-    pub(crate) async fn restart(&mut self) -> Result<()> {
-        self.lc = start_qemu(self.lc.take().unwrap()).await?.lc.take();
-        Ok(())
+// Feeds a recorded console log instead of a vm's output, so readiness detection can be tested
+// without booting anything. Input is discarded.
+#[derive(Debug)]
+pub(crate) struct SerialReplay {
+    log: PathBuf,
+}
+
+impl SerialSource for SerialReplay {
+    fn reader(&self) -> io::Result<SerialReader> {
+        Ok(Box::new(async_std::fs::File::from(std::fs::File::open(
+            &self.log,
+        )?)))
     }
-    #[instrument]
-    pub(crate) async fn stop(&self) -> Result<()> {
-        if !self.is_running().await? {
-            return Ok(());
+    fn writer(&self) -> io::Result<SerialWriter> {
+        Ok(Box::new(io::sink()))
+    }
+}
+
+impl SerialConsole {
+    fn new(
+        source: impl SerialSource + 'static,
+        sinks: Vec<SerialSink>,
+        options: SerialOptions,
+    ) -> Self {
+        SerialConsole {
+            source: Arc::new(source),
+            sinks: Arc::new(Mutex::new(sinks)),
+            options,
+            stats: Arc::new(SerialStats {
+                bytes_read: AtomicU64::new(0),
+                since: Instant::now(),
+            }),
         }
+    }
 
-        let pid = self.get_pid().await?;
-        let mut monitor_socket = UnixStream::connect(self.monitor_path())
-            .await
-            .expect("Could not open monitor socket");
-        monitor_socket
-            .write_all("q\n".as_bytes())
-            .await
-            .expect("Could not write to socket");
+    pub(crate) async fn connect(
+        serial_socket: PathBuf,
+        sinks: Vec<SerialSink>,
+        options: SerialOptions,
+    ) -> core::result::Result<Self, SerialError> {
+        let connection =
+            io::timeout(Duration::from_secs(1), UnixStream::connect(serial_socket)).await;
+        Ok(Self::new(
+            connection.map_err(SerialError::Connecting)?,
+            sinks,
+            options,
+        ))
+    }
 
-        let wait_until_pid_stops_existing = async {
-            while run_command_without_output("ps", vec!["-p", &pid.to_string()]).await? {}
-            Ok(())
-        };
+    pub(crate) fn replay(log: PathBuf, sinks: Vec<SerialSink>, options: SerialOptions) -> Self {
+        Self::new(SerialReplay { log }, sinks, options)
+    }
 
-        match async_std::future::timeout(Duration::from_secs(2), wait_until_pid_stops_existing)
-            .await
-        {
-            Ok(r) => r.map_err(QemuError::Shell),
-            Err(_) => {
-                let killed =
-                    shell::run_command_without_output("kill", vec!["-9", &pid.to_string()])
-                        .await
-                        .map_err(QemuError::Shell)?;
-                if killed {
-                    Ok(())
-                } else {
-                    Err(QemuError::CouldNotKill("kill failed"))
-                }
-            }
-        }
+    // A console which is already connected, e.g. firecracker's stdin and stdout
+    pub(crate) fn attach(
+        stream: UnixStream,
+        sinks: Vec<SerialSink>,
+        options: SerialOptions,
+    ) -> Self {
+        Self::new(stream, sinks, options)
     }
-    async fn get_pid(&self) -> Result<usize> {
-        let pid_file_path = self
-            .lc
-            .as_ref()
-            .expect("qemu handle in invalid state")
-            .temp_dir
-            .path()
-            .join("pidfile");
 
-        let mut buf = vec![0; 64];
-        let read_len = match async_std::fs::File::open(pid_file_path).await {
-            Ok(mut f) => f
-                .read(&mut buf)
-                .await
-                .map_err(|e| QemuError::IO(e, "reading pidfile"))?,
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                return Err(QemuError::NotRunning());
-            }
-            Err(e) => {
-                return Err(QemuError::IO(e, "opening pidfile"));
-            }
-        };
+    pub(crate) fn stats(&self) -> &SerialStats {
+        &self.stats
+    }
 
-        assert!(read_len > 0);
-        let pid_slice = from_utf8(&buf[0..read_len - 1]).map_err(QemuError::PidFileNonUtf)?;
-        pid_slice
-            .parse::<usize>()
-            .map_err(QemuError::PidFileNonNumeric)
+    // Replaces where the output goes, subscribers keep receiving lines
+    pub(crate) fn set_sinks(&self, sinks: Vec<SerialSink>) {
+        let mut current = self.sinks.lock().unwrap();
+        current.retain(|sink| matches!(sink, SerialSink::Channel(_)));
+        current.extend(sinks);
     }
-    // Test if the pid file exists
-    async fn is_running(&self) -> Result<bool> {
-        match self.get_pid().await {
-            Ok(pid) => run_command_without_output("ps", vec!["-p", &pid.to_string()])
-                .await
-                .map_err(QemuError::Shell),
-            Err(QemuError::NotRunning()) => Ok(false),
-            Err(e) => Err(e),
-        }
+
+    // Receives all lines from now on, until the receiver is dropped
+    pub(crate) fn subscribe(&self) -> Receiver<String> {
+        let (sender, receiver) = async_std::channel::unbounded();
+        self.sinks.lock().unwrap().push(SerialSink::Channel(sender));
+        receiver
     }
-}
 
-impl Display for QemuProcessHandle {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!(
-            "TapDevice: {}, Ip: {}",
-            self.lc.as_ref().unwrap().tap.device(),
-            self.lc.as_ref().unwrap().tap.ip()
-        ))
+    pub(crate) async fn write(&self, input: &str) -> core::result::Result<(), SerialError> {
+        self.source
+            .writer()
+            .map_err(SerialError::Writing)?
+            .write_all(input.as_bytes())
+            .await
+            .map_err(SerialError::Writing)
     }
-}
 
-impl Drop for QemuProcessHandle {
-    fn drop(&mut self) {
-        if self.lc.is_some() {
-            info!("Stopping Qemu");
-            if let Err(e) = task::block_on(self.stop()) {
-                error!("Failed to stop qemu: {e:?}");
-            }
-        }
+    // Runs until reading fails, the output ends or no sink is left. Subscribers are closed
+    // afterwards, as no more lines will arrive.
+    pub(crate) async fn read(self) -> core::result::Result<(), SerialError> {
+        let mut reader = self.source.reader().map_err(SerialError::Reading)?;
+        let result = serial_read_lines(&mut reader, self.options, &self.stats, |line| {
+            // lines are handed out as soon as the read with their newline returned
+            let timestamp = self.options.timestamps.then(line_timestamp);
+            let mut sinks = self.sinks.lock().unwrap();
+            sinks.retain_mut(|sink| sink.write_line(line, timestamp.as_deref()));
+            sinks.is_empty()
+        })
+        .await;
+        self.sinks.lock().unwrap().clear();
+        result
     }
 }
 
-pub async fn serial_with_command(
-    command: &str,
-    serial_socket: PathBuf,
-    node_id: usize,
-) -> core::result::Result<(), SerialError> {
-    let connection = io::timeout(Duration::from_secs(1), UnixStream::connect(serial_socket)).await;
-    let mut connection = connection.map_err(SerialError::Connecting)?;
-
-    connection
-        .write_all(command.as_bytes())
-        .await
-        .map_err(SerialError::Writing)?;
-
-    serial_listen(connection, node_id).await
+// e.g. 2024-01-01T12:00:00Z
+fn line_timestamp() -> String {
+    DateTime::<Utc>::from(SystemTime::now()).to_rfc3339_opts(SecondsFormat::Secs, true)
 }
 
-async fn serial_listen(
-    mut connection: UnixStream,
-    node_id: usize,
+// Reads lines until `f` returns true
+async fn serial_read_lines(
+    connection: &mut (impl io::Read + Unpin),
+    options: SerialOptions,
+    stats: &SerialStats,
+    mut f: impl FnMut(&str) -> bool,
 ) -> core::result::Result<(), SerialError> {
-    let mut buf = vec![0u8; 4096];
+    let mut buf = vec![0u8; options.buffer_size];
     let mut current_index = 0;
     loop {
         let result = io::timeout(
@@ -472,35 +2418,177 @@ async fn serial_listen(
         let result = match result {
             Err(e) => {
                 if e.kind() == io::ErrorKind::TimedOut {
-                    continue;
+                    match options.no_output_timeout {
+                        Some(timeout)
+                            if stats.bytes_read.load(Ordering::Relaxed) == 0
+                                && stats.since.elapsed() >= timeout =>
+                        {
+                            return Err(SerialError::NoOutput(timeout));
+                        }
+                        _ => continue,
+                    }
                 } else {
                     return Err(SerialError::Reading(e));
                 }
             }
+            Ok(0) => {
+                // the output ended, e.g. a replayed log without a trailing newline
+                if current_index > 0 {
+                    f(&String::from_utf8_lossy(&buf[..current_index]));
+                }
+                return Err(SerialError::Closed);
+            }
             Ok(r) => r,
         };
+        stats.bytes_read.fetch_add(result as u64, Ordering::Relaxed);
 
+        let mut done = false;
         (buf, current_index) = chunk_to_lines(buf, current_index + result, |line| {
-            println!("[{}] {}", node_id, line);
+            done |= f(line);
         })?;
+        // no newline in a full buffer, hand out what we have instead of reading nothing
+        if current_index == buf.len() {
+            done |= f(&String::from_utf8_lossy(&buf));
+            current_index = 0;
+        }
+
+        if done {
+            return Ok(());
+        }
     }
 }
 
-pub async fn serial(
-    serial_socket: PathBuf,
-    node_id: usize,
+const BOOT_TIMEOUT_TAIL_LINES: usize = 20;
+
+// `lines` has to be subscribed to a console which is being read
+// Without a timeout it waits until the marker shows up or the console closes
+pub async fn wait_for_serial_marker(
+    lines: Receiver<String>,
+    marker: &Regex,
+    boot_timeout: Option<Duration>,
 ) -> core::result::Result<(), SerialError> {
-    let connection = io::timeout(Duration::from_secs(1), UnixStream::connect(serial_socket)).await;
-    let mut connection = connection.map_err(SerialError::Connecting)?;
-    serial_listen(connection, node_id).await
+    let mut last_lines = VecDeque::with_capacity(BOOT_TIMEOUT_TAIL_LINES);
+    let wait_for_marker = async {
+        while let Ok(line) = lines.recv().await {
+            if last_lines.len() == BOOT_TIMEOUT_TAIL_LINES {
+                last_lines.pop_front();
+            }
+            let found = marker.is_match(&line);
+            last_lines.push_back(line);
+            if found {
+                return Ok(());
+            }
+        }
+        Err(SerialError::Closed)
+    };
+
+    let Some(boot_timeout) = boot_timeout else {
+        return wait_for_marker.await;
+    };
+    let result = async_std::future::timeout(boot_timeout, wait_for_marker).await;
+    match result {
+        Ok(r) => r,
+        Err(_) => Err(SerialError::BootTimeout(last_lines.into())),
+    }
+}
+
+#[test]
+fn regex_boot_marker() {
+    let marker = Regex::new("nesWorker.*started").unwrap();
+    let wait = |lines: &[&str]| {
+        let (sender, receiver) = async_std::channel::unbounded();
+        for line in lines {
+            sender.try_send(line.to_string()).unwrap();
+        }
+        drop(sender);
+        task::block_on(wait_for_serial_marker(
+            receiver,
+            &marker,
+            Some(Duration::from_secs(1)),
+        ))
+    };
+    assert!(wait(&["booting", "nesWorker 3 started on 10.0.0.3"]).is_ok());
+    assert!(matches!(
+        wait(&["nesWorker 3 starting"]),
+        Err(SerialError::Closed)
+    ));
+}
+
+const CAPTURE_BEGIN_MARKER: &str = "__VMLAUNCHER_BEGIN__";
+const CAPTURE_EXIT_STATUS: &str = "__RC=";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub lines: Vec<String>,
+    // None if the capture stopped at the terminator, before the command finished
+    pub exit_status: Option<i32>,
+}
+
+impl CommandOutput {
+    pub fn output(&self) -> String {
+        self.lines.join("\n")
+    }
+}
+
+// Runs `command` on the guest console and returns its output and exit status. The capture stops
+// early at the first line containing `terminator`, for commands which keep running. Interrupts
+// whatever is currently running in the foreground of the console.
+pub async fn serial_capture(
+    console: &SerialConsole,
+    command: &str,
+    terminator: Option<&str>,
+    timeout: Duration,
+) -> core::result::Result<CommandOutput, SerialError> {
+    let lines = console.subscribe();
+    console
+        .write(&format!(
+            "\x03\necho {CAPTURE_BEGIN_MARKER}; {command}; echo {CAPTURE_EXIT_STATUS}$?\n"
+        ))
+        .await?;
+
+    let capture = async {
+        let mut output = None;
+        while let Ok(line) = lines.recv().await {
+            let line = line.trim_end_matches('\r');
+            let Some(captured) = output.as_mut() else {
+                if line == CAPTURE_BEGIN_MARKER {
+                    output = Some(vec![]);
+                }
+                continue;
+            };
+            if let Some(status) = line
+                .strip_prefix(CAPTURE_EXIT_STATUS)
+                .and_then(|s| s.parse().ok())
+            {
+                return Ok(CommandOutput {
+                    lines: output.unwrap_or_default(),
+                    exit_status: Some(status),
+                });
+            }
+            captured.push(line.to_string());
+            if terminator.is_some_and(|t| line.contains(t)) {
+                return Ok(CommandOutput {
+                    lines: output.unwrap_or_default(),
+                    exit_status: None,
+                });
+            }
+        }
+        Err(SerialError::Closed)
+    };
+
+    match async_std::future::timeout(timeout, capture).await {
+        Ok(r) => r,
+        Err(_) => Err(SerialError::CaptureTimeout(timeout)),
+    }
 }
 
 fn chunk_to_lines(
     mut buf: Vec<u8>,
     bytes_used: usize,
-    f: impl Fn(&str),
+    mut f: impl FnMut(&str),
 ) -> core::result::Result<(Vec<u8>, usize), SerialError> {
     let mut current_index = bytes_used;
+    let buffer_size = buf.len();
     let output = from_utf8(&buf[0..bytes_used]).map_err(SerialError::UTF8)?;
     match output.rfind('\n') {
         None => {}
@@ -510,13 +2598,275 @@ fn chunk_to_lines(
             }
             current_index = bytes_used - (size + 1);
             buf.drain(0..size + 1);
-            buf.resize(4096, 0);
+            buf.resize(buffer_size, 0);
         }
     }
 
     Ok((buf, current_index))
 }
 
+#[test]
+fn serial_lines_reach_all_sinks() {
+    task::block_on(async {
+        let (guest, host) = UnixStream::pair().unwrap();
+        let (sender, first) = async_std::channel::unbounded();
+        let console = SerialConsole::new(
+            host,
+            vec![SerialSink::Channel(sender)],
+            SerialOptions::default(),
+        );
+        let second = console.subscribe();
+        let reader = task::spawn(console.clone().read());
+
+        (&guest).write_all(b"booting\nready\n").await.unwrap();
+        for lines in [&first, &second] {
+            assert_eq!(lines.recv().await.unwrap(), "booting");
+            assert_eq!(lines.recv().await.unwrap(), "ready");
+        }
+
+        // the reader stops once every sink is gone
+        drop((first, second));
+        (&guest).write_all(b"shutdown\n").await.unwrap();
+        assert!(reader.await.is_ok());
+    });
+}
+
+#[test]
+fn captured_command_output() {
+    task::block_on(async {
+        let (guest, host) = UnixStream::pair().unwrap();
+        let console = SerialConsole::new(host, vec![], SerialOptions::default());
+        let reader = task::spawn(console.clone().read());
+        let guest_output = async {
+            task::sleep(Duration::from_millis(50)).await;
+            // the console echoes the command line before running it
+            (&guest)
+                .write_all(
+                    b"$ echo __VMLAUNCHER_BEGIN__; ls /missing; echo __RC=$?\r\n\
+                    __VMLAUNCHER_BEGIN__\r\nls: /missing: No such file\r\n__RC=2\r\n",
+                )
+                .await
+                .unwrap();
+        };
+        let (output, _) = futures::future::join(
+            serial_capture(&console, "ls /missing", None, Duration::from_secs(5)),
+            guest_output,
+        )
+        .await;
+        let output = output.unwrap();
+        assert_eq!(output.output(), "ls: /missing: No such file");
+        assert_eq!(output.exit_status, Some(2));
+
+        let guest_output = async {
+            task::sleep(Duration::from_millis(50)).await;
+            (&guest)
+                .write_all(b"__VMLAUNCHER_BEGIN__\nstarting\nlistening on 8432\nserving\n")
+                .await
+                .unwrap();
+        };
+        let (output, _) = futures::future::join(
+            serial_capture(
+                &console,
+                "worker",
+                Some("listening"),
+                Duration::from_secs(5),
+            ),
+            guest_output,
+        )
+        .await;
+        let output = output.unwrap();
+        assert_eq!(output.lines, vec!["starting", "listening on 8432"]);
+        assert_eq!(output.exit_status, None);
+
+        drop(guest);
+        let _ = reader.await;
+    });
+}
+
+#[test]
+fn replayed_boot_log() {
+    let dir = tempdir::TempDir::new("replay").unwrap();
+    let booted = dir.path().join("booted.log");
+    let crashed = dir.path().join("crashed.log");
+    std::fs::write(
+        &booted,
+        "[    0.000000] Linux version 6.1.73-flatcar\r\nFlatcar Container Linux\r\n\
+        localhost login: ",
+    )
+    .unwrap();
+    std::fs::write(&crashed, "[    1.200000] Kernel panic - not syncing\n").unwrap();
+
+    let replay = |log: &PathBuf| {
+        let console = SerialConsole::replay(log.clone(), vec![], SerialOptions::default());
+        let lines = console.subscribe();
+        task::block_on(async {
+            let reader = task::spawn(console.read());
+            let marker = Regex::new("log(in)?:").unwrap();
+            let result = wait_for_serial_marker(lines, &marker, Some(Duration::from_secs(5))).await;
+            assert!(matches!(reader.await, Err(SerialError::Closed)));
+            result
+        })
+    };
+    assert!(replay(&booted).is_ok());
+    assert!(matches!(replay(&crashed), Err(SerialError::Closed)));
+}
+
+#[test]
+fn machine_properties() {
+    let qv = QemuVirtualizationMode {
+        machine: Some(Q35),
+        properties: vec!["kernel-irqchip=split".parse().unwrap()],
+        cpu: None,
+        accel: Some("kvm".to_string()),
+    };
+    let args = qv.as_args().collect::<Vec<_>>();
+    assert_eq!(args.iter().filter(|a| *a == "-machine").count(), 1);
+    assert_eq!(
+        args[..2],
+        ["-machine", "type=q35,accel=kvm,kernel-irqchip=split"]
+    );
+
+    assert!("kernel-irqchip".parse::<MachineProperty>().is_err());
+    assert!("a=1,b=2".parse::<MachineProperty>().is_err());
+}
+
+#[test]
+fn small_serial_buffer() {
+    task::block_on(async {
+        let (guest, host) = UnixStream::pair().unwrap();
+        let console = SerialConsole::new(
+            host,
+            vec![],
+            SerialOptions {
+                buffer_size: 8,
+                no_output_timeout: None,
+                timestamps: false,
+            },
+        );
+        let lines = console.subscribe();
+        let reader = task::spawn(console.clone().read());
+
+        (&guest).write_all(b"0123456789\nok\n").await.unwrap();
+        assert_eq!(lines.recv().await.unwrap(), "01234567");
+        assert_eq!(lines.recv().await.unwrap(), "89");
+        assert_eq!(lines.recv().await.unwrap(), "ok");
+        assert_eq!(console.stats().bytes_read.load(Ordering::Relaxed), 14);
+
+        drop(lines);
+        (&guest).write_all(b"bye\n").await.unwrap();
+        assert!(reader.await.is_ok());
+    });
+}
+
+#[test]
+fn silent_serial_console() {
+    task::block_on(async {
+        let (_guest, host) = UnixStream::pair().unwrap();
+        let options = SerialOptions {
+            no_output_timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let console = SerialConsole::new(host, vec![SerialSink::Stdout(0)], options);
+        assert!(matches!(
+            console.read().await,
+            Err(SerialError::NoOutput(_))
+        ));
+    });
+}
+
+#[test]
+fn timestamped_serial_lines() {
+    let dir = tempdir::TempDir::new("serial").unwrap();
+    let log = dir.path().join("serial.log");
+    task::block_on(async {
+        let (guest, host) = UnixStream::pair().unwrap();
+        let options = SerialOptions {
+            timestamps: true,
+            ..Default::default()
+        };
+        let console = SerialConsole::new(
+            host,
+            vec![SerialSink::File(std::fs::File::create(&log).unwrap())],
+            options,
+        );
+        let lines = console.subscribe();
+        let reader = task::spawn(console.read());
+        (&guest).write_all(b"booted\n").await.unwrap();
+        assert_eq!(lines.recv().await.unwrap(), "booted");
+        drop(guest);
+        assert!(matches!(reader.await, Err(SerialError::Closed)));
+    });
+
+    let logged = std::fs::read_to_string(&log).unwrap();
+    let (timestamp, line) = logged
+        .strip_prefix('[')
+        .and_then(|l| l.split_once("] "))
+        .unwrap();
+    assert!(DateTime::parse_from_rfc3339(timestamp).is_ok());
+    assert!(timestamp.ends_with('Z'));
+    assert_eq!(line, "booted\n");
+}
+
+#[test]
+fn pflash_firmware() {
+    let firmware = tempdir::TempDir::new("ovmf").unwrap();
+    let vm = tempdir::TempDir::new("vm").unwrap();
+    let code = firmware.path().join("OVMF_CODE.fd");
+    let vars = firmware.path().join("OVMF_VARS.fd");
+    std::fs::write(&code, b"code").unwrap();
+
+    let missing = task::block_on(PflashConfig::prepare(&code, &vars, vm.path()));
+    assert!(matches!(missing, Err(QemuError::MissingFirmware(path)) if path == vars));
+
+    std::fs::write(&vars, b"vars").unwrap();
+    let pflash = task::block_on(PflashConfig::prepare(&code, &vars, vm.path())).unwrap();
+    let vm_vars = vm.path().join("OVMF_VARS.fd");
+    assert_eq!(std::fs::read(&vm_vars).unwrap(), b"vars");
+    assert_eq!(
+        pflash.as_args().collect::<Vec<_>>(),
+        vec![
+            "-drive".to_string(),
+            format!(
+                "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                code.display()
+            ),
+            "-drive".to_string(),
+            format!("if=pflash,format=raw,unit=1,file={}", vm_vars.display()),
+        ]
+    );
+}
+
+#[test]
+fn security_args() {
+    assert_eq!(SecurityConfig::default().as_args().count(), 0);
+
+    let sandboxed = SecurityConfig {
+        sandbox: true,
+        run_as: None,
+    };
+    assert_eq!(
+        sandboxed.as_args().collect::<Vec<_>>(),
+        vec![
+            "-sandbox",
+            "on,obsolete=deny,elevateprivileges=deny,resourcecontrol=deny"
+        ]
+    );
+
+    let dropped = SecurityConfig {
+        sandbox: true,
+        run_as: Some("nobody".to_string()),
+    };
+    assert_eq!(
+        dropped.as_args().collect::<Vec<_>>(),
+        vec![
+            "-sandbox",
+            "on,obsolete=deny,elevateprivileges=allow,resourcecontrol=deny",
+            "-runas",
+            "nobody"
+        ]
+    );
+}
+
 #[test]
 fn test_chunk_to_lines() {
     let mut vec = vec![0u8; 4096];
@@ -593,6 +2943,79 @@ pub enum QemuError {
     PidFileNonNumeric(#[source] std::num::ParseIntError),
     #[error("Could not kill qemu process")]
     CouldNotKill(&'static str),
+    #[error("Monitor command `{0}` failed: {1}")]
+    Monitor(String, String),
+    #[error("QMP command `{0}` failed: {1}")]
+    Qmp(String, String),
+    #[error("Cannot change number of vcpus from {0} to {1} (maxcpus: {2})")]
+    CpuHotplug(usize, usize, usize),
+    #[error("VM was launched without a balloon device")]
+    NoBalloon(),
+    #[error("Socket path {0:?} exceeds the unix socket path limit, use a shorter --socket-dir")]
+    SocketPathTooLong(PathBuf),
+    #[error("TPM requested but swtpm is not installed")]
+    SwtpmNotInstalled(#[source] which::Error),
+    #[error("Invalid security configuration: {0}")]
+    Security(String),
+    #[error("Firmware image {0:?} does not exist")]
+    MissingFirmware(PathBuf),
+    #[error("Drive {0:?} does not exist")]
+    MissingDrive(PathBuf),
+    #[error("Boot image or kernel {0:?} does not exist")]
+    MissingBootFile(PathBuf),
+    #[error("Hugepage mount {0:?} does not exist")]
+    MissingHugepages(PathBuf),
+    #[error("Machine property {0} is set twice or managed by the launcher")]
+    MachineProperty(String),
+    #[error("{0} disk queues are not between 1 and the {1} vcpus of the vm")]
+    DiskQueues(usize, usize),
+    #[error("Could not apply the cpu limit")]
+    Cgroup(#[source] CgroupError),
+    #[error("Migration ended with status: {0}")]
+    Migration(String),
+    #[error("Migration did not complete within {0:?}")]
+    MigrationTimeout(Duration),
+    #[error("Only vms booted from an image can be captured")]
+    CaptureWithoutImage,
+    #[error("Could not {1} captured vm {2:?}")]
+    CapturedVm(#[source] std::io::Error, &'static str, PathBuf),
+    #[error("Snapshots need qcow2 images, {0:?} is not one")]
+    SnapshotUnsupported(PathBuf),
+    #[error("{0}")]
+    StartupFailed(Box<LaunchFailure>),
+}
+
+// What qemu prints when a resource it needs is held by someone else for the moment, e.g. a tap
+// which is still being released or a port another vm has just bound
+const TRANSIENT_STARTUP_ERRORS: [&str; 3] = [
+    "busy",
+    "address already in use",
+    "resource temporarily unavailable",
+];
+
+impl QemuError {
+    // A single attempt failed because a tap, port or kvm was busy. Invalid configurations and
+    // missing binaries fail the same way every time, as does a qemu which was killed, most likely
+    // by the OOM killer, or a socket directory the launcher may not write to.
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            QemuError::StartupFailed(failure) => {
+                failure.status.signal().is_none()
+                    && failure.log_tail.iter().any(|line| {
+                        let line = line.to_lowercase();
+                        TRANSIENT_STARTUP_ERRORS.iter().any(|e| line.contains(e))
+                    })
+            }
+            QemuError::IO(e, _) => matches!(
+                e.kind(),
+                ErrorKind::WouldBlock | ErrorKind::AddrInUse | ErrorKind::Interrupted
+            ),
+            QemuError::NotRunning()
+            | QemuError::PidFileNonUtf(_)
+            | QemuError::PidFileNonNumeric(_) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -605,27 +3028,253 @@ pub enum SerialError {
     Reading(#[source] std::io::Error),
     #[error("While reading utf8")]
     UTF8(#[source] std::str::Utf8Error),
+    #[error("Boot marker did not appear in time")]
+    BootTimeout(Vec<String>),
+    #[error("Command output did not complete within {0:?}")]
+    CaptureTimeout(Duration),
+    #[error("Serial console was closed")]
+    Closed,
+    #[error("Guest did not write to the serial console within {0:?}")]
+    NoOutput(Duration),
+}
+
+async fn start_swtpm(temp_dir: &Path, socket_path: &Path) -> Result<()> {
+    which::which(SWTPM_BINARY).map_err(QemuError::SwtpmNotInstalled)?;
+    let state_dir = temp_dir.join("tpm");
+    async_std::fs::create_dir_all(&state_dir)
+        .await
+        .map_err(|e| QemuError::IO(e, "creating tpm state directory"))?;
+
+    run_shell_command(
+        SWTPM_BINARY,
+        &vec![
+            "socket",
+            "--tpm2",
+            "--daemon",
+            "--terminate",
+            "--tpmstate",
+            &format!("dir={}", state_dir.to_str().unwrap()),
+            "--ctrl",
+            &format!("type=unixio,path={}", socket_path.to_str().unwrap()),
+            "--pid",
+            &format!("file={}", temp_dir.join("swtpm.pid").to_str().unwrap()),
+        ],
+    )
+    .await
+    .map_err(QemuError::Shell)?;
+    Ok(())
 }
 
 #[instrument]
 pub async fn start_qemu(lc: LaunchConfiguration) -> Result<QemuProcessHandle> {
-    run_shell_command(
-        QEMU_BINARY,
-        &create_qemu_arguments(&lc)
+    start_qemu_with_retries(lc, 0).await
+}
+
+const LAUNCH_LOG_TAIL_LINES: usize = 10;
+
+// A launch qemu refused, with everything needed to reproduce it. The vm dir is gone by the time
+// the error is reported, so the error carries the command line itself.
+#[derive(Debug)]
+pub struct LaunchFailure {
+    pub(crate) binary: String,
+    pub(crate) args: Vec<String>,
+    // last lines qemu wrote to stderr
+    pub(crate) log_tail: Vec<String>,
+    pub(crate) status: ExitStatus,
+}
+
+impl Display for LaunchFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Qemu failed to start ({})", self.status)?;
+        if let Some(last) = self.log_tail.last() {
+            write!(f, ": {last}")?;
+        }
+        write!(f, ", command line: {}", self.command_line())
+    }
+}
+
+impl LaunchFailure {
+    // Pasted into a shell, runs qemu the way the launcher did
+    fn command_line(&self) -> String {
+        std::iter::once(self.binary.clone())
+            .chain(self.args.iter().map(|arg| shell_quote(arg)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+// qemu daemonizes once the vm is set up, everything that goes wrong before, e.g. a tap which is
+// already in use or a missing firmware file, ends up on stderr
+fn startup_result(output: Output, binary: &str, args: Vec<String>) -> Result<()> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    let lines = stderr.lines().collect::<Vec<_>>();
+    let failure = LaunchFailure {
+        binary: binary.to_string(),
+        args,
+        log_tail: lines[lines.len().saturating_sub(LAUNCH_LOG_TAIL_LINES)..]
             .iter()
-            .map(|s| s.as_ref())
+            .map(|l| l.to_string())
             .collect(),
-    )
-    .await
-    .map_err(|e| QemuError::Shell(e))?;
+        status: output.status,
+    };
+    error!(status = %output.status, %stderr, command_line = %failure.command_line(), "Qemu failed to start");
+    Err(QemuError::StartupFailed(Box::new(failure)))
+}
 
-    let qh = QemuProcessHandle { lc: Some(lc) };
-    async_std::fs::set_permissions(qh.serial_path(), Permissions::from_mode(0o666))
-        .await
-        .map_err(|e| QemuError::IO(e, "Changing permission of Serial"))?;
-    async_std::fs::set_permissions(qh.monitor_path(), Permissions::from_mode(0o666))
-        .await
-        .map_err(|e| QemuError::IO(e, "Changing permission of Monitor"))?;
+#[test]
+fn startup_failures() {
+    let output = task::block_on(run_command_with_output(
+        "sh",
+        &vec![
+            "-c",
+            "echo 'warning: host lacks x2apic' >&2; \
+             echo 'could not open /dev/net/tun: tap3 busy' >&2; exit 1",
+        ],
+    ))
+    .unwrap();
+    let args = vec!["-netdev".to_string(), "tap,ifname=tap3".to_string()];
+    let error = startup_result(output, QEMU_BINARY, args.clone()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Qemu failed to start (exit status: 1): could not open /dev/net/tun: tap3 busy, \
+         command line: qemu-system-x86_64 '-netdev' 'tap,ifname=tap3'"
+    );
+    assert!(error.is_retryable());
+    let QemuError::StartupFailed(failure) = &error else {
+        panic!("unexpected error {error:?}");
+    };
+    assert_eq!(failure.args, args);
+    assert_eq!(failure.log_tail.len(), 2);
+
+    // killed, e.g. by the OOM killer
+    let killed = Output {
+        status: ExitStatus::from_raw(9),
+        stdout: vec![],
+        stderr: vec![],
+    };
+    assert!(!startup_result(killed, QEMU_BINARY, vec![])
+        .unwrap_err()
+        .is_retryable());
+
+    // an invalid configuration fails the same way on every attempt
+    let invalid = Output {
+        status: ExitStatus::from_raw(1 << 8),
+        stdout: vec![],
+        stderr: b"qemu-system-x86_64: -m 0: Invalid RAM size".to_vec(),
+    };
+    assert!(!startup_result(invalid, QEMU_BINARY, vec![])
+        .unwrap_err()
+        .is_retryable());
+    let port_in_use = Output {
+        status: ExitStatus::from_raw(1 << 8),
+        stdout: vec![],
+        stderr: b"Failed to bind socket: Address already in use".to_vec(),
+    };
+    assert!(startup_result(port_in_use, QEMU_BINARY, vec![])
+        .unwrap_err()
+        .is_retryable());
+
+    let denied = QemuError::IO(
+        ErrorKind::PermissionDenied.into(),
+        "creating socket directory",
+    );
+    assert!(!denied.is_retryable());
+    assert!(QemuError::IO(ErrorKind::AddrInUse.into(), "binding socket").is_retryable());
+}
+
+const LAUNCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Launches which failed for a reason that may go away on its own are retried up to `retries`
+// times, after stopping whatever the failed attempt started. The vm dir and tap are reused.
+pub async fn start_qemu_with_retries(
+    mut lc: LaunchConfiguration,
+    retries: usize,
+) -> Result<QemuProcessHandle> {
+    lc.security.validate(&lc.tap)?;
+    lc.validate_socket_paths()?;
+    lc.validate_qemu_binary()?;
+    lc.validate_machine_properties()?;
+    lc.validate_disk_queues()?;
+    lc.validate_boot()?;
+    lc.validate_extra_drives()?;
+    lc.validate_hugepages()?;
+    let mut attempt = 0;
+    loop {
+        // dropping the handle on failure cleans up a running swtpm
+        let mut qh = QemuProcessHandle {
+            lc: Some(lc),
+            pid: None,
+            cgroup: None,
+        };
+        let Err(e) = qh.launch().await else {
+            let sockets = qh.sockets();
+            info!(
+                device = qh.tap().device(),
+                monitor = ?sockets.monitor,
+                qmp = ?sockets.qmp,
+                serial = ?sockets.serial,
+                "Qemu started"
+            );
+            return Ok(qh);
+        };
+        if attempt == retries || !e.is_retryable() {
+            return Err(e);
+        }
+        if let Err(stop_error) = qh.stop(StopTimeouts::ON_DROP).await {
+            error!(?stop_error, "Could not clean up failed qemu launch");
+        }
+        lc = qh.lc.take().unwrap();
+        attempt += 1;
+        warn!(attempt, retries, %e, "Retrying qemu launch");
+        task::sleep(LAUNCH_RETRY_DELAY).await;
+    }
+}
 
-    Ok(qh)
+impl QemuProcessHandle {
+    async fn launch(&mut self) -> Result<()> {
+        let lc = self.lc.as_ref().unwrap();
+        async_std::fs::create_dir_all(lc.socket_path("").as_path())
+            .await
+            .map_err(|e| QemuError::IO(e, "creating socket directory"))?;
+        if lc.tpm {
+            start_swtpm(lc.vm_dir.path(), &lc.socket_path(SWTPM_SOCKET)).await?;
+        }
+        let args = create_qemu_arguments(lc);
+        let output =
+            run_command_with_output(lc.qemu_binary(), &args.iter().map(|s| s.as_ref()).collect())
+                .await
+                .map_err(QemuError::Shell)?;
+        startup_result(output, lc.qemu_binary(), args)?;
+
+        self.pid = self.get_pid().await.ok();
+        let lc = self.lc.as_ref().unwrap();
+        // taskset only pins threads which exist, qemu started its vcpu threads before it wrote
+        // the pidfile. Vcpus hotplugged later run on any cpu.
+        if let Some(cpus) = lc.cpu_affinity.as_ref() {
+            let pid = self.get_pid().await?.to_string();
+            run_shell_command(
+                TASKSET_BINARY,
+                &vec!["--all-tasks", "--pid", "--cpu-list", &cpus.cpu_list(), &pid],
+            )
+            .await
+            .map_err(QemuError::Shell)?;
+        }
+        if let Some(limit) = lc.cpu_limit {
+            let pid = self.get_pid().await?;
+            let name = format!("{QEMU_NAME_PREFIX}{}", self.tap().device());
+            let cgroup = self
+                .cgroup
+                .insert(Cgroup::create(&name, &limit).map_err(QemuError::Cgroup)?);
+            cgroup.add_process(pid).map_err(QemuError::Cgroup)?;
+        }
+        lc.socket_access.apply(&self.serial_path()).await?;
+        if lc.hmp_monitor {
+            lc.socket_access.apply(&self.monitor_path()).await?;
+        }
+        lc.socket_access.apply(&self.qmp_path()).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,80 @@
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use async_std::net::TcpStream;
+use async_std::task;
+use futures::future::join_all;
+use thiserror::Error;
+use tracing::{debug, info};
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Error, Debug)]
+pub(crate) enum ReadinessError {
+    #[error("{0} did not accept connections within {1:?}")]
+    Timeout(SocketAddrV4, Duration),
+}
+
+// A NES worker accepts connections on its rpc port once it is up, which is well after the guest
+// printed its boot marker
+async fn wait_ready(address: SocketAddrV4, timeout: Duration) -> Result<(), ReadinessError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match async_std::future::timeout(remaining, TcpStream::connect(address)).await {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(e)) => debug!(%address, %e, "Not ready yet"),
+            Err(_) => return Err(ReadinessError::Timeout(address, timeout)),
+        }
+        if Instant::now() + PROBE_INTERVAL >= deadline {
+            return Err(ReadinessError::Timeout(address, timeout));
+        }
+        task::sleep(PROBE_INTERVAL).await;
+    }
+}
+
+// Probes every vm at once and waits until all of them are ready or out of time. Returns the ids
+// of the vms which did not become ready, with the reason.
+pub(crate) async fn wait_all_ready(
+    vms: &[(usize, SocketAddrV4)],
+    timeout: Duration,
+) -> Result<(), Vec<(usize, ReadinessError)>> {
+    info!(
+        count = vms.len(),
+        ?timeout,
+        "Waiting for vms to become ready"
+    );
+    let failures = join_all(
+        vms.iter()
+            .map(|(id, address)| async move { (*id, wait_ready(*address, timeout).await) }),
+    )
+    .await
+    .into_iter()
+    .filter_map(|(id, result)| result.err().map(|e| (id, e)))
+    .collect::<Vec<_>>();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+#[test]
+fn readiness() {
+    use std::net::{Ipv4Addr, TcpListener};
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let ready = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+    // a port which was just free is most likely still free
+    let closed = {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port())
+    };
+
+    let timeout = Duration::from_secs(1);
+    assert!(task::block_on(wait_all_ready(&[(1, ready)], timeout)).is_ok());
+    let failures = task::block_on(wait_all_ready(&[(1, ready), (2, closed)], timeout)).unwrap_err();
+    assert!(matches!(
+        failures.as_slice(),
+        [(2, ReadinessError::Timeout(address, _))] if *address == closed
+    ));
+}
@@ -0,0 +1,274 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use async_std::task;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::network::{userbridge, usertap};
+use crate::qemu::{QEMU_BINARY, QEMU_NAME_PREFIX};
+use crate::shell::{run_command_without_output, ShellError};
+use crate::topology::Topology;
+
+const NET_DEVICES: &str = "/sys/class/net";
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub(crate) enum ResetError {
+    #[error("Could not read {1}")]
+    IO(#[source] std::io::Error, &'static str),
+    #[error("Could not kill qemu process {0}")]
+    Kill(u32, #[source] ShellError),
+    #[error("Could not signal qemu process {0}, it is not ours or already gone")]
+    KillRefused(u32),
+    #[error("Qemu process {0} did not exit after it was killed")]
+    StillRunning(u32),
+    #[error("Could not remove tap {0}")]
+    Tap(String, #[source] usertap::UserTapError),
+    #[error("Could not remove bridge {0}")]
+    Bridge(String, #[source] userbridge::UserBridgeError),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum StaleResource {
+    Qemu(u32, String),
+    Tap(String),
+    Bridge(String),
+}
+
+impl Display for StaleResource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StaleResource::Qemu(pid, name) => write!(f, "qemu {name} (pid {pid})"),
+            StaleResource::Tap(name) => write!(f, "tap {name}"),
+            StaleResource::Bridge(name) => write!(f, "bridge {name}"),
+        }
+    }
+}
+
+// Only names the launcher generates, e.g. tbr0, tap3 or tap1_3, so similarly named devices of
+// other software and other topologies are left alone
+fn is_launcher_device(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix).is_some_and(|rest| {
+        rest.starts_with(|c: char| c.is_ascii_digit())
+            && rest.chars().all(|c| c.is_ascii_digit() || c == '_')
+    })
+}
+
+// The `-name` of a qemu process started for the topology, from its nul separated cmdline. The
+// process has to run the launcher's qemu binary, which is compared by file name.
+fn launcher_qemu_name(cmdline: &[u8], topology: &Topology, qemu_binary: &Path) -> Option<String> {
+    let args = cmdline
+        .split(|b| *b == 0)
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>();
+    let binary = Path::new(args.first()?.as_ref()).file_name()?;
+    if Some(binary) != qemu_binary.file_name() {
+        return None;
+    }
+    args.windows(2)
+        .find(|w| w[0] == "-name")
+        .map(|w| w[1].to_string())
+        .filter(|name| {
+            name.strip_prefix(QEMU_NAME_PREFIX)
+                .is_some_and(|tap| is_launcher_device(tap, &topology.tap_prefix()))
+        })
+}
+
+fn find_qemu_processes(
+    topology: &Topology,
+    qemu_binary: &Path,
+) -> Result<Vec<StaleResource>, ResetError> {
+    let mut processes = vec![];
+    for entry in std::fs::read_dir("/proc").map_err(|e| ResetError::IO(e, "/proc"))? {
+        let Ok(entry) = entry else { continue };
+        let Some(pid) = entry.file_name().to_str().and_then(|p| p.parse().ok()) else {
+            continue;
+        };
+        // processes may exit while we are looking at them
+        let Ok(cmdline) = std::fs::read(entry.path().join("cmdline")) else {
+            continue;
+        };
+        if let Some(name) = launcher_qemu_name(&cmdline, topology, qemu_binary) {
+            processes.push(StaleResource::Qemu(pid, name));
+        }
+    }
+    Ok(processes)
+}
+
+fn find_devices(topology: &Topology) -> Result<Vec<StaleResource>, ResetError> {
+    let mut taps = vec![];
+    let mut bridges = vec![];
+    for entry in std::fs::read_dir(NET_DEVICES).map_err(|e| ResetError::IO(e, NET_DEVICES))? {
+        let Ok(entry) = entry else { continue };
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.path().join("tun_flags").exists()
+            && is_launcher_device(&name, &topology.tap_prefix())
+        {
+            taps.push(StaleResource::Tap(name));
+        } else if entry.path().join("bridge").exists()
+            && is_launcher_device(&name, &topology.bridge_prefix())
+        {
+            bridges.push(StaleResource::Bridge(name));
+        }
+    }
+    taps.sort_by_key(|t| t.to_string());
+    bridges.sort_by_key(|b| b.to_string());
+    // taps are detached from their bridge before it is deleted
+    taps.append(&mut bridges);
+    Ok(taps)
+}
+
+// Whether the process is gone within the timeout. A stale qemu is not our child, it is gone once
+// /proc no longer lists it.
+async fn wait_for_exit(pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Path::new("/proc").join(pid.to_string()).exists() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        task::sleep(EXIT_POLL_INTERVAL).await;
+    }
+    true
+}
+
+// Terminates the process and kills it if it does not exit in time
+async fn kill_process(pid: u32) -> Result<(), ResetError> {
+    for signal in ["-TERM", "-KILL"] {
+        match run_command_without_output("kill", vec![signal, &pid.to_string()]).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ResetError::KillRefused(pid)),
+            Err(e) => return Err(ResetError::Kill(pid, e)),
+        }
+        if wait_for_exit(pid, KILL_TIMEOUT).await {
+            return Ok(());
+        }
+        warn!(pid, signal, "Qemu did not exit");
+    }
+    Err(ResetError::StillRunning(pid))
+}
+
+// What reset_host found, and whether it could remove it
+#[derive(Debug)]
+pub(crate) struct Reset {
+    pub(crate) removed: Vec<StaleResource>,
+    pub(crate) failed: Vec<(StaleResource, ResetError)>,
+}
+
+// Removes everything a crashed launcher of the topology may have left behind: qemu processes
+// first, as they keep their taps open, then taps and bridges. The launcher installs no
+// masquerade or route rules, the bridge's route is gone with the bridge. A device which can not
+// be removed, e.g. because another process still uses it, does not stop the others.
+pub(crate) async fn reset_host(
+    topology: &Topology,
+    qemu_binary: Option<&Path>,
+    dry_run: bool,
+) -> Result<Reset, ResetError> {
+    let qemu_binary = qemu_binary.unwrap_or(Path::new(QEMU_BINARY));
+    let mut stale = find_qemu_processes(topology, qemu_binary)?;
+    stale.extend(find_devices(topology)?);
+    let mut reset = Reset {
+        removed: vec![],
+        failed: vec![],
+    };
+    if dry_run {
+        reset.removed = stale;
+        return Ok(reset);
+    }
+
+    for resource in stale {
+        info!(%resource, "Removing");
+        let result = match &resource {
+            StaleResource::Qemu(pid, _) => kill_process(*pid).await,
+            StaleResource::Tap(name) => {
+                usertap::Tap::remove(name).map_err(|e| ResetError::Tap(name.clone(), e))
+            }
+            StaleResource::Bridge(name) => {
+                userbridge::Bridge::remove(name).map_err(|e| ResetError::Bridge(name.clone(), e))
+            }
+        };
+        match result {
+            Ok(()) => reset.removed.push(resource),
+            Err(e) => {
+                warn!(%resource, ?e, "Could not remove");
+                reset.failed.push((resource, e));
+            }
+        }
+    }
+    Ok(reset)
+}
+
+#[test]
+fn launcher_resources() {
+    let default = Topology::default();
+    let qemu = Path::new(QEMU_BINARY);
+    let (bridge_prefix, tap_prefix) = (default.bridge_prefix(), default.tap_prefix());
+    assert!(is_launcher_device("tbr0", &bridge_prefix));
+    assert!(is_launcher_device("tap12", &tap_prefix));
+    assert!(is_launcher_device("tap1_3", &tap_prefix));
+    assert!(!is_launcher_device("tap", &tap_prefix));
+    assert!(!is_launcher_device("tapvpn0", &tap_prefix));
+    assert!(!is_launcher_device("tbr_lan", &bridge_prefix));
+
+    assert_eq!(
+        launcher_qemu_name(
+            b"/usr/bin/qemu-system-x86_64\0-name\0nes-tap3\0-nographic\0",
+            &default,
+            qemu
+        ),
+        Some("nes-tap3".to_string())
+    );
+    assert_eq!(
+        launcher_qemu_name(b"qemu-system-x86_64\0-name\0other-vm\0", &default, qemu),
+        None
+    );
+    assert_eq!(
+        launcher_qemu_name(b"vim\0-name\0nes-notes\0", &default, qemu),
+        None
+    );
+
+    // named topologies only see their own resources
+    let named: Topology = "exp1".parse().unwrap();
+    assert!(is_launcher_device("exp1-tap1_3", &named.tap_prefix()));
+    assert!(!is_launcher_device("exp1-tap1_3", &tap_prefix));
+    assert!(!is_launcher_device("tap3", &named.tap_prefix()));
+    let cmdline = b"qemu-system-x86_64\0-name\0nes-exp1-tap3\0";
+    assert_eq!(
+        launcher_qemu_name(cmdline, &named, qemu),
+        Some("nes-exp1-tap3".to_string())
+    );
+    assert_eq!(launcher_qemu_name(cmdline, &default, qemu), None);
+
+    // a custom qemu is matched instead of qemu-system-x86_64
+    let custom = Path::new("/opt/qemu/bin/qemu-dev");
+    let cmdline = b"/opt/qemu/bin/qemu-dev\0-name\0nes-tap3\0";
+    assert_eq!(
+        launcher_qemu_name(cmdline, &default, custom),
+        Some("nes-tap3".to_string())
+    );
+    assert_eq!(
+        launcher_qemu_name(b"qemu-system-x86_64\0-name\0nes-tap3\0", &default, custom),
+        None
+    );
+}
+
+#[test]
+fn kill_stale_process() {
+    let mut child = std::process::Command::new("sleep")
+        .arg("30")
+        .spawn()
+        .unwrap();
+    let pid = child.id();
+    // reaps it, as the init process does for a stale qemu
+    let reaper = std::thread::spawn(move || child.wait().unwrap());
+    assert!(task::block_on(kill_process(pid)).is_ok());
+    assert!(!reaper.join().unwrap().success());
+
+    // pids are at most 2^22, this one never exists
+    assert!(matches!(
+        task::block_on(kill_process(1 << 23)),
+        Err(ResetError::KillRefused(_))
+    ));
+}
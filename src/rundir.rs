@@ -0,0 +1,120 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tempdir::TempDir;
+use tracing::warn;
+
+#[derive(Debug)]
+struct RunDirState {
+    root: PathBuf,
+    // only a root which did not exist before is removed again
+    created_root: bool,
+    vm_dirs: Mutex<Vec<PathBuf>>,
+}
+
+impl Drop for RunDirState {
+    fn drop(&mut self) {
+        for dir in self.vm_dirs.get_mut().unwrap().drain(..) {
+            if let Err(e) = std::fs::remove_dir_all(&dir) {
+                warn!(?dir, %e, "Could not remove vm directory");
+            }
+        }
+        if self.created_root {
+            let _ = std::fs::remove_dir(&self.root);
+        }
+    }
+}
+
+// Launcher scoped root directory, every vm gets a named subdirectory in it. All of them are
+// removed once the launcher and every vm which used it are gone.
+#[derive(Debug, Clone)]
+pub(crate) struct RunDir {
+    state: Arc<RunDirState>,
+}
+
+impl RunDir {
+    pub(crate) fn create(root: &Path) -> std::io::Result<Self> {
+        let created_root = !root.exists();
+        std::fs::create_dir_all(root)?;
+        Ok(RunDir {
+            state: Arc::new(RunDirState {
+                root: root.to_path_buf(),
+                created_root,
+                vm_dirs: Mutex::new(vec![]),
+            }),
+        })
+    }
+
+    fn vm_dir(&self, name: &str) -> std::io::Result<VmDir> {
+        let path = self.state.root.join(name);
+        let mut vm_dirs = self.state.vm_dirs.lock().unwrap();
+        if vm_dirs.contains(&path) {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} is used by another vm", path.display()),
+            ));
+        }
+        // leftovers of an earlier run which was not shut down cleanly
+        match std::fs::remove_dir_all(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        std::fs::create_dir(&path)?;
+        vm_dirs.push(path.clone());
+        Ok(VmDir::Named {
+            path,
+            _run_dir: self.clone(),
+        })
+    }
+}
+
+// Holds a vm's config, image copy, pidfile and (by default) sockets
+#[derive(Debug)]
+pub(crate) enum VmDir {
+    Temp(TempDir),
+    // keeps the run dir alive until the vm is gone
+    Named { path: PathBuf, _run_dir: RunDir },
+}
+
+impl VmDir {
+    // `<run dir>/<name>/` if there is a run dir, a random temp dir otherwise
+    pub(crate) fn create(run_dir: Option<&RunDir>, name: &str) -> std::io::Result<Self> {
+        match run_dir {
+            Some(run_dir) => run_dir.vm_dir(name),
+            None => TempDir::new(name).map(VmDir::Temp),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            VmDir::Temp(dir) => dir.path(),
+            VmDir::Named { path, .. } => path,
+        }
+    }
+}
+
+#[test]
+fn named_vm_dirs() {
+    let parent = TempDir::new("run_dir").unwrap();
+    let root = parent.path().join("run");
+    std::fs::create_dir_all(root.join("worker-2")).unwrap();
+    std::fs::write(root.join("worker-2/stale"), b"").unwrap();
+
+    let run_dir = RunDir::create(&root).unwrap();
+    let first = VmDir::create(Some(&run_dir), "worker-1").unwrap();
+    let second = VmDir::create(Some(&run_dir), "worker-2").unwrap();
+    assert_eq!(first.path(), root.join("worker-1"));
+    assert!(!second.path().join("stale").exists());
+    assert!(VmDir::create(Some(&run_dir), "worker-1").is_err());
+
+    // cleaned up once the launcher and all vms are done with it
+    drop(run_dir);
+    drop(first);
+    assert!(root.join("worker-1").exists());
+    drop(second);
+    assert!(!root.join("worker-1").exists());
+    assert!(!root.join("worker-2").exists());
+    // the root existed before, so it is left alone
+    assert!(root.exists());
+}
@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use ipnet::Ipv4Net;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::image::{self, ImageError};
+
+#[derive(Error, Debug)]
+pub(crate) enum SessionError {
+    #[error("Could not {1} session log {2:?}")]
+    IO(#[source] std::io::Error, &'static str, PathBuf),
+    #[error("Line {1} of the session log is invalid")]
+    Invalid(#[source] serde_json::Error, usize),
+    #[error("Session log is empty")]
+    Empty,
+    #[error("Could not checksum {1:?}")]
+    Checksum(#[source] ImageError, PathBuf),
+    #[error("{0:?} changed since the session was recorded: expected sha256 {1}, got {2}")]
+    Changed(PathBuf, String, String),
+}
+
+type Result<T> = std::result::Result<T, SessionError>;
+
+// The networks the session ran on, the first line of the log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SessionHeader {
+    pub(crate) ip_range: Ipv4Net,
+    #[serde(default)]
+    pub(crate) segments: BTreeMap<String, Ipv4Net>,
+}
+
+// A command as it was executed, with the sha256 of every image and binary it used
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SessionEntry<C> {
+    pub(crate) command: C,
+    #[serde(default)]
+    pub(crate) files: BTreeMap<PathBuf, String>,
+}
+
+// Every command a launcher executed, one json document per line, so a topology can be
+// reproduced from a bug report with `replay`
+#[derive(Debug)]
+pub(crate) struct SessionLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+async fn checksum(path: &Path) -> Result<String> {
+    image::sha256(path)
+        .await
+        .map_err(|e| SessionError::Checksum(e, path.to_path_buf()))
+}
+
+impl SessionLog {
+    pub(crate) fn create(path: &Path, header: &SessionHeader) -> Result<Self> {
+        let mut file =
+            File::create(path).map_err(|e| SessionError::IO(e, "create", path.to_path_buf()))?;
+        writeln!(file, "{}", serde_json::to_string(header).unwrap())
+            .map_err(|e| SessionError::IO(e, "write", path.to_path_buf()))?;
+        Ok(SessionLog {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub(crate) fn record(
+        &self,
+        command: &impl Serialize,
+        files: BTreeMap<PathBuf, String>,
+    ) -> Result<()> {
+        let entry = SessionEntry { command, files };
+        writeln!(
+            self.file.lock().unwrap(),
+            "{}",
+            serde_json::to_string(&entry).unwrap()
+        )
+        .map_err(|e| SessionError::IO(e, "write", self.path.clone()))
+    }
+}
+
+// images are usually shared by all workers, image::sha256 only hashes them once
+pub(crate) async fn checksums(files: &[&Path]) -> Result<BTreeMap<PathBuf, String>> {
+    let mut checksums = BTreeMap::new();
+    for path in files {
+        checksums.insert(path.to_path_buf(), checksum(path).await?);
+    }
+    Ok(checksums)
+}
+
+pub(crate) fn read<C: DeserializeOwned>(
+    path: &Path,
+) -> Result<(SessionHeader, Vec<SessionEntry<C>>)> {
+    let file = File::open(path).map_err(|e| SessionError::IO(e, "open", path.to_path_buf()))?;
+    let mut lines = BufReader::new(file)
+        .lines()
+        .map(|l| l.map_err(|e| SessionError::IO(e, "read", path.to_path_buf())));
+    let header = lines.next().ok_or(SessionError::Empty)??;
+    let header = serde_json::from_str(&header).map_err(|e| SessionError::Invalid(e, 1))?;
+    let entries = lines
+        .enumerate()
+        .map(|(i, line)| serde_json::from_str(&line?).map_err(|e| SessionError::Invalid(e, i + 2)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((header, entries))
+}
+
+// Replaying on other images or binaries would not reproduce the session
+pub(crate) async fn check_files<C>(entries: &[SessionEntry<C>]) -> Result<()> {
+    let files = entries
+        .iter()
+        .flat_map(|entry| entry.files.iter())
+        .collect::<BTreeMap<_, _>>();
+    for (path, expected) in files {
+        let actual = checksum(path).await?;
+        if &actual != expected {
+            return Err(SessionError::Changed(
+                path.clone(),
+                expected.clone(),
+                actual,
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn session_logs() {
+    let dir = tempdir::TempDir::new("session").unwrap();
+    let log_path = dir.path().join("session.log");
+    let binary = dir.path().join("unikernel");
+    std::fs::write(&binary, b"v1").unwrap();
+
+    let header = SessionHeader {
+        ip_range: "10.0.0.0/24".parse().unwrap(),
+        segments: BTreeMap::new(),
+    };
+    let log = SessionLog::create(&log_path, &header).unwrap();
+    let files = async_std::task::block_on(checksums(&[&binary])).unwrap();
+    log.record(&"first".to_string(), files).unwrap();
+    log.record(&"second".to_string(), BTreeMap::new()).unwrap();
+
+    let (read_header, entries) = read::<String>(&log_path).unwrap();
+    assert_eq!(read_header, header);
+    assert_eq!(
+        entries
+            .iter()
+            .map(|e| e.command.as_str())
+            .collect::<Vec<_>>(),
+        vec!["first", "second"]
+    );
+    assert!(async_std::task::block_on(check_files(&entries)).is_ok());
+
+    std::fs::write(&binary, b"v2").unwrap();
+    assert!(matches!(
+        async_std::task::block_on(check_files(&entries)),
+        Err(SessionError::Changed(path, _, _)) if path == binary
+    ));
+}
@@ -1,6 +1,9 @@
 use async_process::Command;
 use futures_lite::AsyncWriteExt;
+use nix::sys::signal::Signal;
 use std::fmt::{Display, Formatter};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 use std::process::{ExitStatus, Output, Stdio};
 use std::str::Utf8Error;
 use strum_macros::Display;
@@ -27,6 +30,26 @@ impl ShellError {
             enu,
         }
     }
+
+    fn unsuccessful(status: ExitStatus) -> Self {
+        match (status.code(), status.signal()) {
+            (None, Some(signal)) => Self::new(ShellErrorEnum::KilledBySignal(signal)),
+            (code, _) => Self::new(ShellErrorEnum::UnexpectedExitCode(code.unwrap_or(-1))),
+        }
+    }
+}
+
+fn signal_name(signal: i32) -> String {
+    Signal::try_from(signal).map_or_else(|_| format!("signal {signal}"), |s| s.to_string())
+}
+
+// Processes are killed with SIGKILL by the OOM killer, and by hand
+fn signal_hint(signal: i32) -> &'static str {
+    if signal == Signal::SIGKILL as i32 {
+        " (OOM?)"
+    } else {
+        ""
+    }
 }
 
 #[derive(Error, Debug)]
@@ -37,8 +60,10 @@ enum ShellErrorEnum {
     SpawnFailed(#[source] std::io::Error),
     #[error("Could not write to stdin")]
     WritingToStdinFailed(#[source] std::io::Error),
-    #[error("Unexpected Exit Code")]
-    UnexpectedExitCode(ExitStatus),
+    #[error("Unexpected Exit Code {0}")]
+    UnexpectedExitCode(i32),
+    #[error("Killed by {}{}", signal_name(*.0), signal_hint(*.0))]
+    KilledBySignal(i32),
     #[error("General IO Error")]
     IOFailed(#[source] std::io::Error),
     #[error("General IO Error")]
@@ -57,6 +82,11 @@ pub fn stderr_as_str(output: &Output) -> Result<&str> {
         .map_err(|e| ShellError::new(ShellErrorEnum::InvalidUTF8Output(e)))
 }
 
+// Resolves `command` through PATH, or checks that it exists if it is a path
+pub(crate) fn find_binary(command: &str) -> Result<PathBuf> {
+    which::which(command).map_err(|e| ShellError::new(ShellErrorEnum::BinaryNotFound(e)))
+}
+
 #[tracing::instrument(skip(data), level = tracing::Level::DEBUG, err(level = tracing::Level::INFO))]
 pub async fn run_shell_command_with_stdin(
     command: &str,
@@ -64,15 +94,13 @@ pub async fn run_shell_command_with_stdin(
     data: &[u8],
 ) -> Result<String> {
     trace!("starting");
-    let mut child = Command::new(
-        which::which(command).map_err(|e| ShellError::new(ShellErrorEnum::BinaryNotFound(e)))?,
-    )
-    .args(args)
-    .stdin(Stdio::piped())
-    .stdout(Stdio::piped())
-    .stderr(Stdio::piped())
-    .spawn()
-    .map_err(|e| ShellError::new(ShellErrorEnum::SpawnFailed(e)))?;
+    let mut child = Command::new(find_binary(command)?)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| ShellError::new(ShellErrorEnum::SpawnFailed(e)))?;
 
     child
         .stdin
@@ -93,13 +121,11 @@ pub async fn run_shell_command_with_stdin(
 
     if !exit_status.success() {
         error!(
-            status = ?exit_status.code(),
+            status = %exit_status,
             error = stderr_as_str(&output)?,
             "Unexpected Exit status"
         );
-        return Err(ShellError::new(ShellErrorEnum::UnexpectedExitCode(
-            exit_status,
-        )));
+        return Err(ShellError::unsuccessful(exit_status));
     }
 
     let stdout = stdout_as_str(&output)?;
@@ -112,21 +138,33 @@ pub async fn run_shell_command(command: &str, args: &Vec<&str>) -> Result<String
     run_shell_command_with_env(command, &args, vec![]).await
 }
 
-#[tracing::instrument(level = tracing::Level::INFO, err(level = tracing::Level::INFO))]
 pub async fn run_shell_command_with_env(
     command: &str,
     args: &Vec<&str>,
     envs: Vec<(&str, &str)>,
 ) -> Result<String> {
-    let mut child = Command::new(
-        which::which(command).map_err(|e| ShellError::new(ShellErrorEnum::BinaryNotFound(e)))?,
-    )
-    .args(args)
-    .envs(envs)
-    .stdout(Stdio::piped())
-    .stderr(Stdio::piped())
-    .spawn()
-    .map_err(|e| ShellError::new(ShellErrorEnum::SpawnFailed(e)))?;
+    run_shell_command_in_dir(command, args, envs, None).await
+}
+
+// Like run_shell_command_with_env, running in `dir` instead of the launcher's working directory
+#[tracing::instrument(level = tracing::Level::INFO, err(level = tracing::Level::INFO))]
+pub async fn run_shell_command_in_dir(
+    command: &str,
+    args: &Vec<&str>,
+    envs: Vec<(&str, &str)>,
+    dir: Option<&Path>,
+) -> Result<String> {
+    let mut child = Command::new(find_binary(command)?);
+    if let Some(dir) = dir {
+        child.current_dir(dir);
+    }
+    let mut child = child
+        .args(args)
+        .envs(envs)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| ShellError::new(ShellErrorEnum::SpawnFailed(e)))?;
     let exit_status = child
         .status()
         .await
@@ -141,13 +179,11 @@ pub async fn run_shell_command_with_env(
         error!(
             ?command,
             args = args.join(" "),
-            status = ?exit_status.code(),
+            status = %exit_status,
             error = stderr_as_str(&output)?,
             "Unexpected Exit status"
         );
-        return Err(ShellError::new(ShellErrorEnum::UnexpectedExitCode(
-            exit_status,
-        )));
+        return Err(ShellError::unsuccessful(exit_status));
     }
 
     let stdout = stdout_as_str(&output)?;
@@ -156,17 +192,26 @@ pub async fn run_shell_command_with_env(
     return Ok(stdout.to_string());
 }
 
+// Like run_shell_command, but an unsuccessful exit is up to the caller, e.g. to report stderr
+#[tracing::instrument(level = tracing::Level::DEBUG, err(level = tracing::Level::INFO))]
+pub(crate) async fn run_command_with_output(command: &str, args: &Vec<&str>) -> Result<Output> {
+    Command::new(find_binary(command)?)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| ShellError::new(ShellErrorEnum::SpawnFailed(e)))
+}
+
 #[tracing::instrument(level = tracing::Level::DEBUG)]
 pub async fn run_command_without_output(command: &str, args: Vec<&str>) -> Result<bool> {
-    let mut child = Command::new(
-        which::which(command).map_err(|e| ShellError::new(ShellErrorEnum::BinaryNotFound(e)))?,
-    )
-    .args(args)
-    .stdout(Stdio::null())
-    .stderr(Stdio::null())
-    .stdin(Stdio::null())
-    .spawn()
-    .map_err(|e| ShellError::new(ShellErrorEnum::SpawnFailed(e)))?;
+    let mut child = Command::new(find_binary(command)?)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|e| ShellError::new(ShellErrorEnum::SpawnFailed(e)))?;
     let exit_status = child
         .status()
         .await
@@ -174,3 +219,27 @@ pub async fn run_command_without_output(command: &str, args: Vec<&str>) -> Resul
 
     return Ok(exit_status.success());
 }
+
+#[test]
+fn killed_by_signal() {
+    let error =
+        async_std::task::block_on(run_shell_command("sh", &vec!["-c", "kill -9 $$"])).unwrap_err();
+    assert!(matches!(error.enu, ShellErrorEnum::KilledBySignal(9)));
+    assert_eq!(error.enu.to_string(), "Killed by SIGKILL (OOM?)");
+
+    let error = async_std::task::block_on(run_shell_command("sh", &vec!["-c", "kill -TERM $$"]))
+        .unwrap_err();
+    assert_eq!(error.enu.to_string(), "Killed by SIGTERM");
+
+    let error =
+        async_std::task::block_on(run_shell_command("sh", &vec!["-c", "exit 3"])).unwrap_err();
+    assert!(matches!(error.enu, ShellErrorEnum::UnexpectedExitCode(3)));
+    assert_eq!(error.enu.to_string(), "Unexpected Exit Code 3");
+}
+
+#[test]
+fn missing_binaries() {
+    assert!(find_binary("sh").is_ok());
+    let error = find_binary("/nonexistent/qemu-system-x86_64").unwrap_err();
+    assert!(matches!(error.enu, ShellErrorEnum::BinaryNotFound(_)));
+}
@@ -1,29 +1,48 @@
 use std::net::IpAddr;
+use std::path::PathBuf;
 
 use indoc::indoc;
 use once_cell::unsync::Lazy;
 use ouroboros::self_referencing;
 use rust_embed::{EmbeddedFile, RustEmbed};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tinytemplate::TinyTemplate;
 
 use crate::nes::{
-    Source, WorkerQueryProcessingConfigurationBuilder, WorkerQueryProcessingConfigurationInternal,
+    FieldType, LogicalSource, SchemaField, Source, TCPSourceConfigBuilder,
+    WorkerQueryProcessingConfigurationBuilder, WorkerQueryProcessingConfigurationInternal,
 };
 
 thread_local! {
-pub static TEMPLATES: Lazy<Templates> = Lazy::new(Templates::create);
+pub static TEMPLATES: Lazy<Result<Templates, (&'static str, String)>> = Lazy::new(Templates::create);
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum TemplateError {
+    #[error("Template {0} could not be loaded: {1}")]
+    Invalid(&'static str, String),
+    #[error("Could not render template {1}")]
+    Render(#[source] tinytemplate::error::Error, &'static str),
+    #[error("Templates are not available to render {1}")]
+    Unavailable(#[source] std::thread::AccessError, &'static str),
 }
 
 const WORKER_CONFIG_TEMPLATE: &str = "worker_config";
 const DOCKER_UNIT_TEMPLATE: &str = "dockerunit";
 const NETWORK_CONFIGURATION_TEMPLATE: &str = "networkconfiguration";
 const DOCKER_DAEMON_CONFIG_TEMPLATE: &str = "dockerdaemon";
-const TEMPLATE_FILES: [&str; 4] = [
+const COORDINATOR_CONFIG_TEMPLATE: &str = "coordinator_config";
+const BOND_NETDEV_TEMPLATE: &str = "bondnetdev";
+const BOND_MEMBERS_TEMPLATE: &str = "bondmembers";
+const TEMPLATE_FILES: [&str; 7] = [
     WORKER_CONFIG_TEMPLATE,
     DOCKER_UNIT_TEMPLATE,
     NETWORK_CONFIGURATION_TEMPLATE,
     DOCKER_DAEMON_CONFIG_TEMPLATE,
+    COORDINATOR_CONFIG_TEMPLATE,
+    BOND_NETDEV_TEMPLATE,
+    BOND_MEMBERS_TEMPLATE,
 ];
 
 #[derive(RustEmbed)]
@@ -39,61 +58,167 @@ pub struct Templates {
 }
 
 impl Templates {
-    fn create() -> Self {
-        TemplatesBuilder {
-            files: TEMPLATE_FILES
-                .into_iter()
-                .map(|name| {
-                    (
-                        name,
-                        TemplateAssets::get(&format!("{}.template", name)).unwrap(),
-                    )
-                })
-                .collect(),
+    // Fails with the name of the first template which is missing or does not parse
+    fn create() -> Result<Self, (&'static str, String)> {
+        let files = TEMPLATE_FILES
+            .into_iter()
+            .map(|name| {
+                TemplateAssets::get(&format!("{}.template", name))
+                    .map(|file| (name, file))
+                    .ok_or((name, "not embedded".to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        TemplatesTryBuilder {
+            files,
             tt_builder: |files| {
                 let mut tt = TinyTemplate::new();
                 for (name, file) in files {
-                    let template_str = std::str::from_utf8(file.data.as_ref()).unwrap();
-                    tt.add_template(name, template_str).unwrap();
+                    let template_str = std::str::from_utf8(file.data.as_ref())
+                        .map_err(|e| (*name, e.to_string()))?;
+                    tt.add_template(name, template_str)
+                        .map_err(|e| (*name, e.to_string()))?;
                 }
-                tt
+                Ok(tt)
             },
         }
-        .build()
+        .try_build()
     }
 
-    pub(crate) fn worker_config(wc: &WorkerConfiguration) -> String {
+    fn render(name: &'static str, context: &impl Serialize) -> Result<String, TemplateError> {
+        // the thread local is gone while the thread is shutting down
         TEMPLATES
-            .try_with(|t| t.borrow_tt().render(WORKER_CONFIG_TEMPLATE, &wc).unwrap())
-            .unwrap()
+            .try_with(|templates| match templates.as_ref() {
+                Ok(t) => t
+                    .borrow_tt()
+                    .render(name, context)
+                    .map_err(|e| TemplateError::Render(e, name)),
+                Err((name, reason)) => Err(TemplateError::Invalid(name, reason.clone())),
+            })
+            .map_err(|e| TemplateError::Unavailable(e, name))?
     }
-    pub(crate) fn docker_unit(wc: &WorkerConfiguration) -> String {
-        TEMPLATES
-            .try_with(|t| t.borrow_tt().render(DOCKER_UNIT_TEMPLATE, &wc).unwrap())
-            .unwrap()
+
+    pub(crate) fn worker_config(wc: &WorkerConfiguration) -> Result<String, TemplateError> {
+        Self::render(WORKER_CONFIG_TEMPLATE, wc)
+    }
+    pub(crate) fn docker_unit(wc: &WorkerConfiguration) -> Result<String, TemplateError> {
+        Self::render(DOCKER_UNIT_TEMPLATE, wc)
     }
 
-    pub(crate) fn docker_daemon(wc: &WorkerConfiguration) -> String {
-        TEMPLATES
-            .try_with(|t| {
-                t.borrow_tt()
-                    .render(DOCKER_DAEMON_CONFIG_TEMPLATE, &wc)
-                    .unwrap()
-            })
-            .unwrap()
+    pub(crate) fn docker_daemon(wc: &WorkerConfiguration) -> Result<String, TemplateError> {
+        Self::render(DOCKER_DAEMON_CONFIG_TEMPLATE, wc)
     }
-    pub(crate) fn network_config(wc: &WorkerConfiguration) -> String {
-        TEMPLATES
-            .try_with(|t| {
-                t.borrow_tt()
-                    .render(NETWORK_CONFIGURATION_TEMPLATE, &wc)
-                    .unwrap()
-            })
-            .unwrap()
+    pub(crate) fn network_config(wc: &WorkerConfiguration) -> Result<String, TemplateError> {
+        Self::render(NETWORK_CONFIGURATION_TEMPLATE, wc)
+    }
+    // Only for workers with a bond
+    pub(crate) fn bond_netdev(wc: &WorkerConfiguration) -> Result<String, TemplateError> {
+        Self::render(BOND_NETDEV_TEMPLATE, wc)
+    }
+    pub(crate) fn bond_members(wc: &WorkerConfiguration) -> Result<String, TemplateError> {
+        Self::render(BOND_MEMBERS_TEMPLATE, wc)
+    }
+
+    pub(crate) fn coordinator_config(
+        cc: &CoordinatorConfiguration,
+    ) -> Result<String, TemplateError> {
+        Self::render(COORDINATOR_CONFIG_TEMPLATE, cc)
+    }
+
+    // Renders every template with a representative configuration, so a broken template is
+    // reported at startup instead of failing the first launch that uses it
+    pub(crate) fn self_test() -> Result<(), TemplateError> {
+        let mut wc = WorkerConfiguration {
+            ip_addr: IpAddr::from([10, 0, 0, 2]),
+            host_ip_addr: IpAddr::from([10, 0, 0, 1]),
+            worker_id: 2,
+            parent_id: 1,
+            sources: vec![TCPSourceConfigBuilder::default()
+                .logical_source_name("self_test".to_string())
+                .socket_port(8080)
+                .build()
+                .unwrap()
+                .into()],
+            timeouts: CoordinatorTimeouts {
+                coordinator_health_check_wait_time: Some(10),
+                worker_health_check_wait_time: Some(10),
+            },
+            ..Default::default()
+        };
+        Self::worker_config(&wc)?;
+        Self::docker_unit(&wc)?;
+        Self::docker_daemon(&wc)?;
+        Self::network_config(&wc)?;
+        wc.bond = Some(BondConfiguration {
+            mode: BondMode::BalanceRr,
+            mac: "52:54:00:00:00:01".to_string(),
+            member_macs: vec!["52:54:00:00:00:01".to_string()],
+        });
+        Self::network_config(&wc)?;
+        Self::bond_netdev(&wc)?;
+        Self::bond_members(&wc)?;
+        Self::coordinator_config(&CoordinatorConfiguration {
+            logical_sources: vec![LogicalSource {
+                logical_source_name: "self_test".to_string(),
+                fields: vec![SchemaField {
+                    name: "id".to_string(),
+                    field_type: FieldType::Uint64,
+                }],
+            }],
+        })?;
+        Ok(())
     }
 }
 
-#[derive(Serialize)]
+#[test]
+fn self_test() {
+    Templates::self_test().unwrap();
+}
+
+// The parts of the coordinator's config a topology defines itself, so experiments do not rely
+// on the coordinator already knowing the schemas
+#[derive(Serialize, Clone, Default)]
+pub(crate) struct CoordinatorConfiguration {
+    pub(crate) logical_sources: Vec<LogicalSource>,
+}
+
+#[test]
+fn logical_sources() {
+    let cc = CoordinatorConfiguration {
+        logical_sources: vec![LogicalSource {
+            logical_source_name: "bid".to_string(),
+            fields: vec![
+                SchemaField {
+                    name: "id".to_string(),
+                    field_type: FieldType::Uint64,
+                },
+                SchemaField {
+                    name: "price".to_string(),
+                    field_type: FieldType::Float64,
+                },
+            ],
+        }],
+    };
+    assert_eq!(
+        Templates::coordinator_config(&cc).unwrap(),
+        indoc! {"
+            logicalSources:
+              - logicalSourceName: bid
+                fields:
+                  - name: id
+                    type: UINT64
+                  - name: price
+                    type: FLOAT64
+        "}
+    );
+    assert_eq!(
+        Templates::coordinator_config(&CoordinatorConfiguration::default())
+            .unwrap()
+            .trim(),
+        ""
+    );
+}
+
+#[derive(Serialize, Clone)]
 pub(crate) struct WorkerConfiguration {
     pub(crate) ip_addr: IpAddr,
     pub(crate) host_ip_addr: IpAddr,
@@ -102,6 +227,135 @@ pub(crate) struct WorkerConfiguration {
     pub(crate) sources: Vec<Source>,
     pub(crate) log_level: &'static str,
     pub(crate) query_processing: WorkerQueryProcessingConfigurationInternal,
+    pub(crate) ports: WorkerPorts,
+    pub(crate) timeouts: CoordinatorTimeouts,
+    #[serde(skip)]
+    pub(crate) config_file: WorkerConfigFile,
+    pub(crate) extra_units: Vec<ExtraUnit>,
+    pub(crate) extra_files: Vec<ExtraFile>,
+    // the guest network runs over a bond of several taps instead of eth0
+    pub(crate) bond: Option<BondConfiguration>,
+}
+
+// Bonding modes of systemd-networkd, which configures the network of the flatcar guest.
+// 802.3ad and balance-xor need a switch which aggregates the links, the host bridge treats the
+// taps as unrelated ports, so scripts asking for them are rejected.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BondMode {
+    BalanceRr,
+    ActiveBackup,
+    Broadcast,
+    BalanceTlb,
+    BalanceAlb,
+}
+
+// The guest bonds the nics with the member macs into bond0, which takes over eth0's address
+#[derive(Debug, Serialize, Clone)]
+pub(crate) struct BondConfiguration {
+    pub(crate) mode: BondMode,
+    pub(crate) mac: String,
+    pub(crate) member_macs: Vec<String>,
+}
+
+// Additional systemd unit for the flatcar guest, e.g. a metrics exporter next to the worker
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExtraUnit {
+    pub(crate) name: String,
+    pub(crate) contents: String,
+    #[serde(default = "unit_enabled")]
+    pub(crate) enabled: bool,
+}
+
+fn unit_enabled() -> bool {
+    true
+}
+
+// Additional file staged into the flatcar guest
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExtraFile {
+    pub(crate) path: PathBuf,
+    pub(crate) contents: String,
+}
+
+// worker_config.yaml is either rendered from the template or a complete file which is staged
+// as is, for NES settings the template does not cover
+#[derive(Debug, Clone, Default)]
+pub(crate) enum WorkerConfigFile {
+    #[default]
+    Rendered,
+    Raw(String),
+}
+
+// A worker without sources or extras, the launcher fills in the address and ids
+impl Default for WorkerConfiguration {
+    fn default() -> Self {
+        WorkerConfiguration {
+            ip_addr: IpAddr::from([0, 0, 0, 0]),
+            host_ip_addr: IpAddr::from([0, 0, 0, 0]),
+            worker_id: 0,
+            parent_id: 0,
+            sources: vec![],
+            log_level: "LOG_INFO",
+            query_processing: WorkerQueryProcessingConfigurationInternal::default(),
+            ports: WorkerPorts::default(),
+            timeouts: CoordinatorTimeouts::default(),
+            config_file: WorkerConfigFile::Rendered,
+            extra_units: vec![],
+            extra_files: vec![],
+            bond: None,
+        }
+    }
+}
+
+impl WorkerConfiguration {
+    pub(crate) fn worker_config_yaml(&self) -> Result<String, TemplateError> {
+        match &self.config_file {
+            WorkerConfigFile::Rendered => Templates::worker_config(self),
+            WorkerConfigFile::Raw(content) => Ok(content.clone()),
+        }
+    }
+}
+
+// Seconds between health checks of the coordinator connection, left to NES if unset. Slow
+// bring-ups of many workers at once need longer waits to register reliably.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct CoordinatorTimeouts {
+    pub(crate) coordinator_health_check_wait_time: Option<u64>,
+    pub(crate) worker_health_check_wait_time: Option<u64>,
+}
+
+// Ports of the NES worker, the same for flatcar and unikernel workers
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct WorkerPorts {
+    pub(crate) data_port: u16,
+    pub(crate) rpc_port: u16,
+    pub(crate) coordinator_port: u16,
+}
+
+impl Default for WorkerPorts {
+    fn default() -> Self {
+        WorkerPorts {
+            data_port: 8432,
+            rpc_port: 8433,
+            coordinator_port: 8434,
+        }
+    }
+}
+
+impl WorkerPorts {
+    // Command line flags of the worker binary, for workers without a config file
+    pub(crate) fn as_args(&self) -> Vec<String> {
+        vec![
+            format!("--dataPort={}", self.data_port),
+            format!("--rpcPort={}", self.rpc_port),
+            format!("--coordinatorPort={}", self.coordinator_port),
+        ]
+    }
 }
 
 #[test]
@@ -109,10 +363,6 @@ fn physical_sources() {
     let wc = WorkerConfiguration {
         ip_addr: IpAddr::from([10, 0, 0, 1]),
         host_ip_addr: IpAddr::from([10, 0, 0, 2]),
-        worker_id: 0,
-        parent_id: 0,
-        sources: vec![],
-        log_level: "LOG_INFO",
         query_processing: WorkerQueryProcessingConfigurationBuilder::default()
             .buffer_size(8192)
             .total_number_of_buffers(4096)
@@ -122,10 +372,11 @@ fn physical_sources() {
             .build()
             .unwrap()
             .into(),
+        ..Default::default()
     };
 
     assert_eq!(
-        &Templates::worker_config(&wc),
+        &Templates::worker_config(&wc).unwrap(),
         indoc! {r#"
                 logLevel: LOG_INFO
                 localWorkerIp: 10.0.0.1
@@ -147,14 +398,11 @@ fn physical_sources() {
     let wc = WorkerConfiguration {
         ip_addr: IpAddr::from([10, 0, 0, 1]),
         host_ip_addr: IpAddr::from([10, 0, 0, 2]),
-        worker_id: 0,
-        parent_id: 0,
         log_level: "LOG_DEBUG",
-        sources: vec![],
-        query_processing: WorkerQueryProcessingConfigurationInternal::default(),
+        ..Default::default()
     };
     assert_eq!(
-        &Templates::worker_config(&wc),
+        &Templates::worker_config(&wc).unwrap(),
         indoc! {r#"
                 logLevel: LOG_DEBUG
                 localWorkerIp: 10.0.0.1
@@ -182,3 +430,52 @@ fn physical_sources() {
                 "#}
     );
 }
+
+#[test]
+fn worker_ports() {
+    let ports: WorkerPorts = serde_yaml::from_str("rpcPort: 9433").unwrap();
+    assert_eq!(
+        ports.as_args(),
+        vec![
+            "--dataPort=8432",
+            "--rpcPort=9433",
+            "--coordinatorPort=8434"
+        ]
+    );
+}
+
+#[test]
+fn configured_worker_ports() {
+    let wc = WorkerConfiguration {
+        ports: WorkerPorts {
+            coordinator_port: 9434,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let config = Templates::worker_config(&wc).unwrap();
+    assert!(config.contains("dataPort: 8432\nrpcPort: 8433\ncoordinatorPort: 9434\n"));
+}
+
+#[test]
+fn coordinator_timeouts() {
+    let timeouts: CoordinatorTimeouts =
+        serde_yaml::from_str("coordinatorHealthCheckWaitTime: 10").unwrap();
+    let wc = WorkerConfiguration {
+        timeouts,
+        ..Default::default()
+    };
+
+    let config = Templates::worker_config(&wc).unwrap();
+    assert!(config.contains("\ncoordinatorHealthCheckWaitTime: 10\n"));
+    assert!(!config.contains("workerHealthCheckWaitTime"));
+}
+
+#[test]
+fn raw_worker_config() {
+    let wc = WorkerConfiguration {
+        config_file: WorkerConfigFile::Raw("logLevel: LOG_TRACE\n".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(wc.worker_config_yaml().unwrap(), "logLevel: LOG_TRACE\n");
+}
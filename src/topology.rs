@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// longest name which keeps `<name>-tap<segment>_<id>` within the 15 bytes of an interface name
+const MAX_NAME_LENGTH: usize = 6;
+
+// Namespaces the bridges, taps, qemu processes and vm directories of a launcher, so several
+// experiments can share a host and be reset independently. Without a name the plain tbr0 and
+// tap1 names are used.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Topology {
+    name: Option<String>,
+}
+
+impl FromStr for Topology {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = s.len() <= MAX_NAME_LENGTH
+            && s.starts_with(|c: char| c.is_ascii_lowercase())
+            && s.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+        if !valid {
+            return Err(format!(
+                "expected up to {MAX_NAME_LENGTH} lowercase letters and digits, starting with a letter, got {s}"
+            ));
+        }
+        Ok(Topology {
+            name: Some(s.to_string()),
+        })
+    }
+}
+
+impl Topology {
+    pub(crate) fn bridge_prefix(&self) -> String {
+        match self.name.as_ref() {
+            Some(name) => format!("{name}-br"),
+            None => "tbr".to_string(),
+        }
+    }
+
+    pub(crate) fn tap_prefix(&self) -> String {
+        match self.name.as_ref() {
+            Some(name) => format!("{name}-tap"),
+            None => "tap".to_string(),
+        }
+    }
+
+    // 0 is the default network, segments start at 1
+    pub(crate) fn bridge(&self, segment: usize) -> String {
+        format!("{}{segment}", self.bridge_prefix())
+    }
+
+    pub(crate) fn segment_tap_prefix(&self, segment: usize) -> String {
+        format!("{}{segment}_", self.tap_prefix())
+    }
+
+    // `<root>/<name>/`, so the vm directories and sockets of different topologies don't collide
+    pub(crate) fn namespaced_dir(&self, root: &Path) -> PathBuf {
+        match self.name.as_ref() {
+            Some(name) => root.join(name),
+            None => root.to_path_buf(),
+        }
+    }
+}
+
+#[test]
+fn topology_names() {
+    let default = Topology::default();
+    assert_eq!(default.bridge(0), "tbr0");
+    assert_eq!(default.tap_prefix(), "tap");
+    assert_eq!(default.segment_tap_prefix(2), "tap2_");
+    assert_eq!(
+        default.namespaced_dir(Path::new("/run/vml")),
+        Path::new("/run/vml")
+    );
+
+    let named: Topology = "exp1".parse().unwrap();
+    assert_eq!(named.bridge(1), "exp1-br1");
+    assert_eq!(named.segment_tap_prefix(1), "exp1-tap1_");
+    assert_eq!(
+        named.namespaced_dir(Path::new("/run/vml")),
+        Path::new("/run/vml/exp1")
+    );
+
+    assert!("".parse::<Topology>().is_err());
+    assert!("1exp".parse::<Topology>().is_err());
+    assert!("exp-1".parse::<Topology>().is_err());
+    assert!("toolong".parse::<Topology>().is_err());
+}
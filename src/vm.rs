@@ -0,0 +1,42 @@
+use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::pin::Pin;
+
+use thiserror::Error;
+
+use crate::firecracker::FirecrackerError;
+use crate::network::TapUser;
+use crate::qemu::{GdbStub, QemuError, QemuProcessHandle, StopTimeouts, VmStatus};
+
+#[derive(Error, Debug)]
+pub(crate) enum VmError {
+    #[error("Qemu Error")]
+    Qemu(#[source] QemuError),
+    #[error("Firecracker Error")]
+    Firecracker(#[source] FirecrackerError),
+}
+
+pub(crate) type VmFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, VmError>> + Send + 'a>>;
+
+// A running vm, whichever hypervisor it runs on. Launching is specific to the hypervisor and
+// connects the console, everything the launcher does with a vm afterwards goes through this.
+pub(crate) trait VmHandle: Debug + Display + Send + Sync {
+    fn tap(&self) -> &TapUser;
+    fn pid(&self) -> Option<usize>;
+    fn gdb(&self) -> Option<GdbStub> {
+        None
+    }
+    fn status(&self) -> VmFuture<'_, VmStatus>;
+    fn is_running(&self) -> VmFuture<'_, bool> {
+        Box::pin(async move { Ok(matches!(self.status().await?, VmStatus::Running { .. })) })
+    }
+    fn stop(&self, timeouts: StopTimeouts) -> VmFuture<'_, ()>;
+    fn restart(&mut self) -> VmFuture<'_, ()>;
+    // Hotplug, migration and snapshots go through qemu's monitor
+    fn as_qemu(&self) -> Option<&QemuProcessHandle> {
+        None
+    }
+    fn as_qemu_mut(&mut self) -> Option<&mut QemuProcessHandle> {
+        None
+    }
+}